@@ -4,31 +4,49 @@ extern crate alga;
 extern crate nalgebra as na;
 extern crate ncollide3d;
 extern crate nrays;
-extern crate png;
 
 use na::{Isometry3, Perspective3, Point2, Point3, Unit, Vector2, Vector3};
-use ncollide3d::bounding_volume::{HasBoundingVolume, AABB};
-use ncollide3d::query::RayCast;
-use ncollide3d::shape::{Ball, Capsule, Cone, Cuboid, Cylinder, Plane, SupportMap, TriMesh};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::shape::{Ball, Capsule, Cone, Cuboid, Cylinder, Plane};
+use nrays::bidirectional;
+use nrays::csg::{Csg, CsgOp};
+use nrays::curves::{self, CurveKind, Curves};
+use nrays::displacement::{self, EdgeLength, Tessellator};
+use nrays::hair_material::{HairMaterial, HairModel};
+use nrays::heightfield::Heightfield;
 use nrays::light::Light;
+use nrays::math::Scalar;
 use nrays::material::Material;
+use nrays::medium::{DensityGrid, Medium};
+use nrays::mesh::{self, Mesh, NormalWeighting};
+use nrays::minkowski_sum::{MinkowskiSum, Summand};
 use nrays::mtl;
+use nrays::normal_map::NormalMap;
 use nrays::normal_material::NormalMaterial;
 use nrays::obj;
 use nrays::phong_material::PhongMaterial;
+use nrays::photon_map::{self, PhotonMap};
+use nrays::point_cloud::{self, PointCloud, PointShape};
+use nrays::postfx::{Bloom, ChromaticAberration, FilmGrain, PostProcess, Sharpen, Vignette};
+use nrays::primitives::{BoundedPlane, Disk, Hyperboloid, Paraboloid, Quad, Torus, Triangle};
 use nrays::scene;
 use nrays::scene::Scene;
 use nrays::scene_node::SceneNode;
+use nrays::sdf::{Sdf, SdfGeometry};
+use nrays::subdivision;
+use nrays::subsurface_material::SubsurfaceMaterial;
+use nrays::triangle_mesh::{self, MeshPart, TriangleMesh};
 use nrays::texture2d::{Interpolation, Overflow, Texture2d};
 use nrays::uv_material::UVMaterial;
 use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::iter::Enumerate;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::str::SplitWhitespace;
+use std::str::{Lines, SplitWhitespace};
 use std::sync::Arc;
 
 fn main() {
@@ -54,11 +72,13 @@ fn main() {
 
     let _ = file.read_to_string(&mut descr);
 
-    let (lights, nodes, cameras) = parse(&descr[..]);
+    let (lights, nodes, cameras, postfx, fog, caustics) = parse(&descr[..]);
     let nnodes = nodes.len();
     let nlights = lights.len();
     let ncams = cameras.len();
-    let scene = Arc::new(Scene::new(nodes, lights, Vector3::from_element(1.0)));
+    let mut scene = Scene::new(nodes, lights, Vector3::from_element(1.0));
+    scene.set_medium(fog);
+    let scene = Arc::new(scene);
     println!(
         "Scene loaded. {} lights, {} objects, {} cameras.",
         nlights, nnodes, ncams
@@ -72,7 +92,7 @@ fn main() {
             100000.0,
         ).unwrap();
 
-        let camera = Isometry3::<f64>::look_at_rh(&c.eye, &c.at, &Vector3::y());
+        let camera = Isometry3::<Scalar>::look_at_rh(&c.eye, &c.at, &Vector3::y());
 
         let projection = (perspective * camera.to_homogeneous())
             .try_inverse()
@@ -83,20 +103,71 @@ fn main() {
             c.aa.x as usize, c.aa.y
         );
 
-        let pixels = scene::render(
-            &scene,
-            &c.resolution,
-            c.aa.x as usize,
-            c.aa.y,
-            c.eye,
-            projection,
-        );
+        // Caustics are traced by the bidirectional path tracer itself.
+        let caustics = if c.bidirectional.is_some() {
+            None
+        } else {
+            caustics
+        };
+
+        let mut pixels = match c.bidirectional {
+            Some(max_depth) => bidirectional::render(
+                &scene,
+                &c.resolution,
+                c.aa.x as usize,
+                c.eye,
+                projection,
+                max_depth,
+            ),
+            None => scene::render(
+                &scene,
+                &c.resolution,
+                c.aa.x as usize,
+                c.aa.y,
+                c.eye,
+                projection,
+            ),
+        };
+
+        // Progressive photon mapping: each pass traces a new photon map with a smaller radius,
+        // and the caustics are the average of the estimates of the passes at the points seen
+        // from the camera.
+        if let Some(params) = caustics {
+            let points = photon_map::visible_points(
+                &scene,
+                &c.resolution,
+                c.aa.x as usize,
+                c.aa.y,
+                c.eye,
+                projection,
+            );
+            let mut acc = vec![Vector3::new(0.0f32, 0.0, 0.0); pixels.pixels().len()];
+
+            for pass in 0..params.passes {
+                let radius = photon_map::progressive_radius(params.radius, pass, params.alpha);
+                let map = PhotonMap::trace_caustics(&scene, params.nphotons, radius);
+                println!("Pass {}: {} caustic photons stored.", pass, map.len());
+
+                map.splat(&points[..], &mut acc[..]);
+            }
+
+            for (p, a) in pixels.pixels_mut().iter_mut().zip(acc.iter()) {
+                *p = *p + *a / params.passes as f32;
+            }
+        }
 
         println!("Rays cast.");
 
+        if !postfx.is_empty() {
+            println!("Applying post-processing effects.");
+            postfx.apply(&mut pixels);
+        }
+
         println!("Saving image to: {}", c.output);
-        pixels.to_png(&Path::new(&c.output[..]));
-        println!("Image saved.");
+        match pixels.save(&Path::new(&c.output[..])) {
+            Ok(()) => println!("Image saved."),
+            Err(e) => panic!("Failed to save the output image {}: {}", c.output, e),
+        }
     }
 }
 
@@ -111,36 +182,85 @@ enum Mode {
     LightMode,
     ShapeMode,
     CameraMode,
+    PostFxMode,
     NoMode,
 }
 
 #[derive(Clone)]
 enum Shape {
-    GBall(f64),
-    GPlane(Vector3<f64>),
-    GCuboid(Vector3<f64>),
-    GCylinder(f64, f64),
-    GCapsule(f64, f64),
-    GCone(f64, f64),
+    GBall(Scalar),
+    GPlane(Vector3<Scalar>),
+    GCuboid(Vector3<Scalar>),
+    GCylinder(Scalar, Scalar),
+    GCapsule(Scalar, Scalar),
+    GCone(Scalar, Scalar),
     GObj(String, String),
+    GTorus(Scalar, Scalar),
+    GDisk(Scalar, Scalar),
+    GQuad(Point3<Scalar>, Vector3<Scalar>, Vector3<Scalar>),
+    GTriangle(Point3<Scalar>, Point3<Scalar>, Point3<Scalar>),
+    GParaboloid(Scalar, Scalar),
+    GHyperboloid(Scalar, Scalar, Scalar),
+    GBoundedPlane(Vector3<Scalar>, Vector2<Scalar>),
+    GHeightfield(String, Vector2<Scalar>, Scalar),
+    GCurves(String, CurveKind),
+    GPoints(String, Scalar, PointShape),
+    GSdf(Sdf),
+    GCsg(CsgShape),
+}
+
+#[derive(Clone)]
+enum CsgShape {
+    // The material name comes with the line it is given at.
+    Leaf(Shape, Isometry3<Scalar>, Option<(usize, String)>),
+    Node(CsgOp, Box<CsgShape>, Box<CsgShape>),
+}
+
+#[derive(Clone)]
+enum Effect {
+    EBloom(Scalar, Scalar, Scalar),
+    EVignette(Scalar),
+    EChromaticAberration(Scalar),
+    EFilmGrain(Scalar),
+    ESharpen(Scalar),
+}
+
+// The parameters of the caustics photon mapping, with one photon map per pass.
+#[derive(Clone, Copy)]
+struct Caustics {
+    nphotons: usize,
+    radius: Scalar,
+    passes: usize,
+    alpha: Scalar,
+}
+
+// The target length of the edges of a displaced mesh: in world units, or in pixels of the
+// first camera.
+#[derive(Clone, Copy)]
+enum DisplaceEdge {
+    World(Scalar),
+    Pixels(Scalar),
 }
 
 struct Camera {
-    eye: Point3<f64>,
-    at: Point3<f64>,
-    fovy: f64,
-    resolution: Vector2<f64>,
-    aa: Vector2<f64>,
+    eye: Point3<Scalar>,
+    at: Point3<Scalar>,
+    fovy: Scalar,
+    resolution: Vector2<Scalar>,
+    aa: Vector2<Scalar>,
+    // The maximum depth of the paths of a bidirectional path tracer, if one renders the image.
+    bidirectional: Option<usize>,
     output: String,
 }
 
 impl Camera {
     pub fn new(
-        eye: Point3<f64>,
-        at: Point3<f64>,
-        fovy: f64,
-        resolution: Vector2<f64>,
-        aa: Vector2<f64>,
+        eye: Point3<Scalar>,
+        at: Point3<Scalar>,
+        fovy: Scalar,
+        resolution: Vector2<Scalar>,
+        aa: Vector2<Scalar>,
+        bidirectional: Option<usize>,
         output: String,
     ) -> Camera {
         assert!(
@@ -154,6 +274,7 @@ impl Camera {
             fovy: fovy,
             resolution: resolution,
             aa: aa,
+            bidirectional: bidirectional,
             output: output,
         }
     }
@@ -162,20 +283,29 @@ impl Camera {
 struct Properties {
     superbloc: usize,
     geom: Vec<(usize, Shape)>,
-    pos: Option<(usize, Point3<f64>)>,
-    angle: Option<(usize, Vector3<f64>)>,
+    effects: Vec<(usize, Effect)>,
+    pos: Option<(usize, Point3<Scalar>)>,
+    angle: Option<(usize, Vector3<Scalar>)>,
     material: Option<(usize, String)>,
-    eye: Option<(usize, Point3<f64>)>,
-    at: Option<(usize, Point3<f64>)>,
-    fovy: Option<(usize, f64)>,
-    color: Option<(usize, Point3<f64>)>,
-    resolution: Option<(usize, Vector2<f64>)>,
+    eye: Option<(usize, Point3<Scalar>)>,
+    at: Option<(usize, Point3<Scalar>)>,
+    fovy: Option<(usize, Scalar)>,
+    color: Option<(usize, Point3<Scalar>)>,
+    resolution: Option<(usize, Vector2<Scalar>)>,
     output: Option<(usize, String)>,
-    refl: Option<(usize, Vector2<f64>)>,
-    refr: Option<(usize, f64)>,
-    aa: Option<(usize, Vector2<f64>)>,
-    radius: Option<(usize, f64)>,
-    nsample: Option<(usize, f64)>,
+    refl: Option<(usize, Vector2<Scalar>)>,
+    refr: Option<(usize, Scalar)>,
+    aa: Option<(usize, Vector2<Scalar>)>,
+    bidirectional: Option<(usize, usize)>,
+    radius: Option<(usize, Scalar)>,
+    nsample: Option<(usize, Scalar)>,
+    name: Option<(usize, String)>,
+    define: Option<(usize, String)>,
+    instance: Option<(usize, String)>,
+    displace: Option<(usize, (String, Scalar, Option<DisplaceEdge>))>,
+    subdiv: Option<(usize, (usize, Option<Scalar>))>,
+    subsurface: Option<(usize, Vector3<Scalar>)>,
+    interior: Option<(usize, Arc<Medium>)>,
     solid: bool,
 }
 
@@ -184,6 +314,7 @@ impl Properties {
         Properties {
             superbloc: l,
             geom: Vec::new(),
+            effects: Vec::new(),
             pos: None,
             angle: None,
             material: None,
@@ -196,8 +327,16 @@ impl Properties {
             refl: None,
             refr: None,
             aa: None,
+            bidirectional: None,
             radius: None,
             nsample: None,
+            name: None,
+            define: None,
+            instance: None,
+            displace: None,
+            subdiv: None,
+            subsurface: None,
+            interior: None,
             solid: false,
         }
     }
@@ -211,15 +350,31 @@ fn warn(line: usize, err: &str) {
     println!("At line {}: {}", line, err)
 }
 
-fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
+fn parse(
+    string: &str,
+) -> (
+    Vec<Light>,
+    Vec<Arc<SceneNode>>,
+    Vec<Camera>,
+    PostProcess,
+    Option<Arc<Medium>>,
+    Option<Caustics>,
+) {
     let mut nodes = Vec::new();
     let mut lights = Vec::new();
     let mut cameras = Vec::new();
+    let mut postfx = PostProcess::new();
+    let mut named = HashMap::new();
     let mut props = Properties::new(0);
     let mut mode = Mode::NoMode;
     let mut mtllib = HashMap::new();
+    let mut media = HashMap::new();
+    let mut fog = None;
+    let mut caustics = None;
+
+    let mut lines = string.lines().enumerate();
 
-    for (l, line) in string.lines().enumerate() {
+    while let Some((l, line)) = lines.next() {
         let mut words = line.split_whitespace();
         let tag = words.next();
 
@@ -255,6 +410,10 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                     match w {
                         // top-level commands
                         "mtllib" => register_mtllib(&parse_name(l, words)[..], &mut mtllib),
+                        "hair" => register_hair(l, words, &mut mtllib),
+                        "medium" => register_medium(l, words, &mut media),
+                        "fog" => fog = Some(find_medium(l, words, &media)),
+                        "caustics" => caustics = Some(parse_caustics(l, words)),
                         "light" => {
                             let old = mem::replace(&mut props, Properties::new(l));
                             register(
@@ -264,6 +423,8 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                                 &mut lights,
                                 &mut nodes,
                                 &mut cameras,
+                                &mut postfx,
+                                &mut named,
                             );
                             mode = Mode::LightMode;
                         }
@@ -276,6 +437,8 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                                 &mut lights,
                                 &mut nodes,
                                 &mut cameras,
+                                &mut postfx,
+                                &mut named,
                             );
                             mode = Mode::ShapeMode;
                         }
@@ -288,9 +451,25 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                                 &mut lights,
                                 &mut nodes,
                                 &mut cameras,
+                                &mut postfx,
+                                &mut named,
                             );
                             mode = Mode::CameraMode;
                         }
+                        "postfx" => {
+                            let old = mem::replace(&mut props, Properties::new(l));
+                            register(
+                                &mode,
+                                old,
+                                &mut mtllib,
+                                &mut lights,
+                                &mut nodes,
+                                &mut cameras,
+                                &mut postfx,
+                                &mut named,
+                            );
+                            mode = Mode::PostFxMode;
+                        }
                         // common attributes
                         "color" => {
                             props.color =
@@ -313,6 +492,9 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                         "refl" => props.refl = Some((l, parse_duet(l, words))),
                         "refr" => props.refr = Some((l, parse_number(l, words))),
                         "aa" => props.aa = Some((l, parse_duet(l, words))),
+                        "bidirectional" => {
+                            props.bidirectional = Some((l, parse_max_depth(l, words)))
+                        }
                         "radius" => props.radius = Some((l, parse_number(l, words))),
                         "nsample" => props.nsample = Some((l, parse_number(l, words))),
                         // geometries
@@ -322,8 +504,35 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
                         "cylinder" => props.geom.push((l, parse_cylinder(l, words))),
                         "capsule" => props.geom.push((l, parse_capsule(l, words))),
                         "cone" => props.geom.push((l, parse_cone(l, words))),
+                        "torus" => props.geom.push((l, parse_torus(l, words))),
+                        "disk" => props.geom.push((l, parse_disk(l, words))),
+                        "quad" => props.geom.push((l, parse_quad(l, words))),
+                        "triangle" => props.geom.push((l, parse_triangle(l, words))),
+                        "paraboloid" => props.geom.push((l, parse_paraboloid(l, words))),
+                        "hyperboloid" => props.geom.push((l, parse_hyperboloid(l, words))),
+                        "bounded_plane" => props.geom.push((l, parse_bounded_plane(l, words))),
+                        "heightfield" => props.geom.push((l, parse_heightfield(l, words))),
+                        "curves" => props.geom.push((l, parse_curves(l, words))),
+                        "points" => props.geom.push((l, parse_points(l, words))),
                         "obj" => props.geom.push((l, parse_obj(l, words))),
+                        "sdf" => props.geom.push((l, parse_sdf(l, words, &mut lines))),
+                        "csg" => props.geom.push((l, parse_csg(l, words, &mut lines))),
                         "solid" => props.solid = true,
+                        "interior" => props.interior = Some((l, find_medium(l, words, &media))),
+                        "name" => props.name = Some((l, parse_name(l, words))),
+                        "define" => props.define = Some((l, parse_name(l, words))),
+                        "instance" => props.instance = Some((l, parse_name(l, words))),
+                        "displace" => props.displace = Some((l, parse_displace(l, words))),
+                        "subdiv" => props.subdiv = Some((l, parse_subdiv(l, words))),
+                        "subsurface" => props.subsurface = Some((l, parse_triplet(l, words))),
+                        // post-processing effects
+                        "bloom" => props.effects.push((l, parse_bloom(l, words))),
+                        "vignette" => props.effects.push((l, parse_vignette(l, words))),
+                        "chromatic_aberration" => {
+                            props.effects.push((l, parse_chromatic_aberration(l, words)))
+                        }
+                        "grain" => props.effects.push((l, parse_grain(l, words))),
+                        "sharpen" => props.effects.push((l, parse_sharpen(l, words))),
                         _ => {
                             println!("Warning: unknown line {} ignored: `{}'", l, line);
                         }
@@ -340,9 +549,11 @@ fn parse(string: &str) -> (Vec<Light>, Vec<Arc<SceneNode>>, Vec<Camera>) {
         &mut lights,
         &mut nodes,
         &mut cameras,
+        &mut postfx,
+        &mut named,
     );
 
-    (lights, nodes, cameras)
+    (lights, nodes, cameras, postfx, fog, caustics)
 }
 
 fn register(
@@ -352,11 +563,49 @@ fn register(
     lights: &mut Vec<Light>,
     nodes: &mut Vec<Arc<SceneNode>>,
     cameras: &mut Vec<Camera>,
+    postfx: &mut PostProcess,
+    named: &mut HashMap<String, Vec<Arc<SceneNode>>>,
 ) {
     match *mode {
         Mode::LightMode => register_light(props, lights),
-        Mode::ShapeMode => register_geometry(props, mtllib, nodes),
+        Mode::ShapeMode => {
+            let first = nodes.len();
+            // A defined geometry is only a template for instances, and is not rendered itself.
+            let name = match (props.name.clone(), props.define.clone()) {
+                (Some(_), Some((l, _))) => error(l, "a geometry cannot be both named and defined."),
+                (name, None) => name.map(|(_, name)| (name, true)),
+                (None, Some((_, name))) => Some((name, false)),
+            };
+            let interior = props.interior.clone();
+
+            if props.instance.is_some() {
+                register_instance(props, mtllib, named, nodes)
+            } else {
+                register_geometry(props, mtllib, &cameras[..], nodes)
+            }
+
+            if let Some((l, medium)) = interior {
+                for node in nodes[first..].iter_mut() {
+                    let node = Arc::get_mut(node).unwrap();
+
+                    if !node.solid {
+                        error(l, "interior media are only supported on solid geometries.");
+                    }
+
+                    node.medium = Some(medium.clone());
+                }
+            }
+
+            if let Some((name, rendered)) = name {
+                let _ = named.insert(name, nodes[first..].to_vec());
+
+                if !rendered {
+                    nodes.truncate(first);
+                }
+            }
+        }
         Mode::CameraMode => register_camera(props, cameras),
+        Mode::PostFxMode => register_postfx(props, postfx),
         Mode::NoMode => register_nothing(props),
     }
 }
@@ -388,7 +637,16 @@ fn fail_if_none<T>(t: &Option<(usize, T)>, l: usize, attrname: &str) {
 }
 
 fn register_nothing(props: Properties) {
+    warn_if_some(&props.name);
+    warn_if_some(&props.define);
+    warn_if_some(&props.interior);
+    warn_if_some(&props.instance);
+    warn_if_some(&props.displace);
+    warn_if_some(&props.subdiv);
+    warn_if_some(&props.subsurface);
+    warn_if_some(&props.bidirectional);
     warn_if_not_empty(&props.geom[..]);
+    warn_if_not_empty(&props.effects[..]);
     warn_if_some(&props.pos);
     warn_if_some(&props.angle);
     warn_if_some(&props.material);
@@ -406,7 +664,15 @@ fn register_nothing(props: Properties) {
 }
 
 fn register_camera(props: Properties, cameras: &mut Vec<Camera>) {
+    warn_if_some(&props.name);
+    warn_if_some(&props.define);
+    warn_if_some(&props.interior);
+    warn_if_some(&props.instance);
+    warn_if_some(&props.displace);
+    warn_if_some(&props.subdiv);
+    warn_if_some(&props.subsurface);
     warn_if_not_empty(&props.geom[..]);
+    warn_if_not_empty(&props.effects[..]);
     warn_if_some(&props.pos);
     warn_if_some(&props.angle);
     warn_if_some(&props.material);
@@ -429,12 +695,22 @@ fn register_camera(props: Properties, cameras: &mut Vec<Camera>) {
     let fov = props.fovy.unwrap().1;
     let res = props.resolution.unwrap().1;
     let name = props.output.unwrap().1;
+    let bidirectional = props.bidirectional.map(|b| b.1);
 
-    cameras.push(Camera::new(eye, at, fov, res, aa.1, name));
+    cameras.push(Camera::new(eye, at, fov, res, aa.1, bidirectional, name));
 }
 
 fn register_light(props: Properties, lights: &mut Vec<Light>) {
+    warn_if_some(&props.name);
+    warn_if_some(&props.define);
+    warn_if_some(&props.interior);
+    warn_if_some(&props.instance);
+    warn_if_some(&props.displace);
+    warn_if_some(&props.subdiv);
+    warn_if_some(&props.subsurface);
+    warn_if_some(&props.bidirectional);
     warn_if_not_empty(&props.geom[..]);
+    warn_if_not_empty(&props.effects[..]);
     warn_if_some(&props.angle);
     warn_if_some(&props.material);
     warn_if_some(&props.eye);
@@ -458,6 +734,42 @@ fn register_light(props: Properties, lights: &mut Vec<Light>) {
     lights.push(light);
 }
 
+fn register_postfx(props: Properties, postfx: &mut PostProcess) {
+    warn_if_some(&props.name);
+    warn_if_some(&props.define);
+    warn_if_some(&props.interior);
+    warn_if_some(&props.instance);
+    warn_if_some(&props.displace);
+    warn_if_some(&props.subdiv);
+    warn_if_some(&props.subsurface);
+    warn_if_some(&props.bidirectional);
+    warn_if_not_empty(&props.geom[..]);
+    warn_if_some(&props.pos);
+    warn_if_some(&props.angle);
+    warn_if_some(&props.material);
+    warn_if_some(&props.eye);
+    warn_if_some(&props.at);
+    warn_if_some(&props.fovy);
+    warn_if_some(&props.color);
+    warn_if_some(&props.output);
+    warn_if_some(&props.resolution);
+    warn_if_some(&props.refl);
+    warn_if_some(&props.refr);
+    warn_if_some(&props.aa);
+    warn_if_some(&props.radius);
+    warn_if_some(&props.nsample);
+
+    for (_, e) in props.effects.into_iter() {
+        match e {
+            Effect::EBloom(t, i, r) => postfx.push(Bloom::new(t as f32, i as f32, r as f32)),
+            Effect::EVignette(s) => postfx.push(Vignette::new(s as f32)),
+            Effect::EChromaticAberration(s) => postfx.push(ChromaticAberration::new(s as f32)),
+            Effect::EFilmGrain(s) => postfx.push(FilmGrain::new(s as f32)),
+            Effect::ESharpen(a) => postfx.push(Sharpen::new(a as f32)),
+        }
+    }
+}
+
 fn register_mtllib(
     path: &str,
     mtllib: &mut HashMap<String, (f32, Arc<Box<Material + 'static + Send + Sync>>)>,
@@ -484,34 +796,264 @@ fn register_mtllib(
         });
 
         let alpha = m.alpha;
+        let color = mtl_material(&m, t, a);
 
-        let color = Box::new(PhongMaterial::new(
+        mtllib.insert(m.name, (alpha, color));
+    }
+}
+
+// The material described by an mtl material: with subsurface scattering if it has a mean free
+// path, Phong otherwise.
+fn mtl_material(
+    m: &mtl::MtlMaterial,
+    texture: Option<Texture2d>,
+    alpha: Option<Texture2d>,
+) -> Arc<Box<Material + 'static + Send + Sync>> {
+    let material = match m.mean_free_path {
+        Some(mfp) => Box::new(SubsurfaceMaterial::new(m.ambiant, m.diffuse, mfp, texture))
+            as Box<Material + 'static + Send + Sync>,
+        None => Box::new(PhongMaterial::new(
             m.ambiant,
             m.diffuse,
             m.specular,
-            t,
-            a,
+            texture,
+            alpha,
             m.shininess,
-        )) as Box<Material + 'static + Send + Sync>;
+        )) as Box<Material + 'static + Send + Sync>,
+    };
+
+    Arc::new(material)
+}
+
+// Registers a hair material, with an ambiant color of a tenth of its color:
+//
+//     hair <name> kajiya_kay <r> <g> <b> <specular_r> <specular_g> <specular_b> <exponent>
+//     hair <name> marschner <r> <g> <b> <cuticle_tilt_degrees> <roughness_degrees>
+fn register_hair<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+    mtllib: &mut HashMap<String, (f32, Arc<Box<Material + 'static + Send + Sync>>)>,
+) {
+    let name = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a material name was expected."));
+    let model = ws
+        .next()
+        .unwrap_or_else(|| error(l, "`kajiya_kay' or `marschner' was expected."));
+    let (color, model) = match model {
+        "kajiya_kay" => {
+            let v = parse_numbers(l, &mut ws, 7);
+            let specular = Point3::new(v[3] as f32, v[4] as f32, v[5] as f32);
+
+            (Point3::new(v[0], v[1], v[2]), HairModel::KajiyaKay(specular, v[6] as f32))
+        }
+        "marschner" => {
+            let v = parse_numbers(l, &mut ws, 5);
+            let shift = v[3].to_radians() as f32;
+            let roughness = v[4].to_radians() as f32;
+
+            (Point3::new(v[0], v[1], v[2]), HairModel::Marschner(shift, roughness))
+        }
+        _ => error(l, &format!("unknown hair model: {}", model)[..]),
+    };
+
+    let color = Point3::new(color.x as f32, color.y as f32, color.z as f32);
+    let ambiant = Point3::from_coordinates(color.coords * 0.1);
+    let material = Box::new(HairMaterial::new(ambiant, color, model))
+        as Box<Material + 'static + Send + Sync>;
+
+    mtllib.insert(name.to_string(), (1.0, Arc::new(material)));
+}
+
+// Registers a participating medium, homogeneous or scaled by a grid of densities read from a
+// raw voxel file and spanning the given box:
+//
+//     medium <name> <absorption_r> <absorption_g> <absorption_b>
+//                   <scattering_r> <scattering_g> <scattering_b> <asymmetry>
+//                   [grid <path> <nx> <ny> <nz> <minx> <miny> <minz> <maxx> <maxy> <maxz>]
+fn register_medium<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+    media: &mut HashMap<String, Arc<Medium>>,
+) {
+    let name = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a medium name was expected."));
+    let v = parse_numbers(l, &mut ws, 7);
+    let sigma_a = Vector3::new(v[0] as f32, v[1] as f32, v[2] as f32);
+    let sigma_s = Vector3::new(v[3] as f32, v[4] as f32, v[5] as f32);
+    let g = v[6] as f32;
+
+    if v[..6].iter().any(|s| *s < 0.0) {
+        error(l, "the coefficients of a medium must be non-negative.");
+    }
 
-        mtllib.insert(m.name, (alpha, Arc::new(color)));
+    if g <= -1.0 || g >= 1.0 {
+        error(l, "the asymmetry of a medium must range from -1 to 1, excluded.");
+    }
+
+    let medium = match ws.next() {
+        None => Medium::new_homogeneous(sigma_a, sigma_s, g),
+        Some("grid") => {
+            let path = ws
+                .next()
+                .unwrap_or_else(|| error(l, "a path and 9 numbers were expected, found 0."));
+            let v = parse_numbers(l, &mut ws, 9);
+            let dims = Vector3::new(v[0] as usize, v[1] as usize, v[2] as usize);
+            let bounds = AABB::new(Point3::new(v[3], v[4], v[5]), Point3::new(v[6], v[7], v[8]));
+
+            if dims.x < 2 || dims.y < 2 || dims.z < 2 {
+                error(l, "a density grid must have at least 2 samples along each axis.");
+            }
+
+            let grid = DensityGrid::from_raw_file(&Path::new(path), dims, bounds)
+                .unwrap_or_else(|e| error(l, &format!("failed to load {}: {}", path, e)[..]));
+
+            Medium::new_heterogeneous(sigma_a, sigma_s, g, grid)
+        }
+        Some(w) => error(l, &format!("`grid' was expected, found: {}", w)[..]),
+    };
+
+    let _ = media.insert(name.to_string(), Arc::new(medium));
+}
+
+fn find_medium<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+    media: &HashMap<String, Arc<Medium>>,
+) -> Arc<Medium> {
+    let name = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a medium name was expected."));
+
+    match media.get(name) {
+        Some(medium) => medium.clone(),
+        None => error(l, &format!("unknown medium: {}", name)[..]),
+    }
+}
+
+// Parses the parameters of the caustics, with 1 pass and alpha = 2/3 by default:
+//
+//     caustics <nphotons> <radius> [<passes> [<alpha>]]
+fn parse_caustics<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Caustics {
+    let v = parse_numbers(l, &mut ws, 2);
+    let rest: Vec<Scalar> = ws
+        .map(|w| {
+            Scalar::from_str(w)
+                .unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", w)[..]))
+        })
+        .collect();
+
+    if rest.len() > 2 {
+        warn(l, "dropped unexpected caustics parameters.");
+    }
+
+    let passes = rest.get(0).map(|p| *p as usize).unwrap_or(1);
+    let alpha = rest.get(1).cloned().unwrap_or(2.0 / 3.0);
+
+    if v[1] <= 0.0 || passes == 0 || alpha <= 0.0 || alpha >= 1.0 {
+        error(l, "the radius and passes must be positive, and alpha between 0 and 1.");
+    }
+
+    Caustics {
+        nphotons: v[0] as usize,
+        radius: v[1],
+        passes: passes,
+        alpha: alpha,
+    }
+}
+
+fn parse_transform(props: &Properties) -> Isometry3<Scalar> {
+    let pos = props.pos.as_ref().unwrap().1;
+    let mut angle = props.angle.as_ref().unwrap().1;
+
+    angle.x = angle.x.to_radians();
+    angle.y = angle.y.to_radians();
+    angle.z = angle.z.to_radians();
+
+    Isometry3::new(pos.coords, angle)
+}
+
+fn register_instance(
+    props: Properties,
+    mtllib: &mut HashMap<String, (f32, Arc<Box<Material + 'static + Send + Sync>>)>,
+    named: &HashMap<String, Vec<Arc<SceneNode>>>,
+    nodes: &mut Vec<Arc<SceneNode>>,
+) {
+    warn_if_not_empty(&props.geom[..]);
+    warn_if_not_empty(&props.effects[..]);
+    warn_if_some(&props.eye);
+    warn_if_some(&props.at);
+    warn_if_some(&props.fovy);
+    warn_if_some(&props.color);
+    warn_if_some(&props.output);
+    warn_if_some(&props.resolution);
+    warn_if_some(&props.displace);
+    warn_if_some(&props.subdiv);
+    warn_if_some(&props.subsurface);
+    warn_if_some(&props.bidirectional);
+    warn_if_some(&props.aa);
+    warn_if_some(&props.radius);
+    warn_if_some(&props.nsample);
+
+    fail_if_none(&props.pos, props.superbloc, "pos <x> <y> <z>");
+    fail_if_none(&props.angle, props.superbloc, "angle <x> <y> <z>");
+
+    let (l, ref gname) = *props.instance.as_ref().unwrap();
+    let template = named
+        .get(gname)
+        .unwrap_or_else(|| error(l, &format!("unknown geometry: {}", gname)[..]));
+
+    let material = props.material.as_ref().map(|&(l, ref mname)| match mtllib.get(mname) {
+        None => error(l, &format!("attempted to use an unknown material: {}", *mname)[..]),
+        Some(&(ref a, ref m)) => (a.clone(), m.clone()),
+    });
+
+    let transform = parse_transform(&props);
+
+    // Every node of the template shares the same transform, which the instance replaces.
+    for t in template.iter() {
+        let mut node = t.instance(transform);
+
+        // The material also overrides the materials of the parts of a mesh, e.g., of the
+        // groups of an OBJ file.
+        if let Some((ref alpha, ref m)) = material {
+            node.alpha = *alpha;
+            node.material_override = Some(m.clone());
+        }
+
+        if let Some((_, refl)) = props.refl {
+            node.refl_mix = refl.x as f32;
+            node.refl_atenuation = refl.y as f32;
+        }
+
+        if let Some((_, refr)) = props.refr {
+            node.refr_coeff = refr;
+        }
+
+        node.solid = node.solid || props.solid;
+
+        nodes.push(Arc::new(node));
     }
 }
 
 fn register_geometry(
     props: Properties,
     mtllib: &mut HashMap<String, (f32, Arc<Box<Material + 'static + Send + Sync>>)>,
+    cameras: &[Camera],
     nodes: &mut Vec<Arc<SceneNode>>,
 ) {
+    warn_if_not_empty(&props.effects[..]);
     warn_if_some(&props.eye);
     warn_if_some(&props.at);
     warn_if_some(&props.fovy);
     warn_if_some(&props.color);
     warn_if_some(&props.output);
     warn_if_some(&props.resolution);
+    warn_if_some(&props.bidirectional);
 
     fail_if_none(&props.pos, props.superbloc, "pos <x> <y> <z>");
-    fail_if_none(&props.angle, props.superbloc, "color <r> <g> <b>");
+    fail_if_none(&props.angle, props.superbloc, "angle <x> <y> <z>");
     fail_if_empty(
         &props.geom[..],
         props.superbloc,
@@ -542,14 +1084,7 @@ fn register_geometry(
             }
         }
 
-        let pos = props.pos.as_ref().unwrap().1;
-        let mut angle = props.angle.as_ref().unwrap().1;
-
-        angle.x = angle.x.to_radians();
-        angle.y = angle.y.to_radians();
-        angle.z = angle.z.to_radians();
-
-        transform = Isometry3::new(pos.coords, angle);
+        transform = parse_transform(&props);
         normals = None;
 
         let refl_param = props
@@ -560,34 +1095,75 @@ fn register_geometry(
         refl_a = refl_param.y as f32;
 
         let refr_param = props.refr.unwrap_or((props.superbloc, 1.0)).1;
-        refr_c = refr_param as f64;
+        refr_c = refr_param as Scalar;
+    }
+
+    if let Some((l, _)) = props.displace {
+        match props.geom[0].1 {
+            Shape::GObj(..) => {}
+            _ => warn(l, "displacement is only supported on obj meshes."),
+        }
+    }
+
+    if let Some((l, _)) = props.subdiv {
+        match props.geom[0].1 {
+            Shape::GObj(..) => {}
+            _ => warn(l, "subdivision is only supported on obj meshes."),
+        }
+    }
+
+    if let Some((l, _)) = props.subsurface {
+        match props.geom[0].1 {
+            Shape::GObj(..) => {}
+            _ => warn(l, "subsurface scattering is only supported on obj meshes."),
+        }
+
+        // The random walks need to find the surface from the inside of the geometry.
+        if solid {
+            error(l, "subsurface scattering is not supported on solid geometries.");
+        }
+
+        if special {
+            warn(l, "subsurface scattering is ignored by the uvs and normals materials.");
+        }
     }
 
     if props.geom.len() > 1 {
-        let mut geoms = Vec::new();
+        let mut summands = Vec::new();
 
         for &(ref l, ref g) in props.geom.iter() {
-            match *g {
-                Shape::GBall(ref r) => {
-                    geoms.push(Box::new(Ball::new(*r)) as Box<SupportMapShape + Send + Sync>)
+            let summand = match *g {
+                Shape::GBall(r) => Box::new(Ball::new(r)) as Summand,
+                Shape::GCuboid(rs) => Box::new(Cuboid::new(rs)) as Summand,
+                Shape::GCylinder(h, r) => Box::new(Cylinder::new(h, r)) as Summand,
+                Shape::GCapsule(h, r) => Box::new(Capsule::new(h, r)) as Summand,
+                Shape::GCone(h, r) => Box::new(Cone::new(h, r)) as Summand,
+                _ => {
+                    warn(*l, "unsupported geometry on a Minkowski sum.");
+                    continue;
                 }
-                Shape::GCuboid(ref rs) => {
-                    geoms.push(Box::new(Cuboid::new(*rs)) as Box<SupportMapShape + Send + Sync>)
-                }
-                Shape::GCylinder(ref h, ref r) => geoms
-                    .push(Box::new(Cylinder::new(*h, *r)) as Box<SupportMapShape + Send + Sync>),
-                Shape::GCapsule(ref h, ref r) => {
-                    geoms.push(Box::new(Capsule::new(*h, *r)) as Box<SupportMapShape + Send + Sync>)
-                }
-                Shape::GCone(ref h, ref r) => {
-                    geoms.push(Box::new(Cone::new(*h, *r)) as Box<SupportMapShape + Send + Sync>)
-                }
-                _ => println!(
-                    "Warning: unsuported geometry on a minkosky sum at line {}.",
-                    *l
-                ),
-            }
+            };
+
+            summands.push((Isometry3::identity(), summand));
+        }
+
+        if summands.is_empty() {
+            error(props.superbloc, "a Minkowski sum needs at least one convex geometry.")
         }
+
+        nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(MinkowskiSum::new(summands)),
+            normals,
+            solid,
+        )));
+
+        return;
     }
 
     match props.geom[0].1.clone() {
@@ -657,28 +1233,186 @@ fn register_geometry(
             normals,
             solid,
         ))),
+        Shape::GTorus(big, small) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Torus::new(big, small)),
+            normals,
+            solid,
+        ))),
+        Shape::GDisk(r, inner) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Disk::new_annulus(r, inner)),
+            normals,
+            solid,
+        ))),
+        Shape::GQuad(o, u, v) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Quad::new(o, u, v)),
+            normals,
+            solid,
+        ))),
+        Shape::GTriangle(a, b, c) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Triangle::new(a, b, c)),
+            normals,
+            solid,
+        ))),
+        Shape::GParaboloid(r, h) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Paraboloid::new(r, h)),
+            normals,
+            solid,
+        ))),
+        Shape::GHyperboloid(w, r, h) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(Hyperboloid::new(w, r, h)),
+            normals,
+            solid,
+        ))),
+        Shape::GBoundedPlane(n, he) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(BoundedPlane::new(Unit::new_normalize(n), he)),
+            normals,
+            solid,
+        ))),
+        Shape::GHeightfield(tname, extents, scale) => {
+            let l = props.geom[0].0;
+            let texture = load_texture(Path::new(""), &tname[..], false)
+                .unwrap_or_else(|| error(l, &format!("failed to load {}.", tname)[..]));
+
+            if texture.width() < 2 || texture.height() < 2 {
+                error(l, "a heightfield texture must have at least 2 texels along each axis.");
+            }
+
+            nodes.push(Arc::new(SceneNode::new(
+                material,
+                refl_m,
+                refl_a,
+                alpha,
+                refr_c,
+                transform,
+                Box::new(Heightfield::from_texture(&texture, extents, scale)),
+                normals,
+                solid,
+            )))
+        }
+        Shape::GCurves(path, kind) => {
+            let l = props.geom[0].0;
+            let strands = curves::parse_file(&Path::new(&path[..]))
+                .unwrap_or_else(|e| error(l, &format!("failed to load {}: {}", path, e)[..]));
+
+            nodes.push(Arc::new(SceneNode::new(
+                material,
+                refl_m,
+                refl_a,
+                alpha,
+                refr_c,
+                transform,
+                Box::new(Curves::new(&strands[..], kind)),
+                normals,
+                solid,
+            )))
+        }
+        Shape::GPoints(path, radius, shape) => {
+            let l = props.geom[0].0;
+            let data = point_cloud::parse_file(&Path::new(&path[..]))
+                .unwrap_or_else(|e| error(l, &format!("failed to load {}: {}", path, e)[..]));
+
+            nodes.push(Arc::new(SceneNode::new(
+                material,
+                refl_m,
+                refl_a,
+                alpha,
+                refr_c,
+                transform,
+                Box::new(PointCloud::new(data, radius, shape)),
+                normals,
+                solid,
+            )))
+        }
+        Shape::GSdf(sdf) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(SdfGeometry::new(sdf)),
+            normals,
+            solid,
+        ))),
+        Shape::GCsg(csg) => nodes.push(Arc::new(SceneNode::new(
+            material,
+            refl_m,
+            refl_a,
+            alpha,
+            refr_c,
+            transform,
+            Box::new(build_csg(csg, mtllib)),
+            normals,
+            solid,
+        ))),
         Shape::GObj(objpath, mtlpath) => {
             let mtlpath = Path::new(&mtlpath[..]);
             let os = obj::parse_file(&Path::new(&objpath[..]), &mtlpath, "").unwrap();
+            let os = match props.subdiv {
+                Some((l, (levels, crease_angle))) => subdivide_obj(l, os, levels, crease_angle),
+                None => os,
+            };
 
             if os.len() > 0 {
-                let coords: Vec<Point3<f64>> = os[0]
+                let coords: Vec<Point3<Scalar>> = os[0]
                     .1
                     .coords()
                     .iter()
-                    .map(|a| Point3::new(a.x as f64, a.y as f64, a.z as f64) / 4.0f64)
+                    .map(|a| Point3::new(a.x as Scalar, a.y as Scalar, a.z as Scalar) / 4.0)
                     .collect(); // XXX: remove this arbitrary division by 4.0!
-                let uvs: Vec<Point2<f64>> = os[0]
+                let uvs: Vec<Point2<Scalar>> = os[0]
                     .1
                     .uvs()
                     .iter()
-                    .flat_map(|a| vec![Point2::new(a.x as f64, a.y as f64)].into_iter())
+                    .flat_map(|a| vec![Point2::new(a.x as Scalar, a.y as Scalar)].into_iter())
                     .collect();
-                let ns: Vec<Vector3<f64>> = os[0]
+                let ns: Vec<Vector3<Scalar>> = os[0]
                     .1
                     .normals()
                     .iter()
-                    .map(|a| Vector3::new(a.x as f64, a.y as f64, a.z as f64))
+                    .map(|a| Vector3::new(a.x as Scalar, a.y as Scalar, a.z as Scalar))
                     .collect();
 
                 for n in ns.iter() {
@@ -687,111 +1421,248 @@ fn register_geometry(
                     }
                 }
 
-                for (_, o, mat) in os.into_iter() {
-                    let mut o = o;
-                    let faces = o.mut_faces().unwrap();
-                    let mesh: Box<TriMesh<f64>>;
-
-                    mesh = Box::new(TriMesh::new(coords.clone(), faces, Some(uvs.clone())));
-
-                    match mat {
-                        Some(m) => {
-                            let t = match m.diffuse_texture {
-                                None => None,
-                                Some(ref t) => {
-                                    let mut p = PathBuf::new();
-                                    p.push(mtlpath);
-                                    p.push(&t[..]);
-
-                                    {
-                                        let file = File::open(p.clone());
-
-                                        if file.is_err() {
-                                            panic!(format!(
-                                                "Image not found: {}",
-                                                p.to_str().unwrap()
-                                            ));
-                                        }
-                                    }
-
-                                    Texture2d::from_png(
-                                        &p,
-                                        false,
-                                        Interpolation::Bilinear,
-                                        Overflow::Wrap,
-                                    )
+                let groups: Vec<(Vec<Point3<usize>>, Option<mtl::MtlMaterial>)> = os
+                    .into_iter()
+                    .map(|(_, mut o, mat)| (o.mut_faces().unwrap(), mat))
+                    .collect();
+
+                let (coords, ns, uvs, groups) = match props.displace {
+                    Some((l, (ref tname, scale, edge))) => {
+                        let edge_length = edge.map(|edge| match edge {
+                            DisplaceEdge::World(length) => EdgeLength::World(length),
+                            DisplaceEdge::Pixels(pixels) => {
+                                let camera = cameras.first().unwrap_or_else(|| {
+                                    error(l, "edge lengths in pixels need a camera defined first.")
+                                });
+
+                                // The tessellation happens in the local space of the mesh.
+                                EdgeLength::Screen {
+                                    eye: transform.inverse() * camera.eye,
+                                    pixel_angle: camera.fovy.to_radians() / camera.resolution.y,
+                                    pixels: pixels,
                                 }
-                            };
+                            }
+                        });
 
-                            let a = match m.opacity_map {
-                                None => None,
-                                Some(ref a) => {
-                                    let mut p = PathBuf::new();
-                                    p.push(mtlpath);
-                                    p.push(&a[..]);
-
-                                    {
-                                        let file = File::open(p.clone());
-
-                                        if file.is_err() {
-                                            panic!(format!(
-                                                "Image not found: {}",
-                                                p.to_str().unwrap()
-                                            ));
-                                        }
-                                    }
-
-                                    Texture2d::from_png(
-                                        &p,
-                                        true,
-                                        Interpolation::Bilinear,
-                                        Overflow::Wrap,
-                                    )
+                        displace_obj(coords, ns, uvs, groups, tname, scale, edge_length)
+                    }
+                    None => (coords, ns, uvs, groups),
+                };
+
+                let coords = Arc::new(coords);
+                let ns = Arc::new(ns);
+                let uvs = Arc::new(uvs);
+
+                // Tangents are only needed by normal and bump maps.
+                let has_nmap = groups.iter().any(|&(_, ref mat)| {
+                    mat.as_ref()
+                        .map(|m| m.normal_map.is_some() || m.bump_map.is_some())
+                        .unwrap_or(false)
+                });
+
+                let tangents = if has_nmap {
+                    let faces: Vec<Point3<usize>> =
+                        groups.iter().flat_map(|g| g.0.iter().cloned()).collect();
+
+                    Some(Arc::new(triangle_mesh::compute_tangents(
+                        &coords[..],
+                        &ns[..],
+                        &uvs[..],
+                        &faces[..],
+                    )))
+                } else {
+                    None
+                };
+
+                // The groups share a single BVH, their materials and normal maps being given
+                // per triangle. Only groups with different opacities, which is a property of
+                // scene nodes, need different nodes.
+                let mut batches: Vec<(f32, Vec<Point3<usize>>, Vec<MeshPart>, Vec<usize>)> =
+                    Vec::new();
+                // Whether some groups have no mtl material to scatter light below the surface.
+                let mut plain_groups = false;
+
+                for (faces, mat) in groups.into_iter() {
+                    let (alpha, part) = match mat {
+                        Some(mut m) => {
+                            let t = m
+                                .diffuse_texture
+                                .as_ref()
+                                .and_then(|t| load_texture(mtlpath, t, false));
+                            let a = m
+                                .opacity_map
+                                .as_ref()
+                                .and_then(|a| load_texture(mtlpath, a, true));
+                            let nmap = match m.normal_map {
+                                Some(ref n) => {
+                                    load_texture(mtlpath, n, false).map(NormalMap::Tangent)
                                 }
+                                None => m
+                                    .bump_map
+                                    .as_ref()
+                                    .and_then(|b| load_texture(mtlpath, b, false))
+                                    .map(|b| NormalMap::Bump(b, m.bump_multiplier as Scalar)),
                             };
 
-                            let alpha = m.alpha * alpha;
-                            let color = Arc::new(Box::new(PhongMaterial::new(
-                                m.ambiant,
-                                m.diffuse,
-                                m.specular,
-                                t,
-                                a,
-                                m.shininess,
-                            ))
-                                as Box<Material + 'static + Send + Sync>);
-
-                            nodes.push(Arc::new(SceneNode::new(
-                                if special { material.clone() } else { color },
-                                refl_m,
-                                refl_a,
-                                alpha,
-                                refr_c,
-                                transform,
-                                mesh,
-                                None,
-                                solid,
-                            )));
+                            if let Some((_, mfp)) = props.subsurface {
+                                m.mean_free_path = Some(na::convert(mfp));
+                            }
+
+                            let color = mtl_material(&m, t, a);
+                            let part = MeshPart {
+                                material: if special { material.clone() } else { color },
+                                nmap: nmap,
+                            };
+
+                            (m.alpha * alpha, part)
+                        }
+                        None => {
+                            let part = MeshPart {
+                                material: material.clone(),
+                                nmap: None,
+                            };
+
+                            plain_groups = true;
+
+                            (alpha, part)
                         }
-                        None => nodes.push(Arc::new(SceneNode::new(
-                            material.clone(),
-                            refl_m,
-                            refl_a,
-                            alpha,
-                            refr_c,
-                            transform,
-                            mesh,
-                            None,
-                            solid,
-                        ))),
+                    };
+
+                    let ibatch = match batches.iter().position(|b| b.0 == alpha) {
+                        Some(i) => i,
+                        None => {
+                            batches.push((alpha, Vec::new(), Vec::new(), Vec::new()));
+                            batches.len() - 1
+                        }
+                    };
+
+                    let batch = &mut batches[ibatch];
+                    let part_id = batch.2.len();
+
+                    batch.3.extend(faces.iter().map(|_| part_id));
+                    batch.1.extend(faces.into_iter());
+                    batch.2.push(part);
+                }
+
+                if let Some((l, _)) = props.subsurface {
+                    if plain_groups && !special {
+                        warn(l, "subsurface scattering is ignored on groups without mtl material.");
                     }
                 }
+
+                for (alpha, faces, parts, part_ids) in batches.into_iter() {
+                    let mut mesh = TriangleMesh::new(
+                        coords.clone(),
+                        faces,
+                        Some(ns.clone()),
+                        Some(uvs.clone()),
+                    );
+                    mesh.set_tangents(tangents.clone());
+                    mesh.set_parts(parts, part_ids);
+
+                    nodes.push(Arc::new(SceneNode::new(
+                        material.clone(),
+                        refl_m,
+                        refl_a,
+                        alpha,
+                        refr_c,
+                        transform,
+                        Box::new(mesh),
+                        None,
+                        solid,
+                    )));
+                }
             }
         }
     }
 }
 
-fn parse_triplet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector3<f64> {
+// Subdivides every group of an obj file `levels` times, with infinitely sharp creases where
+// adjacent faces make an angle greater than `crease_angle` degrees.
+fn subdivide_obj(
+    l: usize,
+    os: Vec<(String, Mesh, Option<mtl::MtlMaterial>)>,
+    levels: usize,
+    crease_angle: Option<Scalar>,
+) -> Vec<(String, Mesh, Option<mtl::MtlMaterial>)> {
+    if os.is_empty() {
+        return os;
+    }
+
+    let subdivided = {
+        let meshes: Vec<&Mesh> = os.iter().map(|o| &o.1).collect();
+        let creases = crease_angle
+            .map(|a| subdivision::angle_creases(&meshes[..], a.to_radians() as f32))
+            .unwrap_or(Vec::new());
+
+        subdivision::subdivide_shared(&meshes[..], levels, &creases[..])
+            .unwrap_or_else(|e| error(l, &format!("failed to subdivide the mesh: {}.", e)[..]))
+    };
+
+    os.into_iter()
+        .zip(subdivided.into_iter())
+        .map(|((name, _, mat), mesh)| (name, mesh, mat))
+        .collect()
+}
+
+// Tessellates the faces of every group so that their edges are shorter than `edge_length`
+// (the bounding box diagonal divided by 256 by default), then moves the vertices along their
+// normals by the height read from `tname` times `scale`.
+fn displace_obj(
+    coords: Vec<Point3<Scalar>>,
+    ns: Vec<Vector3<Scalar>>,
+    uvs: Vec<Point2<Scalar>>,
+    groups: Vec<(Vec<Point3<usize>>, Option<mtl::MtlMaterial>)>,
+    tname: &str,
+    scale: Scalar,
+    edge_length: Option<EdgeLength>,
+) -> (
+    Vec<Point3<Scalar>>,
+    Vec<Vector3<Scalar>>,
+    Vec<Point2<Scalar>>,
+    Vec<(Vec<Point3<usize>>, Option<mtl::MtlMaterial>)>,
+) {
+    if coords.is_empty() {
+        return (coords, ns, uvs, groups);
+    }
+
+    let texture = load_texture(Path::new(""), tname, false).expect("Image not found.");
+    let edge_length = edge_length.unwrap_or_else(|| {
+        let mins = coords.iter().fold(coords[0], |m, p| na::inf(&m, p));
+        let maxs = coords.iter().fold(coords[0], |m, p| na::sup(&m, p));
+
+        EdgeLength::World(na::distance(&mins, &maxs) / 256.0)
+    });
+
+    // A single tessellator splits the edges shared by several groups the same way.
+    let mut tessellator = Tessellator::new(coords, ns, uvs, edge_length);
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(faces, mat)| (tessellator.tessellate(&faces[..]), mat))
+        .collect();
+    let (mut coords, ns, uvs) = tessellator.unwrap();
+
+    displacement::displace(&mut coords[..], &ns[..], &uvs[..], &texture, scale);
+
+    let faces: Vec<Point3<usize>> = groups.iter().flat_map(|g| g.0.iter().cloned()).collect();
+    let ns = mesh::vertex_normals(&coords[..], &faces[..], NormalWeighting::Area);
+
+    (coords, ns, uvs, groups)
+}
+
+fn load_texture(dir: &Path, name: &str, opacity: bool) -> Option<Texture2d> {
+    let mut p = PathBuf::new();
+    p.push(dir);
+    p.push(name);
+
+    if File::open(p.clone()).is_err() {
+        panic!(format!("Image not found: {}", p.to_str().unwrap()));
+    }
+
+    Texture2d::from_png(&p, opacity, Interpolation::Bilinear, Overflow::Wrap)
+}
+
+fn parse_triplet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector3<Scalar> {
     let sx = ws
         .next()
         .unwrap_or_else(|| error(l, "3 components were expected, found 0."));
@@ -802,13 +1673,13 @@ fn parse_triplet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector3<f64> {
         .next()
         .unwrap_or_else(|| error(l, "3 components were expected, found 2."));
 
-    let x: Result<f64, _> = FromStr::from_str(sx);
-    let y: Result<f64, _> = FromStr::from_str(sy);
-    let z: Result<f64, _> = FromStr::from_str(sz);
+    let x: Result<Scalar, _> = FromStr::from_str(sx);
+    let y: Result<Scalar, _> = FromStr::from_str(sy);
+    let z: Result<Scalar, _> = FromStr::from_str(sz);
 
-    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sx)[..]));
-    let y = y.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sy)[..]));
-    let z = z.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sz)[..]));
+    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sx)[..]));
+    let y = y.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sy)[..]));
+    let z = z.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sz)[..]));
 
     Vector3::new(x, y, z)
 }
@@ -818,19 +1689,37 @@ fn parse_name<'a>(_: usize, ws: SplitWhitespace<'a>) -> String {
     res.join(" ")
 }
 
-fn parse_number<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> f64 {
+// Parses the optional maximum depth of the paths of a bidirectional path tracer:
+//
+//     bidirectional [<maxdepth>]
+//
+// Unlike the default renderer, whose lights do not fall off, the bidirectional path tracer
+// divides the light reaching a point by its squared distance to the light: the color of a light
+// is then its intensity, and the same scene usually needs brighter lights.
+fn parse_max_depth<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> usize {
+    match ws.next() {
+        Some(w) => {
+            let depth: Result<usize, _> = FromStr::from_str(w);
+
+            depth.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a depth.", w)[..]))
+        }
+        None => bidirectional::DEFAULT_MAX_DEPTH,
+    }
+}
+
+fn parse_number<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Scalar {
     let sx = ws
         .next()
         .unwrap_or_else(|| error(l, "1 component was expected, found 0."));
 
-    let x: Result<f64, _> = FromStr::from_str(sx);
+    let x: Result<Scalar, _> = FromStr::from_str(sx);
 
-    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sx)[..]));
+    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sx)[..]));
 
     x
 }
 
-fn parse_duet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector2<f64> {
+fn parse_duet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector2<Scalar> {
     let sx = ws
         .next()
         .unwrap_or_else(|| error(l, "2 components were expected, found 0."));
@@ -838,11 +1727,11 @@ fn parse_duet<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Vector2<f64> {
         .next()
         .unwrap_or_else(|| error(l, "2 components were expected, found 1."));
 
-    let x: Result<f64, _> = FromStr::from_str(sx);
-    let y: Result<f64, _> = FromStr::from_str(sy);
+    let x: Result<Scalar, _> = FromStr::from_str(sx);
+    let y: Result<Scalar, _> = FromStr::from_str(sy);
 
-    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sx)[..]));
-    let y = y.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a f64.", sy)[..]));
+    let x = x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sx)[..]));
+    let y = y.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sy)[..]));
 
     Vector2::new(x, y)
 }
@@ -883,6 +1772,139 @@ fn parse_cone<'a>(l: usize, ws: SplitWhitespace<'a>) -> Shape {
     Shape::GCone(v.x, v.y)
 }
 
+fn parse_torus<'a>(l: usize, ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_duet(l, ws);
+
+    if v.y <= 0.0 || v.x < v.y {
+        error(l, "the tube radius of a torus must be positive and at most its major radius.");
+    }
+
+    Shape::GTorus(v.x, v.y)
+}
+
+fn parse_disk<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let radius = parse_numbers(l, &mut ws, 1)[0];
+
+    // The inner radius is optional.
+    let inner_radius = ws.next().map(|s| {
+        let x: Result<Scalar, _> = FromStr::from_str(s);
+        x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", s)[..]))
+    });
+
+    let inner_radius = inner_radius.unwrap_or(0.0);
+
+    if inner_radius < 0.0 || radius <= inner_radius {
+        error(l, "the radius of a disk must be greater than its non-negative inner radius.");
+    }
+
+    Shape::GDisk(radius, inner_radius)
+}
+
+fn parse_quad<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_numbers(l, &mut ws, 9);
+    let edge_u = Vector3::new(v[3], v[4], v[5]);
+    let edge_v = Vector3::new(v[6], v[7], v[8]);
+
+    if na::norm_squared(&edge_u.cross(&edge_v)) == 0.0 {
+        error(l, "the edges of a quad must not be collinear.");
+    }
+
+    Shape::GQuad(Point3::new(v[0], v[1], v[2]), edge_u, edge_v)
+}
+
+fn parse_triangle<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_numbers(l, &mut ws, 9);
+
+    Shape::GTriangle(
+        Point3::new(v[0], v[1], v[2]),
+        Point3::new(v[3], v[4], v[5]),
+        Point3::new(v[6], v[7], v[8]),
+    )
+}
+
+fn parse_paraboloid<'a>(l: usize, ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_duet(l, ws);
+
+    if v.x <= 0.0 || v.y <= 0.0 {
+        error(l, "the radius and height of a paraboloid must be positive.");
+    }
+
+    Shape::GParaboloid(v.x, v.y)
+}
+
+fn parse_hyperboloid<'a>(l: usize, ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_triplet(l, ws);
+
+    if v.x < 0.0 || v.y <= v.x || v.z <= 0.0 {
+        error(l, "a hyperboloid must be wider at its rims than at its waist, and not flat.");
+    }
+
+    Shape::GHyperboloid(v.x, v.y, v.z)
+}
+
+fn parse_bounded_plane<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let v = parse_numbers(l, &mut ws, 5);
+    let normal = Vector3::new(v[0], v[1], v[2]);
+
+    if na::norm_squared(&normal) == 0.0 {
+        error(l, "the normal of a bounded plane must not be zero.");
+    }
+
+    if v[3] <= 0.0 || v[4] <= 0.0 {
+        error(l, "the half extents of a bounded plane must be positive.");
+    }
+
+    Shape::GBoundedPlane(normal, Vector2::new(v[3], v[4]))
+}
+
+fn parse_heightfield<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let texture = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a texture path and 3 numbers were expected, found 0."));
+    let v = parse_numbers(l, &mut ws, 3);
+
+    if v[0] <= 0.0 || v[1] <= 0.0 {
+        error(l, "the extents of a heightfield must be positive.");
+    }
+
+    Shape::GHeightfield(texture.to_string(), Vector2::new(v[0], v[1]), v[2])
+}
+
+fn parse_curves<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let path = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a path was expected, found 0."));
+
+    // Curves are round by default.
+    let kind = match ws.next() {
+        None | Some("round") => CurveKind::Round,
+        Some("flat") => CurveKind::Flat,
+        Some(k) => error(l, &format!("unknown curve kind: {}", k)[..]),
+    };
+
+    Shape::GCurves(path.to_string(), kind)
+}
+
+fn parse_points<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
+    let path = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a path and a radius were expected, found 0."));
+    let radius = parse_numbers(l, &mut ws, 1)[0];
+
+    if radius <= 0.0 {
+        error(l, "the radius of the points must be positive.");
+    }
+
+    // Points are rendered as disks by default.
+    let shape = match ws.next() {
+        None | Some("disk") => PointShape::Disk,
+        Some("sphere") => PointShape::Sphere,
+        Some(s) => error(l, &format!("unknown point shape: {}", s)[..]),
+    };
+
+    Shape::GPoints(path.to_string(), radius, shape)
+}
+
 fn parse_obj<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
     let objpath = ws
         .next()
@@ -894,13 +1916,348 @@ fn parse_obj<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> Shape {
     Shape::GObj(objpath.to_string(), mtlpath.to_string())
 }
 
-trait SupportMapShape:
-    SupportMap<f64> + 'static + Send + Sync + RayCast<f64> + HasBoundingVolume<f64, AABB<f64>>
-{
+fn parse_subdiv<'a>(l: usize, mut ws: SplitWhitespace<'a>) -> (usize, Option<Scalar>) {
+    let slevels = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a number of levels was expected, found 0."));
+
+    let levels: Result<usize, _> = FromStr::from_str(slevels);
+    let levels = levels
+        .unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as an integer.", slevels)[..]));
+
+    // The crease angle is optional.
+    let crease_angle = ws.next().map(|s| {
+        let x: Result<Scalar, _> = FromStr::from_str(s);
+        x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", s)[..]))
+    });
+
+    (levels, crease_angle)
+}
+
+// Parses `displace <texture> <scale> [<edge_length> | <pixels>px]`.
+fn parse_displace<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+) -> (String, Scalar, Option<DisplaceEdge>) {
+    let texture = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a texture path and a scale were expected, found 0."));
+    let sscale = ws
+        .next()
+        .unwrap_or_else(|| error(l, "a texture path and a scale were expected, found 1."));
+
+    let scale: Result<Scalar, _> = FromStr::from_str(sscale);
+    let scale =
+        scale.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sscale)[..]));
+
+    // The maximum edge length is optional.
+    let edge = ws.next().map(|s| {
+        let (number, pixels) = if s.ends_with("px") {
+            (&s[..s.len() - 2], true)
+        } else {
+            (s, false)
+        };
+        let x: Result<Scalar, _> = FromStr::from_str(number);
+        let x =
+            x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", s)[..]));
+
+        if x <= 0.0 {
+            error(l, "the edge length must be positive.")
+        }
+
+        if pixels {
+            DisplaceEdge::Pixels(x)
+        } else {
+            DisplaceEdge::World(x)
+        }
+    });
+
+    (texture.to_string(), scale, edge)
+}
+
+fn parse_sdf<'a>(l: usize, ws: SplitWhitespace<'a>, lines: &mut Enumerate<Lines<'a>>) -> Shape {
+    let mut fields = parse_sdf_block(l, ws, lines);
+
+    if fields.len() != 1 {
+        error(l, "an sdf block must contain exactly one field.")
+    }
+
+    Shape::GSdf(fields.pop().unwrap())
+}
+
+// Parses the fields of the block opened by a `{' ending the line `l`, up to its closing `}'.
+fn parse_sdf_block<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+    lines: &mut Enumerate<Lines<'a>>,
+) -> Vec<Sdf> {
+    match ws.next() {
+        Some("{") => {}
+        _ => error(l, "`{' was expected."),
+    }
+
+    let mut res = Vec::new();
+
+    loop {
+        let (fl, line) = lines
+            .next()
+            .unwrap_or_else(|| error(l, "this block is never closed."));
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            None => {}
+            Some("}") => return res,
+            Some(w) => {
+                if w.as_bytes()[0] != ('#' as u8) {
+                    res.push(parse_sdf_field(fl, w, words, lines))
+                }
+            }
+        }
+    }
+}
+
+fn parse_sdf_field<'a>(
+    l: usize,
+    tag: &str,
+    mut ws: SplitWhitespace<'a>,
+    lines: &mut Enumerate<Lines<'a>>,
+) -> Sdf {
+    match tag {
+        "sphere" => Sdf::Sphere(parse_number(l, ws)),
+        "box" => Sdf::Cuboid(parse_triplet(l, ws)),
+        "torus" => {
+            let radii = parse_duet(l, ws);
+            Sdf::Torus(radii.x, radii.y)
+        }
+        "round_box" => {
+            let v = parse_numbers(l, &mut ws, 4);
+            Sdf::RoundCuboid(Vector3::new(v[0], v[1], v[2]), v[3])
+        }
+        "union" | "subtract" | "intersect" => {
+            sdf_operation(l, tag, 0.0, parse_sdf_block(l, ws, lines))
+        }
+        "smooth_union" | "smooth_subtract" | "smooth_intersect" => {
+            let k = parse_numbers(l, &mut ws, 1)[0];
+            sdf_operation(l, &tag[7..], k, parse_sdf_block(l, ws, lines))
+        }
+        "twist" => {
+            let k = parse_numbers(l, &mut ws, 1)[0];
+            let s = sdf_operation(l, "union", 0.0, parse_sdf_block(l, ws, lines));
+            Sdf::Twist(Box::new(s), k)
+        }
+        "repeat" => {
+            let v = parse_numbers(l, &mut ws, 6);
+            let s = sdf_operation(l, "union", 0.0, parse_sdf_block(l, ws, lines));
+            Sdf::Repeat(
+                Box::new(s),
+                Vector3::new(v[0], v[1], v[2]),
+                Vector3::new(v[3] as usize, v[4] as usize, v[5] as usize),
+            )
+        }
+        "translate" => {
+            let v = parse_numbers(l, &mut ws, 3);
+            let s = sdf_operation(l, "union", 0.0, parse_sdf_block(l, ws, lines));
+            let m = Isometry3::new(Vector3::new(v[0], v[1], v[2]), na::zero());
+            Sdf::Transform(Box::new(s), m)
+        }
+        "rotate" => {
+            let v = parse_numbers(l, &mut ws, 3);
+            let s = sdf_operation(l, "union", 0.0, parse_sdf_block(l, ws, lines));
+            let angle = Vector3::new(v[0], v[1], v[2]).map(|a| a.to_radians());
+            Sdf::Transform(Box::new(s), Isometry3::new(na::zero(), angle))
+        }
+        _ => error(l, &format!("unknown signed distance field: {}", tag)[..]),
+    }
+}
+
+// Folds the fields of a block with a blending operation.
+fn sdf_operation(l: usize, op: &str, k: Scalar, fields: Vec<Sdf>) -> Sdf {
+    let mut fields = fields.into_iter();
+    let first = fields
+        .next()
+        .unwrap_or_else(|| error(l, "this block contains no field."));
+
+    fields.fold(first, |acc, s| match op {
+        "union" => Sdf::SmoothUnion(Box::new(acc), Box::new(s), k),
+        "subtract" => Sdf::SmoothSubtraction(Box::new(acc), Box::new(s), k),
+        _ => Sdf::SmoothIntersection(Box::new(acc), Box::new(s), k),
+    })
+}
+
+fn parse_numbers<'a>(l: usize, ws: &mut SplitWhitespace<'a>, n: usize) -> Vec<Scalar> {
+    (0..n)
+        .map(|i| {
+            let sx = ws.next().unwrap_or_else(|| {
+                error(
+                    l,
+                    &format!("{} components were expected, found {}.", n, i)[..],
+                )
+            });
+            let x: Result<Scalar, _> = FromStr::from_str(sx);
+
+            x.unwrap_or_else(|_| error(l, &format!("failed to parse `{}' as a number.", sx)[..]))
+        })
+        .collect()
+}
+
+fn parse_csg<'a>(l: usize, mut ws: SplitWhitespace<'a>, lines: &mut Enumerate<Lines<'a>>) -> Shape {
+    let op = parse_csg_op(l, &mut ws);
+    let children = parse_csg_block(l, ws, lines, &Isometry3::identity(), &None);
+
+    Shape::GCsg(csg_operation(l, op, children))
+}
+
+fn parse_csg_op<'a>(l: usize, ws: &mut SplitWhitespace<'a>) -> CsgOp {
+    match ws.next() {
+        Some("union") => CsgOp::Union,
+        Some("intersect") => CsgOp::Intersection,
+        Some("subtract") => CsgOp::Difference,
+        Some(op) => error(l, &format!("unknown csg operation: {}", op)[..]),
+        None => error(l, "a csg operation was expected: union, intersect or subtract."),
+    }
+}
+
+// Parses the children of the block opened by a `{' ending the line `l`, up to its closing `}'.
+// The children are placed by `m` and use the material `mat` unless they override it.
+fn parse_csg_block<'a>(
+    l: usize,
+    mut ws: SplitWhitespace<'a>,
+    lines: &mut Enumerate<Lines<'a>>,
+    m: &Isometry3<Scalar>,
+    mat: &Option<(usize, String)>,
+) -> Vec<CsgShape> {
+    match ws.next() {
+        Some("{") => {}
+        _ => error(l, "`{' was expected."),
+    }
+
+    let mut res = Vec::new();
+
+    loop {
+        let (cl, line) = lines
+            .next()
+            .unwrap_or_else(|| error(l, "this block is never closed."));
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            None => {}
+            Some("}") => return res,
+            Some(w) => {
+                if w.as_bytes()[0] != ('#' as u8) {
+                    res.push(parse_csg_child(cl, w, words, lines, m, mat))
+                }
+            }
+        }
+    }
+}
+
+fn parse_csg_child<'a>(
+    l: usize,
+    tag: &str,
+    mut ws: SplitWhitespace<'a>,
+    lines: &mut Enumerate<Lines<'a>>,
+    m: &Isometry3<Scalar>,
+    mat: &Option<(usize, String)>,
+) -> CsgShape {
+    let leaf = |shape| CsgShape::Leaf(shape, *m, mat.clone());
+
+    match tag {
+        "ball" => leaf(parse_ball(l, ws)),
+        "box" => leaf(parse_box(l, ws)),
+        "cylinder" => leaf(parse_cylinder(l, ws)),
+        "capsule" => leaf(parse_capsule(l, ws)),
+        "cone" => leaf(parse_cone(l, ws)),
+        "torus" => leaf(parse_torus(l, ws)),
+        "sdf" => leaf(parse_sdf(l, ws, lines)),
+        "csg" => {
+            let op = parse_csg_op(l, &mut ws);
+            csg_operation(l, op, parse_csg_block(l, ws, lines, m, mat))
+        }
+        "translate" => {
+            let v = parse_numbers(l, &mut ws, 3);
+            let m = m * Isometry3::new(Vector3::new(v[0], v[1], v[2]), na::zero());
+            csg_operation(l, CsgOp::Union, parse_csg_block(l, ws, lines, &m, mat))
+        }
+        "rotate" => {
+            let v = parse_numbers(l, &mut ws, 3);
+            let angle = Vector3::new(v[0], v[1], v[2]).map(|a| a.to_radians());
+            let m = m * Isometry3::new(na::zero(), angle);
+            csg_operation(l, CsgOp::Union, parse_csg_block(l, ws, lines, &m, mat))
+        }
+        "material" => {
+            let name = ws
+                .next()
+                .unwrap_or_else(|| error(l, "a material name was expected."));
+            let mat = Some((l, name.to_string()));
+            csg_operation(l, CsgOp::Union, parse_csg_block(l, ws, lines, m, &mat))
+        }
+        "plane" | "disk" | "quad" | "triangle" | "paraboloid" | "hyperboloid" | "bounded_plane"
+        | "heightfield" | "curves" | "points" | "obj" => {
+            error(l, "only closed geometries are supported on csg blocks.")
+        }
+        _ => error(l, &format!("unknown csg child: {}", tag)[..]),
+    }
+}
+
+// Folds the children of a block with a boolean operation.
+fn csg_operation(l: usize, op: CsgOp, children: Vec<CsgShape>) -> CsgShape {
+    let mut children = children.into_iter();
+    let first = children
+        .next()
+        .unwrap_or_else(|| error(l, "this block contains no geometry."));
+
+    children.fold(first, |acc, c| CsgShape::Node(op, Box::new(acc), Box::new(c)))
+}
+
+// Converts a parsed CSG tree into its geometry. The alpha of the child materials is ignored.
+fn build_csg(
+    csg: CsgShape,
+    mtllib: &HashMap<String, (f32, Arc<Box<Material + 'static + Send + Sync>>)>,
+) -> Csg {
+    match csg {
+        CsgShape::Leaf(shape, m, mname) => {
+            let material = mname.map(|(l, mname)| match mtllib.get(&mname) {
+                None => error(l, &format!("attempted to use an unknown material: {}", mname)[..]),
+                Some(&(_, ref material)) => material.clone(),
+            });
+
+            match shape {
+                Shape::GBall(r) => Csg::leaf(Ball::new(r), m, material),
+                Shape::GCuboid(rs) => Csg::leaf(Cuboid::new(rs), m, material),
+                Shape::GCylinder(h, r) => Csg::leaf(Cylinder::new(h, r), m, material),
+                Shape::GCapsule(h, r) => Csg::leaf(Capsule::new(h, r), m, material),
+                Shape::GCone(h, r) => Csg::leaf(Cone::new(h, r), m, material),
+                Shape::GTorus(big, small) => Csg::leaf(Torus::new(big, small), m, material),
+                Shape::GSdf(sdf) => Csg::leaf(SdfGeometry::new(sdf), m, material),
+                _ => unreachable!(),
+            }
+        }
+        CsgShape::Node(op, a, b) => Csg::node(op, build_csg(*a, mtllib), build_csg(*b, mtllib)),
+    }
+}
+
+fn parse_bloom<'a>(l: usize, ws: SplitWhitespace<'a>) -> Effect {
+    let v = parse_triplet(l, ws);
+
+    if v.z < 0.0 {
+        error(l, "the bloom radius must be positive.");
+    }
+
+    Effect::EBloom(v.x, v.y, v.z)
+}
+
+fn parse_vignette<'a>(l: usize, ws: SplitWhitespace<'a>) -> Effect {
+    Effect::EVignette(parse_number(l, ws))
+}
+
+fn parse_chromatic_aberration<'a>(l: usize, ws: SplitWhitespace<'a>) -> Effect {
+    Effect::EChromaticAberration(parse_number(l, ws))
+}
+
+fn parse_grain<'a>(l: usize, ws: SplitWhitespace<'a>) -> Effect {
+    Effect::EFilmGrain(parse_number(l, ws))
 }
 
-impl<T> SupportMapShape for T
-where
-    T: SupportMap<f64> + 'static + Send + Sync + RayCast<f64> + HasBoundingVolume<f64, AABB<f64>>,
-{
+fn parse_sharpen<'a>(l: usize, ws: SplitWhitespace<'a>) -> Effect {
+    Effect::ESharpen(parse_number(l, ws))
 }
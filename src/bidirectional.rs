@@ -0,0 +1,710 @@
+//! A bidirectional path tracer: subpaths traced from the camera are connected to subpaths traced
+//! from the lights, and the estimators of every connection are weighted by multiple importance
+//! sampling.
+
+use film::Film;
+use image::{Image, Vless};
+use math::{self, Point, Scalar, Vect};
+use na::{self, Matrix4, Point2, Point3, Point4, Vector3};
+use ncollide3d::query::Ray;
+use num::Bounded;
+use num_cpus;
+use rand::random;
+use ray_with_energy::RayWithEnergy;
+use sampling;
+use scene::Scene;
+use std::cmp;
+use std::f32;
+use std::sync::Arc;
+use std::thread;
+
+/// The default maximum number of bounces of a path.
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+// The maximum number of fully transparent surfaces crossed by a connection.
+const MAX_CROSSINGS: usize = 64;
+
+/// Renders `scene` seen from `camera_eye` by bidirectional path tracing, with `ray_per_pixel`
+/// paths of at most `max_depth` bounces per pixel.
+///
+/// `projection` maps the normalized device coordinates to the world coordinates, like for
+/// `scene::render`. Each camera subpath is connected to a subpath traced from a light chosen at
+/// random, and the connections of the light subpath to the camera are splatted to the pixels
+/// they reach.
+///
+/// The BSDF of a surface mixes the specular reflection and refraction of its node with a
+/// diffuse reflection of the diffuse color of its material, in the proportions used by
+/// `Scene::shade`. Lights are points of intensity `color` jittered over their sphere, and fall
+/// off with the squared distance, unlike with `scene::render`. Participating media only
+/// attenuate the paths.
+pub fn render(
+    scene: &Arc<Scene>,
+    resolution: &Vless,
+    ray_per_pixel: usize,
+    camera_eye: Point,
+    projection: Matrix4<Scalar>,
+    max_depth: usize,
+) -> Image {
+    assert!(ray_per_pixel > 0);
+
+    let camera = Camera::new(camera_eye, projection, resolution);
+    let film = Arc::new(Film::new(resolution));
+    let npixels = film.width() * film.height();
+    let resx = film.width();
+
+    println!("Tracing {} bidirectional paths.", npixels * ray_per_pixel);
+
+    let num_thread = num_cpus::get();
+    let mut children = Vec::new();
+
+    for i in 0..num_thread {
+        let scene = scene.clone();
+        let film = film.clone();
+        let camera = camera.clone();
+        let parts = npixels / num_thread + 1;
+        let low_limit = parts * i;
+        let up_limit = cmp::min(parts * (i + 1), npixels);
+
+        children.push(thread::spawn(move || {
+            for ipt in low_limit..up_limit {
+                let j = ipt / resx;
+                let i = ipt - j * resx;
+                let mut color = na::zero::<Vector3<f32>>();
+
+                for _ in 0usize..ray_per_pixel {
+                    color = color + sample(&scene, &camera, &film, i, j, max_depth);
+                }
+
+                film.add(i, j, &color);
+            }
+        }));
+    }
+
+    for child in children.into_iter() {
+        let _ = child.join();
+    }
+
+    // Light subpaths are splatted once per camera sample: both are averaged the same way.
+    film.to_image(1.0 / ray_per_pixel as f32)
+}
+
+// Traces a camera subpath through the pixel at column `i` and row `j` and a light subpath, and
+// connects them. Returns the contributions to that pixel, and splats the connections of the
+// light subpath to the camera to `film`.
+fn sample(
+    scene: &Scene,
+    camera: &Camera,
+    film: &Film,
+    i: usize,
+    j: usize,
+    max_depth: usize,
+) -> Vector3<f32> {
+    let x = i as Scalar + random::<Scalar>() - 0.5;
+    let y = j as Scalar + random::<Scalar>() - 0.5;
+    let dir = camera.direction(x, y);
+    let pdf = camera.importance(&dir).1;
+
+    let mut eye_path = Vec::with_capacity(max_depth + 1);
+    eye_path.push(Vertex::camera(camera));
+
+    let ray = RayWithEnergy::new(camera.eye, dir);
+    let ones = Vector3::new(1.0, 1.0, 1.0);
+    let escaped = random_walk(scene, ray, ones, pdf, false, max_depth + 1, &mut eye_path);
+
+    // Only the camera subpaths reach the background.
+    let mut res = match escaped {
+        Some(beta) => beta.component_mul(scene.background()),
+        None => na::zero(),
+    };
+
+    if scene.lights().is_empty() {
+        return res;
+    }
+
+    let mut light_path = Vec::with_capacity(max_depth + 1);
+    let light = sample_light(scene);
+    let four_pi = 4.0 * math::PI;
+    let ray = RayWithEnergy::new(light.pt, sampling::uniform_sphere());
+    let beta = light.beta * four_pi as f32;
+
+    light_path.push(light);
+    let _ = random_walk(scene, ray, beta, 1.0 / four_pi, true, max_depth + 1, &mut light_path);
+
+    for t in 1..eye_path.len() + 1 {
+        for s in 1..light_path.len() + 1 {
+            // Lights are not visible.
+            if s + t < 3 || s + t - 2 > max_depth {
+                continue;
+            }
+
+            if t == 1 {
+                if let Some(raster) = camera.raster(&light_path[s - 1].pt) {
+                    let c = connect(scene, camera, &light_path[..s], &eye_path[..1]);
+
+                    if c != na::zero::<Vector3<f32>>() {
+                        film.splat(&raster, &c);
+                    }
+                }
+            } else {
+                res = res + connect(scene, camera, &light_path[..s], &eye_path[..t]);
+            }
+        }
+    }
+
+    res
+}
+
+// The weighted contribution of the path made of the vertices of `light_path`, followed by the
+// vertices of `eye_path` in reverse order.
+//
+// A light subpath with a single vertex is replaced by a new point sampled on a light.
+fn connect(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    eye_path: &[Vertex],
+) -> Vector3<f32> {
+    let sampled;
+    let light_path = if light_path.len() == 1 {
+        sampled = [sample_light(scene)];
+        &sampled[..]
+    } else {
+        light_path
+    };
+
+    let qs = &light_path[light_path.len() - 1];
+    let pt = &eye_path[eye_path.len() - 1];
+    let c = qs
+        .beta
+        .component_mul(&qs.f(camera, pt))
+        .component_mul(&pt.f(camera, qs))
+        .component_mul(&pt.beta);
+
+    if c == na::zero::<Vector3<f32>>() {
+        return c;
+    }
+
+    match geometry(scene, qs, pt) {
+        Some(g) => c.component_mul(&g) * mis_weight(camera, light_path, eye_path) as f32,
+        None => na::zero(),
+    }
+}
+
+// The weight of the connection of `light_path` to `eye_path` by the balance heuristic, among the
+// strategies generating a path of the same length.
+//
+// The ratios between the density of each strategy and the density of this one are the products
+// of the ratios between the reverse and forward densities of the vertices between them.
+fn mis_weight(camera: &Camera, light_path: &[Vertex], eye_path: &[Vertex]) -> Scalar {
+    let s = light_path.len();
+    let t = eye_path.len();
+    let qs = &light_path[s - 1];
+    let pt = &eye_path[t - 1];
+    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+    let pt_minus = if t > 1 { Some(&eye_path[t - 2]) } else { None };
+
+    // The forward and reverse densities of each vertex, and whether it is specular, once the
+    // subpaths are connected.
+    let mut light_pdfs: Vec<_> = light_path
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+    let mut eye_pdfs: Vec<_> = eye_path
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+
+    light_pdfs[s - 1].2 = false;
+    eye_pdfs[t - 1].2 = false;
+    light_pdfs[s - 1].1 = pt.pdf(camera, pt_minus, qs);
+    eye_pdfs[t - 1].1 = qs.pdf(camera, qs_minus, pt);
+
+    if let Some(qs_minus) = qs_minus {
+        light_pdfs[s - 2].1 = qs.pdf(camera, Some(pt), qs_minus);
+    }
+
+    if let Some(pt_minus) = pt_minus {
+        eye_pdfs[t - 2].1 = pt.pdf(camera, Some(qs), pt_minus);
+    }
+
+    // Null densities are those of specular vertices, whose ratios cancel out.
+    let remap = |pdf: Scalar| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+
+    // The camera is a pinhole: paths cannot reach it by themselves.
+    for i in (1..t).rev() {
+        ratio = ratio * remap(eye_pdfs[i].1) / remap(eye_pdfs[i].0);
+
+        if !eye_pdfs[i].2 && !eye_pdfs[i - 1].2 {
+            sum = sum + ratio;
+        }
+    }
+
+    ratio = 1.0;
+
+    // The lights are points: paths cannot reach them by themselves.
+    for i in (0..s).rev() {
+        ratio = ratio * remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+
+        if i > 0 && !light_pdfs[i].2 && !light_pdfs[i - 1].2 {
+            sum = sum + ratio;
+        }
+    }
+
+    1.0 / (1.0 + sum)
+}
+
+// Extends `path` by tracing `ray`, leaving its last vertex with the weight `beta` along a
+// direction sampled with the density `pdf` w.r.t. solid angle, until it has `max_vertices`
+// vertices.
+//
+// Returns the weight of the ray leaving the scene, if any.
+fn random_walk(
+    scene: &Scene,
+    ray: RayWithEnergy,
+    beta: Vector3<f32>,
+    pdf: Scalar,
+    from_light: bool,
+    max_vertices: usize,
+    path: &mut Vec<Vertex>,
+) -> Option<Vector3<f32>> {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    let mut refr = 1.0;
+
+    while path.len() < max_vertices {
+        let (sn, inter) = match scene.cast_ray(&ray.ray, ray.tmin) {
+            Some(hit) => hit,
+            None => {
+                let tr = scene.media_transmittance(&ray.ray, ray.tmin, Bounded::max_value());
+                return Some(beta.component_mul(&tr));
+            }
+        };
+
+        beta = beta.component_mul(&scene.media_transmittance(&ray.ray, ray.tmin, inter.toi));
+
+        let pt = ray.ray.origin + ray.ray.dir * inter.toi;
+        let material = sn.material_at(&inter);
+        let alpha = material
+            .ambiant(&pt, &inter.shading_normal, &inter.uvs)
+            .w * sn.alpha;
+        let mut kd = material
+            .diffuse(&pt, &inter.shading_normal, &inter.uvs)
+            .coords;
+
+        if let Some(c) = inter.color {
+            kd = kd.component_mul(&c.coords);
+        }
+
+        let transmit_prob = 1.0 - alpha as Scalar;
+        let reflect_prob = (alpha * sn.refl_mix) as Scalar;
+        let diffuse_prob = (alpha * (1.0 - sn.refl_mix)) as Scalar;
+        let wo = -ray.ray.dir;
+
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface,
+            pt: pt,
+            error: inter.error,
+            normal: inter.normal,
+            shading_normal: inter.shading_normal,
+            wo: wo,
+            beta: beta,
+            diffuse: kd * diffuse_prob as f32,
+            diffuse_prob: diffuse_prob,
+            delta: false,
+            from_light: from_light,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        };
+
+        let pdf_area = convert_density(pdf_fwd, &path[path.len() - 1], &vertex);
+        vertex.pdf_fwd = pdf_area;
+
+        // Choose a lobe of the BSDF with the weight it has in `Scene::shade`.
+        let u = random::<Scalar>();
+        let mut pdf_rev = 0.0;
+        let dir = if u < transmit_prob {
+            let (n1, n2) = if refr == 1.0 {
+                (1.0, sn.refr_coeff)
+            } else {
+                (sn.refr_coeff, 1.0)
+            };
+
+            refr = n2;
+            vertex.delta = true;
+
+            math::refract(&ray.ray.dir, &inter.shading_normal, n1, n2)
+        } else if u < transmit_prob + reflect_prob || diffuse_prob == 0.0 {
+            vertex.delta = true;
+
+            math::reflect(&ray.ray.dir, &inter.shading_normal)
+        } else {
+            let facing = if na::dot(&wo, &inter.shading_normal) < 0.0 {
+                -inter.shading_normal
+            } else {
+                inter.shading_normal
+            };
+            let dir = sampling::cosine_direction(&facing);
+            let pi = math::PI;
+
+            beta = beta.component_mul(&kd);
+
+            if from_light {
+                beta = beta * shading_correction(&wo, &dir, &inter.normal, &inter.shading_normal);
+            }
+
+            pdf_fwd = diffuse_prob * na::dot(&dir, &facing) / pi;
+            pdf_rev = diffuse_prob * na::dot(&wo, &facing) / pi;
+
+            dir
+        };
+
+        if vertex.delta {
+            pdf_fwd = 0.0;
+        }
+
+        let prev = path.len() - 1;
+        let pdf_area = convert_density(pdf_rev, &vertex, &path[prev]);
+        path[prev].pdf_rev = pdf_area;
+        path.push(vertex);
+
+        if beta == na::zero::<Vector3<f32>>() {
+            break;
+        }
+
+        ray = RayWithEnergy::spawn(&pt, &inter.error, &inter.normal, dir, refr, 1.0);
+    }
+
+    None
+}
+
+// Chooses a light of `scene` uniformly at random, and samples a point of its sphere.
+fn sample_light(scene: &Scene) -> Vertex {
+    let lights = scene.lights();
+    let i = cmp::min(
+        (random::<f32>() * lights.len() as f32) as usize,
+        lights.len() - 1,
+    );
+    let light = &lights[i];
+    let pdf = 1.0 / lights.len() as Scalar;
+
+    Vertex {
+        kind: VertexKind::Light,
+        pt: light.pos + sampling::uniform_sphere() * light.radius,
+        error: na::zero(),
+        normal: na::zero(),
+        shading_normal: na::zero(),
+        wo: na::zero(),
+        beta: light.color.coords / pdf as f32,
+        diffuse: na::zero(),
+        diffuse_prob: 0.0,
+        delta: false,
+        from_light: true,
+        pdf_fwd: pdf,
+        pdf_rev: 0.0,
+    }
+}
+
+// The geometric term between the vertices `a` and `b`, times the transmittance of the segment
+// joining them, or `None` if that segment is blocked.
+fn geometry(scene: &Scene, a: &Vertex, b: &Vertex) -> Option<Vector3<f32>> {
+    let mut dir = b.pt - a.pt;
+    let sq_dist = na::norm_squared(&dir);
+
+    if sq_dist == 0.0 {
+        return None;
+    }
+
+    dir = dir / sq_dist.sqrt();
+
+    let mut g = 1.0 / sq_dist;
+
+    if a.kind != VertexKind::Light {
+        g = g * na::dot(&a.shading_normal, &dir).abs();
+    }
+
+    if b.kind != VertexKind::Light {
+        g = g * na::dot(&b.shading_normal, &dir).abs();
+    }
+
+    transmittance(scene, a, b).map(|tr| tr * g as f32)
+}
+
+// The transmittance of the segment joining the vertices `a` and `b`, or `None` if it is blocked.
+//
+// Fully transparent surfaces that do not bend the light, e.g., the boundaries of the media, do
+// not block it.
+fn transmittance(scene: &Scene, a: &Vertex, b: &Vertex) -> Option<Vector3<f32>> {
+    let orig = a.origin_toward(&b.pt);
+    let mut dir = b.origin_toward(&a.pt) - orig;
+    let dist = dir.normalize_mut();
+    let ray = Ray::new(orig, dir);
+    // The origin is offset from the surface of `a`: only the surfaces crossed need a `tmin`.
+    let mut t = 0.0;
+
+    for _ in 0..MAX_CROSSINGS {
+        let hit = scene.cast_ray(&ray, t);
+
+        match hit {
+            Some((sn, inter)) => {
+                if inter.toi >= dist {
+                    break;
+                }
+
+                let pt = ray.origin + ray.dir * inter.toi;
+                let material = sn.material_at(&inter);
+                let alpha = material
+                    .ambiant(&pt, &inter.shading_normal, &inter.uvs)
+                    .w * sn.alpha;
+
+                if alpha != 0.0 || sn.refr_coeff != 1.0 {
+                    return None;
+                }
+
+                t = inter.toi + na::norm(&inter.error);
+            }
+            None => break,
+        }
+    }
+
+    Some(scene.media_transmittance(&ray, tmin, dist))
+}
+
+// The factor correcting the asymmetry of the BSDFs with shading normals for the importance
+// scattered from `wo` to `wi`, at a surface with the geometric normal `normal`.
+fn shading_correction(wo: &Vect, wi: &Vect, normal: &Vect, shading_normal: &Vect) -> f32 {
+    let num = na::dot(wo, shading_normal) * na::dot(wi, normal);
+    let den = na::dot(wo, normal) * na::dot(wi, shading_normal);
+
+    if den == 0.0 {
+        0.0
+    } else {
+        (num / den).abs() as f32
+    }
+}
+
+// Converts the density `pdf` w.r.t. solid angle of the direction from `from` to `to` into a
+// density w.r.t. the area around `to`.
+fn convert_density(pdf: Scalar, from: &Vertex, to: &Vertex) -> Scalar {
+    let dir = to.pt - from.pt;
+    let sq_dist = na::norm_squared(&dir);
+
+    if sq_dist == 0.0 {
+        return 0.0;
+    }
+
+    let mut res = pdf / sq_dist;
+
+    if to.kind == VertexKind::Surface {
+        res = res * (na::dot(&to.normal, &dir) / sq_dist.sqrt()).abs();
+    }
+
+    res
+}
+
+// A pinhole camera, with a film at distance 1 from its eye.
+#[derive(Clone)]
+struct Camera {
+    eye: Point,
+    forward: Vect,
+    // The area of the film.
+    area: Scalar,
+    resolution: Vless,
+    // From the normalized device coordinates to the world coordinates, and conversely.
+    unprojection: Matrix4<Scalar>,
+    projection: Matrix4<Scalar>,
+}
+
+impl Camera {
+    fn new(eye: Point, unprojection: Matrix4<Scalar>, resolution: &Vless) -> Camera {
+        let projection = unprojection
+            .try_inverse()
+            .expect("The camera projection is not invertible.");
+        let forward = na::normalize(&(unproject(&unprojection, 0.0, 0.0) - eye));
+        let on_film = |x, y| {
+            let dir = unproject(&unprojection, x, y) - eye;
+            dir / na::dot(&dir, &forward)
+        };
+        let width = na::norm(&(on_film(1.0, 0.0) - on_film(-1.0, 0.0)));
+        let height = na::norm(&(on_film(0.0, 1.0) - on_film(0.0, -1.0)));
+
+        Camera {
+            eye: eye,
+            forward: forward,
+            area: width * height,
+            resolution: resolution.clone(),
+            unprojection: unprojection,
+            projection: projection,
+        }
+    }
+
+    // The unit direction of the ray through the raster position `(x, y)`.
+    fn direction(&self, x: Scalar, y: Scalar) -> Vect {
+        let device_x = (x / self.resolution.x - 0.5) * 2.0;
+        let device_y = -(y / self.resolution.y - 0.5) * 2.0;
+
+        na::normalize(&(unproject(&self.unprojection, device_x, device_y) - self.eye))
+    }
+
+    // The raster position where `pt` is seen, if it is in the field of view.
+    fn raster(&self, pt: &Point) -> Option<Point2<Scalar>> {
+        if na::dot(&(*pt - self.eye), &self.forward) <= 0.0 {
+            return None;
+        }
+
+        let h = self.projection * Point4::new(pt.x, pt.y, pt.z, 1.0);
+        let device_x = h.x / h.w;
+        let device_y = h.y / h.w;
+
+        if device_x.abs() > 1.0 || device_y.abs() > 1.0 {
+            return None;
+        }
+
+        Some(Point2::new(
+            (device_x / 2.0 + 0.5) * self.resolution.x,
+            (0.5 - device_y / 2.0) * self.resolution.y,
+        ))
+    }
+
+    // The importance emitted along the unit direction `dir`, and the density of the camera rays
+    // along it w.r.t. solid angle.
+    fn importance(&self, dir: &Vect) -> (Scalar, Scalar) {
+        let cos = na::dot(dir, &self.forward);
+
+        if cos <= 0.0 || self.raster(&(self.eye + *dir)).is_none() {
+            return (0.0, 0.0);
+        }
+
+        let cos3 = cos * cos * cos;
+
+        (1.0 / (self.area * cos3 * cos), 1.0 / (self.area * cos3))
+    }
+}
+
+// The point of the near plane at the normalized device coordinates `(x, y)`.
+fn unproject(unprojection: &Matrix4<Scalar>, x: Scalar, y: Scalar) -> Point {
+    let h = *unprojection * Point4::new(x, y, -1.0, 1.0);
+
+    Point3::from_homogeneous(h.coords).unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// A vertex of a camera or light subpath.
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    pt: Point,
+    // A bound of the error of each coordinate of the point of a surface, zero otherwise.
+    error: Vect,
+    // The geometric and shading normals of a surface, the direction of the camera, or zero for
+    // a light.
+    normal: Vect,
+    shading_normal: Vect,
+    // The unit direction toward the previous vertex of the subpath.
+    wo: Vect,
+    // The product of the BSDFs and cosines divided by the densities since the subpath start.
+    beta: Vector3<f32>,
+    // The diffuse reflectance of the BSDF, and the probability to sample its diffuse lobe.
+    diffuse: Vector3<f32>,
+    diffuse_prob: Scalar,
+    // Whether the subpath left this vertex along a specular lobe.
+    delta: bool,
+    // Whether this vertex belongs to a light subpath, transporting importance.
+    from_light: bool,
+    // The densities w.r.t. area of this vertex sampled from the previous vertex of its subpath,
+    // and from the next one.
+    pdf_fwd: Scalar,
+    pdf_rev: Scalar,
+}
+
+impl Vertex {
+    fn camera(camera: &Camera) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            pt: camera.eye,
+            error: na::zero(),
+            normal: camera.forward,
+            shading_normal: camera.forward,
+            wo: na::zero(),
+            beta: Vector3::new(1.0, 1.0, 1.0),
+            diffuse: na::zero(),
+            diffuse_prob: 0.0,
+            delta: false,
+            from_light: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    // The origin of a ray leaving this vertex toward `target`.
+    fn origin_toward(&self, target: &Point) -> Point {
+        if self.kind == VertexKind::Surface {
+            math::offset_ray_origin(&self.pt, &self.error, &self.normal, &(*target - self.pt))
+        } else {
+            self.pt
+        }
+    }
+
+    // The BSDF, importance or emission of this vertex toward the vertex `next`.
+    fn f(&self, camera: &Camera, next: &Vertex) -> Vector3<f32> {
+        let wi = na::normalize(&(next.pt - self.pt));
+
+        match self.kind {
+            VertexKind::Camera => Vector3::from_element(camera.importance(&wi).0 as f32),
+            VertexKind::Light => Vector3::new(1.0, 1.0, 1.0),
+            VertexKind::Surface => {
+                let cos_o = na::dot(&self.wo, &self.shading_normal);
+                let cos_i = na::dot(&wi, &self.shading_normal);
+
+                // The diffuse lobe only reflects.
+                if cos_o * cos_i <= 0.0 {
+                    return na::zero();
+                }
+
+                let f = self.diffuse / f32::consts::PI;
+
+                if self.from_light {
+                    f * shading_correction(&self.wo, &wi, &self.normal, &self.shading_normal)
+                } else {
+                    f
+                }
+            }
+        }
+    }
+
+    // The density w.r.t. area of sampling the vertex `next` from this vertex, reached from the
+    // vertex `prev`.
+    fn pdf(&self, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> Scalar {
+        let wn = na::normalize(&(next.pt - self.pt));
+        let pi = math::PI;
+
+        let pdf = match self.kind {
+            VertexKind::Camera => camera.importance(&wn).1,
+            VertexKind::Light => 1.0 / (4.0 * pi),
+            VertexKind::Surface => {
+                let wp = match prev {
+                    Some(prev) => na::normalize(&(prev.pt - self.pt)),
+                    None => return 0.0,
+                };
+                let cos_p = na::dot(&wp, &self.shading_normal);
+                let cos_n = na::dot(&wn, &self.shading_normal);
+
+                if cos_p * cos_n <= 0.0 {
+                    0.0
+                } else {
+                    self.diffuse_prob * cos_n.abs() / pi
+                }
+            }
+        };
+
+        convert_density(pdf, self, next)
+    }
+}
@@ -0,0 +1,1409 @@
+//! Flattened bounding volume hierarchy built with the surface area heuristic.
+//!
+//! The binary tree built with the surface area heuristic is collapsed into a 4-wide tree whose
+//! nodes store the bounding volumes of their children in structure-of-arrays form, so that a
+//! single ray is tested against the four children at once. Packets of rays traverse the same
+//! tree, the rays of a packet being tested 4-wide against each child.
+
+use math::{Isometry, Point, Scalar, Vect};
+use na;
+use ncollide3d::bounding_volume::{BoundingVolume, AABB};
+use ncollide3d::query::Ray;
+use num::Bounded;
+use ray_packet::{self, Mask, RayPacket, PACKET_SIZE};
+
+/// The maximum number of primitives on a leaf.
+///
+/// This matches the width of the packet and SIMD primitive tests.
+pub const MAX_LEAF_SIZE: usize = 4;
+
+/// The number of children of the nodes of the wide tree.
+pub const WIDTH: usize = 4;
+
+// Number of buckets used to evaluate the surface area heuristic along each axis.
+const NUM_BINS: usize = 16;
+
+// Cost of traversing an internal node relative to the cost of a primitive test.
+const TRAVERSAL_COST: Scalar = 1.0;
+
+/// A node of a flattened BVH.
+#[derive(Clone)]
+pub struct BVHNode {
+    aabb: AABB<Scalar>,
+    // For leaves, the index of the first primitive on `BVH::indices`. For internal nodes, the
+    // index of the second child (the first one immediately follows its parent).
+    offset: usize,
+    // Number of primitives of a leaf, zero for internal nodes.
+    count: usize,
+}
+
+impl BVHNode {
+    /// The bounding volume of this node.
+    #[inline]
+    pub fn aabb(&self) -> &AABB<Scalar> {
+        &self.aabb
+    }
+
+    /// Whether this node is a leaf.
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.count != 0
+    }
+}
+
+// A node of the wide tree, with up to `WIDTH` children.
+#[derive(Clone)]
+struct WideNode {
+    // The bounds of the children along each axis.
+    mins: [[Scalar; WIDTH]; 3],
+    maxs: [[Scalar; WIDTH]; 3],
+    // For a leaf child, the index of its first primitive on `BVH::indices`; for an internal
+    // child, its index on `BVH::wide`.
+    offsets: [usize; WIDTH],
+    // Number of primitives of a leaf child, zero for internal children.
+    counts: [usize; WIDTH],
+    // Number of children, the first ones of the arrays above.
+    len: usize,
+    // The index of the parent of this node on `BVH::wide`, and the slot of this node in it. The
+    // root is its own parent.
+    parent: (usize, usize),
+}
+
+/// A bounding volume hierarchy stored as a flat array of nodes in depth-first order.
+///
+/// The BVH only stores primitive indices: the primitives themselves are owned and tested by
+/// the caller.
+#[derive(Clone)]
+pub struct BVH {
+    nodes: Vec<BVHNode>,
+    wide: Vec<WideNode>,
+    indices: Vec<usize>,
+}
+
+impl BVH {
+    /// Builds a BVH over primitives with the given bounding volumes, using a binned surface
+    /// area heuristic.
+    ///
+    /// The i-th primitive is identified by the index `i` in every query.
+    pub fn new(aabbs: &[AABB<Scalar>]) -> BVH {
+        let mut indices: Vec<usize> = (0..aabbs.len()).collect();
+        let mut nodes = Vec::with_capacity(2 * aabbs.len() / MAX_LEAF_SIZE + 1);
+
+        if !aabbs.is_empty() {
+            let centroids: Vec<Point> = aabbs.iter().map(|bv| bv.center()).collect();
+            let _ = build(aabbs, &centroids[..], &mut indices[..], 0, &mut nodes);
+        }
+
+        let wide = collapse(&nodes[..]);
+
+        BVH {
+            nodes: nodes,
+            wide: wide,
+            indices: indices,
+        }
+    }
+
+    /// The nodes of the binary tree of this BVH. The root, if any, is the first one.
+    #[inline]
+    pub fn nodes(&self) -> &[BVHNode] {
+        &self.nodes[..]
+    }
+
+    /// Whether this BVH contains no primitive.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The bounding volume of every primitive of this BVH.
+    #[inline]
+    pub fn aabb(&self) -> Option<&AABB<Scalar>> {
+        self.nodes.first().map(|n| &n.aabb)
+    }
+
+    /// Updates the bounding volume of every node after some primitives moved, keeping the
+    /// topology of the tree.
+    ///
+    /// `aabbs` are the new bounding volumes of the primitives this BVH was built from. The
+    /// quality of the tree degrades as primitives move away from their original position, see
+    /// `sah_cost`.
+    pub fn refit(&mut self, aabbs: &[AABB<Scalar>]) {
+        // Children are always stored after their parent.
+        for id in (0..self.nodes.len()).rev() {
+            let aabb = self.node_bounds(aabbs, id);
+            self.nodes[id].aabb = aabb;
+        }
+
+        self.wide = collapse(&self.nodes[..]);
+    }
+
+    /// Adds the primitive `i` to this BVH without rebuilding it.
+    ///
+    /// `aabbs` are the bounding volumes of the primitives, `aabbs[i]` being the one of the new
+    /// primitive, which must not already be part of this BVH. The primitive is added to the
+    /// leaf whose bounding volume grows the least, which is split following the surface area
+    /// heuristic once it holds more than `MAX_LEAF_SIZE` primitives. Only the ancestors of that
+    /// leaf are refitted. See `sah_cost_per_leaf` to decide when to rebuild the BVH instead.
+    pub fn insert(&mut self, aabbs: &[AABB<Scalar>], i: usize) {
+        if self.nodes.is_empty() {
+            self.indices.push(i);
+            self.nodes.push(BVHNode {
+                aabb: aabbs[i].clone(),
+                offset: 0,
+                count: 1,
+            });
+            self.wide = collapse(&self.nodes[..]);
+
+            return;
+        }
+
+        let mut id = 0;
+
+        while !self.nodes[id].is_leaf() {
+            let left = id + 1;
+            let right = self.nodes[id].offset;
+            let growth = |c: usize| {
+                let bv = &self.nodes[c].aabb;
+                half_area(&bv.merged(&aabbs[i])) - half_area(bv)
+            };
+
+            id = if growth(left) <= growth(right) { left } else { right };
+        }
+
+        // The primitives of a leaf are contiguous: make room after the last one.
+        let pos = self.nodes[id].offset + self.nodes[id].count;
+        let (w, s) = self.wide_leaf(self.nodes[id].offset);
+        self.indices.insert(pos, i);
+
+        for (k, node) in self.nodes.iter_mut().enumerate() {
+            if k != id && node.is_leaf() && node.offset >= pos {
+                node.offset = node.offset + 1;
+            }
+        }
+
+        for node in self.wide.iter_mut() {
+            for c in 0..node.len {
+                if node.counts[c] != 0 && node.offsets[c] >= pos {
+                    node.offsets[c] = node.offsets[c] + 1;
+                }
+            }
+        }
+
+        self.nodes[id].count = self.nodes[id].count + 1;
+        self.wide[w].counts[s] = self.nodes[id].count;
+
+        if self.nodes[id].count > MAX_LEAF_SIZE {
+            self.split_leaf(aabbs, id);
+
+            if id == 0 {
+                // The whole tree was a single leaf.
+                self.wide = collapse(&self.nodes[..]);
+
+                return;
+            }
+
+            // The subtree replacing the leaf hangs from the slot of the leaf.
+            let children = vec![id + 1, self.nodes[id].offset];
+            let sub = collapse_node(&self.nodes[..], children, &mut self.wide);
+            self.wide[sub].parent = (w, s);
+            self.wide[w].offsets[s] = sub;
+            self.wide[w].counts[s] = 0;
+        }
+
+        let mut path = self.ancestors(id);
+        path.push(id);
+        self.refit_nodes(aabbs, &path[..]);
+
+        let aabb = self.nodes[id].aabb.clone();
+        self.set_wide_bounds(w, s, &aabb);
+    }
+
+    /// Removes the primitive `i` from this BVH without rebuilding it.
+    ///
+    /// As with `Vec::remove`, the primitives following `i` are renumbered: `aabbs` are the
+    /// bounding volumes of the primitives after the removal. Only the ancestors of the leaf of
+    /// `i` are refitted.
+    pub fn remove(&mut self, aabbs: &[AABB<Scalar>], i: usize) {
+        let pos = match self.indices.iter().position(|j| *j == i) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let id = self
+            .nodes
+            .iter()
+            .position(|n| n.is_leaf() && n.offset <= pos && pos < n.offset + n.count)
+            .unwrap();
+
+        let (w, s) = self.wide_leaf(self.nodes[id].offset);
+        let _ = self.indices.remove(pos);
+
+        for j in self.indices.iter_mut() {
+            if *j > i {
+                *j = *j - 1;
+            }
+        }
+
+        for (k, node) in self.nodes.iter_mut().enumerate() {
+            if k != id && node.is_leaf() && node.offset > pos {
+                node.offset = node.offset - 1;
+            }
+        }
+
+        for node in self.wide.iter_mut() {
+            for c in 0..node.len {
+                if node.counts[c] != 0 && node.offsets[c] > pos {
+                    node.offsets[c] = node.offsets[c] - 1;
+                }
+            }
+        }
+
+        self.nodes[id].count = self.nodes[id].count - 1;
+        self.wide[w].counts[s] = self.nodes[id].count;
+
+        let mut path = self.ancestors(id);
+
+        if self.nodes[id].count == 0 {
+            self.remove_leaf(id);
+
+            if self.nodes.is_empty() {
+                self.wide.clear();
+
+                return;
+            }
+
+            // The parent of the leaf was removed as well, the ids of its ancestors are unchanged.
+            let _ = path.pop();
+            self.refit_nodes(aabbs, &path[..]);
+            self.remove_wide_slot(w, s);
+        } else {
+            path.push(id);
+            self.refit_nodes(aabbs, &path[..]);
+
+            let aabb = self.nodes[id].aabb.clone();
+            self.set_wide_bounds(w, s, &aabb);
+        }
+    }
+
+    // The bounding volume of the node `id` computed from its primitives or its children.
+    fn node_bounds(&self, aabbs: &[AABB<Scalar>], id: usize) -> AABB<Scalar> {
+        let node = &self.nodes[id];
+
+        if node.is_leaf() {
+            bounds(aabbs, &self.indices[node.offset..node.offset + node.count])
+        } else {
+            self.nodes[id + 1].aabb.merged(&self.nodes[node.offset].aabb)
+        }
+    }
+
+    // The ids of the ancestors of the node `id`, from the root.
+    fn ancestors(&self, id: usize) -> Vec<usize> {
+        let mut res = Vec::new();
+        let mut k = 0;
+
+        // The subtree of the first child of a node precedes the subtree of its second child.
+        while k != id {
+            res.push(k);
+            k = if id < self.nodes[k].offset {
+                k + 1
+            } else {
+                self.nodes[k].offset
+            };
+        }
+
+        res
+    }
+
+    // Recomputes the bounding volumes of the nodes `path`, given from the root.
+    fn refit_nodes(&mut self, aabbs: &[AABB<Scalar>], path: &[usize]) {
+        for id in path.iter().rev() {
+            let aabb = self.node_bounds(aabbs, *id);
+            self.nodes[*id].aabb = aabb;
+        }
+    }
+
+    // The wide node and slot of the leaf whose primitives start at `offset` on `indices`.
+    fn wide_leaf(&self, offset: usize) -> (usize, usize) {
+        for (w, node) in self.wide.iter().enumerate() {
+            for c in 0..node.len {
+                if node.counts[c] != 0 && node.offsets[c] == offset {
+                    return (w, c);
+                }
+            }
+        }
+
+        panic!("Every leaf of the binary tree has a slot on the wide tree.")
+    }
+
+    // Sets the bounding volume of the slot `s` of the wide node `w`, and updates the slots of
+    // its ancestors.
+    fn set_wide_bounds(&mut self, w: usize, s: usize, aabb: &AABB<Scalar>) {
+        for i in 0..3 {
+            self.wide[w].mins[i][s] = aabb.mins()[i];
+            self.wide[w].maxs[i][s] = aabb.maxs()[i];
+        }
+
+        self.refit_wide_ancestors(w);
+    }
+
+    // Updates the slots of the ancestors of the wide node `w` with the bounds of their
+    // children.
+    fn refit_wide_ancestors(&mut self, w: usize) {
+        let mut w = w;
+
+        while w != 0 {
+            let (parent, slot) = self.wide[w].parent;
+
+            for i in 0..3 {
+                let (min, max) = {
+                    let node = &self.wide[w];
+                    let mins = &node.mins[i][..node.len];
+                    let maxs = &node.maxs[i][..node.len];
+
+                    (
+                        mins.iter().fold(Bounded::max_value(), |m: Scalar, x| m.min(*x)),
+                        maxs.iter().fold(-Bounded::max_value(), |m: Scalar, x| m.max(*x)),
+                    )
+                };
+
+                self.wide[parent].mins[i][slot] = min;
+                self.wide[parent].maxs[i][slot] = max;
+            }
+
+            w = parent;
+        }
+    }
+
+    // Removes the slot `s` of the wide node `w`, with the nodes left without children.
+    fn remove_wide_slot(&mut self, w: usize, s: usize) {
+        let last = self.wide[w].len - 1;
+
+        {
+            let node = &mut self.wide[w];
+
+            for i in 0..3 {
+                node.mins[i][s] = node.mins[i][last];
+                node.maxs[i][s] = node.maxs[i][last];
+            }
+
+            node.offsets[s] = node.offsets[last];
+            node.counts[s] = node.counts[last];
+            node.len = last;
+        }
+
+        if s != last && self.wide[w].counts[s] == 0 {
+            let child = self.wide[w].offsets[s];
+            self.wide[child].parent = (w, s);
+        }
+
+        if last == 0 && w != 0 {
+            // The node is left unreachable.
+            let (parent, slot) = self.wide[w].parent;
+            self.remove_wide_slot(parent, slot);
+        } else {
+            self.refit_wide_ancestors(w);
+        }
+    }
+
+    // Replaces the leaf `id` by a subtree built with the surface area heuristic.
+    fn split_leaf(&mut self, aabbs: &[AABB<Scalar>], id: usize) {
+        let first = self.nodes[id].offset;
+        let count = self.nodes[id].count;
+        let centroids: Vec<Point> = aabbs.iter().map(|bv| bv.center()).collect();
+        let mut subtree = Vec::new();
+        let _ = build(
+            aabbs,
+            &centroids[..],
+            &mut self.indices[first..first + count],
+            first,
+            &mut subtree,
+        );
+
+        // The subtree replaces the leaf in place, shifting the nodes following it.
+        let shift = subtree.len() - 1;
+
+        for node in self.nodes.iter_mut() {
+            if !node.is_leaf() && node.offset > id {
+                node.offset = node.offset + shift;
+            }
+        }
+
+        for node in subtree.iter_mut() {
+            if !node.is_leaf() {
+                node.offset = node.offset + id;
+            }
+        }
+
+        let tail = self.nodes.split_off(id + 1);
+        let _ = self.nodes.pop();
+        self.nodes.extend(subtree.into_iter());
+        self.nodes.extend(tail.into_iter());
+    }
+
+    // Removes the empty leaf `id`, whose sibling replaces their parent.
+    fn remove_leaf(&mut self, id: usize) {
+        if id == 0 {
+            self.nodes.clear();
+            return;
+        }
+
+        // The parent of a first child immediately precedes it.
+        let parent = self
+            .nodes
+            .iter()
+            .enumerate()
+            .position(|(k, n)| k != id && !n.is_leaf() && n.offset == id)
+            .unwrap_or(id - 1);
+        let removed = [parent, id];
+        let mut nodes = Vec::with_capacity(self.nodes.len() - 2);
+
+        for (k, node) in self.nodes.iter().enumerate() {
+            if k == parent || k == id {
+                continue;
+            }
+
+            let mut node = node.clone();
+
+            if !node.is_leaf() {
+                let below = removed.iter().filter(|r| **r < node.offset).count();
+                node.offset = node.offset - below;
+            }
+
+            nodes.push(node);
+        }
+
+        self.nodes = nodes;
+    }
+
+    /// The expected cost of a ray traversal following the surface area heuristic, in units of
+    /// primitive tests.
+    pub fn sah_cost(&self) -> Scalar {
+        let root_area = match self.nodes.first() {
+            Some(root) => half_area(&root.aabb),
+            None => return 0.0,
+        };
+
+        if root_area <= 0.0 {
+            return self.indices.len() as Scalar;
+        }
+
+        self.nodes.iter().fold(0.0, |acc, node| {
+            let cost = if node.is_leaf() {
+                node.count as Scalar
+            } else {
+                TRAVERSAL_COST
+            };
+
+            acc + cost * half_area(&node.aabb) / root_area
+        })
+    }
+
+    /// The SAH cost of this BVH divided by its number of leaves.
+    ///
+    /// Unlike `sah_cost`, this does not grow merely because primitives are added, which makes it
+    /// suitable to compare the quality of a BVH before and after insertions.
+    pub fn sah_cost_per_leaf(&self) -> Scalar {
+        let leaves = self.nodes.iter().filter(|n| n.is_leaf()).count();
+
+        if leaves == 0 {
+            0.0
+        } else {
+            self.sah_cost() / leaves as Scalar
+        }
+    }
+
+    /// Finds the closest primitive hit by `ray` with a time of impact smaller than `max_toi`.
+    ///
+    /// `f(i, max_toi)` casts the ray on the i-th primitive and returns its time of impact and
+    /// any data the caller needs. Nodes are visited front-to-back so that `f` is rarely called
+    /// on occluded primitives.
+    pub fn cast_ray<T, F>(&self, ray: &Ray<Scalar>, max_toi: Scalar, mut f: F) -> Option<(usize, T)>
+    where
+        F: FnMut(usize, Scalar) -> Option<(Scalar, T)>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = inverse_direction(&ray.dir);
+        let mut best_toi = max_toi;
+        let mut best = None;
+        // Subtrees and leaves to visit: (offset, count, time of entry), as in `WideNode`.
+        let mut stack = Vec::with_capacity(64);
+
+        if let Some(toi) = ray_aabb(&self.nodes[0].aabb, &ray.origin, &inv_dir, best_toi) {
+            stack.push((0, 0, toi));
+        }
+
+        while let Some((offset, count, entry)) = stack.pop() {
+            if entry > best_toi {
+                continue;
+            }
+
+            if count != 0 {
+                for prim in self.indices[offset..offset + count].iter() {
+                    if let Some((toi, data)) = f(*prim, best_toi) {
+                        if toi < best_toi {
+                            best_toi = toi;
+                            best = Some((*prim, data));
+                        }
+                    }
+                }
+            } else {
+                let node = &self.wide[offset];
+                let (hits, entries) = ray_aabb4(node, &ray.origin, &inv_dir, best_toi);
+                let mut order = [0; WIDTH];
+                let mut len = 0;
+
+                // Sort the children hit by decreasing time of entry, and push them in this
+                // order so that the closest one is visited first.
+                for c in 0..node.len {
+                    if hits[c] {
+                        let mut i = len;
+
+                        while i > 0 && entries[order[i - 1]] < entries[c] {
+                            order[i] = order[i - 1];
+                            i = i - 1;
+                        }
+
+                        order[i] = c;
+                        len = len + 1;
+                    }
+                }
+
+                for c in order[..len].iter() {
+                    stack.push((node.offsets[*c], node.counts[*c], entries[*c]));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Calls `f` on every primitive whose bounding volume is hit by `ray` before `max_toi`.
+    ///
+    /// The traversal stops as soon as `f` returns `false`, in which case this returns `false`
+    /// as well.
+    pub fn visit_ray<F: FnMut(usize) -> bool>(
+        &self,
+        ray: &Ray<Scalar>,
+        max_toi: Scalar,
+        mut f: F,
+    ) -> bool {
+        if self.nodes.is_empty() {
+            return true;
+        }
+
+        let inv_dir = inverse_direction(&ray.dir);
+
+        if ray_aabb(&self.nodes[0].aabb, &ray.origin, &inv_dir, max_toi).is_none() {
+            return true;
+        }
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(id) = stack.pop() {
+            let node = &self.wide[id];
+            let (hits, _) = ray_aabb4(node, &ray.origin, &inv_dir, max_toi);
+
+            for c in (0..node.len).rev() {
+                if !hits[c] {
+                    continue;
+                }
+
+                if node.counts[c] != 0 {
+                    let first = node.offsets[c];
+
+                    for prim in self.indices[first..first + node.counts[c]].iter() {
+                        if !f(*prim) {
+                            return false;
+                        }
+                    }
+                } else {
+                    stack.push(node.offsets[c]);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Traverses this BVH with a packet of rays, looking for the closest hits.
+    ///
+    /// `f(i, mask, max_tois)` tests the i-th primitive against the rays of the lanes active on
+    /// `mask` and must lower `max_tois[lane]` for every ray it hits. Rays never enter subtrees
+    /// beyond their current `max_tois`.
+    pub fn cast_packet<F>(&self, packet: &RayPacket, max_tois: &mut [Scalar; PACKET_SIZE], mut f: F)
+    where
+        F: FnMut(usize, &Mask, &mut [Scalar; PACKET_SIZE]),
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let (mask, entry) = packet.intersects_aabb(&self.nodes[0].aabb, max_tois, &packet.mask());
+        // Subtrees and leaves to visit: (offset, count, lanes, time of entry), as in `cast_ray`.
+        let mut stack = Vec::with_capacity(64);
+        stack.push((0, 0, mask, entry));
+
+        while let Some((offset, count, mut mask, entry)) = stack.pop() {
+            // The rays that hit a primitive since the node was pushed may not reach it anymore.
+            for lane in 0..PACKET_SIZE {
+                mask[lane] = mask[lane] && entry <= max_tois[lane];
+            }
+
+            if !ray_packet::any(&mask) {
+                continue;
+            }
+
+            if count != 0 {
+                for prim in self.indices[offset..offset + count].iter() {
+                    f(*prim, &mask, max_tois);
+                }
+            } else {
+                let node = &self.wide[offset];
+                let (hits, entries) = packet_aabb4(node, packet, max_tois, &mask);
+                let mut order = [0; WIDTH];
+                let mut len = 0;
+
+                // Push the children by decreasing time of entry so the closest one is visited
+                // first.
+                for c in 0..node.len {
+                    if ray_packet::any(&hits[c]) {
+                        let mut i = len;
+
+                        while i > 0 && entries[order[i - 1]] < entries[c] {
+                            order[i] = order[i - 1];
+                            i = i - 1;
+                        }
+
+                        order[i] = c;
+                        len = len + 1;
+                    }
+                }
+
+                for c in order[..len].iter() {
+                    stack.push((node.offsets[*c], node.counts[*c], hits[*c], entries[*c]));
+                }
+            }
+        }
+    }
+
+    /// Calls `f` on every primitive whose bounding volume is hit by at least one active ray of
+    /// `packet` before its maximum time of impact.
+    ///
+    /// `f(i, lanes)` receives the lanes whose ray hit the bounding volume of the i-th primitive,
+    /// and deactivates the lanes of the rays that must not be traced further. The traversal
+    /// stops once every lane of `active` has been deactivated.
+    pub fn visit_packet<F>(
+        &self,
+        packet: &RayPacket,
+        max_tois: &[Scalar; PACKET_SIZE],
+        active: &mut Mask,
+        mut f: F,
+    ) where
+        F: FnMut(usize, &mut Mask),
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let (mask, _) = packet.intersects_aabb(&self.nodes[0].aabb, max_tois, active);
+        // Subtrees and leaves to visit: (offset, count, lanes), as in `WideNode`.
+        let mut stack = Vec::with_capacity(64);
+        stack.push((0, 0, mask));
+
+        while let Some((offset, count, mut mask)) = stack.pop() {
+            for lane in 0..PACKET_SIZE {
+                mask[lane] = mask[lane] && active[lane];
+            }
+
+            if !ray_packet::any(&mask) {
+                continue;
+            }
+
+            if count != 0 {
+                for prim in self.indices[offset..offset + count].iter() {
+                    let mut lanes = mask;
+
+                    for lane in 0..PACKET_SIZE {
+                        lanes[lane] = lanes[lane] && active[lane];
+                    }
+
+                    f(*prim, &mut lanes);
+
+                    for lane in 0..PACKET_SIZE {
+                        if mask[lane] && !lanes[lane] {
+                            active[lane] = false;
+                        }
+                    }
+
+                    if !ray_packet::any(active) {
+                        return;
+                    }
+                }
+            } else {
+                let node = &self.wide[offset];
+                let (hits, _) = packet_aabb4(node, packet, max_tois, &mask);
+
+                for c in (0..node.len).rev() {
+                    if ray_packet::any(&hits[c]) {
+                        stack.push((node.offsets[c], node.counts[c], hits[c]));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Computes the bounding volume of `aabb` transformed by `m`.
+pub fn transform_aabb(aabb: &AABB<Scalar>, m: &Isometry) -> AABB<Scalar> {
+    let mins = aabb.mins();
+    let maxs = aabb.maxs();
+    let mut res_mins = m * *mins;
+    let mut res_maxs = res_mins;
+
+    for i in 1..8 {
+        let corner = Point::new(
+            if i & 1 != 0 { maxs.x } else { mins.x },
+            if i & 2 != 0 { maxs.y } else { mins.y },
+            if i & 4 != 0 { maxs.z } else { mins.z },
+        );
+        let pt = m * corner;
+
+        res_mins = na::inf(&res_mins, &pt);
+        res_maxs = na::sup(&res_maxs, &pt);
+    }
+
+    AABB::new(res_mins, res_maxs)
+}
+
+/// The component-wise inverse of a ray direction, used by `ray_aabb`.
+#[inline]
+pub fn inverse_direction(dir: &Vect) -> Vect {
+    Vect::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z)
+}
+
+/// Slab test between a ray and an AABB, returning the time of entry of the ray, clamped to zero.
+#[inline]
+pub fn ray_aabb(
+    aabb: &AABB<Scalar>,
+    origin: &Point,
+    inv_dir: &Vect,
+    max_toi: Scalar,
+) -> Option<Scalar> {
+    let mut tmin = 0.0;
+    let mut tmax = max_toi;
+
+    for i in 0..3 {
+        let t1 = (aabb.mins()[i] - origin[i]) * inv_dir[i];
+        let t2 = (aabb.maxs()[i] - origin[i]) * inv_dir[i];
+        let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+
+        // Written so that NaNs (a ray parallel to and on a slab boundary) are ignored.
+        if near > tmin {
+            tmin = near
+        }
+
+        if far < tmax {
+            tmax = far
+        }
+
+        if tmin > tmax {
+            return None;
+        }
+    }
+
+    Some(tmin)
+}
+
+/// Slab test between a ray and an AABB, returning the times of entry and exit of the line of the
+/// ray, if its exit is not behind the ray origin.
+///
+/// The time of entry is negative if the ray origin is inside the AABB.
+#[inline]
+pub fn ray_aabb_interval(aabb: &AABB<Scalar>, ray: &Ray<Scalar>) -> Option<(Scalar, Scalar)> {
+    let mut tmin: Scalar = -Bounded::max_value();
+    let mut tmax: Scalar = Bounded::max_value();
+
+    for i in 0..3 {
+        let inv = 1.0 / ray.dir[i];
+        let t1 = (aabb.mins()[i] - ray.origin[i]) * inv;
+        let t2 = (aabb.maxs()[i] - ray.origin[i]) * inv;
+
+        // `max` and `min` ignore the NaNs of rays parallel to and on a slab boundary.
+        tmin = tmin.max(t1.min(t2));
+        tmax = tmax.min(t1.max(t2));
+    }
+
+    if tmin <= tmax && tmax >= 0.0 {
+        Some((tmin, tmax))
+    } else {
+        None
+    }
+}
+
+// Slab test between a ray and the children of a wide node, returning which children are hit
+// and their times of entry, clamped to zero.
+//
+// The loops over the children have no early exit so that the compiler can vectorize them.
+#[inline]
+fn ray_aabb4(
+    node: &WideNode,
+    origin: &Point,
+    inv_dir: &Vect,
+    max_toi: Scalar,
+) -> ([bool; WIDTH], [Scalar; WIDTH]) {
+    let mut tmin = [0.0; WIDTH];
+    let mut tmax = [max_toi; WIDTH];
+
+    for i in 0..3 {
+        let o = origin[i];
+        let inv = inv_dir[i];
+
+        for c in 0..WIDTH {
+            let t1 = (node.mins[i][c] - o) * inv;
+            let t2 = (node.maxs[i][c] - o) * inv;
+            let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+
+            // Written so that NaNs (a ray parallel to and on a slab boundary) are ignored.
+            tmin[c] = if near > tmin[c] { near } else { tmin[c] };
+            tmax[c] = if far < tmax[c] { far } else { tmax[c] };
+        }
+    }
+
+    let mut hits = [false; WIDTH];
+
+    for c in 0..WIDTH {
+        hits[c] = tmin[c] <= tmax[c];
+    }
+
+    (hits, tmin)
+}
+
+// Slab test between a packet and the children of a wide node, returning for each child the
+// lanes of the rays hitting it and their smallest time of entry.
+#[inline]
+fn packet_aabb4(
+    node: &WideNode,
+    packet: &RayPacket,
+    max_tois: &[Scalar; PACKET_SIZE],
+    mask: &Mask,
+) -> ([Mask; WIDTH], [Scalar; WIDTH]) {
+    let mut hits = [[false; PACKET_SIZE]; WIDTH];
+    let mut entries: [Scalar; WIDTH] = [Bounded::max_value(); WIDTH];
+
+    for c in 0..node.len {
+        let mins = [node.mins[0][c], node.mins[1][c], node.mins[2][c]];
+        let maxs = [node.maxs[0][c], node.maxs[1][c], node.maxs[2][c]];
+        let (lanes, entry) = packet.intersects_box(&mins, &maxs, max_tois, mask);
+
+        hits[c] = lanes;
+        entries[c] = entry;
+    }
+
+    (hits, entries)
+}
+
+/// Half the surface area of an AABB.
+#[inline]
+pub fn half_area(aabb: &AABB<Scalar>) -> Scalar {
+    let e = *aabb.maxs() - *aabb.mins();
+
+    e.x * e.y + e.y * e.z + e.z * e.x
+}
+
+fn bounds(aabbs: &[AABB<Scalar>], indices: &[usize]) -> AABB<Scalar> {
+    let mut res = aabbs[indices[0]].clone();
+
+    for i in indices[1..].iter() {
+        res.merge(&aabbs[*i]);
+    }
+
+    res
+}
+
+// Builds the subtree of the primitives `indices`, which start at `first` on the global index
+// array, and returns the id of its root.
+fn build(
+    aabbs: &[AABB<Scalar>],
+    centroids: &[Point],
+    indices: &mut [usize],
+    first: usize,
+    nodes: &mut Vec<BVHNode>,
+) -> usize {
+    let id = nodes.len();
+
+    nodes.push(BVHNode {
+        aabb: bounds(aabbs, indices),
+        offset: first,
+        count: indices.len(),
+    });
+
+    if indices.len() <= MAX_LEAF_SIZE {
+        return id;
+    }
+
+    let mid = split(aabbs, centroids, indices);
+    let (left, right) = indices.split_at_mut(mid);
+
+    let _ = build(aabbs, centroids, left, first, nodes);
+    let right_id = build(aabbs, centroids, right, first + mid, nodes);
+
+    nodes[id].offset = right_id;
+    nodes[id].count = 0;
+
+    id
+}
+
+// Collapses a binary tree into a wide tree, whose root is the first node.
+fn collapse(nodes: &[BVHNode]) -> Vec<WideNode> {
+    let mut wide = Vec::with_capacity(nodes.len() / 2 + 1);
+
+    match nodes.first() {
+        None => {}
+        Some(root) if root.is_leaf() => {
+            let _ = collapse_node(nodes, vec![0], &mut wide);
+        }
+        Some(root) => {
+            let _ = collapse_node(nodes, vec![1, root.offset], &mut wide);
+        }
+    }
+
+    wide
+}
+
+// Adds to `wide` the node whose children are at least the binary nodes `children`, and
+// returns its index.
+fn collapse_node(nodes: &[BVHNode], children: Vec<usize>, wide: &mut Vec<WideNode>) -> usize {
+    let mut children = children;
+
+    // Replace the internal child with the largest area by its own children, as it is the most
+    // likely to be hit, until the node is full.
+    while children.len() < WIDTH {
+        let mut largest: Option<(usize, Scalar)> = None;
+
+        for (i, c) in children.iter().enumerate() {
+            let area = half_area(&nodes[*c].aabb);
+
+            if !nodes[*c].is_leaf() && largest.map(|(_, a)| area > a).unwrap_or(true) {
+                largest = Some((i, area));
+            }
+        }
+
+        match largest {
+            Some((i, _)) => {
+                let c = children.swap_remove(i);
+                children.push(c + 1);
+                children.push(nodes[c].offset);
+            }
+            None => break,
+        }
+    }
+
+    let id = wide.len();
+
+    wide.push(WideNode {
+        mins: [[0.0; WIDTH]; 3],
+        maxs: [[0.0; WIDTH]; 3],
+        offsets: [0; WIDTH],
+        counts: [0; WIDTH],
+        len: children.len(),
+        parent: (id, 0),
+    });
+
+    for (slot, c) in children.iter().enumerate() {
+        let node = &nodes[*c];
+        let offset = if node.is_leaf() {
+            node.offset
+        } else {
+            let child = collapse_node(nodes, vec![*c + 1, node.offset], wide);
+            wide[child].parent = (id, slot);
+            child
+        };
+
+        let w = &mut wide[id];
+
+        for i in 0..3 {
+            w.mins[i][slot] = node.aabb.mins()[i];
+            w.maxs[i][slot] = node.aabb.maxs()[i];
+        }
+
+        w.offsets[slot] = offset;
+        w.counts[slot] = node.count;
+    }
+
+    id
+}
+
+// Partitions `indices` following the binned SAH and returns the size of the first part.
+fn split(aabbs: &[AABB<Scalar>], centroids: &[Point], indices: &mut [usize]) -> usize {
+    let n = indices.len();
+    let mut cmins = centroids[indices[0]];
+    let mut cmaxs = cmins;
+
+    for i in indices[1..].iter() {
+        cmins = na::inf(&cmins, &centroids[*i]);
+        cmaxs = na::sup(&cmaxs, &centroids[*i]);
+    }
+
+    let parent_area = half_area(&bounds(aabbs, indices));
+    let mut best: Option<(usize, usize, Scalar)> = None; // (axis, last bin on the left, cost)
+
+    for axis in 0..3 {
+        let extent = cmaxs[axis] - cmins[axis];
+
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let mut counts = [0usize; NUM_BINS];
+        let mut bins: Vec<Option<AABB<Scalar>>> = vec![None; NUM_BINS];
+
+        for i in indices.iter() {
+            let b = bin_of(centroids[*i][axis], cmins[axis], extent);
+            counts[b] = counts[b] + 1;
+            bins[b] = Some(match bins[b].take() {
+                None => aabbs[*i].clone(),
+                Some(bv) => bv.merged(&aabbs[*i]),
+            });
+        }
+
+        // Sweep from the right to get the area and count of every right part.
+        let mut right_areas = [0.0; NUM_BINS];
+        let mut right_counts = [0usize; NUM_BINS];
+        let mut acc: Option<AABB<Scalar>> = None;
+        let mut count = 0;
+
+        for b in (1..NUM_BINS).rev() {
+            acc = merge_option(acc, &bins[b]);
+            count = count + counts[b];
+            right_areas[b] = acc.as_ref().map(|bv| half_area(bv)).unwrap_or(0.0);
+            right_counts[b] = count;
+        }
+
+        let mut acc: Option<AABB<Scalar>> = None;
+        let mut count = 0;
+
+        for b in 0..NUM_BINS - 1 {
+            acc = merge_option(acc, &bins[b]);
+            count = count + counts[b];
+
+            if count == 0 || right_counts[b + 1] == 0 {
+                continue;
+            }
+
+            let left_area = acc.as_ref().map(|bv| half_area(bv)).unwrap_or(0.0);
+            let cost = TRAVERSAL_COST
+                + (left_area * count as Scalar
+                    + right_areas[b + 1] * right_counts[b + 1] as Scalar)
+                    / parent_area;
+
+            if best.map(|(_, _, c)| cost < c).unwrap_or(true) {
+                best = Some((axis, b, cost));
+            }
+        }
+    }
+
+    match best {
+        Some((axis, last_left_bin, _)) => {
+            let extent = cmaxs[axis] - cmins[axis];
+            let mut i = 0;
+            let mut j = n;
+
+            while i < j {
+                if bin_of(centroids[indices[i]][axis], cmins[axis], extent) <= last_left_bin {
+                    i = i + 1;
+                } else {
+                    j = j - 1;
+                    indices.swap(i, j);
+                }
+            }
+
+            i
+        }
+        // All centroids coincide: any partition is as good as another.
+        None => n / 2,
+    }
+}
+
+#[inline]
+fn bin_of(c: Scalar, min: Scalar, extent: Scalar) -> usize {
+    let b = ((c - min) / extent * NUM_BINS as Scalar) as usize;
+
+    if b >= NUM_BINS {
+        NUM_BINS - 1
+    } else {
+        b
+    }
+}
+
+#[inline]
+fn merge_option(acc: Option<AABB<Scalar>>, bv: &Option<AABB<Scalar>>) -> Option<AABB<Scalar>> {
+    match (acc, bv.as_ref()) {
+        (Some(a), Some(b)) => Some(a.merged(b)),
+        (None, Some(b)) => Some(b.clone()),
+        (a, None) => a,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A row of unit cubes along the x axis, each one raised by its index modulo 3.
+    fn cubes(n: usize) -> Vec<AABB<Scalar>> {
+        (0..n)
+            .map(|i| {
+                let mins = Point::new(2.0 * i as Scalar, (i % 3) as Scalar, 0.0);
+
+                AABB::new(mins, mins + Vect::new(1.0, 1.0, 1.0))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cast_ray_finds_the_closest_primitive() {
+        let aabbs = cubes(100);
+        let bvh = BVH::new(&aabbs[..]);
+        let ray = Ray::new(Point::new(-10.0, 1.5, 0.5), Vect::x());
+        let hit = bvh.cast_ray(&ray, 1000.0, |i, max_toi| {
+            ray_aabb(&aabbs[i], &ray.origin, &inverse_direction(&ray.dir), max_toi)
+                .map(|toi| (toi, ()))
+        });
+
+        // The first cube spanning the height 1.5 is the second one.
+        assert_eq!(hit.map(|(i, _)| i), Some(1));
+    }
+
+    #[test]
+    fn visit_ray_visits_every_primitive_hit() {
+        let aabbs = cubes(100);
+        let bvh = BVH::new(&aabbs[..]);
+        let ray = Ray::new(Point::new(-10.0, 0.5, 0.5), Vect::x());
+        let mut visited = Vec::new();
+
+        // Whole leaves are visited: keep the primitives actually hit.
+        assert!(bvh.visit_ray(&ray, 1000.0, |i| {
+            if ray_aabb(&aabbs[i], &ray.origin, &inverse_direction(&ray.dir), 1000.0).is_some() {
+                visited.push(i);
+            }
+
+            true
+        }));
+
+        visited.sort();
+        let expected: Vec<usize> = (0..100).filter(|i| i % 3 == 0).collect();
+
+        assert_eq!(visited, expected);
+    }
+
+    // The primitives hit by a ray along the x axis at the height of the cubes of index
+    // `i % 3 == 0`.
+    fn visit_row(bvh: &BVH, aabbs: &[AABB<Scalar>]) -> Vec<usize> {
+        let ray = Ray::new(Point::new(-10.0, 0.5, 0.5), Vect::x());
+        let mut visited = Vec::new();
+
+        let _ = bvh.visit_ray(&ray, 1000.0, |i| {
+            if ray_aabb(&aabbs[i], &ray.origin, &inverse_direction(&ray.dir), 1000.0).is_some() {
+                visited.push(i);
+            }
+
+            true
+        });
+
+        visited.sort();
+        visited
+    }
+
+    #[test]
+    fn insert_into_an_existing_bvh() {
+        let aabbs = cubes(50);
+        let mut bvh = BVH::new(&aabbs[..10]);
+
+        for i in 10..50 {
+            bvh.insert(&aabbs[..i + 1], i);
+        }
+
+        let expected: Vec<usize> = (0..50).filter(|i| i % 3 == 0).collect();
+
+        assert_eq!(visit_row(&bvh, &aabbs[..]), expected);
+        assert_eq!(bvh.aabb().unwrap().maxs().x, 99.0);
+    }
+
+    #[test]
+    fn remove_renumbers_the_following_primitives() {
+        let mut aabbs = cubes(50);
+        let mut bvh = BVH::new(&aabbs[..]);
+
+        // Remove the first nine cubes, out of order.
+        for _ in 0..3 {
+            let _ = aabbs.remove(0);
+            bvh.remove(&aabbs[..], 0);
+            let _ = aabbs.remove(1);
+            bvh.remove(&aabbs[..], 1);
+            let _ = aabbs.remove(0);
+            bvh.remove(&aabbs[..], 0);
+        }
+
+        let expected: Vec<usize> = (0..41).filter(|i| i % 3 == 0).collect();
+
+        assert_eq!(visit_row(&bvh, &aabbs[..]), expected);
+
+        while !aabbs.is_empty() {
+            let _ = aabbs.pop();
+            let last = aabbs.len();
+            bvh.remove(&aabbs[..], last);
+        }
+
+        assert!(bvh.is_empty());
+    }
+
+    // Checks that the slots of the wide node `w` are bounded by the primitives they hold, and
+    // returns the number of these primitives.
+    fn check_wide(bvh: &BVH, aabbs: &[AABB<Scalar>], w: usize) -> usize {
+        let node = &bvh.wide[w];
+        let mut count = 0;
+
+        for s in 0..node.len {
+            let (aabb, n) = if node.counts[s] != 0 {
+                let indices = &bvh.indices[node.offsets[s]..node.offsets[s] + node.counts[s]];
+                (bounds(aabbs, indices), node.counts[s])
+            } else {
+                let child = &bvh.wide[node.offsets[s]];
+                assert_eq!(child.parent, (w, s));
+
+                let mut aabb = AABB::new(
+                    Point::new(child.mins[0][0], child.mins[1][0], child.mins[2][0]),
+                    Point::new(child.maxs[0][0], child.maxs[1][0], child.maxs[2][0]),
+                );
+
+                for c in 1..child.len {
+                    aabb.merge(&AABB::new(
+                        Point::new(child.mins[0][c], child.mins[1][c], child.mins[2][c]),
+                        Point::new(child.maxs[0][c], child.maxs[1][c], child.maxs[2][c]),
+                    ));
+                }
+
+                (aabb, check_wide(bvh, aabbs, node.offsets[s]))
+            };
+
+            for i in 0..3 {
+                assert_eq!(node.mins[i][s], aabb.mins()[i]);
+                assert_eq!(node.maxs[i][s], aabb.maxs()[i]);
+            }
+
+            count = count + n;
+        }
+
+        count
+    }
+
+    #[test]
+    fn insert_and_remove_keep_the_wide_tree_bounds_tight() {
+        let mut aabbs = cubes(60);
+        let mut bvh = BVH::new(&aabbs[..20]);
+
+        for i in 20..60 {
+            bvh.insert(&aabbs[..i + 1], i);
+            assert_eq!(check_wide(&bvh, &aabbs[..i + 1], 0), i + 1);
+        }
+
+        while aabbs.len() > 1 {
+            let i = aabbs.len() / 3;
+            let _ = aabbs.remove(i);
+            bvh.remove(&aabbs[..], i);
+            assert_eq!(check_wide(&bvh, &aabbs[..], 0), aabbs.len());
+            assert_eq!(bvh.aabb(), Some(&bounds(&aabbs[..], &bvh.indices[..])));
+        }
+    }
+
+    // Rays along the x axis at the heights of the three rows of cubes, and one missing them.
+    fn row_packet() -> RayPacket {
+        let rays = [0.5, 1.5, 2.5, 4.5]
+            .iter()
+            .map(|y| Ray::new(Point::new(-10.0, *y, 0.5), Vect::x()))
+            .collect();
+
+        RayPacket::new(rays)
+    }
+
+    #[test]
+    fn cast_packet_matches_cast_ray() {
+        let aabbs = cubes(100);
+        let bvh = BVH::new(&aabbs[..]);
+        let packet = row_packet();
+        let mut max_tois = [1000.0; PACKET_SIZE];
+        let mut hits = [None; PACKET_SIZE];
+
+        bvh.cast_packet(&packet, &mut max_tois, |i, mask, max_tois| {
+            for lane in 0..PACKET_SIZE {
+                if !mask[lane] {
+                    continue;
+                }
+
+                let ray = packet.ray(lane);
+                let inv_dir = inverse_direction(&ray.dir);
+
+                if let Some(toi) = ray_aabb(&aabbs[i], &ray.origin, &inv_dir, max_tois[lane]) {
+                    max_tois[lane] = toi;
+                    hits[lane] = Some(i);
+                }
+            }
+        });
+
+        for lane in 0..PACKET_SIZE {
+            let ray = packet.ray(lane);
+            let hit = bvh.cast_ray(ray, 1000.0, |i, max_toi| {
+                ray_aabb(&aabbs[i], &ray.origin, &inverse_direction(&ray.dir), max_toi)
+                    .map(|toi| (toi, ()))
+            });
+
+            assert_eq!(hits[lane], hit.map(|(i, _)| i));
+        }
+
+        assert_eq!(hits, [Some(0), Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn visit_packet_visits_every_primitive_hit_until_deactivated() {
+        let aabbs = cubes(100);
+        let bvh = BVH::new(&aabbs[..]);
+        let packet = row_packet();
+        let mut active = packet.mask();
+        let mut visited = vec![Vec::new(); PACKET_SIZE];
+
+        bvh.visit_packet(&packet, &[1000.0; PACKET_SIZE], &mut active, |i, lanes| {
+            for lane in 0..PACKET_SIZE {
+                let ray = packet.ray(lane);
+                let inv_dir = inverse_direction(&ray.dir);
+
+                if lanes[lane] && ray_aabb(&aabbs[i], &ray.origin, &inv_dir, 1000.0).is_some() {
+                    visited[lane].push(i);
+
+                    // The second ray stops at its first hit, like a shadow ray.
+                    if lane == 1 {
+                        lanes[lane] = false;
+                    }
+                }
+            }
+        });
+
+        visited[0].sort();
+        let expected: Vec<usize> = (0..100).filter(|i| i % 3 == 0).collect();
+
+        assert_eq!(visited[0], expected);
+        assert_eq!(visited[1].len(), 1);
+        assert!(visited[3].is_empty());
+        assert_eq!(active, [true, false, true, true]);
+    }
+
+    #[test]
+    fn single_primitive() {
+        let aabbs = cubes(1);
+        let bvh = BVH::new(&aabbs[..]);
+        let ray = Ray::new(Point::new(0.5, 0.5, -1.0), Vect::z());
+
+        assert!(bvh.cast_ray(&ray, 10.0, |_, _| Some((1.0, ()))).is_some());
+    }
+}
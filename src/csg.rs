@@ -0,0 +1,257 @@
+//! Constructive solid geometry.
+//!
+//! Rays are cast on each leaf of a CSG tree to collect the intervals along which they are
+//! inside it. Those intervals are then combined by the boolean operations of the tree.
+
+use intersection::Intersection;
+use material::Material;
+use math::{Isometry, Scalar, Vect};
+use na::{self, Point2};
+use ncollide3d::bounding_volume::{BoundingVolume, AABB};
+use ncollide3d::query::Ray;
+use num::{Bounded, Float};
+use scene_node::Geometry;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// The maximum number of times the surface of a single CSG leaf is crossed by a ray.
+pub const MAX_CROSSINGS: usize = 64;
+
+/// A boolean operation on solids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsgOp {
+    /// The points inside either solid.
+    Union,
+    /// The points inside both solids.
+    Intersection,
+    /// The points inside the first solid but not inside the second one.
+    Difference,
+}
+
+/// A tree of solids combined by boolean operations.
+///
+/// The geometries of the leaves must be closed.
+pub enum Csg {
+    /// A geometry placed by the given transformation, with a material overriding the one of the
+    /// scene node, if any.
+    Leaf(Arc<Geometry>, Isometry, Option<Arc<Box<Material + Send + Sync>>>),
+    /// A boolean operation on two solids.
+    Node(CsgOp, Box<Csg>, Box<Csg>),
+}
+
+// A point where a ray crosses the surface of a solid.
+#[derive(Clone)]
+struct Boundary {
+    t: Scalar,
+    normal: Vect,
+    shading_normal: Vect,
+    uvs: Option<Point2<Scalar>>,
+    material: Option<Arc<Box<Material + Send + Sync>>>,
+}
+
+impl Boundary {
+    // The boundary of a solid that extends infinitely along the ray.
+    fn infinite(t: Scalar) -> Boundary {
+        Boundary {
+            t: t,
+            normal: na::zero(),
+            shading_normal: na::zero(),
+            uvs: None,
+            material: None,
+        }
+    }
+}
+
+// An interval along which a ray is inside a solid.
+type Span = (Boundary, Boundary);
+
+impl Csg {
+    /// Creates a leaf of a CSG tree.
+    pub fn leaf<G: 'static + Geometry>(
+        geometry: G,
+        transform: Isometry,
+        material: Option<Arc<Box<Material + Send + Sync>>>,
+    ) -> Csg {
+        Csg::Leaf(Arc::new(geometry), transform, material)
+    }
+
+    /// Creates a node combining two solids.
+    pub fn node(op: CsgOp, a: Csg, b: Csg) -> Csg {
+        Csg::Node(op, Box::new(a), Box::new(b))
+    }
+
+    // The sorted and disjoint intervals along which `ray` is inside this solid transformed by
+    // `m`.
+    fn spans(&self, m: &Isometry, ray: &Ray<Scalar>) -> Vec<Span> {
+        match *self {
+            Csg::Leaf(ref geometry, ref lm, ref material) => {
+                leaf_spans(&**geometry, &(m * lm), ray, material)
+            }
+            Csg::Node(op, ref a, ref b) => combine(op, a.spans(m, ray), b.spans(m, ray)),
+        }
+    }
+}
+
+impl Geometry for Csg {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, solid: bool) -> Option<Intersection> {
+        let spans = self.spans(m, ray);
+        let (entry, exit) = match spans.first() {
+            Some(&(ref entry, ref exit)) => (entry, exit),
+            None => return None,
+        };
+
+        if entry.t > 0.0 {
+            Some(intersection(entry, ray, true))
+        } else if solid {
+            let normal = -ray.dir;
+
+            Some(Intersection::new(0.0, normal, normal, None))
+        } else if exit.t < Bounded::max_value() {
+            Some(intersection(exit, ray, false))
+        } else {
+            None
+        }
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        match *self {
+            Csg::Leaf(ref geometry, ref lm, _) => geometry.aabb(&(m * lm)),
+            Csg::Node(op, ref a, ref b) => {
+                let aabb_a = a.aabb(m);
+
+                match op {
+                    CsgOp::Union => aabb_a.merged(&b.aabb(m)),
+                    CsgOp::Intersection => {
+                        let aabb_b = b.aabb(m);
+                        let mins = na::sup(aabb_a.mins(), aabb_b.mins());
+                        let maxs = na::inf(aabb_a.maxs(), aabb_b.maxs());
+
+                        // Disjoint boxes give an empty intersection, bounded by a point.
+                        AABB::new(mins, na::sup(&mins, &maxs))
+                    }
+                    CsgOp::Difference => aabb_a,
+                }
+            }
+        }
+    }
+}
+
+// Collects the intervals along which a ray is inside a geometry by casting it again after each
+// crossing of its surface.
+fn leaf_spans(
+    geometry: &Geometry,
+    m: &Isometry,
+    ray: &Ray<Scalar>,
+    material: &Option<Arc<Box<Material + Send + Sync>>>,
+) -> Vec<Span> {
+    let mut res = Vec::new();
+    let inside = geometry
+        .cast(m, ray, true)
+        .map(|inter| inter.toi <= 0.0)
+        .unwrap_or(false);
+    let mut entry = if inside {
+        Some(Boundary::infinite(-Bounded::max_value()))
+    } else {
+        None
+    };
+    let mut start: Scalar = 0.0;
+
+    for _ in 0..MAX_CROSSINGS {
+        let shifted = Ray::new(ray.origin + ray.dir * start, ray.dir);
+        let inter = match geometry.cast(m, &shifted, false) {
+            Some(inter) => inter,
+            None => break,
+        };
+
+        let boundary = Boundary {
+            t: start + inter.toi,
+            normal: inter.normal,
+            shading_normal: inter.shading_normal,
+            uvs: inter.uvs,
+            material: material.clone(),
+        };
+
+        // Restart past the crossing to find the next one.
+        start = boundary.t + (boundary.t.abs() + 1.0) * Scalar::epsilon().sqrt();
+
+        match entry.take() {
+            Some(e) => res.push((e, boundary)),
+            None => entry = Some(boundary),
+        }
+    }
+
+    if let Some(e) = entry {
+        res.push((e, Boundary::infinite(Bounded::max_value())))
+    }
+
+    res
+}
+
+// Applies a boolean operation to two sets of sorted and disjoint intervals.
+fn combine(op: CsgOp, a: Vec<Span>, b: Vec<Span>) -> Vec<Span> {
+    let mut events = Vec::with_capacity((a.len() + b.len()) * 2);
+
+    for (entry, exit) in a.into_iter() {
+        events.push((true, entry));
+        events.push((true, exit));
+    }
+
+    for (entry, exit) in b.into_iter() {
+        events.push((false, entry));
+        events.push((false, exit));
+    }
+
+    events.sort_by(|e1, e2| e1.1.t.partial_cmp(&e2.1.t).unwrap_or(Ordering::Equal));
+
+    let mut res = Vec::new();
+    let mut in_a = false;
+    let mut in_b = false;
+    let mut entry = None;
+
+    for (from_a, boundary) in events.into_iter() {
+        if from_a {
+            in_a = !in_a
+        } else {
+            in_b = !in_b
+        }
+
+        let inside = match op {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        };
+
+        match entry.take() {
+            Some(e) => {
+                if inside {
+                    entry = Some(e)
+                } else {
+                    res.push((e, boundary))
+                }
+            }
+            None => {
+                if inside {
+                    entry = Some(boundary)
+                }
+            }
+        }
+    }
+
+    res
+}
+
+// The intersection at a boundary, with normals pointing outside of the combined solid.
+fn intersection(b: &Boundary, ray: &Ray<Scalar>, entering: bool) -> Intersection {
+    // The boundary of a subtracted solid is crossed in the opposite direction.
+    let flip = (na::dot(&b.normal, &ray.dir) > 0.0) == entering;
+    let (normal, shading_normal) = if flip {
+        (-b.normal, -b.shading_normal)
+    } else {
+        (b.normal, b.shading_normal)
+    };
+
+    let mut res = Intersection::new(b.t, normal, shading_normal, b.uvs);
+    res.material = b.material.clone();
+
+    res
+}
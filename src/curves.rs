@@ -0,0 +1,515 @@
+//! Curves made of cubic segments of varying width, e.g., for hair and fur.
+//!
+//! Rays are cast on the segments in a frame where the ray is the `z` axis, by recursively
+//! subdividing them until they are flat enough to be tested as lines with a width.
+
+use bvh::{self, BVH};
+use intersection::Intersection;
+use math::{Isometry, Point, Scalar, Vect, PI};
+use na::{self, Point2};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use num::Bounded;
+use scene_node::Geometry;
+use std::fs::File;
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The maximum number of subdivisions of a segment when a ray is cast on it.
+pub const MAX_SUBDIVISIONS: usize = 10;
+
+/// The representation of the control points of a strand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurveBasis {
+    /// Cubic Bézier segments sharing their end points: `3 n + 1` points give `n` segments.
+    Bezier,
+    /// A uniform cubic B-spline: `n + 3` points give `n` segments.
+    BSpline,
+}
+
+/// The shape of the cross section of curves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurveKind {
+    /// Flat ribbons always facing the ray.
+    Flat,
+    /// Tubes, rendered as ribbons facing the ray with normals bent across their width.
+    Round,
+}
+
+/// A single curve, e.g., a hair, with a width at each of its control points.
+#[derive(Clone, Debug)]
+pub struct Strand {
+    points: Vec<Point>,
+    widths: Vec<Scalar>,
+    basis: CurveBasis,
+}
+
+impl Strand {
+    /// Creates a strand from its control points and widths.
+    pub fn new(points: Vec<Point>, widths: Vec<Scalar>, basis: CurveBasis) -> Strand {
+        assert!(
+            points.len() == widths.len(),
+            "A strand must have as many widths as control points."
+        );
+        assert!(
+            points.len() >= 4,
+            "A strand must have at least 4 control points."
+        );
+
+        if basis == CurveBasis::Bezier {
+            assert!(
+                (points.len() - 1) % 3 == 0,
+                "A Bézier strand must have 3 n + 1 control points."
+            );
+        }
+
+        Strand {
+            points: points,
+            widths: widths,
+            basis: basis,
+        }
+    }
+
+    /// The control points of this strand.
+    pub fn points(&self) -> &[Point] {
+        &self.points[..]
+    }
+
+    /// The widths of this strand at its control points.
+    pub fn widths(&self) -> &[Scalar] {
+        &self.widths[..]
+    }
+
+    /// The representation of the control points of this strand.
+    pub fn basis(&self) -> CurveBasis {
+        self.basis
+    }
+
+    /// The number of cubic segments of this strand.
+    pub fn nsegments(&self) -> usize {
+        match self.basis {
+            CurveBasis::Bezier => (self.points.len() - 1) / 3,
+            CurveBasis::BSpline => self.points.len() - 3,
+        }
+    }
+
+    // The Bézier control points and the end widths of the `i`-th segment.
+    fn segment(&self, i: usize) -> ([Point; 4], (Scalar, Scalar)) {
+        match self.basis {
+            CurveBasis::Bezier => {
+                let p = &self.points[3 * i..3 * i + 4];
+                let w = &self.widths[3 * i..3 * i + 4];
+
+                ([p[0], p[1], p[2], p[3]], (w[0], w[3]))
+            }
+            CurveBasis::BSpline => {
+                let p = &self.points[i..i + 4];
+                let w = &self.widths[i..i + 4];
+                let mix = |a: &Point, b: &Point, c: &Point, wa: Scalar, wb: Scalar, wc: Scalar| {
+                    Point::from_coordinates((a.coords * wa + b.coords * wb + c.coords * wc) / 6.0)
+                };
+
+                (
+                    [
+                        mix(&p[0], &p[1], &p[2], 1.0, 4.0, 1.0),
+                        mix(&p[1], &p[2], &p[2], 4.0, 2.0, 0.0),
+                        mix(&p[1], &p[2], &p[2], 2.0, 4.0, 0.0),
+                        mix(&p[1], &p[2], &p[3], 1.0, 4.0, 1.0),
+                    ],
+                    (
+                        (w[0] + 4.0 * w[1] + w[2]) / 6.0,
+                        (w[1] + 4.0 * w[2] + w[3]) / 6.0,
+                    ),
+                )
+            }
+        }
+    }
+}
+
+// A cubic Bézier segment of a strand, with a width varying linearly along it.
+struct Segment {
+    points: [Point; 4],
+    widths: (Scalar, Scalar),
+    // The range of the strand parameter covered by this segment.
+    range: (Scalar, Scalar),
+}
+
+// An orthonormal frame whose `z` axis is a ray.
+struct RayFrame {
+    origin: Point,
+    axes: [Vect; 3],
+    // The norm of the ray direction, i.e., the distance traveled per unit of time of impact.
+    speed: Scalar,
+}
+
+impl RayFrame {
+    fn new(ray: &Ray<Scalar>) -> RayFrame {
+        let speed = na::norm(&ray.dir);
+        let dir = ray.dir / speed;
+        let reference = if dir.x.abs() < 0.9 { Vect::x() } else { Vect::y() };
+        let e1 = na::normalize(&(reference - dir * na::dot(&dir, &reference)));
+        let e2 = dir.cross(&e1);
+
+        RayFrame {
+            origin: ray.origin,
+            axes: [e1, e2, dir],
+            speed: speed,
+        }
+    }
+
+    fn project(&self, pt: &Point) -> Point {
+        let dpt = *pt - self.origin;
+
+        Point::new(
+            na::dot(&dpt, &self.axes[0]),
+            na::dot(&dpt, &self.axes[1]),
+            na::dot(&dpt, &self.axes[2]),
+        )
+    }
+}
+
+/// A set of strands, whose segments are indexed by a BVH.
+pub struct Curves {
+    segments: Vec<Segment>,
+    kind: CurveKind,
+    bvh: BVH,
+}
+
+impl Curves {
+    /// Creates a set of curves from its strands and builds its BVH.
+    pub fn new(strands: &[Strand], kind: CurveKind) -> Curves {
+        let mut segments = Vec::new();
+
+        for strand in strands.iter() {
+            let n = strand.nsegments();
+
+            for i in 0..n {
+                let (points, widths) = strand.segment(i);
+
+                segments.push(Segment {
+                    points: points,
+                    widths: widths,
+                    range: (i as Scalar / n as Scalar, (i + 1) as Scalar / n as Scalar),
+                })
+            }
+        }
+
+        // The convex hull of the control points of a Bézier segment contains it.
+        let aabbs: Vec<AABB<Scalar>> = segments
+            .iter()
+            .map(|s| {
+                let p = &s.points;
+                let hw = s.widths.0.max(s.widths.1) / 2.0;
+                let margin = Vect::new(hw, hw, hw);
+                let mins = na::inf(&na::inf(&p[0], &p[1]), &na::inf(&p[2], &p[3]));
+                let maxs = na::sup(&na::sup(&p[0], &p[1]), &na::sup(&p[2], &p[3]));
+
+                AABB::new(mins - margin, maxs + margin)
+            })
+            .collect();
+
+        let bvh = BVH::new(&aabbs[..]);
+
+        Curves {
+            segments: segments,
+            kind: kind,
+            bvh: bvh,
+        }
+    }
+
+    /// The shape of the cross section of these curves.
+    pub fn kind(&self) -> CurveKind {
+        self.kind
+    }
+
+    /// The number of cubic segments of these curves.
+    pub fn nsegments(&self) -> usize {
+        self.segments.len()
+    }
+
+    // Casts a ray, expressed by its frame, on a segment.
+    //
+    // Returns the time of impact and the segment parameter of the hit.
+    fn cast_segment(
+        &self,
+        segment: &Segment,
+        frame: &RayFrame,
+        max_toi: Scalar,
+    ) -> Option<(Scalar, Scalar)> {
+        let p = &segment.points;
+        let cp = [
+            frame.project(&p[0]),
+            frame.project(&p[1]),
+            frame.project(&p[2]),
+            frame.project(&p[3]),
+        ];
+
+        // Subdivide until the segment deviates from a line by a fraction of its width.
+        let mut l0: Scalar = 0.0;
+
+        for i in 0..2 {
+            let d = cp[i].coords - cp[i + 1].coords * 2.0 + cp[i + 2].coords;
+            l0 = l0.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+        }
+
+        let eps = segment.widths.0.max(segment.widths.1) * 0.05;
+        let x = (2.0 as Scalar).sqrt() * 6.0 * l0 / (8.0 * eps);
+        let depth = if eps == 0.0 {
+            0
+        } else if x > 1.0 {
+            (x.log2() as usize / 2).min(MAX_SUBDIVISIONS)
+        } else {
+            0
+        };
+
+        recursive_cast(&cp, segment.widths, 0.0, 1.0, depth, max_toi * frame.speed)
+            .map(|(z, u)| (z / frame.speed, u))
+    }
+
+    // Builds the intersection of a local ray hitting a segment at `toi`, at the segment
+    // parameter `u`.
+    fn intersection(
+        &self,
+        m: &Isometry,
+        ray: &Ray<Scalar>,
+        segment: &Segment,
+        toi: Scalar,
+        u: Scalar,
+    ) -> Intersection {
+        let (center, derivative) = eval_bezier(&segment.points, u);
+        let tangent = if na::norm_squared(&derivative) > 0.0 {
+            na::normalize(&derivative)
+        } else {
+            na::normalize(&(segment.points[3] - segment.points[0]))
+        };
+
+        // The ribbon faces the ray.
+        let facing = -(ray.dir - tangent * na::dot(&ray.dir, &tangent));
+        let facing = if na::norm_squared(&facing) > 0.0 {
+            na::normalize(&facing)
+        } else {
+            let reference = if tangent.x.abs() < 0.9 { Vect::x() } else { Vect::y() };
+            na::normalize(&(reference - tangent * na::dot(&tangent, &reference)))
+        };
+        let bitangent = tangent.cross(&facing);
+
+        let width = segment.widths.0 + (segment.widths.1 - segment.widths.0) * u;
+        let pt = ray.origin + ray.dir * toi;
+        let side = na::dot(&(pt - center), &bitangent) / width;
+        let v = (0.5 + side).max(0.0).min(1.0);
+
+        let shading_normal = match self.kind {
+            CurveKind::Flat => facing,
+            CurveKind::Round => {
+                let theta = (2.0 * v - 1.0) * (PI / 2.0);
+
+                facing * theta.cos() + bitangent * theta.sin()
+            }
+        };
+
+        let range = segment.range;
+        let uvs = Point2::new(range.0 + (range.1 - range.0) * u, v);
+        let mut res = Intersection::new(toi, m * facing, m * shading_normal, Some(uvs));
+        res.tangent_frame = Some((m * tangent, m * tangent.cross(&shading_normal)));
+
+        res
+    }
+}
+
+impl Geometry for Curves {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let inv = m.inverse();
+        let local_ray = Ray::new(inv * ray.origin, inv * ray.dir);
+        let frame = RayFrame::new(&local_ray);
+
+        self.bvh
+            .cast_ray(&local_ray, Bounded::max_value(), |i, max_toi| {
+                self.cast_segment(&self.segments[i], &frame, max_toi)
+                    .map(|(toi, u)| (toi, (toi, u)))
+            })
+            .map(|(i, (toi, u))| self.intersection(m, &local_ray, &self.segments[i], toi, u))
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        match self.bvh.aabb() {
+            Some(aabb) => bvh::transform_aabb(aabb, m),
+            None => AABB::new(m * Point::origin(), m * Point::origin()),
+        }
+    }
+}
+
+// Casts the `z` axis on the part of a segment between the parameters `u0` and `u1`, whose
+// control points are `cp` in the ray frame.
+//
+// Returns the distance along the ray and the segment parameter of the hit.
+fn recursive_cast(
+    cp: &[Point; 4],
+    widths: (Scalar, Scalar),
+    u0: Scalar,
+    u1: Scalar,
+    depth: usize,
+    max_z: Scalar,
+) -> Option<(Scalar, Scalar)> {
+    let w0 = widths.0 + (widths.1 - widths.0) * u0;
+    let w1 = widths.0 + (widths.1 - widths.0) * u1;
+    let hw = w0.max(w1) / 2.0;
+    let mins = na::inf(&na::inf(&cp[0], &cp[1]), &na::inf(&cp[2], &cp[3]));
+    let maxs = na::sup(&na::sup(&cp[0], &cp[1]), &na::sup(&cp[2], &cp[3]));
+
+    if maxs.x + hw < 0.0
+        || mins.x - hw > 0.0
+        || maxs.y + hw < 0.0
+        || mins.y - hw > 0.0
+        || maxs.z + hw < 0.0
+        || mins.z - hw > max_z
+    {
+        return None;
+    }
+
+    if depth > 0 {
+        let (left, right) = split_bezier(cp);
+        let um = (u0 + u1) / 2.0;
+        let hit = recursive_cast(&left, widths, u0, um, depth - 1, max_z);
+        let max_z = hit.map(|h| h.0).unwrap_or(max_z);
+
+        return recursive_cast(&right, widths, um, u1, depth - 1, max_z).or(hit);
+    }
+
+    // The origin must lie between the lines orthogonal to the segment at its ends.
+    let start = Point2::new(cp[0].x, cp[0].y);
+    let end = Point2::new(cp[3].x, cp[3].y);
+
+    if na::dot(&-start.coords, &(Point2::new(cp[1].x, cp[1].y) - start)) < 0.0
+        || na::dot(&-end.coords, &(Point2::new(cp[2].x, cp[2].y) - end)) < 0.0
+    {
+        return None;
+    }
+
+    // The closest point to the origin of the segment approximated as a line.
+    let dir = end - start;
+    let denom = na::norm_squared(&dir);
+
+    if denom == 0.0 {
+        return None;
+    }
+
+    let w = (na::dot(&-start.coords, &dir) / denom).max(0.0).min(1.0);
+    let u = u0 + (u1 - u0) * w;
+    let width = widths.0 + (widths.1 - widths.0) * u;
+    let (pc, _) = eval_bezier(cp, w);
+
+    if pc.x * pc.x + pc.y * pc.y > width * width * 0.25 || pc.z < 0.0 || pc.z > max_z {
+        None
+    } else {
+        Some((pc.z, u))
+    }
+}
+
+// The point and derivative of a cubic Bézier curve at the parameter `t`.
+fn eval_bezier(cp: &[Point; 4], t: Scalar) -> (Point, Vect) {
+    let lerp = |a: &Point, b: &Point| *a + (*b - *a) * t;
+    let c01 = lerp(&cp[0], &cp[1]);
+    let c12 = lerp(&cp[1], &cp[2]);
+    let c23 = lerp(&cp[2], &cp[3]);
+    let c012 = lerp(&c01, &c12);
+    let c123 = lerp(&c12, &c23);
+
+    (lerp(&c012, &c123), (c123 - c012) * 3.0)
+}
+
+// Splits a cubic Bézier curve at its middle with de Casteljau's algorithm.
+fn split_bezier(cp: &[Point; 4]) -> ([Point; 4], [Point; 4]) {
+    let mid = |a: &Point, b: &Point| na::center(a, b);
+    let c01 = mid(&cp[0], &cp[1]);
+    let c12 = mid(&cp[1], &cp[2]);
+    let c23 = mid(&cp[2], &cp[3]);
+    let c012 = mid(&c01, &c12);
+    let c123 = mid(&c12, &c23);
+    let c = mid(&c012, &c123);
+
+    ([cp[0], c01, c012, c], [c, c123, c23, cp[3]])
+}
+
+/// Parses a file describing strands.
+///
+/// See `parse` for the format of the file.
+pub fn parse_file(path: &Path) -> IoResult<Vec<Strand>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut sfile = String::new();
+            file.read_to_string(&mut sfile).map(|_| parse(&sfile[..]))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses a string describing strands.
+///
+/// Each line `curve x0 y0 z0 w0 x1 y1 z1 w1 ...` describes a strand by the positions and
+/// widths of its control points. A line `basis bezier` or `basis bspline` sets the basis of
+/// the strands following it, B-splines by default. Lines starting with `#` are ignored.
+pub fn parse(string: &str) -> Vec<Strand> {
+    let mut res = Vec::new();
+    let mut basis = CurveBasis::BSpline;
+
+    for (l, line) in string.lines().enumerate() {
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            None => {}
+            Some(w) if w.starts_with('#') => {}
+            Some("basis") => {
+                basis = match words.next() {
+                    Some("bezier") => CurveBasis::Bezier,
+                    Some("bspline") => CurveBasis::BSpline,
+                    _ => error(l, "`bezier' or `bspline' was expected."),
+                }
+            }
+            Some("curve") => {
+                let values: Vec<Scalar> = words
+                    .map(|w| {
+                        let x: Result<Scalar, _> = FromStr::from_str(w);
+                        x.unwrap_or_else(|_| {
+                            error(l, &format!("failed to parse `{}' as a number.", w)[..])
+                        })
+                    })
+                    .collect();
+
+                if values.len() % 4 != 0 {
+                    error(l, "each control point must have 3 coordinates and a width.")
+                }
+
+                let npoints = values.len() / 4;
+                let enough = match basis {
+                    CurveBasis::Bezier => npoints >= 4 && (npoints - 1) % 3 == 0,
+                    CurveBasis::BSpline => npoints >= 4,
+                };
+
+                if !enough {
+                    error(l, "invalid number of control points for the curve basis.")
+                }
+
+                let points = values
+                    .chunks(4)
+                    .map(|c| Point::new(c[0], c[1], c[2]))
+                    .collect();
+                let widths = values.chunks(4).map(|c| c[3]).collect();
+
+                res.push(Strand::new(points, widths, basis))
+            }
+            Some(w) => warn(l, &format!("unknown tag `{}' ignored.", w)[..]),
+        }
+    }
+
+    res
+}
+
+fn error(line: usize, err: &str) -> ! {
+    panic!("At line {}: {}", line, err)
+}
+
+fn warn(line: usize, err: &str) {
+    println!("At line {}: {}", line, err)
+}
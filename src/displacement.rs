@@ -0,0 +1,248 @@
+//! Displacement mapping of triangle meshes, with tessellation to a target edge length.
+
+use math::{Point, Scalar, Vect};
+use mesh;
+use na::{self, Point2, Point3};
+use std::collections::HashMap;
+use texture2d::Texture2d;
+
+/// The maximum number of times the triangles of a mesh are split by a tessellator.
+///
+/// Each level may multiply the number of triangles by four.
+pub const MAX_TESSELLATION_LEVELS: usize = 8;
+
+/// The target length of the edges of a tessellated mesh.
+#[derive(Clone, Copy, Debug)]
+pub enum EdgeLength {
+    /// A length in world units.
+    World(Scalar),
+    /// A length in pixels on the image of a camera at `eye` whose pixels span `pixel_angle`
+    /// radians each.
+    Screen {
+        /// The position of the camera.
+        eye: Point,
+        /// The angle spanned by one pixel, e.g., the vertical field of view divided by the
+        /// vertical resolution.
+        pixel_angle: Scalar,
+        /// The target edge length, in pixels.
+        pixels: Scalar,
+    },
+}
+
+impl EdgeLength {
+    /// The largest world-space length of an edge with its middle at `pt`.
+    pub fn max_length_at(&self, pt: &Point) -> Scalar {
+        match *self {
+            EdgeLength::World(length) => length,
+            EdgeLength::Screen {
+                ref eye,
+                pixel_angle,
+                pixels,
+            } => na::distance(eye, pt) * pixel_angle * pixels,
+        }
+    }
+}
+
+/// Splits the triangles of meshes sharing the same vertex buffers until their edges are shorter
+/// than a target length.
+///
+/// An edge is split at its middle if and only if it is too long, whatever the triangle it
+/// belongs to, so that tessellating adjacent triangles, even from different meshes, does not
+/// introduce cracks.
+pub struct Tessellator {
+    vertices: Vec<Point>,
+    normals: Vec<Vect>,
+    uvs: Vec<Point2<Scalar>>,
+    edge_length: EdgeLength,
+    // Vertex inserted on the middle of each split edge.
+    midpoints: HashMap<(usize, usize), usize>,
+}
+
+impl Tessellator {
+    /// Creates a tessellator adding vertices to the given buffers.
+    pub fn new(
+        vertices: Vec<Point>,
+        normals: Vec<Vect>,
+        uvs: Vec<Point2<Scalar>>,
+        edge_length: EdgeLength,
+    ) -> Tessellator {
+        assert!(
+            normals.len() == vertices.len() && uvs.len() == vertices.len(),
+            "A tessellated mesh must have as many normals and texture coordinates as vertices."
+        );
+
+        Tessellator {
+            vertices: vertices,
+            normals: normals,
+            uvs: uvs,
+            edge_length: edge_length,
+            midpoints: HashMap::new(),
+        }
+    }
+
+    /// Tessellates a set of triangles indexing the vertex buffers of this tessellator.
+    pub fn tessellate(&mut self, indices: &[Point3<usize>]) -> Vec<Point3<usize>> {
+        let mut triangles = indices.to_vec();
+
+        for _ in 0..MAX_TESSELLATION_LEVELS {
+            let mut res = Vec::with_capacity(triangles.len());
+
+            for t in triangles.iter() {
+                let mids = [
+                    self.midpoint(t.x, t.y),
+                    self.midpoint(t.y, t.z),
+                    self.midpoint(t.z, t.x),
+                ];
+
+                self.split(&[t.x, t.y, t.z], &mids, &mut res);
+            }
+
+            let done = res.len() == triangles.len();
+            triangles = res;
+
+            if done {
+                break;
+            }
+        }
+
+        triangles
+    }
+
+    /// The vertices, normals and texture coordinates of every mesh tessellated so far.
+    pub fn unwrap(self) -> (Vec<Point>, Vec<Vect>, Vec<Point2<Scalar>>) {
+        (self.vertices, self.normals, self.uvs)
+    }
+
+    // The vertex splitting the edge `(a, b)`, if it is too long.
+    fn midpoint(&mut self, a: usize, b: usize) -> Option<usize> {
+        // Compute the midpoint from sorted indices so that it does not depend on the edge
+        // orientation.
+        let key = if a < b { (a, b) } else { (b, a) };
+
+        if let Some(mid) = self.midpoints.get(&key) {
+            return Some(*mid);
+        }
+
+        let (a, b) = key;
+        let pa = self.vertices[a];
+        let pb = self.vertices[b];
+        let middle = na::center(&pa, &pb);
+
+        if na::distance(&pa, &pb) <= self.edge_length.max_length_at(&middle) {
+            return None;
+        }
+
+        let n = self.normals[a] + self.normals[b];
+        let normal = if na::norm(&n) > 0.0 {
+            na::normalize(&n)
+        } else {
+            self.normals[a]
+        };
+
+        let id = self.vertices.len();
+        self.vertices.push(middle);
+        self.normals.push(normal);
+        self.uvs.push(na::center(&self.uvs[a], &self.uvs[b]));
+        let _ = self.midpoints.insert(key, id);
+
+        Some(id)
+    }
+
+    // Splits the triangle `t` given the vertices splitting each of its edges, starting with
+    // `(t[0], t[1])`.
+    fn split(&self, t: &[usize; 3], mids: &[Option<usize>; 3], out: &mut Vec<Point3<usize>>) {
+        let nsplits = mids.iter().filter(|m| m.is_some()).count();
+
+        match nsplits {
+            0 => out.push(Point3::new(t[0], t[1], t[2])),
+            3 => {
+                let (m01, m12, m20) = (mids[0].unwrap(), mids[1].unwrap(), mids[2].unwrap());
+
+                out.push(Point3::new(t[0], m01, m20));
+                out.push(Point3::new(m01, t[1], m12));
+                out.push(Point3::new(m20, m12, t[2]));
+                out.push(Point3::new(m01, m12, m20));
+            }
+            _ => {
+                // Rotate the triangle so that its first edge is split and, with two splits,
+                // its last one is not.
+                let k = (0..3)
+                    .find(|k| {
+                        mids[*k].is_some() && (nsplits == 1 || mids[(*k + 2) % 3].is_none())
+                    })
+                    .unwrap();
+                let v0 = t[k];
+                let v1 = t[(k + 1) % 3];
+                let v2 = t[(k + 2) % 3];
+                let m01 = mids[k].unwrap();
+
+                if nsplits == 1 {
+                    out.push(Point3::new(v0, m01, v2));
+                    out.push(Point3::new(m01, v1, v2));
+                } else {
+                    let m12 = mids[(k + 1) % 3].unwrap();
+
+                    out.push(Point3::new(m01, v1, m12));
+
+                    // Cut the remaining quad along its shortest diagonal.
+                    let d0 = na::distance(&self.vertices[v0], &self.vertices[m12]);
+                    let d1 = na::distance(&self.vertices[m01], &self.vertices[v2]);
+
+                    if d0 < d1 {
+                        out.push(Point3::new(v0, m01, m12));
+                        out.push(Point3::new(v0, m12, v2));
+                    } else {
+                        out.push(Point3::new(v0, m01, v2));
+                        out.push(Point3::new(m01, m12, v2));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Moves each vertex along its normal by the height read from `texture` at its texture
+/// coordinates, times `scale`.
+///
+/// The height of a texel is the mean of its color channels. Vertices at the same position,
+/// e.g., duplicated along texture seams, are moved together along their mean normal by their
+/// mean height, so that the surface does not split there.
+pub fn displace(
+    vertices: &mut [Point],
+    normals: &[Vect],
+    uvs: &[Point2<Scalar>],
+    texture: &Texture2d,
+    scale: Scalar,
+) {
+    let mut positions = HashMap::new();
+
+    for (i, pt) in vertices.iter().enumerate() {
+        positions
+            .entry(mesh::position_key(pt))
+            .or_insert_with(Vec::new)
+            .push(i);
+    }
+
+    for ids in positions.values() {
+        let mut normal = na::zero::<Vect>();
+        let mut height = 0.0;
+
+        for i in ids.iter() {
+            let c = texture.sample(&uvs[*i]);
+
+            normal = normal + normals[*i];
+            height = height + ((c.x + c.y + c.z) / 3.0) as Scalar;
+        }
+
+        let normal = if na::norm(&normal) > 0.0 {
+            na::normalize(&normal)
+        } else {
+            normals[ids[0]]
+        };
+        let offset = normal * (height / ids.len() as Scalar * scale);
+
+        for i in ids.iter() {
+            vertices[*i] = vertices[*i] + offset;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! A film accumulating the samples of a rendering from several threads.
+
+use image::{Image, Vless};
+use math::Scalar;
+use na::{self, Point2, Vector3};
+use std::iter;
+use std::sync::Mutex;
+
+/// The sums of the colors received by each pixel of an image.
+///
+/// Each row is locked separately, so that threads rendering different parts of the image, or
+/// splatting light paths to arbitrary pixels, rarely wait for each other.
+pub struct Film {
+    resolution: Vless,
+    rows: Vec<Mutex<Vec<Vector3<f32>>>>,
+}
+
+impl Film {
+    /// Creates a black film with the given number of pixel columns and rows.
+    pub fn new(resolution: &Vless) -> Film {
+        let width = resolution.x as usize;
+        let rows = (0..resolution.y as usize)
+            .map(|_| Mutex::new(iter::repeat(na::zero()).take(width).collect()))
+            .collect();
+
+        Film {
+            resolution: resolution.clone(),
+            rows: rows,
+        }
+    }
+
+    /// The number of pixel columns of this film.
+    pub fn width(&self) -> usize {
+        self.resolution.x as usize
+    }
+
+    /// The number of pixel rows of this film.
+    pub fn height(&self) -> usize {
+        self.resolution.y as usize
+    }
+
+    /// Adds `color` to the pixel at column `x` and row `y`, the first row being the top one.
+    pub fn add(&self, x: usize, y: usize, color: &Vector3<f32>) {
+        let mut row = self.rows[y].lock().unwrap();
+        row[x] = row[x] + *color;
+    }
+
+    /// Adds `color` to the pixel containing the raster position `pt`, where the pixel at column
+    /// `x` and row `y` covers the positions within half a unit of `(x, y)`.
+    ///
+    /// Positions outside of the film are ignored.
+    pub fn splat(&self, pt: &Point2<Scalar>, color: &Vector3<f32>) {
+        let x = (pt.x + 0.5).floor();
+        let y = (pt.y + 0.5).floor();
+
+        if x >= 0.0 && y >= 0.0 && (x as usize) < self.width() && (y as usize) < self.height() {
+            self.add(x as usize, y as usize, color)
+        }
+    }
+
+    /// Creates an image from the sums of this film multiplied by `scale`.
+    pub fn to_image(&self, scale: f32) -> Image {
+        let mut pixels = Vec::with_capacity(self.width() * self.height());
+
+        for row in self.rows.iter() {
+            pixels.extend(row.lock().unwrap().iter().map(|c| *c * scale));
+        }
+
+        Image::new(self.resolution.clone(), pixels)
+    }
+}
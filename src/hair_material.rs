@@ -0,0 +1,151 @@
+//! Shading models for hair and fur.
+
+use intersection::Intersection;
+use material::Material;
+use math::{Point, Scalar, Vect};
+use na::{self, Point2, Point3, Point4, Vector3};
+use ray_with_energy::RayWithEnergy;
+use scene::Scene;
+use std::f32;
+
+/// The scattering model of a hair material.
+#[derive(Clone, Copy, Debug)]
+pub enum HairModel {
+    /// The Kajiya–Kay model: a diffuse term depending on the angle between the light and the
+    /// fiber, and a specular highlight with the given color and exponent.
+    KajiyaKay(Point3<f32>, f32),
+    /// A Marschner-style model with the reflection (R), transmission (TT) and internal
+    /// reflection (TRT) lobes, given the tilt of the cuticle scales and the longitudinal
+    /// roughness of the fiber, both in radians.
+    ///
+    /// The azimuthal terms use the approximations of Karis for real-time rendering.
+    Marschner(f32, f32),
+}
+
+/// A material for curves, shaded with respect to the direction of their fibers.
+///
+/// The direction of a fiber is the first vector of the tangent frame of the intersection, e.g.,
+/// the tangent of a curve. Surfaces without a tangent frame are shaded as diffuse.
+pub struct HairMaterial {
+    ambiant_color: Point3<f32>,
+    color: Point3<f32>,
+    model: HairModel,
+}
+
+impl HairMaterial {
+    /// Creates a hair material with the given ambiant color, fiber color, and scattering model.
+    pub fn new(ambiant_color: Point3<f32>, color: Point3<f32>, model: HairModel) -> HairMaterial {
+        HairMaterial {
+            ambiant_color: ambiant_color,
+            color: color,
+            model: model,
+        }
+    }
+
+    /// The scattering model of this material.
+    pub fn model(&self) -> &HairModel {
+        &self.model
+    }
+
+    // The radiance scattered toward `eye` for a unit light coming from `light`, both pointing
+    // away from the fiber with direction `fiber`.
+    fn scattering(&self, fiber: &Vect, light: &Vect, eye: &Vect) -> Vector3<f32> {
+        let tl = na::dot(fiber, light) as f32;
+        let te = na::dot(fiber, eye) as f32;
+        let sin_tl = (1.0 - tl * tl).max(0.0).sqrt();
+
+        match self.model {
+            HairModel::KajiyaKay(specular, exponent) => {
+                let half = *light + *eye;
+                let th = if na::norm_squared(&half) > 0.0 {
+                    na::dot(fiber, &na::normalize(&half)) as f32
+                } else {
+                    0.0
+                };
+                let sin_th = (1.0 - th * th).max(0.0).sqrt();
+
+                self.color.coords * sin_tl + specular.coords * sin_th.powf(exponent)
+            }
+            HairModel::Marschner(shift, roughness) => {
+                // Longitudinal angles, relative to the normal plane of the fiber.
+                let theta_i = tl.max(-1.0).min(1.0).asin();
+                let theta_r = te.max(-1.0).min(1.0).asin();
+                let theta_h = (theta_i + theta_r) / 2.0;
+                let cos_d = ((theta_r - theta_i) / 2.0).cos();
+
+                // Azimuthal angle between the projections on the normal plane.
+                let lp = *light - *fiber * (tl as Scalar);
+                let ep = *eye - *fiber * (te as Scalar);
+                let cos_phi = if na::norm_squared(&lp) > 0.0 && na::norm_squared(&ep) > 0.0 {
+                    na::dot(&na::normalize(&lp), &na::normalize(&ep)) as f32
+                } else {
+                    1.0
+                };
+
+                let m_r = gaussian(roughness, theta_h - shift);
+                let m_tt = gaussian(roughness / 2.0, theta_h + shift / 2.0);
+                let m_trt = gaussian(roughness * 2.0, theta_h + shift * 3.0 / 2.0);
+
+                let n_r = 0.25 * ((1.0 + cos_phi) / 2.0).max(0.0).sqrt();
+                let n_tt = (-3.65 * cos_phi - 3.98).exp();
+                let n_trt = 0.25 * (17.0 * cos_phi - 16.78).exp();
+
+                let white = Vector3::new(1.0, 1.0, 1.0);
+                let absorbed = self.color.coords;
+                let lobes = white * (m_r * n_r)
+                    + absorbed * (m_tt * n_tt)
+                    + absorbed.component_mul(&absorbed) * (m_trt * n_trt);
+
+                lobes * (sin_tl / (cos_d * cos_d).max(1.0e-4))
+            }
+        }
+    }
+}
+
+impl Material for HairMaterial {
+    fn ambiant(&self, _: &Point, _: &Vect, _: &Option<Point2<Scalar>>) -> Point4<f32> {
+        let a = self.ambiant_color;
+
+        Point4::new(a.x, a.y, a.z, 1.0)
+    }
+
+    fn diffuse(&self, _: &Point, _: &Vect, _: &Option<Point2<Scalar>>) -> Point3<f32> {
+        self.color
+    }
+
+    fn compute(
+        &self,
+        ray: &RayWithEnergy,
+        point: &Point,
+        inter: &Intersection,
+        scene: &Scene,
+    ) -> Point4<f32> {
+        let normal = &inter.shading_normal;
+        let fiber = inter.tangent_frame.map(|(t, _)| t);
+        let eye = -na::normalize(&ray.ray.dir);
+        let mut res = self.ambiant_color.coords;
+
+        // compute the contribution of each light
+        for light in scene.lights().iter() {
+            let mut acc = Vector3::new(0.0f32, 0.0, 0.0);
+
+            scene.visible_light_samples(light, point, &inter.error, &inter.normal, |ldir, filter| {
+                let scattered = match fiber {
+                    Some(ref fiber) => self.scattering(fiber, ldir, &eye),
+                    None => self.color.coords * (na::dot(ldir, normal) as f32).max(0.0),
+                };
+
+                acc = acc + light.color.coords.component_mul(&filter.component_mul(&scattered));
+            });
+
+            res.axpy(1.0 / (light.racsample * light.racsample) as f32, &acc, 1.0);
+        }
+
+        Point4::new(res.x, res.y, res.z, 1.0)
+    }
+}
+
+// The normalized gaussian with the given standard deviation.
+fn gaussian(width: f32, x: f32) -> f32 {
+    (-x * x / (2.0 * width * width)).exp() / (width * (2.0 * f32::consts::PI).sqrt())
+}
@@ -0,0 +1,424 @@
+//! Heightfield terrains.
+
+use bvh;
+use intersection::Intersection;
+use math::{self, Isometry, Point, Scalar, Vect};
+use na::{self, Point2, Point3, Vector2};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use num::Bounded;
+use scene_node::Geometry;
+use texture2d::Texture2d;
+use triangle_mesh;
+
+/// A regular grid of heights along the `y` axis, centered at the origin on the `xz` plane.
+///
+/// Each cell of the grid is made of two triangles, shaded with normals interpolated from the
+/// samples. The texture coordinates range from 0 to 1 along the `x` and `z` axes.
+///
+/// Rays are cast by a 2D DDA over a pyramid of the minimum and maximum heights of blocks of
+/// cells, descending only into the blocks whose height range the ray crosses.
+pub struct Heightfield {
+    samples: Vector2<usize>,
+    extents: Vector2<Scalar>,
+    cell: Vector2<Scalar>,
+    heights: Vec<Scalar>,
+    normals: Vec<Vect>,
+    levels: Vec<Level>,
+    aabb: AABB<Scalar>,
+}
+
+// A level of the min/max pyramid. The cells of a level cover 2x2 cells of the level below.
+struct Level {
+    dims: Vector2<usize>,
+    bounds: Vec<(Scalar, Scalar)>,
+}
+
+impl Heightfield {
+    /// Creates a heightfield with the given number of samples along the `x` and `z` axes, and
+    /// the given size along those axes.
+    ///
+    /// The heights are given row by row, with `x` increasing along each row and `z` from one
+    /// row to the next.
+    pub fn new(
+        heights: Vec<Scalar>,
+        samples: Vector2<usize>,
+        extents: Vector2<Scalar>,
+    ) -> Heightfield {
+        assert!(
+            samples.x >= 2 && samples.y >= 2,
+            "A heightfield must have at least 2 samples along each axis."
+        );
+        assert!(
+            heights.len() == samples.x * samples.y,
+            "A heightfield must have one height per sample."
+        );
+        assert!(
+            extents.x > 0.0 && extents.y > 0.0,
+            "The extents of a heightfield must be positive."
+        );
+
+        let cell = Vector2::new(
+            extents.x / (samples.x - 1) as Scalar,
+            extents.y / (samples.y - 1) as Scalar,
+        );
+        let normals = sample_normals(&heights[..], &samples, &cell);
+        let levels = pyramid(&heights[..], &samples);
+        let (lo, hi) = levels.last().unwrap().bounds[0];
+        let half = extents / 2.0;
+
+        Heightfield {
+            samples: samples,
+            extents: extents,
+            cell: cell,
+            heights: heights,
+            normals: normals,
+            levels: levels,
+            aabb: AABB::new(Point::new(-half.x, lo, -half.y), Point::new(half.x, hi, half.y)),
+        }
+    }
+
+    /// Creates a heightfield with one sample per texel of `texture`, with heights given by the
+    /// mean of their color components times `height_scale`.
+    ///
+    /// The `u` and `v` axes of the texture are mapped to the `x` and `z` axes.
+    pub fn from_texture(
+        texture: &Texture2d,
+        extents: Vector2<Scalar>,
+        height_scale: Scalar,
+    ) -> Heightfield {
+        let samples = Vector2::new(texture.width(), texture.height());
+        let mut heights = Vec::with_capacity(samples.x * samples.y);
+
+        for j in 0..samples.y {
+            for i in 0..samples.x {
+                let c = texture.at(i, j);
+
+                heights.push(((c.x + c.y + c.z) / 3.0) as Scalar * height_scale);
+            }
+        }
+
+        Heightfield::new(heights, samples, extents)
+    }
+
+    /// The number of samples of this heightfield along the `x` and `z` axes.
+    pub fn samples(&self) -> &Vector2<usize> {
+        &self.samples
+    }
+
+    /// The size of this heightfield along the `x` and `z` axes.
+    pub fn extents(&self) -> &Vector2<Scalar> {
+        &self.extents
+    }
+
+    /// The height of the sample at the given column and row.
+    pub fn height(&self, i: usize, j: usize) -> Scalar {
+        self.heights[j * self.samples.x + i]
+    }
+
+    // The local position of the sample with the given linear index.
+    fn vertex(&self, id: usize) -> Point {
+        let (i, j) = (id % self.samples.x, id / self.samples.x);
+
+        Point::new(
+            i as Scalar * self.cell.x - self.extents.x / 2.0,
+            self.heights[id],
+            j as Scalar * self.cell.y - self.extents.y / 2.0,
+        )
+    }
+
+    // The texture coordinates of the sample with the given linear index.
+    fn uvs(&self, id: usize) -> Point2<Scalar> {
+        let (i, j) = (id % self.samples.x, id / self.samples.x);
+
+        Point2::new(
+            i as Scalar / (self.samples.x - 1) as Scalar,
+            j as Scalar / (self.samples.y - 1) as Scalar,
+        )
+    }
+
+    // Walks the cells of a level crossed by the ray between `ta` and `tb`, nearest first.
+    //
+    // Returns the time of impact, the sample indices of the triangle hit, and the barycentric
+    // coordinates of the hit relative to its second and third vertices.
+    fn traverse(
+        &self,
+        ray: &Ray<Scalar>,
+        level: usize,
+        ta: Scalar,
+        tb: Scalar,
+    ) -> Option<(Scalar, Point3<usize>, Scalar, Scalar)> {
+        let lvl = &self.levels[level];
+        let scale = (1usize << level) as Scalar;
+        let size = Vector2::new(self.cell.x * scale, self.cell.y * scale);
+        let mins = Vector2::new(-self.extents.x / 2.0, -self.extents.y / 2.0);
+        let start = ray.origin + ray.dir * ta;
+
+        let (mut i, step_i, mut next_i, delta_i) =
+            dda_axis(start.x - mins.x, ray.dir.x, size.x, lvl.dims.x, ta);
+        let (mut j, step_j, mut next_j, delta_j) =
+            dda_axis(start.z - mins.y, ray.dir.z, size.y, lvl.dims.y, ta);
+        let mut t = ta;
+
+        loop {
+            let t_exit = next_i.min(next_j).min(tb);
+            let (lo, hi) = lvl.bounds[j * lvl.dims.x + i];
+            let y0 = ray.origin.y + ray.dir.y * t;
+            let y1 = ray.origin.y + ray.dir.y * t_exit;
+
+            // The ray only needs to be refined where it crosses the height range of the cell.
+            if y0.min(y1) <= hi && y0.max(y1) >= lo {
+                let hit = if level == 0 {
+                    self.cast_cell(ray, i, j)
+                } else {
+                    self.traverse(ray, level - 1, t, t_exit)
+                };
+
+                if hit.is_some() {
+                    return hit;
+                }
+            }
+
+            if t_exit >= tb {
+                return None;
+            }
+
+            if next_i < next_j {
+                match step(i, step_i, lvl.dims.x) {
+                    Some(new_i) => i = new_i,
+                    None => return None,
+                }
+
+                t = next_i;
+                next_i += delta_i;
+            } else {
+                match step(j, step_j, lvl.dims.y) {
+                    Some(new_j) => j = new_j,
+                    None => return None,
+                }
+
+                t = next_j;
+                next_j += delta_j;
+            }
+        }
+    }
+
+    // Casts a ray on the two triangles of the cell at the given column and row.
+    fn cast_cell(
+        &self,
+        ray: &Ray<Scalar>,
+        i: usize,
+        j: usize,
+    ) -> Option<(Scalar, Point3<usize>, Scalar, Scalar)> {
+        let a = j * self.samples.x + i;
+        let b = a + 1;
+        let c = b + self.samples.x;
+        let d = a + self.samples.x;
+
+        // Both triangles face the `+y` axis.
+        [Point3::new(a, c, b), Point3::new(a, d, c)]
+            .iter()
+            .filter_map(|t| {
+                triangle_mesh::ray_triangle(
+                    ray,
+                    &self.vertex(t.x),
+                    &self.vertex(t.y),
+                    &self.vertex(t.z),
+                    Bounded::max_value(),
+                )
+                .map(|(toi, u, v)| (toi, *t, u, v))
+            })
+            .fold(None, |best, hit| match best {
+                Some((toi, ..)) if toi <= hit.0 => best,
+                _ => Some(hit),
+            })
+    }
+}
+
+impl Geometry for Heightfield {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let inv = m.inverse();
+        let ray = Ray::new(inv * ray.origin, inv * ray.dir);
+        let (entry, exit) = match bvh::ray_aabb_interval(&self.aabb, &ray) {
+            Some(ts) => ts,
+            None => return None,
+        };
+
+        let top = self.levels.len() - 1;
+        let (toi, t, u, v) = match self.traverse(&ray, top, entry.max(0.0), exit) {
+            Some(hit) => hit,
+            None => return None,
+        };
+
+        let (a, b, c) = (self.vertex(t.x), self.vertex(t.y), self.vertex(t.z));
+        let w = 1.0 - u - v;
+        let face_normal = na::normalize(&(b - a).cross(&(c - a)));
+        let n = self.normals[t.x] * w + self.normals[t.y] * u + self.normals[t.z] * v;
+        let smooth_normal = if na::norm(&n) > 0.0 {
+            na::normalize(&n)
+        } else {
+            face_normal
+        };
+
+        let (uva, uvb, uvc) = (self.uvs(t.x), self.uvs(t.y), self.uvs(t.z));
+        let uvs = Point2::from_coordinates(uva.coords * w + uvb.coords * u + uvc.coords * v);
+
+        let tangent_frame = triangle_mesh::triangle_tangent(&a, &b, &c, &uva, &uvb, &uvc)
+            .and_then(|(tangent, handedness)| {
+                // Gram-Schmidt orthogonalization against the shading normal.
+                let tangent = tangent - smooth_normal * na::dot(&smooth_normal, &tangent);
+                let norm = na::norm(&tangent);
+
+                if norm > 0.0 {
+                    let tangent = tangent / norm;
+                    let bitangent = smooth_normal.cross(&tangent) * handedness;
+
+                    Some((m * tangent, m * bitangent))
+                } else {
+                    None
+                }
+            });
+
+        // Both normals, and the tangent frame with them, face the ray origin.
+        let flipped = na::dot(&face_normal, &ray.dir) > 0.0;
+        let (normal, shading_normal) = if flipped {
+            (-face_normal, -smooth_normal)
+        } else {
+            (face_normal, smooth_normal)
+        };
+        let tangent_frame = tangent_frame.map(|(t, b)| if flipped { (-t, -b) } else { (t, b) });
+
+        let mut res = Intersection::new(toi, m * normal, m * shading_normal, Some(uvs));
+        res.tangent_frame = tangent_frame;
+
+        Some(res)
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        bvh::transform_aabb(&self.aabb, m)
+    }
+
+    // The bound of the triangle meshes, with the rounding of the vertices computed from the
+    // grid of samples.
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(15))
+    }
+}
+
+// Computes the normal at each sample from the central differences of the heights.
+fn sample_normals(
+    heights: &[Scalar],
+    samples: &Vector2<usize>,
+    cell: &Vector2<Scalar>,
+) -> Vec<Vect> {
+    let h = |i: usize, j: usize| heights[j * samples.x + i];
+    let mut res = Vec::with_capacity(heights.len());
+
+    for j in 0..samples.y {
+        for i in 0..samples.x {
+            let (i0, i1) = (i.saturating_sub(1), (i + 1).min(samples.x - 1));
+            let (j0, j1) = (j.saturating_sub(1), (j + 1).min(samples.y - 1));
+            let dx = (h(i1, j) - h(i0, j)) / ((i1 - i0) as Scalar * cell.x);
+            let dz = (h(i, j1) - h(i, j0)) / ((j1 - j0) as Scalar * cell.y);
+
+            res.push(na::normalize(&Vect::new(-dx, 1.0, -dz)))
+        }
+    }
+
+    res
+}
+
+// Builds the min/max pyramid of the cells of a grid of heights, from the cells themselves up to
+// a single cell covering the whole grid.
+fn pyramid(heights: &[Scalar], samples: &Vector2<usize>) -> Vec<Level> {
+    let h = |i: usize, j: usize| heights[j * samples.x + i];
+    let dims = Vector2::new(samples.x - 1, samples.y - 1);
+    let mut bounds = Vec::with_capacity(dims.x * dims.y);
+
+    for j in 0..dims.y {
+        for i in 0..dims.x {
+            let corners = [h(i, j), h(i + 1, j), h(i, j + 1), h(i + 1, j + 1)];
+            let lo = corners.iter().fold(corners[0], |a, b| a.min(*b));
+            let hi = corners.iter().fold(corners[0], |a, b| a.max(*b));
+
+            bounds.push((lo, hi))
+        }
+    }
+
+    let mut res = vec![Level {
+        dims: dims,
+        bounds: bounds,
+    }];
+
+    while res.last().unwrap().dims != Vector2::new(1, 1) {
+        let next = {
+            let prev = res.last().unwrap();
+            let dims = Vector2::new((prev.dims.x + 1) / 2, (prev.dims.y + 1) / 2);
+            let mut bounds = Vec::with_capacity(dims.x * dims.y);
+
+            for j in 0..dims.y {
+                for i in 0..dims.x {
+                    let mut lo: Scalar = Bounded::max_value();
+                    let mut hi: Scalar = -Bounded::max_value();
+
+                    for cj in 2 * j..(2 * j + 2).min(prev.dims.y) {
+                        for ci in 2 * i..(2 * i + 2).min(prev.dims.x) {
+                            let (clo, chi) = prev.bounds[cj * prev.dims.x + ci];
+
+                            lo = lo.min(clo);
+                            hi = hi.max(chi);
+                        }
+                    }
+
+                    bounds.push((lo, hi))
+                }
+            }
+
+            Level {
+                dims: dims,
+                bounds: bounds,
+            }
+        };
+
+        res.push(next)
+    }
+
+    res
+}
+
+// Sets up the DDA along one axis, from a point at the distance `x` from the grid origin.
+//
+// Returns the cell containing the point, the step between cells, the time of the next cell
+// boundary, and the time between two cell boundaries.
+fn dda_axis(
+    x: Scalar,
+    dir: Scalar,
+    size: Scalar,
+    ncells: usize,
+    t: Scalar,
+) -> (usize, isize, Scalar, Scalar) {
+    let cell = (x / size).floor().max(0.0).min((ncells - 1) as Scalar) as usize;
+
+    if dir > 0.0 {
+        let boundary = (cell + 1) as Scalar * size;
+
+        (cell, 1, t + (boundary - x) / dir, size / dir)
+    } else if dir < 0.0 {
+        let boundary = cell as Scalar * size;
+
+        (cell, -1, t + (boundary - x) / dir, -size / dir)
+    } else {
+        (cell, 0, Bounded::max_value(), Bounded::max_value())
+    }
+}
+
+// Steps a cell index, returning `None` when it leaves the grid.
+fn step(i: usize, step: isize, ncells: usize) -> Option<usize> {
+    let new_i = i as isize + step;
+
+    if new_i < 0 || new_i >= ncells as isize {
+        None
+    } else {
+        Some(new_i as usize)
+    }
+}
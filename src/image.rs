@@ -1,14 +1,62 @@
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::fs::File;
+use std::str::FromStr;
 use na::Vector3;
-use na;
 use math::Scalar;
 
 use std::path::Path;
 use na::Vector2;
-use png;
+use png_writer::PngWriter;
+use stb_image::image::{self as stb, LoadResult};
 
 pub type Vless = Vector2<Scalar>;
 
+/// The number of bits per channel of an encoded image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    /// 8 bits per channel.
+    Eight,
+    /// 16 bits per channel.
+    Sixteen,
+}
+
+impl BitDepth {
+    /// The largest value a channel can take with this depth.
+    pub fn max_value(&self) -> u32 {
+        match *self {
+            BitDepth::Eight => 255,
+            BitDepth::Sixteen => 65535,
+        }
+    }
+
+    /// The number of bytes per channel with this depth.
+    pub fn num_bytes(&self) -> usize {
+        match *self {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+        }
+    }
+
+    /// Quantizes a linear channel value, clamped to `[0, 1]`, and appends its big-endian bytes
+    /// to `out`.
+    pub fn push_quantized(&self, value: f32, out: &mut Vec<u8>) {
+        let max = self.max_value();
+        let v = if value > 0.0 {
+            ((value * max as f32) as u32).min(max)
+        } else {
+            0
+        };
+
+        match *self {
+            BitDepth::Eight => out.push(v as u8),
+            BitDepth::Sixteen => {
+                out.push((v >> 8) as u8);
+                out.push(v as u8);
+            }
+        }
+    }
+}
+
 pub struct Image {
     extents: Vless, // extents of the rendering cube
     pixels:  Vec<Vector3<f32>>
@@ -21,71 +69,397 @@ impl Image {
             pixels:  pixels
         }
     }
+
+    /// The number of pixel columns of this image.
+    pub fn width(&self) -> usize {
+        self.extents.x as usize
+    }
+
+    /// The number of pixel rows of this image.
+    pub fn height(&self) -> usize {
+        self.extents.y as usize
+    }
+
+    /// The linear color of every pixel, row by row.
+    pub fn pixels(&self) -> &[Vector3<f32>] {
+        &self.pixels[..]
+    }
+
+    /// The mutable linear color of every pixel, row by row.
+    pub fn pixels_mut(&mut self) -> &mut [Vector3<f32>] {
+        &mut self.pixels[..]
+    }
+
+    /// The color of the pixel at column `x` and row `y`, the first row being the top one.
+    pub fn pixel(&self, x: usize, y: usize) -> &Vector3<f32> {
+        &self.pixels[y * self.width() + x]
+    }
+
+    /// Sets the color of the pixel at column `x` and row `y`.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Vector3<f32>) {
+        let width = self.width();
+        self.pixels[y * width + x] = color
+    }
+}
+
+impl Image {
+    /// Loads an image from a file.
+    ///
+    /// Files with the `.pfm` extension are read as portable float maps. Any other format
+    /// supported by `stb_image` (PNG included) is read with 8 bits per channel mapped to `[0, 1]`.
+    pub fn load(path: &Path) -> io::Result<Image> {
+        let is_pfm = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.eq_ignore_ascii_case("pfm"))
+            .unwrap_or(false);
+
+        if is_pfm {
+            let file = File::open(path)?;
+            Image::from_pfm(&mut BufReader::new(file))
+        } else {
+            match stb::load(path) {
+                LoadResult::ImageU8(image) => {
+                    let data: Vec<f32> = image.data.iter().map(|c| *c as f32 / 255.0).collect();
+                    Image::from_channels(image.width, image.height, image.depth, &data[..])
+                }
+                LoadResult::ImageF32(image) => {
+                    Image::from_channels(image.width, image.height, image.depth, &image.data[..])
+                }
+                LoadResult::Error(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+
+    /// Reads a portable float map (`PF` for color, `Pf` for grayscale).
+    pub fn from_pfm<R: BufRead>(r: &mut R) -> io::Result<Image> {
+        let magic = read_pfm_token(r)?;
+        let depth = match &magic[..] {
+            "PF" => 3,
+            "Pf" => 1,
+            _ => return Err(invalid_data("not a portable float map")),
+        };
+
+        let width: usize = parse_pfm_token(r)?;
+        let height: usize = parse_pfm_token(r)?;
+        let scale: f32 = parse_pfm_token(r)?;
+        let little_endian = scale < 0.0;
+
+        let row_len = width
+            .checked_mul(depth)
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| n.checked_mul(height).map(|_| n))
+            .ok_or_else(|| invalid_data("the extents of the PFM image are too large"))?;
+
+        if row_len == 0 || height == 0 {
+            return Image::from_channels(width, height, depth, &[]);
+        }
+
+        // The extents of the header are not trusted to allocate the whole image upfront: rows
+        // are read one at a time, so that a truncated file fails before exhausting the memory.
+        let mut rows = Vec::new();
+        let mut bytes = Vec::new();
+
+        for _ in 0..height {
+            bytes.clear();
+            (&mut *r).take(row_len as u64).read_to_end(&mut bytes)?;
+
+            if bytes.len() != row_len {
+                return Err(invalid_data("the PFM image data is truncated"));
+            }
+
+            let row: Vec<f32> = bytes.chunks(4).map(|b| pfm_float(b, little_endian)).collect();
+            rows.push(row);
+        }
+
+        // Rows are stored from bottom to top.
+        let data: Vec<f32> = rows.into_iter().rev().flat_map(|row| row.into_iter()).collect();
+
+        Image::from_channels(width, height, depth, &data[..])
+    }
+
+    fn from_channels(width: usize, height: usize, depth: usize, data: &[f32]) -> io::Result<Image> {
+        let len = width.checked_mul(height).and_then(|n| n.checked_mul(depth));
+
+        if len != Some(data.len()) {
+            return Err(invalid_data("the image data does not match the image extents"));
+        }
+
+        let pixels = match depth {
+            1 | 2 => data
+                .chunks(depth)
+                .map(|c| Vector3::from_element(c[0]))
+                .collect(),
+            3 | 4 => data
+                .chunks(depth)
+                .map(|c| Vector3::new(c[0], c[1], c[2]))
+                .collect(),
+            _ => return Err(invalid_data(&format!("image depth {} not supported", depth)[..])),
+        };
+
+        Ok(Image::new(Vless::new(width as Scalar, height as Scalar), pixels))
+    }
 }
 
 impl Image {
-    pub fn to_ppm<W: Write>(&self, w: &mut W) {
-        // XXX: there is something weird here…
-        let width  = self.extents.x as usize;
-        let height = self.extents.y as usize;
+    /// Saves this image to a file, the format being chosen from the path extension.
+    ///
+    /// Supported extensions are `png` (8 bits per channel), `ppm` (binary, 8 bits per channel)
+    /// and `pfm`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or(String::new());
+
+        // Check the format first so that no empty file is left behind on error.
+        if ext != "png" && ext != "ppm" && ext != "pfm" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported image format: `{}'", ext),
+            ));
+        }
+
+        let mut file = BufWriter::new(File::create(path)?);
+
+        match &ext[..] {
+            "png" => self.write_png(&mut file, BitDepth::Eight)?,
+            "ppm" => self.write_ppm(&mut file, BitDepth::Eight)?,
+            _ => self.write_pfm(&mut file)?,
+        }
+
+        file.flush()
+    }
+
+    /// Writes this image as an ascii (P3) portable pixmap with 8 bits per channel.
+    pub fn to_ppm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let width  = self.width();
+        let height = self.height();
 
-        let _ = w.write("P3\n".as_bytes());
+        write!(w, "P3\n{} {}\n255\n", width, height)?;
 
-        let _ = w.write(format!("{}", width).as_bytes());
-        let _ = w.write(" ".as_bytes());
-        let _ = w.write(format!("{}", height).as_bytes());
-        let _ = w.write("\n".as_bytes());
-        let _ = w.write("255\n".as_bytes());
+        let mut px = Vec::with_capacity(3);
 
         for i in 0 .. height {
-            for j in 0 .. width {
-                let c     = self.pixels[i * width + j];
-                let color = c * 255.0f32;
-                let white = Vector3::from_element(255.0);
-                let valid_color = na::inf(&na::sup(&white, &color), &white);
-                let px  = Vector3::new(valid_color.x as usize, valid_color.y as usize, valid_color.z as usize);
-
-                let _ = w.write(format!("{}", px.x).as_bytes());
-                let _ = w.write(" ".as_bytes());
-                let _ = w.write(format!("{}", px.y).as_bytes());
-                let _ = w.write(" ".as_bytes());
-                let _ = w.write(format!("{}", px.z).as_bytes());
-                let _ = w.write(" ".as_bytes());
+            for c in self.pixels[i * width .. (i + 1) * width].iter() {
+                px.clear();
+                BitDepth::Eight.push_quantized(c.x, &mut px);
+                BitDepth::Eight.push_quantized(c.y, &mut px);
+                BitDepth::Eight.push_quantized(c.z, &mut px);
+
+                write!(w, "{} {} {} ", px[0], px[1], px[2])?;
             }
 
-            let _ = w.write("\n".as_bytes());
+            w.write_all(b"\n")?;
         }
+
+        Ok(())
     }
 
-    pub fn to_png(&self, path: &Path) {
-        let width  = self.extents.x as usize;
-        let height = self.extents.y as usize;
+    /// Writes this image as a binary (P6) portable pixmap.
+    ///
+    /// Colors are clamped to `[0, 1]`. Rows are written one at a time.
+    pub fn write_ppm<W: Write>(&self, w: &mut W, depth: BitDepth) -> io::Result<()> {
+        let width  = self.width();
+        let height = self.height();
+
+        write!(w, "P6\n{} {}\n{}\n", width, height, depth.max_value())?;
+
+        let mut row = Vec::with_capacity(width * 3 * depth.num_bytes());
 
-        let mut data: Vec<u8> = Vec::new();
         for i in 0 .. height {
-            for j in 0 .. width {
-                let c     = self.pixels[i * width + j].clone();
-                let color = c * 255.0f32;
-                let white = Vector3::from_element(255.0);
-                let valid_color = na::inf(&na::sup(&color, &na::zero()), &white);
-                let px = Vector3::new(valid_color.x as usize, valid_color.y as usize, valid_color.z as usize);
-
-                data.push(px.x as u8);
-                data.push(px.y as u8);
-                data.push(px.z as u8);
+            row.clear();
+
+            for c in self.pixels[i * width .. (i + 1) * width].iter() {
+                depth.push_quantized(c.x, &mut row);
+                depth.push_quantized(c.y, &mut row);
+                depth.push_quantized(c.z, &mut row);
             }
+
+            w.write_all(&row[..])?;
         }
 
-        let mut img = png::Image {
-            width:  width  as u32,
-            height: height as u32,
-            pixels: png::PixelsByColorType::RGB8(data)
-        };
+        Ok(())
+    }
+
+    /// Writes this image as a PNG with 8 or 16 bits per channel.
+    ///
+    /// Colors are clamped to `[0, 1]`. Rows are encoded one at a time.
+    pub fn write_png<W: Write>(&self, w: &mut W, depth: BitDepth) -> io::Result<()> {
+        let width  = self.width();
+        let height = self.height();
+        let mut png = PngWriter::new(w, width, height, depth)?;
+
+        for i in 0 .. height {
+            png.write_row(&self.pixels[i * width .. (i + 1) * width])?;
+        }
+
+        png.finish()
+    }
+
+    /// Saves this image to a PNG file with 8 bits per channel.
+    pub fn to_png(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        self.write_png(&mut file, BitDepth::Eight)?;
+        file.flush()
+    }
+
+    /// Writes this image as a little-endian color portable float map, without clamping.
+    pub fn write_pfm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let width  = self.width();
+        let height = self.height();
+
+        write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+
+        let mut row = Vec::with_capacity(width * 3 * 4);
+
+        for i in (0 .. height).rev() {
+            row.clear();
+
+            for c in self.pixels[i * width .. (i + 1) * width].iter() {
+                for v in [c.x, c.y, c.z].iter() {
+                    let bits = v.to_bits();
+                    row.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+                }
+            }
+
+            w.write_all(&row[..])?;
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Decodes the 4 bytes of a PFM float.
+fn pfm_float(b: &[u8], little_endian: bool) -> f32 {
+    let bits = if little_endian {
+        (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    } else {
+        (b[3] as u32) | (b[2] as u32) << 8 | (b[1] as u32) << 16 | (b[0] as u32) << 24
+    };
+
+    f32::from_bits(bits)
+}
+
+// Reads a whitespace-delimited token of a PFM header, consuming the single delimiter after it.
+fn read_pfm_token<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut byte  = [0u8];
+
+    loop {
+        r.read_exact(&mut byte)?;
+        let c = byte[0] as char;
+
+        if c.is_whitespace() {
+            if !token.is_empty() {
+                return Ok(token)
+            }
+        }
+        else {
+            token.push(c)
+        }
+    }
+}
+
+fn parse_pfm_token<R: BufRead, T: FromStr>(r: &mut R) -> io::Result<T> {
+    let token = read_pfm_token(r)?;
+
+    T::from_str(&token[..]).map_err(|_| invalid_data(&format!("failed to parse `{}' in a PFM header", token)[..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use std::{env, fs, process};
+
+    #[test]
+    fn pfm_round_trip() {
+        let pixels = vec![
+            Vector3::new(0.0, 0.5, 1.0),
+            Vector3::new(2.0, -1.0, 0.25),
+            Vector3::new(1.0e-3, 3.0, 4.0),
+            Vector3::new(0.1, 0.2, 0.3),
+            Vector3::new(5.0, 6.0, 7.0),
+            Vector3::new(8.0, 9.0, 10.0),
+        ];
+        let image = Image::new(Vless::new(3.0, 2.0), pixels.clone());
+        let mut bytes = Vec::new();
+
+        image.write_pfm(&mut bytes).unwrap();
+        let read = Image::from_pfm(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(read.width(), 3);
+        assert_eq!(read.height(), 2);
+        assert_eq!(read.pixels(), &pixels[..]);
+    }
+
+    #[test]
+    fn pfm_with_huge_extents_is_invalid() {
+        let header = format!("PF\n{} {}\n-1.0\n", usize::max_value(), 2);
+        let err = Image::from_pfm(&mut Cursor::new(header.into_bytes())).err().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_pfm_is_invalid() {
+        let mut bytes = b"PF\n1000000 1000000\n-1.0\n".to_vec();
+        bytes.extend_from_slice(&[0u8; 12]);
+        let err = Image::from_pfm(&mut Cursor::new(bytes)).err().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn channels_must_match_the_extents() {
+        assert!(Image::from_channels(2, 2, 3, &[0.5; 12]).is_ok());
 
-        let res = png::store_png(&mut img, path);
+        let err = Image::from_channels(2, 2, 3, &[0.5; 11]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-        if !res.is_ok() {
-            panic!("Failed to save the output image.")
+        let err = Image::from_channels(usize::max_value(), 2, 3, &[]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        // Repeated rows and a gradient, to exercise both the matches and the literals.
+        let pixels: Vec<_> = (0..64 * 16)
+            .map(|i| {
+                let x = (i % 64) as f32;
+                Vector3::new(x / 255.0, ((x * 7.0) % 256.0) / 255.0, (i / 64) as f32 / 255.0)
+            })
+            .collect();
+        let image = Image::new(Vless::new(64.0, 16.0), pixels.clone());
+        let path = env::temp_dir().join(format!("nrays-round-trip-{}.png", process::id()));
+
+        image.save(&path).unwrap();
+        let read = Image::load(&path);
+        let _ = fs::remove_file(&path);
+        let read = read.unwrap();
+
+        assert_eq!(read.width(), 64);
+        assert_eq!(read.height(), 16);
+
+        for (a, b) in read.pixels().iter().zip(pixels.iter()) {
+            assert!((*a - *b).norm() < 1.0e-3);
         }
     }
+
+    #[test]
+    fn save_with_unsupported_extension_creates_no_file() {
+        let image = Image::new(Vless::new(1.0, 1.0), vec![Vector3::new(1.0, 1.0, 1.0)]);
+        let path = env::temp_dir().join(format!("nrays-unsupported-{}.bmp", process::id()));
+        let err = image.save(&path).err().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
 }
@@ -0,0 +1,455 @@
+//! Error metrics between two images, e.g., a render and a reference one.
+
+use image::{BitDepth, Image, Vless};
+use math::Scalar;
+use na::{self, Vector3};
+use postfx::{self, luminance};
+use std::f32;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Every metric computed by `compare`.
+#[derive(Clone, Copy, Debug)]
+pub struct Comparison {
+    /// The mean squared error.
+    pub mse: f32,
+    /// The root mean squared error.
+    pub rmse: f32,
+    /// The peak signal-to-noise ratio in decibels, with a peak value of `1.0`.
+    pub psnr: f32,
+    /// The mean structural similarity index of the luminance.
+    pub ssim: f32,
+    /// The mean FLIP-like perceptual error, in `[0, 1]`.
+    pub flip: f32,
+}
+
+/// The largest differences accepted between a render and its reference image.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// The largest accepted root mean squared error.
+    pub max_rmse: f32,
+    /// The smallest accepted mean structural similarity.
+    pub min_ssim: f32,
+    /// The largest accepted mean FLIP-like error.
+    pub max_flip: f32,
+}
+
+impl Tolerance {
+    /// Creates a tolerance from its bounds on the RMSE, the SSIM and the FLIP-like error.
+    pub fn new(max_rmse: f32, min_ssim: f32, max_flip: f32) -> Tolerance {
+        Tolerance {
+            max_rmse: max_rmse,
+            min_ssim: min_ssim,
+            max_flip: max_flip,
+        }
+    }
+
+    /// A tolerance accepting only identical images.
+    pub fn exact() -> Tolerance {
+        Tolerance::new(0.0, 1.0, 0.0)
+    }
+}
+
+impl Comparison {
+    /// Whether every metric of this comparison lies within `tolerance`.
+    pub fn is_within(&self, tolerance: &Tolerance) -> bool {
+        self.rmse <= tolerance.max_rmse
+            && self.ssim >= tolerance.min_ssim
+            && self.flip <= tolerance.max_flip
+    }
+}
+
+/// Panics if `render` differs from `reference` beyond `tolerance`, reporting every metric.
+pub fn assert_similar(render: &Image, reference: &Image, tolerance: &Tolerance) {
+    let comparison = compare(render, reference);
+
+    assert!(
+        comparison.is_within(tolerance),
+        "The render differs from its reference: {:?}, accepted: {:?}.",
+        comparison,
+        tolerance
+    );
+}
+
+/// Panics if `render` differs from the golden image stored at `path` beyond `tolerance`.
+///
+/// On failure, the FLIP-like error map is written next to the golden image, with the `diff.png`
+/// extension, to locate the regression.
+pub fn assert_matches_golden(render: &Image, path: &Path, tolerance: &Tolerance) {
+    let reference = Image::load(path)
+        .unwrap_or_else(|e| panic!("Failed to load the golden image {}: {}", path.display(), e));
+    let comparison = compare(render, &reference);
+
+    if !comparison.is_within(tolerance) {
+        let diff_path = path.with_extension("diff.png");
+        let _ = diff_heatmap(render, &reference).to_png(&diff_path);
+
+        panic!(
+            "The render differs from the golden image {}: {:?}, accepted: {:?}. Error map: {}.",
+            path.display(),
+            comparison,
+            tolerance,
+            diff_path.display()
+        );
+    }
+}
+
+/// Computes every error metric between two images of the same size.
+pub fn compare(a: &Image, b: &Image) -> Comparison {
+    let mse = mse(a, b);
+
+    Comparison {
+        mse: mse,
+        rmse: mse.sqrt(),
+        psnr: psnr_from_mse(mse),
+        ssim: ssim(a, b),
+        flip: flip(a, b),
+    }
+}
+
+/// The mean squared error over every channel of two images of the same size.
+///
+/// Empty images have no error.
+pub fn mse(a: &Image, b: &Image) -> f32 {
+    assert_same_extents(a, b);
+
+    if a.pixels().is_empty() {
+        return 0.0;
+    }
+
+    let mut total = 0.0f64;
+
+    for (ca, cb) in a.pixels().iter().zip(b.pixels().iter()) {
+        let d = *ca - *cb;
+        total = total + na::dot(&d, &d) as f64;
+    }
+
+    (total / (a.pixels().len() * 3) as f64) as f32
+}
+
+/// The root mean squared error over every channel of two images of the same size.
+pub fn rmse(a: &Image, b: &Image) -> f32 {
+    mse(a, b).sqrt()
+}
+
+/// The peak signal-to-noise ratio, in decibels, of two images of the same size.
+///
+/// The peak value is `1.0`. Identical images have an infinite PSNR.
+pub fn psnr(a: &Image, b: &Image) -> f32 {
+    psnr_from_mse(mse(a, b))
+}
+
+fn psnr_from_mse(mse: f32) -> f32 {
+    if mse == 0.0 {
+        f32::INFINITY
+    } else {
+        -10.0 * mse.log10()
+    }
+}
+
+/// The mean structural similarity index of the luminance of two images of the same size.
+///
+/// Local statistics are computed with a gaussian window of standard deviation `1.5` pixels.
+/// Empty images are perfectly similar.
+pub fn ssim(a: &Image, b: &Image) -> f32 {
+    assert_same_extents(a, b);
+
+    if a.pixels().is_empty() {
+        return 1.0;
+    }
+
+    let c1 = 0.01f32 * 0.01;
+    let c2 = 0.03f32 * 0.03;
+    let width = a.width();
+    let height = a.height();
+
+    // Pack the moments to blur them two at a time.
+    let mut m1: Vec<Vector3<f32>> = Vec::with_capacity(a.pixels().len());
+    let mut m2: Vec<Vector3<f32>> = Vec::with_capacity(a.pixels().len());
+
+    for (ca, cb) in a.pixels().iter().zip(b.pixels().iter()) {
+        let x = luminance(ca);
+        let y = luminance(cb);
+
+        m1.push(Vector3::new(x, y, x * x));
+        m2.push(Vector3::new(y * y, x * y, 0.0));
+    }
+
+    postfx::gaussian_blur(&mut m1[..], width, height, 1.5);
+    postfx::gaussian_blur(&mut m2[..], width, height, 1.5);
+
+    let mut total = 0.0f64;
+
+    for (e1, e2) in m1.iter().zip(m2.iter()) {
+        let mu_x = e1.x;
+        let mu_y = e1.y;
+        let var_x = e1.z - mu_x * mu_x;
+        let var_y = e2.x - mu_y * mu_y;
+        let cov = e2.y - mu_x * mu_y;
+
+        let num = (2.0 * mu_x * mu_y + c1) * (2.0 * cov + c2);
+        let den = (mu_x * mu_x + mu_y * mu_y + c1) * (var_x + var_y + c2);
+
+        total = total + (num / den) as f64;
+    }
+
+    (total / m1.len() as f64) as f32
+}
+
+/// The mean of the FLIP-like perceptual error map of two images of the same size.
+///
+/// Empty images have no error.
+pub fn flip(a: &Image, b: &Image) -> f32 {
+    let errors = flip_error_map(a, b);
+
+    if errors.is_empty() {
+        return 0.0;
+    }
+    let total = errors.iter().fold(0.0f64, |acc, e| acc + *e as f64);
+
+    (total / errors.len() as f64) as f32
+}
+
+/// A per-pixel perceptual error map inspired by NVIDIA's FLIP, with values in `[0, 1]`.
+///
+/// Both images are slightly blurred to account for the viewer's spatial sensitivity, then
+/// their color difference is measured with the HyAB distance in CIELAB. This difference is
+/// amplified where the luminance edges of both images disagree.
+pub fn flip_error_map(a: &Image, b: &Image) -> Vec<f32> {
+    assert_same_extents(a, b);
+
+    let width = a.width();
+    let height = a.height();
+    let mut fa = a.pixels().to_vec();
+    let mut fb = b.pixels().to_vec();
+
+    postfx::gaussian_blur(&mut fa[..], width, height, 1.0);
+    postfx::gaussian_blur(&mut fb[..], width, height, 1.0);
+
+    let max_color_error = hyab(
+        &lab(&Vector3::new(0.0, 1.0, 0.0)),
+        &lab(&Vector3::new(0.0, 0.0, 1.0)),
+    )
+    .powf(0.7);
+
+    let ga = luminance_gradients(a);
+    let gb = luminance_gradients(b);
+
+    let mut res = Vec::with_capacity(fa.len());
+
+    for i in 0..fa.len() {
+        let color_error = hyab(&lab(&fa[i]), &lab(&fb[i])).powf(0.7) / max_color_error;
+        let color_error = na::clamp(color_error, 0.0, 1.0);
+        let feature_error = na::clamp((ga[i] - gb[i]).abs(), 0.0, 1.0);
+
+        // Identical colors have no error, even where the edges disagree: 0⁰ would be 1.
+        if color_error == 0.0 {
+            res.push(0.0);
+        } else {
+            res.push(color_error.powf(1.0 - feature_error));
+        }
+    }
+
+    res
+}
+
+/// Maps an error map with values in `[0, 1]` to a false-color image, from black to pale yellow.
+pub fn heatmap(errors: &[f32], width: usize, height: usize) -> Image {
+    assert!(
+        errors.len() == width * height,
+        "The error map size does not match the image extents."
+    );
+
+    let stops = [
+        Vector3::new(0.0f32, 0.0, 0.0),
+        Vector3::new(0.23, 0.06, 0.43),
+        Vector3::new(0.87, 0.29, 0.41),
+        Vector3::new(0.99, 0.65, 0.38),
+        Vector3::new(0.99, 0.99, 0.75),
+    ];
+    let nsegments = (stops.len() - 1) as f32;
+
+    let pixels = errors
+        .iter()
+        .map(|e| {
+            let t = na::clamp(*e, 0.0, 1.0) * nsegments;
+            let i = (t.floor() as usize).min(stops.len() - 2);
+            let s = t - i as f32;
+
+            stops[i] * (1.0 - s) + stops[i + 1] * s
+        })
+        .collect();
+
+    Image::new(Vless::new(width as Scalar, height as Scalar), pixels)
+}
+
+/// The false-color FLIP-like error map of two images of the same size.
+pub fn diff_heatmap(a: &Image, b: &Image) -> Image {
+    heatmap(&flip_error_map(a, b)[..], a.width(), a.height())
+}
+
+/// Writes the false-color FLIP-like error map of two images of the same size as a PNG.
+pub fn write_diff_heatmap<W: Write>(a: &Image, b: &Image, w: &mut W) -> io::Result<()> {
+    diff_heatmap(a, b).write_png(w, BitDepth::Eight)
+}
+
+fn assert_same_extents(a: &Image, b: &Image) {
+    assert!(
+        a.width() == b.width() && a.height() == b.height(),
+        "Cannot compare images with different extents: {}x{} and {}x{}.",
+        a.width(),
+        a.height(),
+        b.width(),
+        b.height()
+    );
+}
+
+fn hyab(a: &Vector3<f32>, b: &Vector3<f32>) -> f32 {
+    let da = a.y - b.y;
+    let db = a.z - b.z;
+
+    (a.x - b.x).abs() + (da * da + db * db).sqrt()
+}
+
+// Linear sRGB (D65) to CIELAB.
+fn lab(c: &Vector3<f32>) -> Vector3<f32> {
+    let x = (0.4124 * c.x + 0.3576 * c.y + 0.1805 * c.z) / 0.95047;
+    let y = 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+    let z = (0.0193 * c.x + 0.1192 * c.y + 0.9505 * c.z) / 1.08883;
+
+    fn f(t: f32) -> f32 {
+        let delta = 6.0f32 / 29.0;
+
+        if t > delta * delta * delta {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    }
+
+    let fx = f(x);
+    let fy = f(y);
+    let fz = f(z);
+
+    Vector3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+// Magnitude of the central-difference gradient of the luminance of each pixel.
+fn luminance_gradients(image: &Image) -> Vec<f32> {
+    let width = image.width();
+    let height = image.height();
+    let lum = |x: usize, y: usize| luminance(image.pixel(x, y));
+    let mut res = Vec::with_capacity(width * height);
+
+    for j in 0..height {
+        for i in 0..width {
+            let dx = lum((i + 1).min(width - 1), j) - lum(i.saturating_sub(1), j);
+            let dy = lum(i, (j + 1).min(height - 1)) - lum(i, j.saturating_sub(1));
+
+            res.push((dx * dx + dy * dy).sqrt() * 0.5);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use material::Material;
+    use math::{Isometry, Point, Vect};
+    use na::Matrix4;
+    use ncollide3d::shape::Ball;
+    use normal_material::NormalMaterial;
+    use scene::{self, Scene};
+    use scene_node::SceneNode;
+    use std::sync::Arc;
+
+    fn uniform(width: usize, height: usize, color: Vector3<f32>) -> Image {
+        let extents = Vless::new(width as Scalar, height as Scalar);
+        Image::new(extents, vec![color; width * height])
+    }
+
+    #[test]
+    fn identical_images_have_no_error() {
+        let a = uniform(8, 8, Vector3::new(0.2, 0.5, 0.8));
+        let comparison = compare(&a, &a);
+
+        assert_eq!(comparison.mse, 0.0);
+        assert_eq!(comparison.flip, 0.0);
+        assert_eq!(comparison.psnr, f32::INFINITY);
+        assert!((comparison.ssim - 1.0).abs() < 1.0e-5);
+        assert_similar(&a, &a, &Tolerance::exact());
+    }
+
+    #[test]
+    fn empty_images_have_no_error() {
+        let a = uniform(0, 0, na::zero());
+        let comparison = compare(&a, &a);
+
+        assert_eq!(comparison.mse, 0.0);
+        assert_eq!(comparison.ssim, 1.0);
+        assert_eq!(comparison.flip, 0.0);
+    }
+
+    #[test]
+    fn mse_of_a_uniform_offset() {
+        let a = uniform(4, 4, Vector3::new(0.0, 0.0, 0.0));
+        let b = uniform(4, 4, Vector3::new(0.5, 0.5, 0.5));
+
+        assert!((mse(&a, &b) - 0.25).abs() < 1.0e-6);
+        assert!((rmse(&a, &b) - 0.5).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn different_images_exceed_a_tight_tolerance() {
+        let a = uniform(8, 8, Vector3::new(0.0, 0.0, 0.0));
+        let b = uniform(8, 8, Vector3::new(1.0, 1.0, 1.0));
+
+        assert!(flip(&a, &b) > 0.0);
+        assert!(!compare(&a, &b).is_within(&Tolerance::new(0.01, 0.99, 0.01)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn assert_similar_panics_on_different_images() {
+        let a = uniform(8, 8, Vector3::new(0.0, 0.0, 0.0));
+        let b = uniform(8, 8, Vector3::new(1.0, 0.0, 0.0));
+
+        assert_similar(&a, &b, &Tolerance::exact());
+    }
+
+    #[test]
+    fn render_matches_golden_image() {
+        // A unit ball colored by its normals, two units in front of a camera with a 90° field of
+        // view. Without jittering the primary rays, the render is deterministic.
+        let material = Arc::new(Box::new(NormalMaterial::new()) as Box<Material + Sync + Send>);
+        let transform = Isometry::new(Vect::new(0.0, 0.0, -2.0), na::zero());
+        let node = SceneNode::new(
+            material,
+            0.0,
+            0.0,
+            1.0,
+            1.0,
+            transform,
+            Box::new(Ball::new(1.0)),
+            None,
+            true,
+        );
+        let scene = Arc::new(Scene::new(vec![Arc::new(node)], Vec::new(), na::zero()));
+
+        // The identity unprojects the near plane to `z = -1` in front of the eye.
+        let render = scene::render(
+            &scene,
+            &Vless::new(24.0, 24.0),
+            1,
+            0.0,
+            Point::origin(),
+            Matrix4::identity(),
+        );
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/golden/normal_ball.png");
+
+        // The golden image is quantized to 8 bits, and silhouette pixels may flip.
+        assert_matches_golden(&render, &path, &Tolerance::new(0.05, 0.95, 0.05));
+    }
+}
@@ -0,0 +1,66 @@
+//! Intersection between a ray and a scene node.
+
+use material::Material;
+use math::{Scalar, Vect};
+use na::{self, Point2, Point3};
+use ncollide3d::query::RayIntersection;
+use std::sync::Arc;
+
+/// The intersection of a ray with a scene node.
+#[derive(Clone, Debug)]
+pub struct Intersection {
+    /// The time of impact of the ray.
+    pub toi: Scalar,
+    /// The normal of the actual surface hit, used to offset the rays leaving it.
+    pub normal: Vect,
+    /// The normal used for shading, e.g., interpolated from the vertex normals of a mesh.
+    pub shading_normal: Vect,
+    /// The texture coordinates at the hit point.
+    pub uvs: Option<Point2<Scalar>>,
+    /// The unit tangent and bitangent at the hit point, following the directions of increasing
+    /// texture coordinates, if known.
+    ///
+    /// When the shading normal is flipped to face the ray, the tangent frame is flipped with it
+    /// so that normal maps perturb both sides of a surface consistently.
+    pub tangent_frame: Option<(Vect, Vect)>,
+    /// The material of the part of the node hit, e.g., a CSG child, overriding the material of
+    /// the node.
+    pub material: Option<Arc<Box<Material + Send + Sync>>>,
+    /// The color of the surface at the hit point, e.g., of a point of a point cloud, modulating
+    /// the color of the material.
+    pub color: Option<Point3<f32>>,
+    /// A bound of the absolute error of each coordinate of the hit point computed from the ray
+    /// and the time of impact, used to offset the rays leaving it.
+    ///
+    /// This is set by the scene node hit, see `Geometry::hit_error`.
+    pub error: Vect,
+}
+
+impl Intersection {
+    /// Creates an intersection with distinct geometric and shading normals.
+    #[inline]
+    pub fn new(
+        toi: Scalar,
+        normal: Vect,
+        shading_normal: Vect,
+        uvs: Option<Point2<Scalar>>,
+    ) -> Intersection {
+        Intersection {
+            toi: toi,
+            normal: normal,
+            shading_normal: shading_normal,
+            uvs: uvs,
+            tangent_frame: None,
+            material: None,
+            color: None,
+            error: na::zero(),
+        }
+    }
+
+    /// Creates an intersection shaded with its geometric normal from an ncollide ray
+    /// intersection.
+    #[inline]
+    pub fn from_ray_intersection(inter: RayIntersection<Scalar>) -> Intersection {
+        Intersection::new(inter.toi, inter.normal, inter.normal, inter.uvs)
+    }
+}
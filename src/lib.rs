@@ -4,11 +4,13 @@ extern crate nalgebra as na;
 extern crate ncollide3d;
 extern crate num_cpus;
 extern crate num_traits as num;
-extern crate png;
 extern crate rand;
 extern crate stb_image;
 
 pub mod image;
+pub mod film;
+pub mod image_metrics;
+pub mod png_writer;
 pub mod light;
 pub mod material;
 pub mod ray_with_energy;
@@ -17,21 +19,56 @@ pub mod scene_node;
 
 pub mod phong_material;
 pub mod texture2d;
+pub mod normal_map;
+
+pub mod postfx;
 
 pub mod normal_material;
 pub mod uv_material;
+pub mod hair_material;
+pub mod subsurface_material;
 
 pub mod mesh;
 pub mod mtl;
 pub mod obj;
 
+pub mod bvh;
+pub mod ray_packet;
+pub mod intersection;
+pub mod triangle_mesh;
+pub mod subdivision;
+pub mod displacement;
+pub mod sdf;
+pub mod minkowski_sum;
+pub mod csg;
+pub mod primitives;
+pub mod heightfield;
+pub mod curves;
+pub mod point_cloud;
+
+pub mod sampling;
+pub mod medium;
+pub mod photon_map;
+pub mod bidirectional;
+
 /// Type aliases for mathematical types.
 pub mod math {
-    use na::{Isometry3, Matrix3, Point3, Rotation3, Vector3};
+    use na::{self, Isometry3, Matrix3, Point3, Rotation3, Vector3};
+    use ncollide3d::query::Ray;
+    use num::Float;
 
     /// The scalar type.
+    ///
+    /// Single precision is selected by the `f32` feature.
+    #[cfg(not(feature = "f32"))]
     pub type Scalar = f64;
 
+    /// The scalar type.
+    ///
+    /// Single precision is selected by the `f32` feature.
+    #[cfg(feature = "f32")]
+    pub type Scalar = f32;
+
     /// The point type.
     pub type Point = Point3<Scalar>;
 
@@ -49,4 +86,127 @@ pub mod math {
 
     /// The inertia tensor type.
     pub type AngularInertia = Matrix3<Scalar>;
+
+    /// Archimedes' constant as a `Scalar`.
+    pub const PI: Scalar = ::std::f64::consts::PI as Scalar;
+
+    /// A bound of the relative rounding error of `n` successive floating point operations.
+    ///
+    /// This is the γ(n) of Higham and PBRT: `n · ε / (1 - n · ε)`, where `ε` is half the machine
+    /// epsilon.
+    #[inline]
+    pub fn gamma(n: usize) -> Scalar {
+        let eps = <Scalar as Float>::epsilon() * 0.5;
+        let n = n as Scalar;
+
+        (n * eps) / (1.0 - n * eps)
+    }
+
+    /// The relative error of the times of impact computed by iterative ray casts, e.g., the
+    /// GJK-based casts of ncollide on support-map shapes.
+    ///
+    /// These iterate until a tolerance of about the square root of the machine epsilon rather
+    /// than accumulate a bounded number of rounding errors.
+    #[inline]
+    pub fn iterative_cast_error() -> Scalar {
+        <Scalar as Float>::epsilon().sqrt()
+    }
+
+    /// A bound of the error of each coordinate of the point `ray.origin + ray.dir * toi`, when
+    /// the time of impact `toi` is computed with a relative error bounded by `relative`.
+    ///
+    /// `relative` should also account for the transformation of the ray to the local frame of
+    /// the geometry: this rounds the origin, whose error then carries over to the hit point. The
+    /// evaluation of the point adds the rounding error of a multiplication and an addition.
+    #[inline]
+    pub fn hit_point_error(ray: &Ray<Scalar>, toi: Scalar, relative: Scalar) -> Vect {
+        let o = ray.origin.coords.map(|x| x.abs());
+        let d = (ray.dir * toi).map(|x| x.abs());
+
+        (o + d) * (relative + gamma(2))
+    }
+
+    /// Moves the origin `pt`, with coordinate errors bounded by `error`, of a ray spawned from a
+    /// surface with geometric normal `normal` toward direction `dir`, so that the ray cannot
+    /// intersect that surface again.
+    ///
+    /// This is PBRT's `OffsetRayOrigin`: the origin is pushed along the normal, on the side of
+    /// `dir`, just outside of the error box of `pt`. Rays spawned from a surface this way need
+    /// no `tmin`, which only serves to continue a ray past a surface it already hit, e.g., a
+    /// transparent one, without moving its origin.
+    pub fn offset_ray_origin(pt: &Point, error: &Vect, normal: &Vect, dir: &Vect) -> Point {
+        let d = normal.x.abs() * error.x + normal.y.abs() * error.y + normal.z.abs() * error.z;
+        let mut offset = *normal * d;
+
+        if na::dot(dir, normal) < 0.0 {
+            offset = -offset;
+        }
+
+        let mut res = *pt + offset;
+
+        // Round away from `pt` so that the offset is not lost to rounding.
+        for i in 0..3 {
+            if offset[i] > 0.0 {
+                res[i] = next_float_up(res[i]);
+            } else if offset[i] < 0.0 {
+                res[i] = next_float_down(res[i]);
+            }
+        }
+
+        res
+    }
+
+    /// The direction `dir` reflected by a surface with the unit normal `normal`.
+    #[inline]
+    pub fn reflect(dir: &Vect, normal: &Vect) -> Vect {
+        *dir - *normal * (na::dot(dir, normal) * 2.0)
+    }
+
+    /// The direction `dir` refracted by a surface with the unit normal `normal`, from a medium
+    /// of refractive index `n1` to a medium of refractive index `n2`.
+    ///
+    /// The component of `dir` tangent to the surface is scaled by `n2 / n1`.
+    #[inline]
+    pub fn refract(dir: &Vect, normal: &Vect, n1: Scalar, n2: Scalar) -> Vect {
+        let dir_along_normal = *normal * na::dot(dir, normal);
+        let tangent = *dir - dir_along_normal;
+
+        na::normalize(&(dir_along_normal + tangent * (n2 / n1)))
+    }
+
+    /// The smallest scalar greater than `v`.
+    #[inline]
+    pub fn next_float_up(v: Scalar) -> Scalar {
+        if v.is_infinite() && v > 0.0 {
+            return v;
+        }
+
+        // Skip the negative zero.
+        let v = if v == -0.0 { 0.0 } else { v };
+        let bits = v.to_bits();
+
+        if v >= 0.0 {
+            Scalar::from_bits(bits + 1)
+        } else {
+            Scalar::from_bits(bits - 1)
+        }
+    }
+
+    /// The largest scalar smaller than `v`.
+    #[inline]
+    pub fn next_float_down(v: Scalar) -> Scalar {
+        if v.is_infinite() && v < 0.0 {
+            return v;
+        }
+
+        // Skip the positive zero.
+        let v = if v == 0.0 { -0.0 } else { v };
+        let bits = v.to_bits();
+
+        if v > 0.0 {
+            Scalar::from_bits(bits - 1)
+        } else {
+            Scalar::from_bits(bits + 1)
+        }
+    }
 }
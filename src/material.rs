@@ -1,17 +1,30 @@
-use na::{Point2, Point4};
+use std::fmt;
+use na::{Point2, Point3, Point4};
+use intersection::Intersection;
 use math::{Scalar, Point, Vect};
 use ray_with_energy::RayWithEnergy;
 use scene::Scene;
 
 pub trait Material {
     fn ambiant(&self, pt: &Point, normal: &Vect, uv: &Option<Point2<Scalar>>) -> Point4<f32>;
+    /// The diffuse reflectance used to reflect the light reaching the surface indirectly, e.g.,
+    /// caustics. Black by default.
+    fn diffuse(&self, _: &Point, _: &Vect, _: &Option<Point2<Scalar>>) -> Point3<f32> {
+        Point3::new(0.0, 0.0, 0.0)
+    }
     fn compute(&self,
-               _:      &RayWithEnergy,
-               pt:     &Point,
-               normal: &Vect,
-               uv:     &Option<Point2<Scalar>>,
-               _:      &Scene)
+               _:     &RayWithEnergy,
+               pt:    &Point,
+               inter: &Intersection,
+               _:     &Scene)
                -> Point4<f32> {
-        self.ambiant(pt, normal, uv)
+        self.ambiant(pt, &inter.shading_normal, &inter.uvs)
+    }
+}
+
+// Materials are opaque, but intersections overriding the material of their node are debuggable.
+impl fmt::Debug for Material + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Material")
     }
 }
@@ -0,0 +1,390 @@
+//! Participating media: volumes absorbing and scattering light, e.g., fog or smoke.
+
+use bvh;
+use math::{self, Point, Scalar, Vect};
+use na::{self, Vector3};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use rand::random;
+use std::f32;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::io::Result as IoResult;
+use std::path::Path;
+
+/// Densities sampled at the vertices of a regular grid spanning a box.
+///
+/// The density is trilinearly interpolated inside the box, and zero outside.
+pub struct DensityGrid {
+    dims: Vector3<usize>,
+    densities: Vec<f32>,
+    bounds: AABB<Scalar>,
+    max_density: f32,
+}
+
+impl DensityGrid {
+    /// Creates a density grid with `dims` samples along each axis.
+    ///
+    /// The samples are ordered with `x` varying fastest, then `y`, then `z`.
+    pub fn new(densities: Vec<f32>, dims: Vector3<usize>, bounds: AABB<Scalar>) -> DensityGrid {
+        assert!(
+            dims.x >= 2 && dims.y >= 2 && dims.z >= 2,
+            "A density grid must have at least 2 samples along each axis."
+        );
+        assert!(
+            densities.len() == dims.x * dims.y * dims.z,
+            "The number of densities does not match the dimensions of the grid."
+        );
+        assert!(
+            densities.iter().all(|d| *d >= 0.0),
+            "Densities must be non-negative."
+        );
+
+        let max_density = densities.iter().fold(0.0f32, |m, d| m.max(*d));
+
+        DensityGrid {
+            dims: dims,
+            densities: densities,
+            bounds: bounds,
+            max_density: max_density,
+        }
+    }
+
+    /// Loads a density grid from a raw voxel file.
+    ///
+    /// See `from_raw` for the format of the file.
+    pub fn from_raw_file(
+        path: &Path,
+        dims: Vector3<usize>,
+        bounds: AABB<Scalar>,
+    ) -> IoResult<DensityGrid> {
+        let mut bytes = Vec::new();
+        let mut file = File::open(path)?;
+        let _ = file.read_to_end(&mut bytes)?;
+
+        DensityGrid::from_raw(&bytes[..], dims, bounds)
+    }
+
+    /// Reads a density grid from raw voxels, with `x` varying fastest, then `y`, then `z`.
+    ///
+    /// The type of the voxels is deduced from the size of the data: either one byte per voxel,
+    /// mapped from [0, 255] to [0, 1], or one little endian 32-bit float per voxel.
+    pub fn from_raw(
+        bytes: &[u8],
+        dims: Vector3<usize>,
+        bounds: AABB<Scalar>,
+    ) -> IoResult<DensityGrid> {
+        let n = dims.x * dims.y * dims.z;
+
+        let densities = if bytes.len() == n {
+            bytes.iter().map(|b| *b as f32 / 255.0).collect()
+        } else if bytes.len() == n * 4 {
+            bytes
+                .chunks(4)
+                .map(|b| {
+                    let bits = (b[0] as u32)
+                        | ((b[1] as u32) << 8)
+                        | ((b[2] as u32) << 16)
+                        | ((b[3] as u32) << 24);
+                    f32::from_bits(bits).max(0.0)
+                })
+                .collect()
+        } else {
+            let err = format!(
+                "{} voxels of 1 or 4 bytes were expected, found {} bytes.",
+                n,
+                bytes.len()
+            );
+            return Err(Error::new(ErrorKind::InvalidData, err));
+        };
+
+        Ok(DensityGrid::new(densities, dims, bounds))
+    }
+
+    /// The number of samples along each axis.
+    pub fn dims(&self) -> &Vector3<usize> {
+        &self.dims
+    }
+
+    /// The box spanned by this grid.
+    pub fn bounds(&self) -> &AABB<Scalar> {
+        &self.bounds
+    }
+
+    /// The greatest density of this grid.
+    pub fn max_density(&self) -> f32 {
+        self.max_density
+    }
+
+    /// The density at `pt`.
+    pub fn density(&self, pt: &Point) -> f32 {
+        let mins = self.bounds.mins();
+        let maxs = self.bounds.maxs();
+        let mut ids = [0usize; 3];
+        let mut fracts = [0.0f32; 3];
+
+        for i in 0..3 {
+            if pt[i] < mins[i] || pt[i] > maxs[i] {
+                return 0.0;
+            }
+
+            let x = (pt[i] - mins[i]) / (maxs[i] - mins[i]) * (self.dims[i] - 1) as Scalar;
+            let id = (x.floor() as usize).min(self.dims[i] - 2);
+
+            ids[i] = id;
+            fracts[i] = (x - id as Scalar) as f32;
+        }
+
+        let at = |i: usize, j: usize, k: usize| {
+            self.densities[ids[0] + i + self.dims.x * (ids[1] + j + self.dims.y * (ids[2] + k))]
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let x00 = lerp(at(0, 0, 0), at(1, 0, 0), fracts[0]);
+        let x10 = lerp(at(0, 1, 0), at(1, 1, 0), fracts[0]);
+        let x01 = lerp(at(0, 0, 1), at(1, 0, 1), fracts[0]);
+        let x11 = lerp(at(0, 1, 1), at(1, 1, 1), fracts[0]);
+
+        lerp(
+            lerp(x00, x10, fracts[1]),
+            lerp(x01, x11, fracts[1]),
+            fracts[2],
+        )
+    }
+}
+
+/// A participating medium with absorption and scattering coefficients, scaled by an optional
+/// density grid, and a Henyey–Greenstein phase function.
+///
+/// The coefficients are per unit length, for each color channel.
+pub struct Medium {
+    sigma_a: Vector3<f32>,
+    sigma_s: Vector3<f32>,
+    g: f32,
+    grid: Option<DensityGrid>,
+}
+
+impl Medium {
+    /// Creates a medium of uniform density.
+    ///
+    /// The asymmetry `g` of the phase function ranges from -1 (backward scattering) to 1
+    /// (forward scattering).
+    pub fn new_homogeneous(sigma_a: Vector3<f32>, sigma_s: Vector3<f32>, g: f32) -> Medium {
+        assert!(
+            sigma_a.iter().all(|s| *s >= 0.0) && sigma_s.iter().all(|s| *s >= 0.0),
+            "The coefficients of a medium must be non-negative."
+        );
+        assert!(
+            g > -1.0 && g < 1.0,
+            "The asymmetry of the phase function must range from -1 to 1, excluded."
+        );
+
+        Medium {
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            g: g,
+            grid: None,
+        }
+    }
+
+    /// Creates a medium whose coefficients are scaled by the densities of `grid`.
+    pub fn new_heterogeneous(
+        sigma_a: Vector3<f32>,
+        sigma_s: Vector3<f32>,
+        g: f32,
+        grid: DensityGrid,
+    ) -> Medium {
+        let mut res = Medium::new_homogeneous(sigma_a, sigma_s, g);
+        res.grid = Some(grid);
+
+        res
+    }
+
+    /// The absorption coefficients of this medium, at unit density.
+    pub fn sigma_a(&self) -> &Vector3<f32> {
+        &self.sigma_a
+    }
+
+    /// The scattering coefficients of this medium, at unit density.
+    pub fn sigma_s(&self) -> &Vector3<f32> {
+        &self.sigma_s
+    }
+
+    /// The asymmetry of the phase function of this medium.
+    pub fn g(&self) -> f32 {
+        self.g
+    }
+
+    /// The density grid of this medium, if it is heterogeneous.
+    pub fn grid(&self) -> Option<&DensityGrid> {
+        self.grid.as_ref()
+    }
+
+    /// The transmittance along `ray`, with a unit direction, between `t0` and `t1`.
+    ///
+    /// Heterogeneous media are estimated by ratio tracking.
+    pub fn transmittance(&self, ray: &Ray<Scalar>, t0: Scalar, t1: Scalar) -> Vector3<f32> {
+        let ones = Vector3::new(1.0, 1.0, 1.0);
+        let (t0, t1) = match self.extent(ray, t0, t1) {
+            Some(extent) => extent,
+            None => return ones,
+        };
+        let sigma_t = self.sigma_a + self.sigma_s;
+
+        if self.grid.is_none() {
+            let d = (t1 - t0) as f32;
+            return sigma_t.map(|s| if s == 0.0 { 1.0 } else { (-s * d).exp() });
+        }
+
+        let majorant = self.majorant();
+
+        if majorant == 0.0 {
+            return ones;
+        }
+
+        let mut res = ones;
+        let mut t = t0;
+
+        loop {
+            t = t + exponential(majorant);
+
+            if t >= t1 {
+                return res;
+            }
+
+            let density = self.density(&(ray.origin + ray.dir * t));
+            res = res.component_mul(&(ones - sigma_t * (density / majorant)));
+        }
+    }
+
+    /// Samples the first scattering event along `ray`, with a unit direction, between `t0` and
+    /// `t1` by delta tracking.
+    ///
+    /// Returns the time of impact of the scattering event, or `None` if the ray leaves the
+    /// medium at `t1`, and the weight of the sample. Colored media are tracked with the
+    /// greatest coefficient of the three channels, the weight correcting for the others.
+    pub fn sample(
+        &self,
+        ray: &Ray<Scalar>,
+        t0: Scalar,
+        t1: Scalar,
+    ) -> (Option<Scalar>, Vector3<f32>) {
+        let ones = Vector3::new(1.0, 1.0, 1.0);
+        let (t0, t1) = match self.extent(ray, t0, t1) {
+            Some(extent) => extent,
+            None => return (None, ones),
+        };
+        let majorant = self.majorant();
+
+        if majorant == 0.0 {
+            return (None, ones);
+        }
+
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let mut weight = ones;
+        let mut t = t0;
+
+        loop {
+            t = t + exponential(majorant);
+
+            if t >= t1 {
+                return (None, weight);
+            }
+
+            let density = self.density(&(ray.origin + ray.dir * t));
+            let local_sigma_t = sigma_t * density;
+            let p_real = (local_sigma_t.x + local_sigma_t.y + local_sigma_t.z) / (3.0 * majorant);
+
+            if random::<f32>() < p_real {
+                let scattering = self.sigma_s * (density / (majorant * p_real));
+
+                return (Some(t), weight.component_mul(&scattering));
+            } else {
+                let null = (Vector3::from_element(majorant) - local_sigma_t)
+                    / (majorant * (1.0 - p_real));
+                weight = weight.component_mul(&null);
+            }
+        }
+    }
+
+    /// The phase function of this medium for light scattered from the propagation direction
+    /// `dir_in` to `dir_out`, both unit vectors.
+    pub fn phase(&self, dir_in: &Vect, dir_out: &Vect) -> f32 {
+        henyey_greenstein(na::dot(dir_in, dir_out) as f32, self.g)
+    }
+
+    /// Samples the propagation direction of light scattered from the propagation direction
+    /// `dir`, proportionally to the phase function of this medium.
+    pub fn sample_phase(&self, dir: &Vect) -> Vect {
+        sample_henyey_greenstein(dir, self.g)
+    }
+
+    fn density(&self, pt: &Point) -> f32 {
+        match self.grid {
+            Some(ref grid) => grid.density(pt),
+            None => 1.0,
+        }
+    }
+
+    // The greatest extinction coefficient of this medium.
+    fn majorant(&self) -> f32 {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let max_density = self.grid.as_ref().map(|g| g.max_density()).unwrap_or(1.0);
+
+        sigma_t.x.max(sigma_t.y).max(sigma_t.z) * max_density
+    }
+
+    // The part of [t0, t1] where the density of this medium may be non-zero.
+    fn extent(&self, ray: &Ray<Scalar>, t0: Scalar, t1: Scalar) -> Option<(Scalar, Scalar)> {
+        let (tmin, tmax) = match self.grid {
+            Some(ref grid) => match bvh::ray_aabb_interval(grid.bounds(), ray) {
+                Some((tmin, tmax)) => (tmin.max(t0), tmax.min(t1)),
+                None => return None,
+            },
+            None => (t0, t1),
+        };
+
+        if tmin < tmax {
+            Some((tmin, tmax))
+        } else {
+            None
+        }
+    }
+}
+
+/// The Henyey–Greenstein phase function with asymmetry `g`, where `cos_theta` is the cosine of
+/// the angle between the propagation directions of the light before and after scattering.
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+
+    (1.0 - g * g) / (4.0 * f32::consts::PI * denom * denom.max(0.0).sqrt())
+}
+
+/// Samples a unit vector whose angle with the unit vector `dir` follows the Henyey–Greenstein
+/// phase function with asymmetry `g`.
+pub fn sample_henyey_greenstein(dir: &Vect, g: f32) -> Vect {
+    let u1 = random::<Scalar>();
+    let u2 = random::<Scalar>();
+    let g = g as Scalar;
+
+    let cos_theta = if g.abs() < 1.0e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+        ((1.0 + g * g - s * s) / (2.0 * g)).max(-1.0).min(1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * u2 * math::PI;
+
+    // An orthonormal basis around `dir`.
+    let other = if dir.x.abs() > 0.9 { Vect::y() } else { Vect::x() };
+    let t1 = na::normalize(&dir.cross(&other));
+    let t2 = dir.cross(&t1);
+
+    *dir * cos_theta + t1 * (sin_theta * phi.cos()) + t2 * (sin_theta * phi.sin())
+}
+
+// Samples an exponential distance with the given rate.
+fn exponential(rate: f32) -> Scalar {
+    -(1.0 - random::<Scalar>()).ln() / rate as Scalar
+}
@@ -2,15 +2,22 @@
 
 use std::sync::Arc;
 use std::iter;
+use std::collections::HashMap;
 use num::Zero;
-use na::{Vector3, Point2, Point3};
+use na::{Vector3, Vector4, Point2, Point3, Real};
 use na;
+use math::{Point, Scalar, Vect};
+use triangle_mesh;
 
 pub type Coord  = Point3<f32>;
 pub type Normal = Vector3<f32>;
 pub type UV     = Point2<f32>;
 pub type Vertex = usize;
 pub type Face   = Point3<Vertex>;
+/// A polygon given by the indices of its vertices, in counterclockwise order.
+pub type Polygon = Vec<Vertex>;
+/// A tangent, with the handedness of the tangent frame as its last component.
+pub type Tangent = Vector4<f32>;
 
 pub enum StorageLocation<T> {
     SharedImmutable(Arc<T>),
@@ -78,10 +85,12 @@ impl<T: Sync + Send + Clone> StorageLocation<T> {
 /// A Mesh contains all geometric data of a mesh: vertex buffer, index buffer, normals and uvs.
 /// It also contains the GPU location of those buffers.
 pub struct Mesh {
-    coords:  StorageLocation<Vec<Coord>>,
-    faces:   StorageLocation<Vec<Face>>,
-    normals: StorageLocation<Vec<Normal>>,
-    uvs:     StorageLocation<Vec<UV>>,
+    coords:   StorageLocation<Vec<Coord>>,
+    faces:    StorageLocation<Vec<Face>>,
+    normals:  StorageLocation<Vec<Normal>>,
+    uvs:      StorageLocation<Vec<UV>>,
+    polygons: Option<StorageLocation<Vec<Polygon>>>,
+    tangents: Option<StorageLocation<Vec<Tangent>>>
 }
 
 impl Mesh {
@@ -108,10 +117,12 @@ impl Mesh {
         };
 
         Mesh {
-            coords:  coords,
-            faces:   faces,
-            normals: normals,
-            uvs:     uvs
+            coords:   coords,
+            faces:    faces,
+            normals:  normals,
+            uvs:      uvs,
+            polygons: None,
+            tangents: None
         }
     }
 
@@ -121,13 +132,171 @@ impl Mesh {
     }
 
     /// Recompute this mesh normals.
+    ///
+    /// The normal of each vertex is the mean of the normals of the faces around it.
     pub fn recompute_normals(&mut self) {
-        panic!("Review that.")
-        /*
+        let coords = &self.coords;
+        let faces  = &self.faces;
+
+        self.normals.write_cow(
+            |normals| compute_normals(&coords.get()[..], &faces.get()[..], normals)
+        )
+    }
+
+    /// Recompute this mesh normals, weighting the normals of the faces around each vertex by
+    /// their angle at this vertex.
+    pub fn recompute_angle_weighted_normals(&mut self) {
+        let coords = &self.coords;
+        let faces  = &self.faces;
+
         self.normals.write_cow(
-            |normals| compute_normals(self.coords.get().as_slice(), self.faces.get().as_slice(), normals)
+            |normals| compute_angle_weighted_normals(&coords.get()[..], &faces.get()[..], normals)
         )
-        */
+    }
+
+    /// Recompute this mesh normals, smoothing them only between faces making an angle smaller
+    /// than `crease_angle` radians.
+    ///
+    /// Faces around the same position share their normal even if they do not share their
+    /// vertices, e.g., on texture seams. Vertices on sharp edges are duplicated.
+    pub fn split_normals(&mut self, crease_angle: f32) {
+        let cos = crease_angle.cos();
+        let mut vertex_ids = HashMap::new();
+        let mut sources    = Vec::new();
+        let mut normals    = Vec::new();
+        let mut new_faces  = Vec::with_capacity(self.faces.get().len());
+
+        {
+            let coords = self.coords.get();
+            let faces  = self.faces.get();
+
+            let face_normals: Vec<Normal> = faces.iter().map(|f| face_normal(&coords[..], f)).collect();
+
+            // The corners, as (face, corner) pairs, around each position.
+            let mut position_ids = HashMap::new();
+            let mut corners: Vec<Vec<(usize, usize)>> = Vec::new();
+
+            for (i, f) in faces.iter().enumerate() {
+                for k in 0usize .. 3 {
+                    let c  = &coords[f[k]];
+                    let id = *position_ids.entry(position_key(c)).or_insert_with(|| {
+                        corners.push(Vec::new());
+                        corners.len() - 1
+                    });
+
+                    corners[id].push((i, k));
+                }
+            }
+
+            let mut split: Vec<Face> = faces.to_vec();
+
+            for around in corners.iter() {
+                for &(i, k) in around.iter() {
+                    let mut n = na::zero::<Normal>();
+
+                    for &(j, l) in around.iter() {
+                        if na::dot(&face_normals[i], &face_normals[j]) >= cos {
+                            n = n + face_normals[j] * corner_angle(&coords[..], &faces[j], l);
+                        }
+                    }
+
+                    if !n.is_zero() {
+                        n = n.normalize()
+                    }
+
+                    // One vertex per original vertex and normal.
+                    let v   = faces[i][k];
+                    let key = (v, [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]);
+                    let id  = *vertex_ids.entry(key).or_insert_with(|| {
+                        sources.push(v);
+                        normals.push(n);
+                        sources.len() - 1
+                    });
+
+                    split[i][k] = id;
+                }
+            }
+
+            new_faces.extend(split.into_iter());
+        }
+
+        self.coords  = select(&self.coords, &sources[..]);
+        self.uvs     = select(&self.uvs, &sources[..]);
+        self.normals = StorageLocation::new(normals, self.normals.is_shared());
+        self.faces   = StorageLocation::new(new_faces, self.faces.is_shared());
+
+        let faces = &self.faces;
+        let _ = self.polygons.as_mut().map(|ps| ps.write_cow(|ps| remap_polygons(ps, &faces.get()[..])));
+
+        if self.tangents.is_some() {
+            self.recompute_tangents()
+        }
+    }
+
+    /// Merges the vertices with the same coordinates, normal and texture coordinates.
+    pub fn weld(&mut self) {
+        let mut vertex_ids = HashMap::new();
+        let mut kept       = Vec::new();
+        let mut remap      = Vec::with_capacity(self.coords.get().len());
+
+        {
+            let coords  = self.coords.get();
+            let normals = self.normals.get();
+            let uvs     = self.uvs.get();
+
+            for i in 0 .. coords.len() {
+                let n   = &normals[i];
+                let key = (
+                    position_key(&coords[i]),
+                    [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()],
+                    [uvs[i].x.to_bits(), uvs[i].y.to_bits()]
+                );
+                let id = *vertex_ids.entry(key).or_insert_with(|| {
+                    kept.push(i);
+                    kept.len() - 1
+                });
+
+                remap.push(id);
+            }
+        }
+
+        if kept.len() == remap.len() {
+            return
+        }
+
+        self.coords   = select(&self.coords, &kept[..]);
+        self.normals  = select(&self.normals, &kept[..]);
+        self.uvs      = select(&self.uvs, &kept[..]);
+        self.tangents = self.tangents.as_ref().map(|ts| select(ts, &kept[..]));
+
+        let remap = &remap[..];
+
+        self.faces.write_cow(|fs| {
+            for f in fs.iter_mut() {
+                *f = Point3::new(remap[f.x], remap[f.y], remap[f.z])
+            }
+        });
+
+        let _ = self.polygons.as_mut().map(|ps| ps.write_cow(|ps| {
+            for p in ps.iter_mut() {
+                for v in p.iter_mut() {
+                    *v = remap[*v]
+                }
+            }
+        }));
+    }
+
+    /// Recompute this mesh tangents from its normals and texture coordinates.
+    pub fn recompute_tangents(&mut self) {
+        let tangents = compute_tangents_array(self.coords(), self.normals(), self.uvs(), self.faces());
+        let shared   = self.coords.is_shared();
+
+        self.tangents = Some(StorageLocation::new(tangents, shared))
+    }
+
+    /// This mesh tangents, if they were computed.
+    pub fn tangents(&self) -> Option<&[Tangent]> {
+        self.tangents.as_ref().map(|ts| &ts.get()[..])
     }
 
     /// This mesh faces.
@@ -135,6 +304,18 @@ impl Mesh {
         &self.faces.get()[..]
     }
 
+    /// The polygons this mesh faces were triangulated from, if they were not all triangles.
+    ///
+    /// The faces are the fan triangulations of those polygons, in the same order.
+    pub fn polygons(&self) -> Option<&[Polygon]> {
+        self.polygons.as_ref().map(|ps| &ps.get()[..])
+    }
+
+    /// Sets the polygons this mesh faces were triangulated from.
+    pub fn set_polygons(&mut self, polygons: Option<StorageLocation<Vec<Polygon>>>) {
+        self.polygons = polygons
+    }
+
     /// This mesh faces.
     pub fn mut_faces(&mut self) -> &mut StorageLocation<Vec<Face>> {
         &mut self.faces
@@ -171,54 +352,298 @@ impl Mesh {
     }
 }
 
+/// How the normals of the faces around a vertex are weighted to compute its normal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalWeighting {
+    /// Every face has the same weight.
+    Uniform,
+    /// Each face is weighted by its angle at the vertex.
+    Angle,
+    /// Each face is weighted by its area.
+    Area
+}
+
+/// Computes the unit normal of each vertex from the normals of the faces around it.
+///
+/// Vertices that are not part of any face, or only of degenerate ones, get a zero normal. Every
+/// normal computation of meshes, whatever the precision of their coordinates, goes through this
+/// function.
+pub fn vertex_normals<N: Real>(coordinates: &[Point3<N>],
+                               faces:       &[Face],
+                               weighting:   NormalWeighting)
+                               -> Vec<Vector3<N>> {
+    let mut res = vec![na::zero::<Vector3<N>>(); coordinates.len()];
+
+    for f in faces.iter() {
+        let cross = face_cross(coordinates, f);
+        let norm  = na::norm(&cross);
+
+        if norm == na::zero() {
+            continue
+        }
+
+        let normal = cross / norm;
+
+        for k in 0usize .. 3 {
+            let weight = match weighting {
+                NormalWeighting::Uniform => na::one(),
+                NormalWeighting::Angle   => corner_angle(coordinates, f, k),
+                // The norm of the cross product is twice the area of the face.
+                NormalWeighting::Area    => norm
+            };
+
+            res[f[k]] = res[f[k]] + normal * weight;
+        }
+    }
+
+    for n in res.iter_mut() {
+        let norm = na::norm(n);
+
+        if norm != na::zero() {
+            *n = *n / norm
+        }
+    }
+
+    res
+}
+
 /// Comutes normals from a set of faces.
 pub fn compute_normals_array(coordinates: &[Coord],
                              faces:       &[Face])
                              -> Vec<Normal> {
-    let mut res = Vec::new();
-
-    compute_normals(coordinates, faces, &mut res);
-
-    res
+    vertex_normals(coordinates, faces, NormalWeighting::Uniform)
 }
 
 /// Comutes normals from a set of faces.
 pub fn compute_normals(coordinates: &[Coord],
                        faces:       &[Face],
                        normals:     &mut Vec<Normal>) {
-    let mut divisor: Vec<f32> = iter::repeat(0.0f32).take(coordinates.len()).collect();
+    *normals = vertex_normals(coordinates, faces, NormalWeighting::Uniform)
+}
 
-    // Grow the output buffer if it is too small.
-    normals.clear();
-    normals.extend(iter::repeat(na::zero::<Vector3<f32>>()).take(coordinates.len()));
+/// Computes normals from a set of faces, weighted by the angle of each face at each vertex.
+pub fn compute_angle_weighted_normals(coordinates: &[Coord],
+                                      faces:       &[Face],
+                                      normals:     &mut Vec<Normal>) {
+    *normals = vertex_normals(coordinates, faces, NormalWeighting::Angle)
+}
 
-    // Accumulate normals ...
-    for f in faces.iter() {
-        let edge1  = coordinates[f.y as usize] - coordinates[f.x as usize];
-        let edge2  = coordinates[f.z as usize] - coordinates[f.x as usize];
-        let cross  = edge1.cross(&edge2);
-        let normal;
+/// Computes per-vertex tangents from a set of faces with texture coordinates.
+///
+/// See `triangle_mesh::compute_tangents`.
+pub fn compute_tangents_array(coordinates: &[Coord],
+                              normals:     &[Normal],
+                              uvs:         &[UV],
+                              faces:       &[Face])
+                              -> Vec<Tangent> {
+    let coordinates: Vec<Point> = coordinates.iter().map(|c| Point::new(c.x as Scalar, c.y as Scalar, c.z as Scalar)).collect();
+    let normals: Vec<Vect>      = normals.iter().map(|n| Vect::new(n.x as Scalar, n.y as Scalar, n.z as Scalar)).collect();
+    let uvs: Vec<Point2<Scalar>> = uvs.iter().map(|uv| Point2::new(uv.x as Scalar, uv.y as Scalar)).collect();
+
+    triangle_mesh::compute_tangents(&coordinates[..], &normals[..], &uvs[..], faces)
+        .iter()
+        .map(|t| Vector4::new(t.x as f32, t.y as f32, t.z as f32, t.w as f32))
+        .collect()
+}
 
-        if !cross.is_zero() {
-            normal = cross.normalize()
+// The cross product of the edges of the face `f`, whose norm is twice its area.
+fn face_cross<N: Real>(coordinates: &[Point3<N>], f: &Face) -> Vector3<N> {
+    (coordinates[f.y] - coordinates[f.x]).cross(&(coordinates[f.z] - coordinates[f.x]))
+}
+
+fn face_normal<N: Real>(coordinates: &[Point3<N>], f: &Face) -> Vector3<N> {
+    let cross = face_cross(coordinates, f);
+    let norm  = na::norm(&cross);
+
+    if norm != na::zero() {
+        cross / norm
+    }
+    else {
+        cross
+    }
+}
+
+// The angle of the face `f` at its k-th vertex.
+fn corner_angle<N: Real>(coordinates: &[Point3<N>], f: &Face, k: usize) -> N {
+    let p  = coordinates[f[k]];
+    let e1 = coordinates[f[(k + 1) % 3]] - p;
+    let e2 = coordinates[f[(k + 2) % 3]] - p;
+    let l  = na::norm(&e1) * na::norm(&e2);
+
+    if l == na::zero() {
+        na::zero()
+    }
+    else {
+        na::clamp(na::dot(&e1, &e2) / l, -na::one::<N>(), na::one()).acos()
+    }
+}
+
+/// Hashable coordinates of a point, identical for positive and negative zeros.
+///
+/// Vertices with the same key are at the same position, e.g., duplicated along texture seams.
+pub fn position_key<N: na::Scalar + Into<f64>>(c: &Point3<N>) -> [u64; 3] {
+    let key = |x: N| (x.into() + 0.0).to_bits();
+
+    [key(c.x), key(c.y), key(c.z)]
+}
+
+// A copy of the selected elements of a buffer, shared if the buffer was.
+fn select<T: Clone + Send + Sync>(buffer: &StorageLocation<Vec<T>>, ids: &[usize]) -> StorageLocation<Vec<T>> {
+    let elements = ids.iter().map(|i| buffer.get()[*i].clone()).collect();
+
+    StorageLocation::new(elements, buffer.is_shared())
+}
+
+// Replaces the vertices of polygons by those of the corresponding corners of their fan
+// triangulations, stored in order in `faces`.
+fn remap_polygons(polygons: &mut Vec<Polygon>, faces: &[Face]) {
+    let mut t = 0;
+
+    for p in polygons.iter_mut() {
+        for (i, v) in p.iter_mut().enumerate() {
+            *v = match i {
+                0 => faces[t].x,
+                1 => faces[t].y,
+                _ => faces[t + i - 2].z
+            }
         }
-        else {
-            normal = cross
+
+        t = t + p.len() - 2;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn mesh(coords: Vec<Coord>, faces: Vec<Face>) -> Mesh {
+        let coords = StorageLocation::new(coords, false);
+        let faces  = StorageLocation::new(faces, false);
+
+        Mesh::new(coords, faces, None, None)
+    }
+
+    fn assert_close(a: &Normal, b: &Normal) {
+        assert!(na::norm(&(*a - *b)) < 1.0e-5, "{:?} != {:?}", a, b);
+    }
+
+    // The unit cube, with the vertex (x, y, z) at index x + 2y + 4z, and quads split in two
+    // triangles.
+    fn cube() -> Mesh {
+        let mut coords = Vec::new();
+
+        for i in 0usize .. 8 {
+            coords.push(Point3::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32));
+        }
+
+        let quads = [
+            [0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]
+        ];
+        let mut faces = Vec::new();
+
+        for q in quads.iter() {
+            faces.push(Point3::new(q[0], q[1], q[2]));
+            faces.push(Point3::new(q[0], q[2], q[3]));
+        }
+
+        mesh(coords, faces)
+    }
+
+    #[test]
+    fn cube_angle_weighted_normals_point_away_from_the_center() {
+        let mut m = cube();
+        m.recompute_angle_weighted_normals();
+
+        let center = Point3::new(0.5, 0.5, 0.5);
+
+        for (c, n) in m.coords().iter().zip(m.normals().iter()) {
+            assert_close(n, &na::normalize(&(*c - center)));
+        }
+    }
+
+    #[test]
+    fn cube_split_normals_are_face_normals() {
+        let mut m = cube();
+        m.split_normals(PI / 4.0);
+
+        // Three vertices with axis-aligned normals per corner.
+        assert_eq!(m.coords().len(), 24);
+
+        for f in m.faces().iter() {
+            let n = face_normal(m.coords(), f);
+
+            for k in 0usize .. 3 {
+                assert_close(&m.normals()[f[k]], &n);
+            }
+        }
+    }
+
+    // A planar fan of six triangles around the origin, each with its own copy of the center.
+    fn fan() -> Mesh {
+        let mut coords = Vec::new();
+        let mut faces  = Vec::new();
+
+        for i in 0usize .. 6 {
+            let a = i as f32 * PI / 3.0;
+            coords.push(Point3::new(a.cos(), a.sin(), 0.0));
+        }
+
+        for i in 0usize .. 6 {
+            coords.push(Point3::new(0.0, 0.0, 0.0));
+            faces.push(Point3::new(6 + i, i, (i + 1) % 6));
+        }
+
+        mesh(coords, faces)
+    }
+
+    #[test]
+    fn fan_normals_are_orthogonal_to_its_plane() {
+        let mut m = fan();
+        m.recompute_normals();
+
+        for n in m.normals().iter() {
+            assert_close(n, &Vector3::z());
         }
+    }
+
+    #[test]
+    fn weld_merges_the_fan_center() {
+        let mut m = fan();
+        m.weld();
 
-        let normals = &mut normals[..];
-        let divisor = &mut divisor[..];
-        normals[f.x as usize] = normals[f.x as usize] + normal;
-        normals[f.y as usize] = normals[f.y as usize] + normal;
-        normals[f.z as usize] = normals[f.z as usize] + normal;
+        assert_eq!(m.coords().len(), 7);
+        assert!(m.faces().iter().all(|f| f.x == m.faces()[0].x));
 
-        divisor[f.x as usize] = divisor[f.x as usize] + 1.0;
-        divisor[f.y as usize] = divisor[f.y as usize] + 1.0;
-        divisor[f.z as usize] = divisor[f.z as usize] + 1.0;
+        m.recompute_angle_weighted_normals();
+        assert_close(&m.normals()[m.faces()[0].x], &Vector3::z());
     }
 
-    // ... and compute the mean
-    for (n, divisor) in normals.iter_mut().zip(divisor.iter()) {
-        *n = *n / *divisor
+    #[test]
+    fn degenerate_triangles_are_ignored() {
+        let coords = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.5, 0.0, 0.0),
+        ];
+        // The second triangle is flat along the x axis.
+        let faces = vec![Point3::new(0, 1, 2), Point3::new(0, 3, 1)];
+        let mut m = mesh(coords, faces);
+
+        let weightings = [NormalWeighting::Uniform, NormalWeighting::Angle, NormalWeighting::Area];
+
+        for weighting in weightings.iter() {
+            let ns = vertex_normals(m.coords(), m.faces(), *weighting);
+
+            for i in 0usize .. 3 {
+                assert_close(&ns[i], &Vector3::z());
+            }
+
+            assert_eq!(ns[3], na::zero::<Normal>());
+        }
+
+        m.split_normals(PI / 4.0);
+        assert!(m.normals().iter().all(|n| n.x == n.x && n.y == n.y && n.z == n.z));
     }
 }
@@ -0,0 +1,73 @@
+//! Minkowski sums of convex shapes.
+
+use math::{Isometry, Point, Scalar, Vect};
+use na;
+use ncollide3d::bounding_volume::{self, HasBoundingVolume, AABB};
+use ncollide3d::query::algorithms::VoronoiSimplex;
+use ncollide3d::query::ray_internal;
+use ncollide3d::query::{Ray, RayCast, RayIntersection};
+use ncollide3d::shape::SupportMap;
+
+/// A convex shape that can be part of a Minkowski sum.
+pub type Summand = Box<SupportMap<Scalar> + Send + Sync>;
+
+/// The Minkowski sum of several convex shapes, each placed by its own transformation.
+///
+/// Rays are cast with GJK on the sum of the support maps of the shapes.
+pub struct MinkowskiSum {
+    summands: Vec<(Isometry, Summand)>,
+}
+
+impl MinkowskiSum {
+    /// Creates the Minkowski sum of a non-empty set of shapes.
+    pub fn new(summands: Vec<(Isometry, Summand)>) -> MinkowskiSum {
+        assert!(
+            !summands.is_empty(),
+            "A Minkowski sum must have at least one summand."
+        );
+
+        MinkowskiSum { summands: summands }
+    }
+
+    /// The shapes summed by this Minkowski sum, with their transformations.
+    pub fn summands(&self) -> &[(Isometry, Summand)] {
+        &self.summands[..]
+    }
+}
+
+impl SupportMap<Scalar> for MinkowskiSum {
+    fn support_point(&self, m: &Isometry, dir: &Vect) -> Point {
+        let local_dir = m.inverse() * *dir;
+        let sum = self
+            .summands
+            .iter()
+            .fold(na::zero::<Vect>(), |acc, &(ref ms, ref s)| {
+                acc + s.support_point(ms, &local_dir).coords
+            });
+
+        m * Point::from_coordinates(sum)
+    }
+}
+
+impl RayCast<Scalar> for MinkowskiSum {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry,
+        ray: &Ray<Scalar>,
+        solid: bool,
+    ) -> Option<RayIntersection<Scalar>> {
+        ray_internal::implicit_toi_and_normal_with_ray(
+            m,
+            self,
+            &mut VoronoiSimplex::new(),
+            ray,
+            solid,
+        )
+    }
+}
+
+impl HasBoundingVolume<Scalar, AABB<Scalar>> for MinkowskiSum {
+    fn bounding_volume(&self, m: &Isometry) -> AABB<Scalar> {
+        bounding_volume::support_map_aabb(m, self)
+    }
+}
@@ -6,7 +6,7 @@ use std::io::Result as IoResult;
 use std::io::Read;
 use std::str::FromStr;
 use std::path::Path;
-use na::Point3;
+use na::{Point3, Vector3};
 use obj::Words;
 use obj;
 
@@ -72,6 +72,16 @@ pub fn parse(string: &str) -> Vec<MtlMaterial> {
                             "map_Ks"      => curr_material.specular_texture = Some(parse_name(l, words)),
                             // specular texture map
                             "map_d" | "map_opacity" => curr_material.opacity_map = Some(parse_name(l, words)),
+                            // bump map
+                            "map_Bump" | "map_bump" | "bump" => {
+                                let (name, multiplier) = parse_bump(l, words);
+                                curr_material.bump_map        = Some(name);
+                                curr_material.bump_multiplier = multiplier;
+                            },
+                            // tangent-space normal map
+                            "norm"        => curr_material.normal_map = Some(parse_name(l, words)),
+                            // subsurface scattering mean free path (non-standard)
+                            "sss"         => curr_material.mean_free_path = Some(parse_color(l, words).coords),
                             _     => {
                                 println!("Warning: unknown line {} ignored: `{}'", l, line);
                             }
@@ -93,6 +103,45 @@ fn parse_name<'a>(_: usize, ws: Words<'a>) -> String {
     res.join(" ")
 }
 
+// Parses a bump map name preceded by texture options, of which only `-bm <multiplier>` is not
+// ignored.
+fn parse_bump<'a>(l: usize, ws: Words<'a>) -> (String, f32) {
+    let mut ws         = ws.peekable();
+    let mut multiplier = 1.0;
+
+    while ws.peek().map(|w| w.starts_with("-")).unwrap_or(false) {
+        match ws.next().unwrap() {
+            "-bm" => {
+                let sm = ws.next().unwrap_or_else(|| error(l, "a bump multiplier was expected."));
+                let m: Result<f32, _> = FromStr::from_str(sm);
+
+                multiplier = m.unwrap_or_else(|e| error(l, &format!("failed to parse `{}' as a f32: {}", sm, e)[..]));
+            },
+            "-blendu" | "-blendv" | "-boost" | "-cc" | "-clamp" | "-imfchan" | "-texres" => {
+                let _ = ws.next();
+            },
+            "-mm" => {
+                let _ = ws.next();
+                let _ = ws.next();
+            },
+            // `-o`, `-s`, `-t`, and unknown options: up to three numbers.
+            _ => {
+                for _ in 0 .. 3 {
+                    if !ws.peek().map(|w| w.parse::<f32>().is_ok()).unwrap_or(false) {
+                        break;
+                    }
+
+                    let _ = ws.next();
+                }
+            }
+        }
+    }
+
+    let res: Vec<&'a str> = ws.collect();
+
+    (res.join(" "), multiplier)
+}
+
 fn parse_color<'a>(l: usize, mut ws: Words<'a>) -> Point3<f32> {
     let sx = ws.next().unwrap_or_else(|| error(l, "3 components were expected, found 0."));
     let sy = ws.next().unwrap_or_else(|| error(l, "3 components were expected, found 1."));
@@ -132,6 +181,12 @@ pub struct MtlMaterial {
     pub specular_texture: Option<String>,
     /// Path to the opacity map.
     pub opacity_map:      Option<String>,
+    /// Path to the bump (height) map.
+    pub bump_map:         Option<String>,
+    /// Scale of the heights of the bump map.
+    pub bump_multiplier:  f32,
+    /// Path to the tangent-space normal map.
+    pub normal_map:       Option<String>,
     /// The ambiant color.
     pub ambiant:          Point3<f32>,
     /// The diffuse color.
@@ -142,6 +197,8 @@ pub struct MtlMaterial {
     pub shininess:        f32,
     /// Alpha blending.
     pub alpha:            f32,
+    /// The mean free path of each color channel, for materials with subsurface scattering.
+    pub mean_free_path:   Option<Vector3<f32>>,
 }
 
 impl MtlMaterial {
@@ -155,6 +212,10 @@ impl MtlMaterial {
             diffuse_texture:  None,
             specular_texture: None,
             opacity_map:      None,
+            bump_map:         None,
+            bump_multiplier:  1.0,
+            normal_map:       None,
+            mean_free_path:   None,
             ambiant:          Point3::new(1.0, 1.0, 1.0),
             diffuse:          Point3::new(1.0, 1.0, 1.0),
             specular:         Point3::new(1.0, 1.0, 1.0),
@@ -171,7 +232,10 @@ impl MtlMaterial {
                ambiant_texture:  Option<String>,
                diffuse_texture:  Option<String>,
                specular_texture: Option<String>,
-               opacity_map:      Option<String>)
+               opacity_map:      Option<String>,
+               bump_map:         Option<String>,
+               bump_multiplier:  f32,
+               normal_map:       Option<String>)
                -> MtlMaterial {
         MtlMaterial {
             name:             name,
@@ -182,8 +246,12 @@ impl MtlMaterial {
             diffuse_texture:  diffuse_texture,
             specular_texture: specular_texture,
             opacity_map:      opacity_map,
+            bump_map:         bump_map,
+            bump_multiplier:  bump_multiplier,
+            normal_map:       normal_map,
             shininess:        shininess,
-            alpha:            alpha
+            alpha:            alpha,
+            mean_free_path:   None
         }
     }
 }
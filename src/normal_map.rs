@@ -0,0 +1,70 @@
+//! Textures perturbing the shading normal of a surface.
+
+use intersection::Intersection;
+use math::{Scalar, Vect};
+use na::{self, Point2};
+use sampling::orthonormal_basis;
+use texture2d::Texture2d;
+
+/// A texture perturbing the shading normal of a scene node.
+#[derive(Clone)]
+pub enum NormalMap {
+    /// A tangent-space normal map: the RGB channels of each texel encode the components of the
+    /// perturbed normal along the tangent, the bitangent and the normal.
+    Tangent(Texture2d),
+    /// A height map and its scale: the normal is tilted against the gradient of the heights
+    /// with respect to the texture coordinates, estimated by finite differences between
+    /// neighboring texels so that it does not depend on the resolution of the map.
+    Bump(Texture2d, Scalar),
+}
+
+impl NormalMap {
+    /// Perturbs the shading normal of an intersection with texture coordinates.
+    pub fn apply(&self, inter: &mut Intersection) {
+        let uvs = match inter.uvs {
+            Some(uvs) => uvs,
+            None => return,
+        };
+
+        let n = inter.shading_normal;
+        let (t, b) = inter
+            .tangent_frame
+            .unwrap_or_else(|| orthonormal_basis(&n));
+
+        let perturbed = match *self {
+            NormalMap::Tangent(ref texture) => {
+                let c = texture.sample(&uvs);
+                let x = (c.x * 2.0 - 1.0) as Scalar;
+                let y = (c.y * 2.0 - 1.0) as Scalar;
+                let z = (c.z * 2.0 - 1.0) as Scalar;
+
+                t * x + b * y + n * z
+            }
+            NormalMap::Bump(ref texture, scale) => {
+                let du = 1.0 / texture.width() as Scalar;
+                let dv = 1.0 / texture.height() as Scalar;
+                let h = height(texture, &uvs);
+                let hu = height(texture, &Point2::new(uvs.x + du, uvs.y));
+                let hv = height(texture, &Point2::new(uvs.x, uvs.y + dv));
+
+                let dhdu = (hu - h) / du;
+                let dhdv = (hv - h) / dv;
+
+                n - t * (dhdu * scale) - b * (dhdv * scale)
+            }
+        };
+
+        let norm = na::norm(&perturbed);
+
+        if norm > 0.0 {
+            inter.shading_normal = perturbed / norm;
+        }
+    }
+}
+
+fn height(texture: &Texture2d, uvs: &Point2<Scalar>) -> Scalar {
+    let c = texture.sample(uvs);
+
+    ((c.x + c.y + c.z) / 3.0) as Scalar
+}
+
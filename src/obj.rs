@@ -12,7 +12,7 @@ use std::collections::hash_map::Entry;
 use num::Bounded;
 use na::{Point3, Vector3, Point2};
 use na;
-use mesh::{Mesh, StorageLocation};
+use mesh::{Mesh, Polygon, StorageLocation};
 use mesh;
 use mtl::MtlMaterial;
 use mtl;
@@ -24,6 +24,16 @@ pub type Normal = Vector3<f32>;
 /// The type of texture coordinates.
 pub type UV     = Point2<f32>;
 
+// The smoothing group of a face, i.e., the faces its vertices normals are averaged with when the
+// file has no normals.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Smoothing {
+    // Smoothed with the faces of the same group.
+    Group(usize),
+    // Flat face, identified by its index in the file.
+    Flat(usize)
+}
+
 /// Iterator through words.
 pub type Words<'a> = Filter<Split<'a, fn(char) -> bool>, fn(&&str) -> bool>;
 
@@ -65,6 +75,11 @@ pub fn parse(string: &str, mtl_base_dir: &Path, basename: &str) -> Vec<(String,
     let mut uvs:        Vec<UV>                = Vec::new();
     let mut groups:     HashMap<String, usize> = HashMap::new();
     let mut groups_ids: Vec<Vec<Point3<usize>>>  = Vec::new();
+    let mut groups_sizes: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut groups_smoothing: HashMap<usize, Vec<Smoothing>> = HashMap::new();
+    // Faces without smoothing group are smoothed together.
+    let mut curr_smoothing                     = Some(0);
+    let mut nfaces                             = 0;
     let mut curr_group: usize                  = 0;
     let mut ignore_normals                     = false;
     let mut ignore_uvs                         = false;
@@ -85,7 +100,16 @@ pub fn parse(string: &str, mtl_base_dir: &Path, basename: &str) -> Vec<(String,
                     match w {
                         "v"      => coords.push(na::origin::<Point3<f32>>() + parse_v_or_vn(l, words)),
                         "vn"     => if !ignore_normals { normals.push(parse_v_or_vn(l, words)) },
-                        "f"      => parse_f(l, words, &coords[..], &uvs[..], &normals[..], &mut ignore_uvs, &mut ignore_normals, &mut groups_ids, curr_group),
+                        "f"      => {
+                            let smoothing = match curr_smoothing {
+                                Some(s) => Smoothing::Group(s),
+                                None    => Smoothing::Flat(nfaces)
+                            };
+
+                            parse_f(l, words, &coords[..], &uvs[..], &normals[..], &mut ignore_uvs, &mut ignore_normals, &mut groups_ids, &mut groups_sizes, &mut groups_smoothing, smoothing, curr_group);
+                            nfaces = nfaces + 1;
+                        },
+                        "s"      => curr_smoothing = parse_s(l, words),
                         "vt"     => if !ignore_uvs { uvs.push(parse_vt(l, words)) },
                         "g"      => {
                             curr_group = parse_g(l, words, basename, &mut groups, &mut groups_ids);
@@ -115,6 +139,8 @@ pub fn parse(string: &str, mtl_base_dir: &Path, basename: &str) -> Vec<(String,
         if ignore_normals { None } else { Some(normals) },
         if ignore_uvs { None } else { Some(uvs) },
         groups_ids,
+        groups_sizes,
+        groups_smoothing,
         groups,
         group2mtl)
 }
@@ -213,9 +239,15 @@ fn parse_f<'a>(l:              usize,
                ignore_uvs:     &mut bool,
                ignore_normals: &mut bool,
                groups_ids:     &mut Vec<Vec<Point3<usize>>>,
+               groups_sizes:   &mut HashMap<usize, Vec<usize>>,
+               groups_smoothing: &mut HashMap<usize, Vec<Smoothing>>,
+               smoothing:      Smoothing,
                curr_group:     usize) {
     // Four formats possible: v   v/t   v//n   v/t/n
     let mut i = 0;
+    // index of the first vertex of the face, shared by every triangle of the fan
+    let first = groups_ids[curr_group].len();
+
     for word in ws {
         let mut curr_ids: Vector3<i32> = Bounded::max_value();
 
@@ -230,9 +262,9 @@ fn parse_f<'a>(l:              usize,
         }
 
         if i > 2 {
-            // on the fly triangulation as trangle fan
+            // on the fly triangulation as triangle fan: (v0, v1, v2), (v0, v2, v3), ...
             let g = &mut groups_ids[curr_group];
-            let p1 = (*g)[g.len() - i];
+            let p1 = (*g)[first];
             let p2 = (*g)[g.len() - 1];
             g.push(p1);
             g.push(p2);
@@ -277,13 +309,41 @@ fn parse_f<'a>(l:              usize,
         i = i + 1;
     }
 
+    if i == 0 {
+        error(l, "a face needs at least one vertex, found 0.");
+    }
+
     // there is not enough vertex to form a triangle. Complete it.
-    if i < 2 {
+    if i < 3 {
         for _ in 0usize .. 3 - i {
             let last = (*groups_ids)[curr_group].last().unwrap().clone();
             groups_ids[curr_group].push(last);
         }
     }
+
+    // keep track of the polygon size to undo the triangulation if needed
+    let size = if i < 3 { 3 } else { i };
+    groups_sizes.entry(curr_group).or_insert(Vec::new()).push(size);
+
+    // and of the smoothing group of each triangle
+    let smoothings = groups_smoothing.entry(curr_group).or_insert(Vec::new());
+    smoothings.extend(repeat(smoothing).take(size - 2));
+}
+
+// Parses a smoothing group: `None` for `off` or `0`, i.e., flat faces.
+fn parse_s<'a>(l: usize, mut ws: Words<'a>) -> Option<usize> {
+    let s = ws.next().unwrap_or_else(|| error(l, "a smoothing group was expected, found 0."));
+
+    if s == "off" {
+        return None;
+    }
+
+    let group: Result<usize, _> = FromStr::from_str(s);
+
+    match group.unwrap_or_else(|e| error(l, &format!("failed to parse `{}' as a smoothing group: {}", s, e)[..])) {
+        0 => None,
+        g => Some(g)
+    }
 }
 
 fn parse_vt<'a>(l: usize, mut ws: Words<'a>) -> UV {
@@ -328,15 +388,18 @@ fn reformat(coords:     Vec<Coord>,
             normals:    Option<Vec<Normal>>,
             uvs:        Option<Vec<UV>>,
             groups_ids: Vec<Vec<Point3<usize>>>,
+            groups_sizes: HashMap<usize, Vec<usize>>,
+            groups_smoothing: HashMap<usize, Vec<Smoothing>>,
             groups:     HashMap<String, usize>,
             group2mtl:  HashMap<usize, MtlMaterial>)
             -> Vec<(String, Mesh, Option<MtlMaterial>)> {
-    let mut vt2id:  HashMap<Point3<usize>, usize> = HashMap::new();
+    let mut vt2id:  HashMap<(Point3<usize>, Smoothing), usize> = HashMap::new();
     let mut vertex_ids: Vec<usize>            = Vec::new();
     let mut resc: Vec<Coord>                = Vec::new();
     let mut resn: Option<Vec<Normal>>       = normals.as_ref().map(|_| Vec::new());
     let mut resu: Option<Vec<UV>>           = uvs.as_ref().map(|_| Vec::new());
     let mut resfs: Vec<Vec<Point3<usize>>>      = Vec::new();
+    let mut resps: Vec<Option<Vec<Polygon>>>    = Vec::new();
     let mut allfs: Vec<Point3<usize>>           = Vec::new();
     let mut names: Vec<String>              = Vec::new();
     let mut mtls:  Vec<Option<MtlMaterial>> = Vec::new();
@@ -345,8 +408,17 @@ fn reformat(coords:     Vec<Coord>,
         names.push(name);
         mtls.push(group2mtl.get(&i).map(|m| m.clone()));
 
-        for point in groups_ids[i].iter() {
-            let idx = match vt2id.get(point) {
+        let smoothings = groups_smoothing.get(&i);
+
+        for (j, point) in groups_ids[i].iter().enumerate() {
+            // Vertices are split between smoothing groups only if their normals are computed.
+            let smoothing = match (normals.as_ref(), smoothings) {
+                (None, Some(ss)) => ss[j / 3],
+                _                => Smoothing::Group(0)
+            };
+            let key = (point.clone(), smoothing);
+
+            let idx = match vt2id.get(&key) {
                 Some(i) => { vertex_ids.push(*i); None },
                 None    => {
                     let idx = resc.len() as usize;
@@ -362,7 +434,7 @@ fn reformat(coords:     Vec<Coord>,
                 }
             };
 
-            let _ = idx.map(|i| vt2id.insert(point.clone(), i));
+            let _ = idx.map(|i| vt2id.insert(key, i));
         }
 
         let mut resf = Vec::with_capacity(vertex_ids.len() / 3);
@@ -375,6 +447,7 @@ fn reformat(coords:     Vec<Coord>,
         }
 
         resfs.push(resf);
+        resps.push(groups_sizes.get(&i).and_then(|sizes| polygons(&vertex_ids[..], &sizes[..])));
         vertex_ids.clear();
     }
 
@@ -385,13 +458,132 @@ fn reformat(coords:     Vec<Coord>,
     let resc = StorageLocation::SharedImmutable(Arc::new(resc));
 
     let mut meshes = Vec::new();
-    for ((fs, name), mtl) in resfs.into_iter().zip(names.into_iter()).zip(mtls.into_iter()) {
+    for (((fs, ps), name), mtl) in resfs.into_iter().zip(resps.into_iter()).zip(names.into_iter()).zip(mtls.into_iter()) {
         if fs.len() != 0 {
             let fs   = StorageLocation::SharedImmutable(Arc::new(fs));
-            let mesh = Mesh::new(resc.clone(), fs, Some(resn.clone()), Some(resu.clone()));
+            let mut mesh = Mesh::new(resc.clone(), fs, Some(resn.clone()), Some(resu.clone()));
+            mesh.set_polygons(ps.map(|ps| StorageLocation::SharedImmutable(Arc::new(ps))));
             meshes.push((name, mesh, mtl))
         }
     }
 
     meshes
 }
+
+// Recovers the polygons fan-triangulated by `parse_f`, unless they are all triangles.
+fn polygons(vertex_ids: &[usize], sizes: &[usize]) -> Option<Vec<Polygon>> {
+    if sizes.iter().all(|s| *s == 3) {
+        return None;
+    }
+
+    let mut res = Vec::with_capacity(sizes.len());
+    let mut i   = 0;
+
+    for size in sizes.iter() {
+        // The fan (v0, v1, v2), (v0, v2, v3), ... has the polygon vertices as its first triangle
+        // followed by the last vertex of each other triangle.
+        let mut p = vertex_ids[i .. i + 3].to_vec();
+
+        for k in 1 .. *size - 2 {
+            p.push(vertex_ids[i + 3 * k + 2]);
+        }
+
+        res.push(p);
+        i = i + 3 * (*size - 2);
+    }
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn faces(obj: &str) -> Vec<[Coord; 3]> {
+        let meshes = parse(obj, Path::new("."), "test");
+        assert_eq!(meshes.len(), 1);
+
+        let mesh = &meshes[0].1;
+        mesh.faces()
+            .iter()
+            .map(|f| [mesh.coords()[f.x], mesh.coords()[f.y], mesh.coords()[f.z]])
+            .collect()
+    }
+
+    fn pentagon() -> Vec<Coord> {
+        vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(2.0, 1.0, 0.0),
+            Point3::new(1.0, 2.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn polygons_are_fans_around_their_first_vertex() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n";
+        let p = pentagon();
+
+        assert_eq!(
+            faces(obj),
+            vec![
+                [p[0], p[1], p[2]],
+                [p[0], p[2], p[3]],
+                [p[0], p[3], p[4]],
+            ]
+        );
+    }
+
+    #[test]
+    fn polygons_are_recovered_from_their_fans() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\nf 1 2 3\n";
+        let meshes = parse(obj, Path::new("."), "test");
+        let polygons = meshes[0].1.polygons().unwrap();
+
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].len(), 5);
+        assert_eq!(polygons[1].len(), 3);
+    }
+
+    #[test]
+    fn incomplete_faces_are_completed() {
+        let obj = "v 0 0 0\nv 1 0 0\nf 1 2\n";
+        let p = pentagon();
+
+        assert_eq!(faces(obj), vec![[p[0], p[1], p[1]]]);
+    }
+
+    fn num_vertices(smoothing: &str) -> usize {
+        let obj = format!(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n{}f 1 2 3\n{}f 2 1 4\n",
+            smoothing, smoothing
+        );
+        let meshes = parse(&obj[..], Path::new("."), "test");
+
+        meshes[0].1.coords().len()
+    }
+
+    #[test]
+    fn faces_are_smooth_by_default() {
+        assert_eq!(num_vertices(""), 4);
+        assert_eq!(num_vertices("s 1\n"), 4);
+    }
+
+    #[test]
+    fn flat_faces_do_not_share_vertices() {
+        assert_eq!(num_vertices("s off\n"), 6);
+        assert_eq!(num_vertices("s 0\n"), 6);
+    }
+
+    #[test]
+    fn smoothing_groups_do_not_share_vertices() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\ns 1\nf 1 2 3\ns 2\nf 2 1 4\n";
+        let meshes = parse(obj, Path::new("."), "test");
+        let mesh = &meshes[0].1;
+
+        assert_eq!(mesh.coords().len(), 6);
+        assert_eq!(mesh.normals()[0], Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(mesh.normals()[3], Vector3::new(0.0, 1.0, 0.0));
+    }
+}
@@ -1,7 +1,7 @@
+use intersection::Intersection;
 use material::Material;
 use math::{Point, Scalar, Vect};
 use na::{self, Point2, Point3, Point4, Vector3};
-use ncollide3d::query::Ray;
 use ray_with_energy::RayWithEnergy;
 use scene::Scene;
 use texture2d::Texture2d;
@@ -69,14 +69,28 @@ impl Material for PhongMaterial {
         }
     }
 
+    fn diffuse(&self, _: &Point, _: &Vect, uvs: &Option<Point2<Scalar>>) -> Point3<f32> {
+        match (uvs.as_ref(), self.texture.as_ref()) {
+            (Some(uvs), Some(tex)) => {
+                let t = tex.sample(uvs);
+                let d = self.diffuse_color;
+
+                Point3::new(d.x * t.x, d.y * t.y, d.z * t.z)
+            }
+            _ => self.diffuse_color,
+        }
+    }
+
     fn compute(
         &self,
         ray: &RayWithEnergy,
         point: &Point,
-        normal: &Vect,
-        uvs: &Option<Point2<Scalar>>,
+        inter: &Intersection,
         scene: &Scene,
     ) -> Point4<f32> {
+        let normal = &inter.shading_normal;
+        let uvs = &inter.uvs;
+
         // initialize with the ambiant color
         let mut res;
         let tex_color;
@@ -99,48 +113,44 @@ impl Material for PhongMaterial {
             alpha = 1.0;
         }
 
-        let tex_color = Point3::new(tex_color.x, tex_color.y, tex_color.z);
+        let tex_color = match inter.color {
+            Some(c) => Point3::new(tex_color.x * c.x, tex_color.y * c.y, tex_color.z * c.z),
+            None => Point3::new(tex_color.x, tex_color.y, tex_color.z),
+        };
         res = self.ambiant_color.coords.component_mul(&tex_color.coords);
 
         // compute the contribution of each light
         for light in scene.lights().iter() {
             let mut acc = Vector3::new(0.0f32, 0.0, 0.0);
-            light.sample(&mut |pos| {
-                let mut ldir = pos - *point;
-                let dist = ldir.normalize_mut() - 0.001f64;
-
-                match scene.intersects_ray(&Ray::new(*point + ldir * 0.001, ldir.clone()), dist) {
-                    None => {}
-                    Some(filter) => {
-                        let dot_ldir_norm = na::dot(&ldir, normal);
-
-                        // diffuse
-                        let dcoeff: f32 = dot_ldir_norm as f32;
-                        let dcoeff = dcoeff.max(0.0);
-                        let diffuse_color =
-                            self.diffuse_color.coords.component_mul(&tex_color.coords);
-
-                        let diffuse = diffuse_color * dcoeff;
-
-                        // specular
-                        let lproj = *normal * dot_ldir_norm;
-                        let rldir = na::normalize(&(-ldir + lproj * 2.0));
-
-                        let scoeff = -na::dot(&rldir, &ray.ray.dir) as f32;
-                        if scoeff > na::zero() {
-                            let scoeff = scoeff.clone().powf(self.shininess);
-                            let specular = self.specular_color * scoeff;
-
-                            acc = acc + light.color.coords.component_mul(
-                                &(filter.component_mul(&(diffuse + specular.coords))),
-                            );
-                        } else {
-                            acc = acc + light
-                                .color
-                                .coords
-                                .component_mul(&(filter.component_mul(&diffuse)));
-                        }
-                    }
+
+            scene.visible_light_samples(light, point, &inter.error, &inter.normal, |ldir, filter| {
+                let dot_ldir_norm = na::dot(ldir, normal);
+
+                // diffuse
+                let dcoeff: f32 = dot_ldir_norm as f32;
+                let dcoeff = dcoeff.max(0.0);
+                let diffuse_color = self.diffuse_color.coords.component_mul(&tex_color.coords);
+
+                let diffuse = diffuse_color * dcoeff;
+
+                // specular
+                let lproj = *normal * dot_ldir_norm;
+                let rldir = na::normalize(&(-*ldir + lproj * 2.0));
+
+                let scoeff = -na::dot(&rldir, &ray.ray.dir) as f32;
+                if scoeff > na::zero() {
+                    let scoeff = scoeff.clone().powf(self.shininess);
+                    let specular = self.specular_color * scoeff;
+
+                    acc = acc + light
+                        .color
+                        .coords
+                        .component_mul(&(filter.component_mul(&(diffuse + specular.coords))));
+                } else {
+                    acc = acc + light
+                        .color
+                        .coords
+                        .component_mul(&(filter.component_mul(&diffuse)));
                 }
             });
 
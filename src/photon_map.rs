@@ -0,0 +1,558 @@
+//! Photon maps: the light reaching surfaces through specular paths, e.g., caustics.
+
+use math::{self, Point, Scalar, Vect};
+use na::{self, Matrix4, Vector3};
+use ncollide3d::query::Ray;
+use rand::random;
+use ray_with_energy::RayWithEnergy;
+use sampling;
+use scene::{self, Scene, Vless};
+use scene_node::SceneNode;
+use std::sync::Arc;
+
+// The maximum number of specular bounces of a photon.
+const MAX_BOUNCES: usize = 16;
+
+/// A packet of light stored where it hit a surface.
+#[derive(Clone, Debug)]
+pub struct Photon {
+    /// The point where the photon hit a surface.
+    pub position: Point,
+    /// The unit propagation direction of the photon.
+    pub direction: Vect,
+    /// The power carried by the photon.
+    pub power: Vector3<f32>,
+}
+
+/// A point of a diffuse surface seen from the camera, possibly through specular bounces, where
+/// the caustics are estimated.
+#[derive(Clone, Debug)]
+pub struct VisiblePoint {
+    /// The index of the pixel the point contributes to, in row-major order.
+    pub pixel: usize,
+    /// The point of the surface.
+    pub position: Point,
+    /// The unit normal of the surface on the side of the camera.
+    pub normal: Vect,
+    /// The diffuse reflectance of the surface times the contribution of the point to its pixel.
+    pub weight: Vector3<f32>,
+}
+
+/// A set of photons indexed by a kd-tree, with the radius of its density estimation.
+pub struct PhotonMap {
+    // The photons in the order of a balanced kd-tree: the median of each range is its root.
+    photons: Vec<Photon>,
+    // The splitting axis of each node.
+    axes: Vec<u8>,
+    radius: Scalar,
+}
+
+impl PhotonMap {
+    /// Builds the kd-tree of a set of photons.
+    ///
+    /// The radiance is estimated from the photons closer than `radius`.
+    pub fn new(photons: Vec<Photon>, radius: Scalar) -> PhotonMap {
+        assert!(radius > 0.0, "The radius of a photon map must be positive.");
+
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        let n = photons.len();
+
+        build(&mut photons[..], &mut axes[..], 0, n);
+
+        PhotonMap {
+            photons: photons,
+            axes: axes,
+            radius: radius,
+        }
+    }
+
+    /// Traces `nphotons` photons from the lights of `scene` and stores the ones reaching a
+    /// diffuse surface after at least one specular bounce.
+    ///
+    /// Photons are only emitted toward the reflective or transparent nodes, through the disk
+    /// bounding each node seen from the light. Lights have no falloff: a photon carries the
+    /// color of its light times the area of that disk, divided by the number of photons sent
+    /// through it. Photons are not attenuated by participating media.
+    ///
+    /// Photons first hitting another node than the one they are sent toward are discarded, since
+    /// the light reaching that node is already carried by the photons sent toward it. Nodes with
+    /// unbounded geometries, e.g., planes, have no disk and do not focus caustics.
+    pub fn trace_caustics(scene: &Scene, nphotons: usize, radius: Scalar) -> PhotonMap {
+        let specular: Vec<_> = scene
+            .nodes()
+            .iter()
+            .filter(|n| n.refl_mix > 0.0 || n.alpha < 1.0)
+            .collect();
+        let mut photons = Vec::new();
+
+        if specular.is_empty() || scene.lights().is_empty() {
+            return PhotonMap::new(photons, radius);
+        }
+
+        let per_node = nphotons / (specular.len() * scene.lights().len()) + 1;
+
+        for light in scene.lights().iter() {
+            for node in specular.iter() {
+                let center = na::center(node.aabb.mins(), node.aabb.maxs());
+                let bound = na::distance(node.aabb.mins(), node.aabb.maxs()) / 2.0;
+                let mut axis = center - light.pos;
+                let dist = axis.normalize_mut();
+
+                if !bound.is_finite() {
+                    println!(
+                        "Warning: caustics are ignored on unbounded reflective or transparent \
+                         geometries."
+                    );
+                    continue;
+                }
+
+                // Lights inside the bounds of a node do not emit toward it.
+                if dist <= bound {
+                    continue;
+                }
+
+                let disk_area = (math::PI * bound * bound) as f32;
+                let power = light.color.coords * (disk_area / per_node as f32);
+                let (t1, t2) = sampling::orthonormal_basis(&axis);
+
+                for _ in 0..per_node {
+                    let r = bound * random::<Scalar>().sqrt();
+                    let phi = 2.0 * math::PI * random::<Scalar>();
+                    let target = center + t1 * (r * phi.cos()) + t2 * (r * phi.sin());
+                    let dir = na::normalize(&(target - light.pos));
+
+                    let ray = Ray::new(light.pos, dir);
+                    trace_photon(scene, ray, power, node, &mut photons);
+                }
+            }
+        }
+
+        PhotonMap::new(photons, radius)
+    }
+
+    /// The number of photons of this map.
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    /// Whether this map has no photons.
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// The photons of this map, in the order of its kd-tree.
+    pub fn photons(&self) -> &[Photon] {
+        &self.photons[..]
+    }
+
+    /// The radius of the density estimation.
+    pub fn radius(&self) -> Scalar {
+        self.radius
+    }
+
+    /// Sets the radius of the density estimation.
+    pub fn set_radius(&mut self, radius: Scalar) {
+        assert!(radius > 0.0, "The radius of a photon map must be positive.");
+        self.radius = radius
+    }
+
+    /// Calls `f` on each photon closer than `radius` to `pt`.
+    pub fn gather<F: FnMut(&Photon)>(&self, pt: &Point, radius: Scalar, mut f: F) {
+        self.gather_range(pt, radius * radius, 0, self.photons.len(), &mut f)
+    }
+
+    /// Estimates the radiance reflected by a diffuse surface with reflectance `diffuse` and unit
+    /// normal `normal` at `pt`, from the photons arriving on the side of the normal.
+    pub fn radiance(&self, pt: &Point, normal: &Vect, diffuse: &Vector3<f32>) -> Vector3<f32> {
+        let mut power = Vector3::new(0.0, 0.0, 0.0);
+
+        self.gather(pt, self.radius, |photon| {
+            if na::dot(&photon.direction, normal) < 0.0 {
+                power = power + photon.power;
+            }
+        });
+
+        let area = (math::PI * self.radius * self.radius) as f32;
+
+        diffuse.component_mul(&power) / area
+    }
+
+    /// Adds the radiance estimated at each of `points` to the color of its pixel in `pixels`.
+    pub fn splat(&self, points: &[VisiblePoint], pixels: &mut [Vector3<f32>]) {
+        for point in points.iter() {
+            let radiance = self.radiance(&point.position, &point.normal, &point.weight);
+            pixels[point.pixel] = pixels[point.pixel] + radiance;
+        }
+    }
+
+    fn gather_range<F: FnMut(&Photon)>(
+        &self,
+        pt: &Point,
+        sq_radius: Scalar,
+        lo: usize,
+        hi: usize,
+        f: &mut F,
+    ) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid] as usize;
+        let delta = pt[axis] - photon.position[axis];
+
+        if na::distance_squared(pt, &photon.position) <= sq_radius {
+            f(photon)
+        }
+
+        // Visit the side of `pt` first, and the other one if the sphere crosses the plane.
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.gather_range(pt, sq_radius, near.0, near.1, f);
+
+        if delta * delta <= sq_radius {
+            self.gather_range(pt, sq_radius, far.0, far.1, f);
+        }
+    }
+}
+
+/// The radius of the density estimation of the `pass`-th pass of a progressive photon mapping
+/// starting with `radius`.
+///
+/// The squared radius is scaled by `(i + alpha) / (i + 1)` after the `i`-th pass, so that the
+/// average of the passes converges, with `alpha` in `]0, 1[` trading variance for bias.
+pub fn progressive_radius(radius: Scalar, pass: usize, alpha: Scalar) -> Scalar {
+    let mut sq_radius = radius * radius;
+
+    for i in 1..pass + 1 {
+        sq_radius = sq_radius * (i as Scalar + alpha) / (i as Scalar + 1.0);
+    }
+
+    sq_radius.sqrt()
+}
+
+/// Collects the points where the rays cast from the camera reach a diffuse surface, with the
+/// same arguments as `scene::render`.
+///
+/// The camera rays are reflected and refracted with the same weights as the renderer mixes the
+/// colors of each hit, so that splatting the radiance of a photon map at these points adds the
+/// caustics to a rendered image. Participating media are ignored, as for the photons.
+pub fn visible_points(
+    scene: &Scene,
+    resolution: &Vless,
+    ray_per_pixel: usize,
+    window_width: Scalar,
+    camera_eye: Point,
+    projection: Matrix4<Scalar>,
+) -> Vec<VisiblePoint> {
+    assert!(ray_per_pixel > 0);
+
+    let npixels = (resolution.x * resolution.y) as usize;
+    let weight = Vector3::from_element(1.0 / ray_per_pixel as f32);
+    let mut points = Vec::new();
+
+    for ipt in 0..npixels {
+        for _ in 0..ray_per_pixel {
+            let ray = scene::primary_ray(resolution, window_width, &camera_eye, &projection, ipt);
+            let ray = RayWithEnergy::new(ray.origin, ray.dir);
+
+            trace_visible_points(scene, &ray, ipt, weight, 0, &mut points);
+        }
+    }
+
+    points
+}
+
+// Follows a camera ray contributing `weight` to the pixel `pixel` through the specular nodes of
+// `scene`, as `Scene::shade` does, and collects the diffuse surfaces it reaches.
+fn trace_visible_points(
+    scene: &Scene,
+    ray: &RayWithEnergy,
+    pixel: usize,
+    weight: Vector3<f32>,
+    bounce: usize,
+    points: &mut Vec<VisiblePoint>,
+) {
+    if bounce == MAX_BOUNCES {
+        return;
+    }
+
+    let (sn, inter) = match scene.cast_ray(&ray.ray, ray.tmin) {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    let pt = ray.ray.origin + ray.ray.dir * inter.toi;
+    let material = sn.material_at(&inter);
+    let alpha = material
+        .ambiant(&pt, &inter.shading_normal, &inter.uvs)
+        .w * sn.alpha;
+    let diffuse = material.diffuse(&pt, &inter.shading_normal, &inter.uvs);
+    let point_weight = (weight * (alpha * (1.0 - sn.refl_mix))).component_mul(&diffuse.coords);
+
+    if !point_weight.iter().all(|w| *w == 0.0) {
+        let normal = if na::dot(&ray.ray.dir, &inter.shading_normal) > 0.0 {
+            -inter.shading_normal
+        } else {
+            inter.shading_normal
+        };
+
+        points.push(VisiblePoint {
+            pixel: pixel,
+            position: pt,
+            normal: normal,
+            weight: point_weight,
+        });
+    }
+
+    if sn.refl_mix != 0.0 && ray.energy > 0.1 {
+        let rdir = math::reflect(&ray.ray.dir, &inter.shading_normal);
+        let energy = ray.energy - sn.refl_atenuation;
+        let reflected =
+            RayWithEnergy::spawn(&pt, &inter.error, &inter.normal, rdir, ray.refr, energy);
+        let w = weight * (alpha * sn.refl_mix);
+
+        trace_visible_points(scene, &reflected, pixel, w, bounce + 1, points);
+    }
+
+    if alpha != 1.0 {
+        let (n1, n2) = if ray.refr == 1.0 {
+            (1.0, sn.refr_coeff)
+        } else {
+            (sn.refr_coeff, 1.0)
+        };
+        let dir = math::refract(&ray.ray.dir, &inter.shading_normal, n1, n2);
+        let refracted = RayWithEnergy::spawn(&pt, &inter.error, &inter.normal, dir, n2, ray.energy);
+        let w = weight * (1.0 - alpha);
+
+        trace_visible_points(scene, &refracted, pixel, w, bounce + 1, points);
+    }
+}
+
+// Traces a photon sent toward the node `target` through the specular nodes of `scene`, choosing
+// to refract, reflect or stop at each hit with the same weights as the renderer mixes them.
+fn trace_photon(
+    scene: &Scene,
+    ray: Ray<Scalar>,
+    power: Vector3<f32>,
+    target: &Arc<SceneNode>,
+    photons: &mut Vec<Photon>,
+) {
+    let mut ray = ray;
+    let mut tmin = 0.0;
+    let mut refr = 1.0;
+    let mut specular = false;
+
+    for bounce in 0..MAX_BOUNCES {
+        let (sn, inter) = match scene.cast_ray(&ray, tmin) {
+            Some(hit) => hit,
+            None => return,
+        };
+
+        // The photons sent toward the node hit first account for this path.
+        if bounce == 0 && !Arc::ptr_eq(sn, target) {
+            return;
+        }
+
+        let pt = ray.origin + ray.dir * inter.toi;
+        let material = sn.material_at(&inter);
+        let alpha = material
+            .ambiant(&pt, &inter.shading_normal, &inter.uvs)
+            .w * sn.alpha;
+        let u = random::<f32>();
+
+        let dir = if u < 1.0 - alpha {
+            let (n1, n2) = if refr == 1.0 {
+                (1.0, sn.refr_coeff)
+            } else {
+                (sn.refr_coeff, 1.0)
+            };
+
+            // Crossing a surface without bending is not a specular bounce.
+            specular = specular || n1 != n2;
+            refr = n2;
+
+            math::refract(&ray.dir, &inter.shading_normal, n1, n2)
+        } else if u < 1.0 - alpha + alpha * sn.refl_mix {
+            specular = true;
+
+            math::reflect(&ray.dir, &inter.shading_normal)
+        } else {
+            // Light reaching a diffuse surface directly is handled by the shadow rays.
+            if specular {
+                photons.push(Photon {
+                    position: pt,
+                    direction: ray.dir,
+                    power: power,
+                });
+            }
+
+            return;
+        };
+
+        let spawned = RayWithEnergy::spawn(&pt, &inter.error, &inter.normal, dir, refr, 1.0);
+        ray = spawned.ray;
+        tmin = spawned.tmin;
+    }
+}
+
+// Sorts the photons of [lo, hi) into a balanced kd-tree, splitting each range along the axis of
+// its greatest extent.
+fn build(photons: &mut [Photon], axes: &mut [u8], lo: usize, hi: usize) {
+    if hi - lo <= 1 {
+        return;
+    }
+
+    let axis = {
+        let range = &mut photons[lo..hi];
+        let first = range[0].position;
+        let (mins, maxs) = range.iter().fold((first, first), |(mins, maxs), p| {
+            (na::inf(&mins, &p.position), na::sup(&maxs, &p.position))
+        });
+        let extents = maxs - mins;
+        let axis = if extents.x >= extents.y && extents.x >= extents.z {
+            0
+        } else if extents.y >= extents.z {
+            1
+        } else {
+            2
+        };
+
+        let median = range.len() / 2;
+        select(range, median, axis);
+
+        axis
+    };
+
+    let mid = (lo + hi) / 2;
+    axes[mid] = axis as u8;
+
+    build(photons, axes, lo, mid);
+    build(photons, axes, mid + 1, hi);
+}
+
+// Moves the photon of rank `k` along `axis` to `photons[k]`, with no photon after it lower than
+// it and no photon before it greater than it, in linear expected time.
+fn select(photons: &mut [Photon], k: usize, axis: usize) {
+    let mut lo = 0;
+    let mut hi = photons.len();
+
+    while hi - lo > 1 {
+        let pivot = median(
+            photons[lo].position[axis],
+            photons[lo + (hi - lo) / 2].position[axis],
+            photons[hi - 1].position[axis],
+        );
+
+        // Three-way partition of [lo, hi): [lo, lt) is lower than the pivot, [lt, gt) equal
+        // to it, or NaN, and [gt, hi) greater.
+        let mut lt = lo;
+        let mut gt = hi;
+        let mut i = lo;
+
+        while i < gt {
+            let x = photons[i].position[axis];
+
+            if x < pivot {
+                photons.swap(lt, i);
+                lt += 1;
+                i += 1;
+            } else if x > pivot {
+                gt -= 1;
+                photons.swap(i, gt);
+            } else {
+                i += 1;
+            }
+        }
+
+        if k < lt {
+            hi = lt
+        } else if k >= gt {
+            lo = gt
+        } else {
+            return;
+        }
+    }
+}
+
+fn median(a: Scalar, b: Scalar, c: Scalar) -> Scalar {
+    a.min(b).max(a.max(b).min(c))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn photons() -> Vec<Photon> {
+        // A deterministic scramble of a grid, with duplicated coordinates.
+        (0..200usize)
+            .map(|i| {
+                let j = (i * 37) % 200;
+                Photon {
+                    position: Point::new((j % 7) as Scalar, (j % 13) as Scalar, (j / 20) as Scalar),
+                    direction: -Vect::y(),
+                    power: Vector3::new(1.0, 1.0, 1.0),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_partitions_around_the_rank() {
+        let mut ps = photons();
+        let k = ps.len() / 3;
+        select(&mut ps[..], k, 1);
+
+        let x = ps[k].position.y;
+        assert!(ps[..k].iter().all(|p| p.position.y <= x));
+        assert!(ps[k + 1..].iter().all(|p| p.position.y >= x));
+    }
+
+    #[test]
+    fn gather_finds_the_photons_in_the_radius() {
+        let ps = photons();
+        let map = PhotonMap::new(ps.clone(), 1.0);
+        let pt = Point::new(3.0, 6.0, 4.5);
+        let radius = 2.5;
+
+        let mut found = 0;
+        map.gather(&pt, radius, |_| found += 1);
+
+        let expected = ps
+            .iter()
+            .filter(|p| na::distance(&p.position, &pt) <= radius)
+            .count();
+
+        assert!(expected > 0);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn splat_adds_the_weighted_radiance_to_the_pixels() {
+        let map = PhotonMap::new(photons(), 1.0);
+        let point = |pixel, normal| VisiblePoint {
+            pixel: pixel,
+            position: Point::new(3.0, 6.0, 4.5),
+            normal: normal,
+            weight: Vector3::new(0.5, 0.25, 0.0),
+        };
+        // The photons travel downward: they only light the surfaces facing up.
+        let points = [point(1, Vect::y()), point(1, Vect::y()), point(2, -Vect::y())];
+        let mut pixels = vec![Vector3::new(1.0, 1.0, 1.0); 3];
+
+        map.splat(&points[..], &mut pixels[..]);
+
+        let radiance = map.radiance(&points[0].position, &Vect::y(), &points[0].weight);
+
+        assert!(radiance.x > 0.0);
+        assert_eq!(pixels[0], Vector3::new(1.0, 1.0, 1.0));
+        assert_eq!(pixels[1], Vector3::new(1.0, 1.0, 1.0) + radiance + radiance);
+        assert_eq!(pixels[2], Vector3::new(1.0, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,643 @@
+//! Streaming PNG encoder writing to any `Write`.
+
+pub use image::BitDepth;
+use na::Vector3;
+use std::cmp;
+use std::io::{self, Write};
+use std::mem;
+
+/// A PNG encoder that writes an RGB image row by row.
+///
+/// Each row is filtered with the PNG filter minimizing its sum of absolute differences, then
+/// compressed and flushed to the underlying writer as soon as it is given. The encoder only
+/// keeps the previous row and the 32KiB deflate window in memory.
+///
+/// The deflate blocks use the fixed Huffman codes, with one block per row: this compresses less
+/// than the dynamic codes of zlib, but needs no buffering of the image.
+pub struct PngWriter<'a, W: 'a + Write> {
+    w: &'a mut W,
+    width: usize,
+    height: usize,
+    depth: BitDepth,
+    row: Vec<u8>,
+    // The unfiltered bytes of the previous row, and the candidate filtered rows.
+    prev_row: Vec<u8>,
+    filtered: Vec<Vec<u8>>,
+    nrows: usize,
+    crc_table: [u32; 256],
+    adler: Adler32,
+    deflater: Deflater,
+}
+
+// The size of the deflate sliding window.
+const WINDOW_SIZE: usize = 32768;
+// The number of bits of the hash of 3 consecutive bytes.
+const HASH_BITS: usize = 15;
+// The length bounds of a deflate match.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// The maximum number of previous occurrences of a hash tested for a match.
+const MAX_CHAIN: usize = 64;
+
+// The base values and extra bits of the deflate length and distance codes.
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+impl<'a, W: Write> PngWriter<'a, W> {
+    /// Writes the PNG header of a `width` × `height` image to `w`.
+    pub fn new(
+        w: &'a mut W,
+        width: usize,
+        height: usize,
+        depth: BitDepth,
+    ) -> io::Result<PngWriter<'a, W>> {
+        if width == 0 || height == 0 || width > 0x7fff_ffff || height > 0x7fff_ffff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid PNG image extents",
+            ));
+        }
+
+        let row_len = width * 3 * depth.num_bytes();
+        let mut res = PngWriter {
+            w: w,
+            width: width,
+            height: height,
+            depth: depth,
+            row: Vec::with_capacity(row_len),
+            prev_row: vec![0; row_len],
+            filtered: (0..5).map(|_| Vec::with_capacity(1 + row_len)).collect(),
+            nrows: 0,
+            crc_table: crc32_table(),
+            adler: Adler32::new(),
+            deflater: Deflater::new(),
+        };
+
+        res.w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        push_u32(&mut ihdr, width as u32);
+        push_u32(&mut ihdr, height as u32);
+        ihdr.push((depth.num_bytes() * 8) as u8);
+        ihdr.push(2); // truecolor
+        ihdr.push(0); // deflate
+        ihdr.push(0); // adaptive filtering
+        ihdr.push(0); // no interlace
+        res.write_chunk(b"IHDR", &ihdr[..])?;
+
+        // zlib header: deflate with a 32K window, no preset dictionary, fast compression.
+        res.write_chunk(b"IDAT", &[0x78, 0x5e])?;
+
+        Ok(res)
+    }
+
+    /// Encodes the next row of the image. Colors are clamped to `[0, 1]`.
+    pub fn write_row(&mut self, row: &[Vector3<f32>]) -> io::Result<()> {
+        if row.len() != self.width {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the row length does not match the PNG image width",
+            ));
+        }
+
+        if self.nrows == self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many rows written to the PNG image",
+            ));
+        }
+
+        self.row.clear();
+
+        for c in row.iter() {
+            self.depth.push_quantized(c.x, &mut self.row);
+            self.depth.push_quantized(c.y, &mut self.row);
+            self.depth.push_quantized(c.z, &mut self.row);
+        }
+
+        let bpp = 3 * self.depth.num_bytes();
+        let mut best = 0;
+        let mut best_cost = u64::max_value();
+
+        for (kind, filtered) in self.filtered.iter_mut().enumerate() {
+            filter_row(kind as u8, bpp, &self.row[..], &self.prev_row[..], filtered);
+
+            // The usual heuristic: filtered bytes close to zero, as signed, compress best.
+            let cost = filtered[1..]
+                .iter()
+                .fold(0u64, |acc, b| acc + (*b as i8 as i64).abs() as u64);
+
+            if cost < best_cost {
+                best = kind;
+                best_cost = cost;
+            }
+        }
+
+        mem::swap(&mut self.row, &mut self.prev_row);
+        self.adler.update(&self.filtered[best][..]);
+        self.deflater.compress(&self.filtered[best][..]);
+        self.nrows = self.nrows + 1;
+
+        let idat = self.deflater.take_output();
+
+        if idat.is_empty() {
+            Ok(())
+        } else {
+            self.write_chunk(b"IDAT", &idat[..])
+        }
+    }
+
+    /// Terminates the PNG stream. Every row must have been written.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.nrows != self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "missing rows in the PNG image",
+            ));
+        }
+
+        self.deflater.finish();
+
+        let mut idat = self.deflater.take_output();
+        push_u32(&mut idat, self.adler.value());
+        self.write_chunk(b"IDAT", &idat[..])?;
+        self.write_chunk(b"IEND", &[])?;
+
+        self.w.flush()
+    }
+
+    fn write_chunk(&mut self, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let mut len = Vec::with_capacity(4);
+        push_u32(&mut len, data.len() as u32);
+
+        let mut crc = 0xffff_ffffu32;
+        crc = crc32_update(&self.crc_table, crc, &kind[..]);
+        crc = crc32_update(&self.crc_table, crc, data);
+
+        let mut crc_bytes = Vec::with_capacity(4);
+        push_u32(&mut crc_bytes, crc ^ 0xffff_ffff);
+
+        self.w.write_all(&len[..])?;
+        self.w.write_all(&kind[..])?;
+        self.w.write_all(data)?;
+        self.w.write_all(&crc_bytes[..])
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.push((v >> 24) as u8);
+    out.push((v >> 16) as u8);
+    out.push((v >> 8) as u8);
+    out.push(v as u8);
+}
+
+// Applies the PNG filter `kind` to `row`, whose previous row is `prev`, with `bpp` bytes per
+// pixel. `out` receives the filter type followed by the filtered bytes.
+fn filter_row(kind: u8, bpp: usize, row: &[u8], prev: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.push(kind);
+
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+
+        let predictor = match kind {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+
+        out.push(row[i].wrapping_sub(predictor));
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// A deflate encoder with LZ77 matching over a sliding window, emitting blocks with the fixed
+// Huffman codes.
+struct Deflater {
+    // The last bytes compressed, and the absolute position of the first one.
+    window: Vec<u8>,
+    base: usize,
+    // The last absolute position plus one of each hash, and the previous position plus one of
+    // each position with the same hash, indexed modulo the window size.
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    bits: u64,
+    nbits: u32,
+    out: Vec<u8>,
+}
+
+impl Deflater {
+    fn new() -> Deflater {
+        Deflater {
+            window: Vec::with_capacity(3 * WINDOW_SIZE),
+            base: 0,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+            bits: 0,
+            nbits: 0,
+            out: Vec::new(),
+        }
+    }
+
+    // Compresses `data` into a non-final block.
+    fn compress(&mut self, data: &[u8]) {
+        let start = self.base + self.window.len();
+        self.window.extend_from_slice(data);
+        let end = self.base + self.window.len();
+
+        // Not the final block, fixed Huffman codes.
+        self.put_bits(0b010, 3);
+
+        let mut pos = start;
+
+        while pos < end {
+            let (len, dist) = self.longest_match(pos, end);
+
+            if len >= MIN_MATCH {
+                self.put_match(len, dist);
+
+                for p in pos..pos + len {
+                    self.insert(p, end);
+                }
+
+                pos = pos + len;
+            } else {
+                let byte = self.window[pos - self.base];
+                self.put_symbol(byte as u16);
+                self.insert(pos, end);
+                pos = pos + 1;
+            }
+        }
+
+        self.put_symbol(256);
+
+        // Only the last window can be referenced by the next matches.
+        if self.window.len() > 2 * WINDOW_SIZE {
+            let excess = self.window.len() - WINDOW_SIZE;
+            let _ = self.window.drain(..excess);
+            self.base = self.base + excess;
+        }
+    }
+
+    // Emits an empty final block and pads the stream to a byte boundary.
+    fn finish(&mut self) {
+        self.put_bits(0b011, 3);
+        self.put_symbol(256);
+
+        if self.nbits > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.nbits = 0;
+        }
+    }
+
+    // The complete bytes of the compressed stream since the last call.
+    fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.out, Vec::new())
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let i = pos - self.base;
+        let w = &self.window;
+        let h = ((w[i] as usize) << 10) ^ ((w[i + 1] as usize) << 5) ^ (w[i + 2] as usize);
+
+        h & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, pos: usize, end: usize) {
+        if pos + MIN_MATCH <= end {
+            let h = self.hash(pos);
+            self.prev[pos % WINDOW_SIZE] = self.head[h];
+            self.head[h] = pos + 1;
+        }
+    }
+
+    // The length and distance of the longest match of the bytes at `pos`, before `end`.
+    fn longest_match(&self, pos: usize, end: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > end {
+            return (0, 0);
+        }
+
+        let max_len = cmp::min(MAX_MATCH, end - pos);
+        let cur = pos - self.base;
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == 0 {
+                break;
+            }
+
+            let c = candidate - 1;
+
+            if c < self.base || pos - c > WINDOW_SIZE {
+                break;
+            }
+
+            let from = c - self.base;
+            let mut len = 0;
+
+            while len < max_len && self.window[from + len] == self.window[cur + len] {
+                len = len + 1;
+            }
+
+            if len > best.0 {
+                best = (len, pos - c);
+
+                if len == max_len {
+                    break;
+                }
+            }
+
+            candidate = self.prev[c % WINDOW_SIZE];
+        }
+
+        best
+    }
+
+    fn put_match(&mut self, len: usize, dist: usize) {
+        let l = LENGTH_BASES.iter().rposition(|b| *b as usize <= len).unwrap();
+        self.put_symbol(257 + l as u16);
+        self.put_bits(
+            (len - LENGTH_BASES[l] as usize) as u32,
+            LENGTH_EXTRA_BITS[l] as u32,
+        );
+
+        let d = DISTANCE_BASES.iter().rposition(|b| *b as usize <= dist).unwrap();
+        // Distance codes are 5 bits long, and Huffman codes are written from their MSB.
+        self.put_bits(reverse_bits(d as u32, 5), 5);
+        self.put_bits(
+            (dist - DISTANCE_BASES[d] as usize) as u32,
+            DISTANCE_EXTRA_BITS[d] as u32,
+        );
+    }
+
+    // Writes a literal/length symbol with its fixed Huffman code.
+    fn put_symbol(&mut self, symbol: u16) {
+        let s = symbol as u32;
+        let (code, len) = match symbol {
+            0..=143 => (0x30 + s, 8),
+            144..=255 => (0x190 + s - 144, 9),
+            256..=279 => (s - 256, 7),
+            _ => (0xc0 + s - 280, 8),
+        };
+
+        self.put_bits(reverse_bits(code, len), len);
+    }
+
+    fn put_bits(&mut self, value: u32, n: u32) {
+        self.bits = self.bits | ((value as u64) << self.nbits);
+        self.nbits = self.nbits + n;
+
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits = self.bits >> 8;
+            self.nbits = self.nbits - 8;
+        }
+    }
+}
+
+fn reverse_bits(code: u32, len: u32) -> u32 {
+    let mut res = 0;
+
+    for i in 0..len {
+        res = res | ((code >> i) & 1) << (len - 1 - i);
+    }
+
+    res
+}
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+
+    for n in 0..256 {
+        let mut c = n as u32;
+
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+
+        table[n] = c;
+    }
+
+    table
+}
+
+fn crc32_update(table: &[u32; 256], mut crc: u32, data: &[u8]) -> u32 {
+    for b in data.iter() {
+        crc = table[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    crc
+}
+
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        // 5552 is the largest number of bytes that cannot overflow `b` before the modulo.
+        for chunk in data.chunks(5552) {
+            for byte in chunk.iter() {
+                self.a = self.a + *byte as u32;
+                self.b = self.b + self.a;
+            }
+
+            self.a = self.a % 65521;
+            self.b = self.b % 65521;
+        }
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use stb_image::image::{self as stb, LoadResult};
+
+    fn encode(width: usize, height: usize, color: &Fn(usize, usize) -> Vector3<f32>) -> Vec<u8> {
+        encode_with_depth(width, height, BitDepth::Eight, color)
+    }
+
+    fn encode_with_depth(
+        width: usize,
+        height: usize,
+        depth: BitDepth,
+        color: &Fn(usize, usize) -> Vector3<f32>,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        {
+            let mut writer = PngWriter::new(&mut out, width, height, depth).unwrap();
+
+            for y in 0..height {
+                let row: Vec<_> = (0..width).map(|x| color(x, y)).collect();
+                writer.write_row(&row[..]).unwrap();
+            }
+
+            writer.finish().unwrap();
+        }
+
+        out
+    }
+
+    #[test]
+    fn uniform_image_is_compressed() {
+        let png = encode(256, 256, &|_, _| Vector3::new(0.2, 0.4, 0.6));
+
+        // The raw pixels take 192KiB.
+        assert!(png.len() < 256 * 256 * 3 / 20);
+    }
+
+    #[test]
+    fn gradient_image_is_compressed() {
+        let png = encode(256, 256, &|x, y| {
+            Vector3::new(x as f32 / 255.0, y as f32 / 255.0, 0.5)
+        });
+
+        // The Sub and Up filters turn gradients into repeated bytes.
+        assert!(png.len() < 256 * 256 * 3 / 10);
+    }
+
+    #[test]
+    fn reverse_bits_reverses_the_lowest_bits() {
+        assert_eq!(reverse_bits(0b0011, 4), 0b1100);
+        assert_eq!(reverse_bits(0b10110, 5), 0b01101);
+    }
+
+    // A deterministic pseudo-random value in `[0, 1)`.
+    fn noise(x: usize, y: usize) -> f32 {
+        let h = (x as u32).wrapping_mul(0x9e37_79b1) ^ (y as u32).wrapping_mul(0x85eb_ca77);
+        let h = (h ^ (h >> 15)).wrapping_mul(0x2c1b_3c6d);
+
+        (h >> 8) as f32 / (1 << 24) as f32
+    }
+
+    // An image mixing noise, gradients, repeated and black rows, so that every filter may be
+    // selected and the deflate stream contains both literals and matches.
+    fn mixed(x: usize, y: usize) -> Vector3<f32> {
+        match (y / 4) % 4 {
+            0 => Vector3::new(noise(x, y), noise(x + 1000, y), noise(x, y + 1000)),
+            1 => Vector3::new(x as f32 / 80.0, y as f32 / 48.0, (x + y) as f32 / 128.0),
+            2 => Vector3::new(noise(x, 0), 0.5, noise(x, 1)),
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Reverts the PNG filter `kind` on `filtered`, without its filter type byte.
+    fn unfilter_row(kind: u8, bpp: usize, filtered: &[u8], prev: &[u8]) -> Vec<u8> {
+        let mut row: Vec<u8> = Vec::with_capacity(filtered.len());
+
+        for i in 0..filtered.len() {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prev[i];
+            let c = if i >= bpp { prev[i - bpp] } else { 0 };
+
+            let predictor = match kind {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                _ => paeth(a, b, c),
+            };
+
+            row.push(filtered[i].wrapping_add(predictor));
+        }
+
+        row
+    }
+
+    #[test]
+    fn every_filter_is_reversible() {
+        let prev: Vec<u8> = (0..48).map(|i| (noise(i, 0) * 256.0) as u8).collect();
+        let row: Vec<u8> = (0..48).map(|i| (noise(i, 1) * 256.0) as u8).collect();
+        let mut out = Vec::new();
+
+        for bpp in [3, 6].iter() {
+            for kind in 0..5 {
+                filter_row(kind, *bpp, &row[..], &prev[..], &mut out);
+
+                assert_eq!(out[0], kind);
+                assert_eq!(unfilter_row(kind, *bpp, &out[1..], &prev[..]), row);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_with_an_independent_decoder() {
+        let (width, height) = (80, 48);
+
+        for depth in [BitDepth::Eight, BitDepth::Sixteen].iter() {
+            let png = encode_with_depth(width, height, *depth, &mixed);
+            let decoded = match stb::load_from_memory(&png[..]) {
+                LoadResult::ImageU8(image) => image,
+                _ => panic!("The PNG image could not be decoded."),
+            };
+
+            assert_eq!(decoded.width, width);
+            assert_eq!(decoded.height, height);
+            assert_eq!(decoded.depth, 3);
+
+            let mut expected = Vec::new();
+
+            for y in 0..height {
+                for x in 0..width {
+                    let c = mixed(x, y);
+
+                    for v in [c.x, c.y, c.z].iter() {
+                        depth.push_quantized(*v, &mut expected);
+                    }
+                }
+            }
+
+            // Sixteen bit channels are decoded to their most significant byte.
+            let expected: Vec<u8> = expected.chunks(depth.num_bytes()).map(|b| b[0]).collect();
+
+            assert!(decoded.data == expected);
+        }
+    }
+}
@@ -0,0 +1,653 @@
+//! Point clouds, e.g., from lidar scans, and their PLY and XYZ loaders.
+
+use bvh::{self, BVH};
+use intersection::Intersection;
+use math::{self, Isometry, Point, Scalar, Vect};
+use na::{self, Point3};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use num::Bounded;
+use scene_node::Geometry;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::io::Result as IoResult;
+use std::path::Path;
+use std::str::{self, FromStr};
+
+/// The shape rendered at each point of a point cloud.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PointShape {
+    /// Disks orthogonal to the point normals, or facing the ray if the cloud has no normals.
+    Disk,
+    /// Spheres.
+    Sphere,
+}
+
+/// The attributes of the points of a point cloud.
+#[derive(Clone, Debug)]
+pub struct PointData {
+    /// The positions of the points.
+    pub points: Vec<Point>,
+    /// The unit normals of the points, if known.
+    pub normals: Option<Vec<Vect>>,
+    /// The colors of the points, if known, with components ranging from 0 to 1.
+    pub colors: Option<Vec<Point3<f32>>>,
+}
+
+/// A set of points rendered as disks or spheres of the same radius, indexed by a BVH.
+pub struct PointCloud {
+    points: Vec<Point>,
+    normals: Option<Vec<Vect>>,
+    colors: Option<Vec<Point3<f32>>>,
+    radius: Scalar,
+    shape: PointShape,
+    bvh: BVH,
+}
+
+impl PointCloud {
+    /// Creates a point cloud and builds its BVH.
+    pub fn new(data: PointData, radius: Scalar, shape: PointShape) -> PointCloud {
+        assert!(radius > 0.0, "The radius of the points must be positive.");
+
+        if let Some(ref normals) = data.normals {
+            assert!(
+                normals.len() == data.points.len(),
+                "A point cloud must have as many normals as points."
+            );
+        }
+
+        if let Some(ref colors) = data.colors {
+            assert!(
+                colors.len() == data.points.len(),
+                "A point cloud must have as many colors as points."
+            );
+        }
+
+        let margin = Vect::new(radius, radius, radius);
+        let aabbs: Vec<AABB<Scalar>> = data
+            .points
+            .iter()
+            .map(|p| AABB::new(*p - margin, *p + margin))
+            .collect();
+
+        let bvh = BVH::new(&aabbs[..]);
+
+        PointCloud {
+            points: data.points,
+            normals: data.normals,
+            colors: data.colors,
+            radius: radius,
+            shape: shape,
+            bvh: bvh,
+        }
+    }
+
+    /// The positions of the points of this cloud.
+    pub fn points(&self) -> &[Point] {
+        &self.points[..]
+    }
+
+    /// The radius of the disks or spheres rendered at each point.
+    pub fn radius(&self) -> Scalar {
+        self.radius
+    }
+
+    /// The shape rendered at each point.
+    pub fn shape(&self) -> PointShape {
+        self.shape
+    }
+
+    // Casts a local ray on the `i`-th point, returning the time of impact and the outward
+    // normal.
+    fn cast_point(&self, ray: &Ray<Scalar>, i: usize, max_toi: Scalar) -> Option<(Scalar, Vect)> {
+        let center = &self.points[i];
+        let r2 = self.radius * self.radius;
+        let oc = *center - ray.origin;
+
+        let hit = match self.shape {
+            PointShape::Disk => {
+                // Without normals, disks face the ray.
+                let normal = match self.normals {
+                    Some(ref ns) => ns[i],
+                    None => -ray.dir,
+                };
+                let denom = na::dot(&ray.dir, &normal);
+
+                if denom == 0.0 {
+                    return None;
+                }
+
+                let toi = na::dot(&oc, &normal) / denom;
+                let dist = ray.origin + ray.dir * toi - *center;
+
+                if na::norm_squared(&dist) > r2 {
+                    return None;
+                }
+
+                (toi, normal)
+            }
+            PointShape::Sphere => {
+                let a = na::norm_squared(&ray.dir);
+                let b = na::dot(&oc, &ray.dir);
+                let disc = b * b - a * (na::norm_squared(&oc) - r2);
+
+                if disc < 0.0 {
+                    return None;
+                }
+
+                // The exit point if the origin is inside the sphere.
+                let sq = disc.sqrt();
+                let toi = if b - sq >= 0.0 { (b - sq) / a } else { (b + sq) / a };
+
+                (toi, ray.origin + ray.dir * toi - *center)
+            }
+        };
+
+        if hit.0 < 0.0 || hit.0 > max_toi {
+            None
+        } else {
+            Some(hit)
+        }
+    }
+}
+
+impl Geometry for PointCloud {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let inv = m.inverse();
+        let local_ray = Ray::new(inv * ray.origin, inv * ray.dir);
+
+        self.bvh
+            .cast_ray(&local_ray, Bounded::max_value(), |i, max_toi| {
+                self.cast_point(&local_ray, i, max_toi)
+                    .map(|(toi, normal)| (toi, (toi, normal)))
+            })
+            .map(|(i, (toi, normal))| {
+                let normal = na::normalize(&normal);
+                // Disks are two-sided.
+                let normal = if self.shape == PointShape::Disk
+                    && na::dot(&normal, &local_ray.dir) > 0.0
+                {
+                    -normal
+                } else {
+                    normal
+                };
+
+                let mut res = Intersection::new(toi, m * normal, m * normal, None);
+                res.color = self.colors.as_ref().map(|cs| cs[i]);
+
+                res
+            })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        match self.bvh.aabb() {
+            Some(aabb) => bvh::transform_aabb(aabb, m),
+            None => AABB::new(m * Point::origin(), m * Point::origin()),
+        }
+    }
+
+    // The transformation of the ray rounds it by γ(3), and the disk and sphere tests compute the
+    // time of impact with dot products, a discriminant and a square root.
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(16))
+    }
+}
+
+/// Loads a point cloud from a PLY file if its extension is `.ply`, or from an XYZ file
+/// otherwise.
+pub fn parse_file(path: &Path) -> IoResult<PointData> {
+    let mut bytes = Vec::new();
+    let mut file = File::open(path)?;
+    let _ = file.read_to_end(&mut bytes)?;
+
+    let is_ply = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("ply"))
+        .unwrap_or(false);
+
+    if is_ply {
+        parse_ply(&bytes[..])
+    } else {
+        match str::from_utf8(&bytes[..]) {
+            Ok(string) => parse_xyz(string),
+            Err(_) => Err(invalid_data("an XYZ file must be valid UTF-8.")),
+        }
+    }
+}
+
+/// Parses a point cloud in the XYZ text format.
+///
+/// Each line gives the position of a point `x y z`, optionally followed by its color
+/// `r g b` with components ranging from 0 to 255, or by its normal and color
+/// `nx ny nz r g b`. Lines starting with `#` are ignored.
+pub fn parse_xyz(string: &str) -> IoResult<PointData> {
+    let mut points = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+
+    for (l, line) in string.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut values = Vec::with_capacity(9);
+
+        for w in line.split(|c: char| c.is_whitespace() || c == ',') {
+            if !w.is_empty() {
+                match Scalar::from_str(w) {
+                    Ok(x) => values.push(x),
+                    Err(_) => {
+                        let err = format!("at line {}: failed to parse `{}' as a number.", l, w);
+                        return Err(invalid_data(&err[..]));
+                    }
+                }
+            }
+        }
+
+        let (normal, color) = match values.len() {
+            3 => (None, None),
+            6 => (None, Some(&values[3..6])),
+            9 => (Some(&values[3..6]), Some(&values[6..9])),
+            n => {
+                let err = format!("at line {}: 3, 6 or 9 values were expected, found {}.", l, n);
+                return Err(invalid_data(&err[..]));
+            }
+        };
+
+        points.push(Point::new(values[0], values[1], values[2]));
+
+        if let Some(n) = normal {
+            normals.push(unit_or_zero(Vect::new(n[0], n[1], n[2])));
+        }
+
+        if let Some(c) = color {
+            colors.push(Point3::new(
+                c[0] as f32 / 255.0,
+                c[1] as f32 / 255.0,
+                c[2] as f32 / 255.0,
+            ));
+        }
+    }
+
+    if (!normals.is_empty() && normals.len() != points.len())
+        || (!colors.is_empty() && colors.len() != points.len())
+    {
+        return Err(invalid_data(
+            "all the points of an XYZ file must have the same attributes.",
+        ));
+    }
+
+    Ok(PointData {
+        points: points,
+        normals: if normals.is_empty() { None } else { Some(normals) },
+        colors: if colors.is_empty() { None } else { Some(colors) },
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Option<PlyType> {
+        match name {
+            "char" | "int8" => Some(PlyType::Int8),
+            "uchar" | "uint8" => Some(PlyType::UInt8),
+            "short" | "int16" => Some(PlyType::Int16),
+            "ushort" | "uint16" => Some(PlyType::UInt16),
+            "int" | "int32" => Some(PlyType::Int32),
+            "uint" | "uint32" => Some(PlyType::UInt32),
+            "float" | "float32" => Some(PlyType::Float32),
+            "double" | "float64" => Some(PlyType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::Int8 | PlyType::UInt8 => 1,
+            PlyType::Int16 | PlyType::UInt16 => 2,
+            PlyType::Int32 | PlyType::UInt32 | PlyType::Float32 => 4,
+            PlyType::Float64 => 8,
+        }
+    }
+
+    // The value of an integer color component ranging from 0 to 1.
+    fn normalize_color(self, x: f64) -> f32 {
+        match self {
+            PlyType::UInt8 | PlyType::Int8 => (x / 255.0) as f32,
+            PlyType::UInt16 | PlyType::Int16 => (x / 65535.0) as f32,
+            _ => x as f32,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    ty: PlyType,
+    // The type of the length of a list property.
+    list: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Parses a point cloud from the vertices of a PLY file, in the ASCII or binary format.
+///
+/// The vertex properties `x`, `y`, `z` give the positions of the points, `nx`, `ny`, `nz`
+/// their normals, and `red`, `green`, `blue` their colors. Other elements, e.g., faces, are
+/// ignored.
+pub fn parse_ply(bytes: &[u8]) -> IoResult<PointData> {
+    let (format, elements, body) = parse_ply_header(bytes)?;
+    let mut reader = PlyReader {
+        bytes: body,
+        pos: 0,
+        format: format,
+    };
+
+    for element in elements.iter() {
+        if element.name != "vertex" {
+            for _ in 0..element.count {
+                for p in element.properties.iter() {
+                    let _ = reader.read_property(p)?;
+                }
+            }
+
+            continue;
+        }
+
+        let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let ids = |names: [&str; 3]| match (find(names[0]), find(names[1]), find(names[2])) {
+            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+            _ => None,
+        };
+
+        let position = match ids(["x", "y", "z"]) {
+            Some(ids) => ids,
+            None => return Err(invalid_data("PLY vertices must have x, y and z properties.")),
+        };
+        let normal = ids(["nx", "ny", "nz"]);
+        let color = ids(["red", "green", "blue"]);
+
+        // The count is read from the header: do not trust it beyond the size of the body, where
+        // every vertex takes at least one byte.
+        let capacity = element.count.min(body.len());
+        let mut points = Vec::with_capacity(capacity);
+        let mut normals = normal.map(|_| Vec::with_capacity(capacity));
+        let mut colors = color.map(|_| Vec::with_capacity(capacity));
+        let mut values = vec![0.0; element.properties.len()];
+
+        for _ in 0..element.count {
+            for (p, value) in element.properties.iter().zip(values.iter_mut()) {
+                *value = reader.read_property(p)?;
+            }
+
+            points.push(Point::new(
+                values[position[0]] as Scalar,
+                values[position[1]] as Scalar,
+                values[position[2]] as Scalar,
+            ));
+
+            if let (Some(ids), Some(normals)) = (normal, normals.as_mut()) {
+                let n = Vect::new(
+                    values[ids[0]] as Scalar,
+                    values[ids[1]] as Scalar,
+                    values[ids[2]] as Scalar,
+                );
+                normals.push(unit_or_zero(n));
+            }
+
+            if let (Some(ids), Some(colors)) = (color, colors.as_mut()) {
+                let props = &element.properties;
+                colors.push(Point3::new(
+                    props[ids[0]].ty.normalize_color(values[ids[0]]),
+                    props[ids[1]].ty.normalize_color(values[ids[1]]),
+                    props[ids[2]].ty.normalize_color(values[ids[2]]),
+                ));
+            }
+        }
+
+        return Ok(PointData {
+            points: points,
+            normals: normals,
+            colors: colors,
+        });
+    }
+
+    Err(invalid_data("a PLY file must have a vertex element."))
+}
+
+// Normalizes a normal read from a file, leaving zero normals, i.e., unknown ones, unchanged.
+fn unit_or_zero(n: Vect) -> Vect {
+    if na::norm_squared(&n) > 0.0 {
+        na::normalize(&n)
+    } else {
+        n
+    }
+}
+
+// Parses the header of a PLY file, returning the format, the elements, and the body of the
+// file.
+fn parse_ply_header(bytes: &[u8]) -> IoResult<(PlyFormat, Vec<PlyElement>, &[u8])> {
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    let mut pos = 0;
+    let mut first = true;
+
+    loop {
+        let end = match bytes[pos..].iter().position(|b| *b == b'\n') {
+            Some(end) => pos + end,
+            None => return Err(invalid_data("the PLY header is never ended.")),
+        };
+        let line = match str::from_utf8(&bytes[pos..end]) {
+            Ok(line) => line.trim(),
+            Err(_) => return Err(invalid_data("the PLY header must be valid UTF-8.")),
+        };
+        pos = end + 1;
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        if first {
+            if words != ["ply"] {
+                return Err(invalid_data("a PLY file must start with `ply'."));
+            }
+
+            first = false;
+            continue;
+        }
+
+        match words.first().map(|w| *w) {
+            None | Some("comment") | Some("obj_info") => {}
+            Some("format") => {
+                format = match words.get(1).map(|w| *w) {
+                    Some("ascii") => Some(PlyFormat::Ascii),
+                    Some("binary_little_endian") => Some(PlyFormat::BinaryLittleEndian),
+                    Some("binary_big_endian") => Some(PlyFormat::BinaryBigEndian),
+                    _ => return Err(invalid_data("unknown PLY format.")),
+                }
+            }
+            Some("element") => {
+                let count = words.get(2).and_then(|c| usize::from_str(c).ok());
+
+                match (words.get(1), count) {
+                    (Some(name), Some(count)) => elements.push(PlyElement {
+                        name: name.to_string(),
+                        count: count,
+                        properties: Vec::new(),
+                    }),
+                    _ => return Err(invalid_data("invalid PLY element.")),
+                }
+            }
+            Some("property") => {
+                let property = if words.get(1) == Some(&"list") {
+                    match (words.get(2), words.get(3), words.get(4)) {
+                        (Some(len), Some(ty), Some(name)) => {
+                            match (PlyType::parse(len), PlyType::parse(ty)) {
+                                (Some(len), Some(ty)) => PlyProperty {
+                                    name: name.to_string(),
+                                    ty: ty,
+                                    list: Some(len),
+                                },
+                                _ => return Err(invalid_data("unknown PLY property type.")),
+                            }
+                        }
+                        _ => return Err(invalid_data("invalid PLY list property.")),
+                    }
+                } else {
+                    match (words.get(1).and_then(|t| PlyType::parse(t)), words.get(2)) {
+                        (Some(ty), Some(name)) => PlyProperty {
+                            name: name.to_string(),
+                            ty: ty,
+                            list: None,
+                        },
+                        _ => return Err(invalid_data("invalid PLY property.")),
+                    }
+                };
+
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return Err(invalid_data("PLY property outside of an element.")),
+                }
+            }
+            Some("end_header") => break,
+            Some(w) => {
+                let err = format!("unknown PLY header keyword: {}", w);
+                return Err(invalid_data(&err[..]));
+            }
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements, &bytes[pos..])),
+        None => Err(invalid_data("the PLY header has no format.")),
+    }
+}
+
+// Reads the values of the body of a PLY file.
+struct PlyReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    format: PlyFormat,
+}
+
+impl<'a> PlyReader<'a> {
+    // Reads a property, returning its value, or the last item of a list.
+    fn read_property(&mut self, p: &PlyProperty) -> IoResult<f64> {
+        match p.list {
+            None => self.read(p.ty),
+            Some(len_ty) => {
+                let len = self.read(len_ty)? as usize;
+                let mut last = 0.0;
+
+                for _ in 0..len {
+                    last = self.read(p.ty)?;
+                }
+
+                Ok(last)
+            }
+        }
+    }
+
+    fn read(&mut self, ty: PlyType) -> IoResult<f64> {
+        if self.format == PlyFormat::Ascii {
+            return self.read_ascii();
+        }
+
+        let size = ty.size();
+
+        if self.pos + size > self.bytes.len() {
+            return Err(invalid_data("unexpected end of the PLY body."));
+        }
+
+        let bytes = &self.bytes[self.pos..self.pos + size];
+        self.pos += size;
+
+        // Assemble the bits in big endian order.
+        let bits = if self.format == PlyFormat::BinaryBigEndian {
+            bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+        } else {
+            bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+        };
+
+        Ok(match ty {
+            PlyType::Int8 => bits as u8 as i8 as f64,
+            PlyType::UInt8 => bits as u8 as f64,
+            PlyType::Int16 => bits as u16 as i16 as f64,
+            PlyType::UInt16 => bits as u16 as f64,
+            PlyType::Int32 => bits as u32 as i32 as f64,
+            PlyType::UInt32 => bits as u32 as f64,
+            PlyType::Float32 => f32::from_bits(bits as u32) as f64,
+            PlyType::Float64 => f64::from_bits(bits),
+        })
+    }
+
+    fn read_ascii(&mut self) -> IoResult<f64> {
+        while self.pos < self.bytes.len() && (self.bytes[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+
+        let start = self.pos;
+
+        while self.pos < self.bytes.len() && !(self.bytes[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+
+        let word = str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+
+        match f64::from_str(word) {
+            Ok(x) => Ok(x),
+            Err(_) if word.is_empty() => Err(invalid_data("unexpected end of the PLY body.")),
+            Err(_) => {
+                let err = format!("failed to parse `{}' as a number.", word);
+                Err(invalid_data(&err[..]))
+            }
+        }
+    }
+}
+
+fn invalid_data(err: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xyz_zero_normals_stay_zero() {
+        let data = parse_xyz("0 0 0 0 0 0 255 255 255\n1 0 0 0 0 2 255 0 0\n").unwrap();
+        let normals = data.normals.unwrap();
+
+        assert_eq!(normals[0], Vect::new(0.0, 0.0, 0.0));
+        assert_eq!(normals[1], Vect::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn ply_vertex_count_beyond_the_body_is_an_error() {
+        let ply = b"ply\nformat ascii 1.0\nelement vertex 4000000000000\n\
+                    property float x\nproperty float y\nproperty float z\nend_header\n0 0 0\n";
+
+        assert!(parse_ply(&ply[..]).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+//! Post-processing effects applied to the linear color buffer of a rendered image.
+
+use image::Image;
+use na::{self, Vector3};
+use rand::random;
+use std::f32;
+
+/// An effect that modifies a rendered image in-place.
+pub trait PostEffect {
+    /// Applies this effect to `image`.
+    fn apply(&self, image: &mut Image);
+}
+
+/// An ordered stack of post-processing effects.
+pub struct PostProcess {
+    effects: Vec<Box<PostEffect + Send + Sync>>,
+}
+
+impl PostProcess {
+    /// Creates an empty post-processing stack.
+    pub fn new() -> PostProcess {
+        PostProcess {
+            effects: Vec::new(),
+        }
+    }
+
+    /// Appends an effect at the end of this stack.
+    pub fn push<E: 'static + PostEffect + Send + Sync>(&mut self, effect: E) {
+        self.effects.push(Box::new(effect))
+    }
+
+    /// Whether this stack contains no effect at all.
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Applies every effect of this stack, in order, to `image`.
+    pub fn apply(&self, image: &mut Image) {
+        for effect in self.effects.iter() {
+            effect.apply(image)
+        }
+    }
+}
+
+/// Threshold-based bloom: pixels brighter than `threshold` bleed light onto their neighbours.
+pub struct Bloom {
+    threshold: f32,
+    intensity: f32,
+    radius: f32,
+}
+
+impl Bloom {
+    /// Creates a bloom effect.
+    ///
+    /// Only the luminance exceeding `threshold` is blurred with a gaussian kernel of standard
+    /// deviation `radius` (in pixels), scaled by `intensity`, and added back to the image.
+    pub fn new(threshold: f32, intensity: f32, radius: f32) -> Bloom {
+        assert!(radius >= 0.0, "The bloom radius must be positive.");
+
+        Bloom {
+            threshold: threshold,
+            intensity: intensity,
+            radius: radius,
+        }
+    }
+}
+
+impl PostEffect for Bloom {
+    fn apply(&self, image: &mut Image) {
+        let width = image.width();
+        let height = image.height();
+
+        let mut bright: Vec<Vector3<f32>> = image
+            .pixels()
+            .iter()
+            .map(|c| {
+                let l = luminance(c);
+
+                if l > self.threshold {
+                    *c * ((l - self.threshold) / l)
+                } else {
+                    na::zero()
+                }
+            })
+            .collect();
+
+        gaussian_blur(&mut bright[..], width, height, self.radius);
+
+        for (px, glow) in image.pixels_mut().iter_mut().zip(bright.iter()) {
+            *px = *px + *glow * self.intensity
+        }
+    }
+}
+
+/// Lens vignetting: darkens the image towards its corners.
+pub struct Vignette {
+    strength: f32,
+}
+
+impl Vignette {
+    /// Creates a vignetting effect. A `strength` of zero leaves the image unchanged.
+    pub fn new(strength: f32) -> Vignette {
+        Vignette { strength: strength }
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(&self, image: &mut Image) {
+        let width = image.width();
+        let height = image.height();
+        let cx = width as f32 * 0.5;
+        let cy = height as f32 * 0.5;
+        let max_sqdist = cx * cx + cy * cy;
+
+        for (ipt, px) in image.pixels_mut().iter_mut().enumerate() {
+            let j = ipt / width;
+            let i = ipt - j * width;
+            let dx = i as f32 + 0.5 - cx;
+            let dy = j as f32 + 0.5 - cy;
+
+            // Natural vignetting follows the cos⁴ law.
+            let r2 = (dx * dx + dy * dy) / max_sqdist;
+            let falloff = 1.0 / (1.0 + self.strength * r2);
+
+            *px = *px * (falloff * falloff)
+        }
+    }
+}
+
+/// Lateral chromatic aberration: the red and blue channels are radially scaled in opposite
+/// directions around the image center.
+pub struct ChromaticAberration {
+    strength: f32,
+}
+
+impl ChromaticAberration {
+    /// Creates a chromatic aberration effect.
+    ///
+    /// `strength` is the relative radial shift of the red and blue channels at the image
+    /// corners, e.g., `0.005` for a half-percent shift.
+    pub fn new(strength: f32) -> ChromaticAberration {
+        ChromaticAberration { strength: strength }
+    }
+}
+
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, image: &mut Image) {
+        let width = image.width();
+        let height = image.height();
+        let cx = width as f32 * 0.5;
+        let cy = height as f32 * 0.5;
+        let src = image.pixels().to_vec();
+
+        for (ipt, px) in image.pixels_mut().iter_mut().enumerate() {
+            let j = ipt / width;
+            let i = ipt - j * width;
+            let dx = i as f32 + 0.5 - cx;
+            let dy = j as f32 + 0.5 - cy;
+
+            let red_scale = 1.0 + self.strength;
+            let blue_scale = 1.0 - self.strength;
+
+            // Pixel centers are half a pixel away from their indices.
+            let red = sample_bilinear(
+                &src[..],
+                width,
+                height,
+                cx + dx * red_scale - 0.5,
+                cy + dy * red_scale - 0.5,
+            );
+            let blue = sample_bilinear(
+                &src[..],
+                width,
+                height,
+                cx + dx * blue_scale - 0.5,
+                cy + dy * blue_scale - 0.5,
+            );
+
+            px.x = red.x;
+            px.z = blue.z;
+        }
+    }
+}
+
+/// Film grain: adds luminance-dependent gaussian noise.
+pub struct FilmGrain {
+    intensity: f32,
+}
+
+impl FilmGrain {
+    /// Creates a film grain effect with noise of standard deviation `intensity`.
+    pub fn new(intensity: f32) -> FilmGrain {
+        FilmGrain {
+            intensity: intensity,
+        }
+    }
+}
+
+impl PostEffect for FilmGrain {
+    fn apply(&self, image: &mut Image) {
+        for px in image.pixels_mut().iter_mut() {
+            // Grain is most visible in the midtones.
+            let l = na::clamp(luminance(px), 0.0, 1.0);
+            let weight = 4.0 * l * (1.0 - l);
+            let noise = gaussian_noise() * self.intensity * weight;
+
+            *px = *px + Vector3::from_element(noise)
+        }
+    }
+}
+
+/// Unsharp masking.
+pub struct Sharpen {
+    amount: f32,
+}
+
+impl Sharpen {
+    /// Creates a sharpening effect. A typical `amount` lies between `0.0` and `1.0`.
+    pub fn new(amount: f32) -> Sharpen {
+        Sharpen { amount: amount }
+    }
+}
+
+impl PostEffect for Sharpen {
+    fn apply(&self, image: &mut Image) {
+        let width = image.width();
+        let height = image.height();
+        let mut blurred = image.pixels().to_vec();
+
+        gaussian_blur(&mut blurred[..], width, height, 1.0);
+
+        for (px, blur) in image.pixels_mut().iter_mut().zip(blurred.iter()) {
+            *px = *px + (*px - *blur) * self.amount
+        }
+    }
+}
+
+/// The Rec. 709 relative luminance of a linear color.
+pub fn luminance(c: &Vector3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Blurs `pixels` in-place with a separable gaussian kernel of standard deviation `sigma`.
+pub fn gaussian_blur(pixels: &mut [Vector3<f32>], width: usize, height: usize, sigma: f32) {
+    if sigma <= 0.0 {
+        return;
+    }
+
+    let radius = (sigma * 3.0).ceil() as isize;
+    let mut kernel: Vec<f32> = (-radius..radius + 1)
+        .map(|x| (-((x * x) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = kernel.iter().fold(0.0, |a, b| a + *b);
+
+    for k in kernel.iter_mut() {
+        *k = *k / total
+    }
+
+    let mut tmp = pixels.to_vec();
+
+    // Horizontal pass.
+    for j in 0..height {
+        for i in 0..width {
+            let mut acc: Vector3<f32> = na::zero();
+
+            for (k, w) in kernel.iter().enumerate() {
+                let x = clamp_index(i as isize + k as isize - radius, width);
+                acc = acc + pixels[j * width + x] * *w;
+            }
+
+            tmp[j * width + i] = acc;
+        }
+    }
+
+    // Vertical pass.
+    for j in 0..height {
+        for i in 0..width {
+            let mut acc: Vector3<f32> = na::zero();
+
+            for (k, w) in kernel.iter().enumerate() {
+                let y = clamp_index(j as isize + k as isize - radius, height);
+                acc = acc + tmp[y * width + i] * *w;
+            }
+
+            pixels[j * width + i] = acc;
+        }
+    }
+}
+
+fn clamp_index(i: isize, len: usize) -> usize {
+    if i < 0 {
+        0
+    } else if i as usize >= len {
+        len - 1
+    } else {
+        i as usize
+    }
+}
+
+fn sample_bilinear(
+    pixels: &[Vector3<f32>],
+    width: usize,
+    height: usize,
+    x: f32,
+    y: f32,
+) -> Vector3<f32> {
+    let x = na::clamp(x, 0.0, (width - 1) as f32);
+    let y = na::clamp(y, 0.0, (height - 1) as f32);
+
+    let x0 = x.floor() as usize;
+    let y0 = y.floor() as usize;
+    let x1 = (x0 + 1).min(width - 1);
+    let y1 = (y0 + 1).min(height - 1);
+    let sx = x - x0 as f32;
+    let sy = y - y0 as f32;
+
+    let top = pixels[y0 * width + x0] * (1.0 - sx) + pixels[y0 * width + x1] * sx;
+    let bottom = pixels[y1 * width + x0] * (1.0 - sx) + pixels[y1 * width + x1] * sx;
+
+    top * (1.0 - sy) + bottom * sy
+}
+
+// Box-Muller transform.
+fn gaussian_noise() -> f32 {
+    let u1 = random::<f32>().max(f32::MIN_POSITIVE);
+    let u2 = random::<f32>();
+
+    (-2.0 * u1.ln()).sqrt() * (2.0 * f32::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Vless;
+
+    fn ramp(width: usize, height: usize) -> Image {
+        let pixels = (0..width * height)
+            .map(|i| {
+                let t = i as f32 / (width * height) as f32;
+                Vector3::new(t, 1.0 - t, 0.5 * t)
+            })
+            .collect();
+
+        Image::new(Vless::new(width as _, height as _), pixels)
+    }
+
+    fn total(image: &Image) -> Vector3<f32> {
+        image.pixels().iter().fold(na::zero(), |a, b| a + *b)
+    }
+
+    fn assert_close(a: &Vector3<f32>, b: &Vector3<f32>) {
+        assert!(na::norm(&(*a - *b)) < 1.0e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn neutral_effects_leave_the_image_unchanged() {
+        let mut stack = PostProcess::new();
+        stack.push(Vignette::new(0.0));
+        stack.push(ChromaticAberration::new(0.0));
+        stack.push(FilmGrain::new(0.0));
+        stack.push(Sharpen::new(0.0));
+        stack.push(Bloom::new(10.0, 1.0, 2.0));
+
+        let original = ramp(7, 5);
+        let mut image = ramp(7, 5);
+        stack.apply(&mut image);
+
+        for (a, b) in image.pixels().iter().zip(original.pixels().iter()) {
+            assert_close(a, b);
+        }
+    }
+
+    #[test]
+    fn vignette_darkens_within_the_unit_range() {
+        let mut image = Image::new(Vless::new(9.0, 9.0), vec![Vector3::from_element(1.0); 81]);
+        Vignette::new(2.0).apply(&mut image);
+
+        let center = image.pixel(4, 4).x;
+        let corner = image.pixel(0, 0).x;
+
+        assert!(center <= 1.0 && center > 0.99);
+        assert!(corner > 0.0 && corner < center);
+
+        for px in image.pixels().iter() {
+            assert!(px.x > 0.0 && px.x <= 1.0);
+        }
+    }
+
+    #[test]
+    fn gaussian_blur_conserves_energy() {
+        let mut pixels = vec![na::zero::<Vector3<f32>>(); 15 * 15];
+        pixels[7 * 15 + 7] = Vector3::new(1.0, 2.0, 3.0);
+
+        gaussian_blur(&mut pixels[..], 15, 15, 1.5);
+
+        let sum = pixels.iter().fold(na::zero(), |a: Vector3<f32>, b| a + *b);
+        assert_close(&sum, &Vector3::new(1.0, 2.0, 3.0));
+        assert!(pixels[7 * 15 + 7].x < 1.0);
+        assert!(pixels[7 * 15 + 8].x > 0.0);
+    }
+
+    #[test]
+    fn bloom_adds_only_the_excess_energy() {
+        // A bright pixel far enough from the borders for the whole kernel to fit in the image.
+        let mut pixels = vec![Vector3::from_element(0.5); 15 * 15];
+        pixels[7 * 15 + 7] = Vector3::from_element(4.0);
+        let mut image = Image::new(Vless::new(15.0, 15.0), pixels);
+        let before = total(&image);
+
+        Bloom::new(1.0, 0.5, 1.0).apply(&mut image);
+
+        // The luminance above the threshold, scaled by the intensity, is spread around.
+        assert_close(&total(&image), &(before + Vector3::from_element(3.0 * 0.5)));
+        assert!(image.pixel(8, 7).x > 0.5);
+        assert_close(image.pixel(0, 0), &Vector3::from_element(0.5));
+    }
+
+    #[test]
+    fn chromatic_aberration_keeps_the_green_channel_and_a_uniform_image() {
+        let original = ramp(8, 6);
+        let mut image = ramp(8, 6);
+        ChromaticAberration::new(0.05).apply(&mut image);
+
+        for (a, b) in image.pixels().iter().zip(original.pixels().iter()) {
+            assert_eq!(a.y, b.y);
+        }
+
+        let mut uniform = Image::new(Vless::new(8.0, 6.0), vec![Vector3::new(0.2, 0.4, 0.6); 48]);
+        ChromaticAberration::new(0.05).apply(&mut uniform);
+
+        for px in uniform.pixels().iter() {
+            assert_close(px, &Vector3::new(0.2, 0.4, 0.6));
+        }
+    }
+
+    #[test]
+    fn sharpen_keeps_flat_regions() {
+        let mut image = Image::new(Vless::new(6.0, 6.0), vec![Vector3::from_element(0.3); 36]);
+        Sharpen::new(1.0).apply(&mut image);
+
+        for px in image.pixels().iter() {
+            assert_close(px, &Vector3::from_element(0.3));
+        }
+    }
+}
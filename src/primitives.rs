@@ -0,0 +1,1094 @@
+//! Analytic primitives with texture coordinates.
+//!
+//! Each primitive is expressed in its local frame and placed by the transformation of the scene
+//! node using it. The texture coordinates of the hit points range from 0 to 1 over the whole
+//! primitive, and the tangent frames follow their directions of increase.
+
+use bvh;
+use intersection::Intersection;
+use math::{self, Isometry, Point, Scalar, Vect, PI};
+use na::{self, Point2, Unit, Vector2};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use num::Bounded;
+use scene_node::Geometry;
+use std::cmp::Ordering;
+use triangle_mesh;
+
+const TWO_PI: Scalar = 2.0 * PI;
+
+// The number of dependent floating point operations computing the times of impact with each
+// kind of primitive, from the transformation of the ray to the local frame to the polished roots
+// of the polynomial solvers. `math::gamma` of these bounds the relative error of the times of
+// impact.
+const PLANE_OPS: usize = 10;
+const TRIANGLE_OPS: usize = 13;
+const QUADRIC_OPS: usize = 16;
+const TORUS_OPS: usize = 24;
+
+// The tolerance under which the quantities of the polynomial solvers, e.g., the discriminants
+// of multiple roots, are considered zero, relative to the magnitude of the terms they are
+// computed from.
+#[cfg(not(feature = "f32"))]
+const TOLERANCE: Scalar = 1.0e-12;
+
+// The tolerance under which the quantities of the polynomial solvers, e.g., the discriminants
+// of multiple roots, are considered zero, relative to the magnitude of the terms they are
+// computed from.
+#[cfg(feature = "f32")]
+const TOLERANCE: Scalar = 1.0e-5;
+
+/// A torus centered at the origin around the `y` axis.
+///
+/// The first texture coordinate goes around the `y` axis, and the second one around the tube.
+pub struct Torus {
+    major_radius: Scalar,
+    minor_radius: Scalar,
+}
+
+impl Torus {
+    /// Creates a torus with the given distance from its center to the center of its tube, and
+    /// radius of its tube.
+    pub fn new(major_radius: Scalar, minor_radius: Scalar) -> Torus {
+        assert!(
+            minor_radius > 0.0 && major_radius >= minor_radius,
+            "The tube of a torus must not intersect its axis."
+        );
+
+        Torus {
+            major_radius: major_radius,
+            minor_radius: minor_radius,
+        }
+    }
+
+    /// The distance from the center of this torus to the center of its tube.
+    pub fn major_radius(&self) -> Scalar {
+        self.major_radius
+    }
+
+    /// The radius of the tube of this torus.
+    pub fn minor_radius(&self) -> Scalar {
+        self.minor_radius
+    }
+
+    fn local_aabb(&self) -> AABB<Scalar> {
+        let r = self.major_radius + self.minor_radius;
+
+        AABB::new(
+            Point::new(-r, -self.minor_radius, -r),
+            Point::new(r, self.minor_radius, r),
+        )
+    }
+
+    // The implicit equation of this torus, negative inside.
+    fn implicit(&self, pt: &Point) -> Scalar {
+        let (big, small) = (self.major_radius, self.minor_radius);
+        let k = na::norm_squared(&pt.coords) + big * big - small * small;
+
+        k * k - 4.0 * big * big * (pt.x * pt.x + pt.z * pt.z)
+    }
+}
+
+impl Geometry for Torus {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, solid: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let (entry, exit) = match bvh::ray_aabb_interval(&self.local_aabb(), &ray) {
+            Some(ts) => ts,
+            None => return None,
+        };
+
+        if solid && self.implicit(&ray.origin) < 0.0 {
+            let normal = -ray.dir;
+
+            return Some(Intersection::new(0.0, m * normal, m * normal, None));
+        }
+
+        // The quartic is solved for a unit direction from the entry point of the bounding box,
+        // for better conditioning.
+        let len = na::norm(&ray.dir);
+        let dir = ray.dir / len;
+        let start = entry.max(0.0);
+        let o = ray.origin + ray.dir * start;
+
+        let (big, small) = (self.major_radius, self.minor_radius);
+        let k = na::norm_squared(&o.coords) + big * big - small * small;
+        let od = na::dot(&o.coords, &dir);
+        let r4 = 4.0 * big * big;
+        let coeffs = [
+            k * k - r4 * (o.x * o.x + o.z * o.z),
+            4.0 * od * k - 2.0 * r4 * (o.x * dir.x + o.z * dir.z),
+            4.0 * od * od + 2.0 * k - r4 * (dir.x * dir.x + dir.z * dir.z),
+            4.0 * od,
+            1.0,
+        ];
+
+        // The roots at the entry point, where the torus touches its bounding box, are hits too.
+        let toi = solve_quartic(coeffs[4], coeffs[3], coeffs[2], coeffs[1], coeffs[0])
+            .into_iter()
+            .map(|s| start + polish_root(&coeffs, s) / len)
+            .find(|toi| *toi > 0.0 && *toi <= exit);
+
+        toi.map(|toi| {
+            let pt = ray.origin + ray.dir * toi;
+            let radial = Vect::new(pt.x, 0.0, pt.z);
+            let rnorm = na::norm(&radial);
+            let radial = if rnorm > 0.0 { radial / rnorm } else { Vect::x() };
+            let normal = pt.coords - radial * big;
+
+            let phi = pt.z.atan2(pt.x);
+            let theta = pt.y.atan2(rnorm - big);
+            let uvs = Point2::new(0.5 + phi / TWO_PI, 0.5 + theta / TWO_PI);
+            let dpdu = Vect::new(-pt.z, 0.0, pt.x) * TWO_PI;
+            let dpdv = (Vect::y() * theta.cos() - radial * theta.sin()) * small * TWO_PI;
+
+            intersection(m, &ray, toi, normal, false, uvs, (dpdu, dpdv))
+        })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        bvh::transform_aabb(&self.local_aabb(), m)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(TORUS_OPS))
+    }
+}
+
+/// A disk, or an annulus, centered at the origin on the plane orthogonal to the `y` axis.
+///
+/// The first texture coordinate goes around the `y` axis, and the second one from the inner
+/// to the outer radius.
+pub struct Disk {
+    radius: Scalar,
+    inner_radius: Scalar,
+}
+
+impl Disk {
+    /// Creates a disk with the given radius.
+    pub fn new(radius: Scalar) -> Disk {
+        Disk::new_annulus(radius, 0.0)
+    }
+
+    /// Creates a disk with the given radius and a hole with the given inner radius.
+    pub fn new_annulus(radius: Scalar, inner_radius: Scalar) -> Disk {
+        assert!(
+            inner_radius >= 0.0 && radius > inner_radius,
+            "The radius of a disk must be greater than its inner radius."
+        );
+
+        Disk {
+            radius: radius,
+            inner_radius: inner_radius,
+        }
+    }
+
+    /// The radius of this disk.
+    pub fn radius(&self) -> Scalar {
+        self.radius
+    }
+
+    /// The radius of the hole of this disk.
+    pub fn inner_radius(&self) -> Scalar {
+        self.inner_radius
+    }
+}
+
+impl Geometry for Disk {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+
+        if ray.dir.y == 0.0 {
+            return None;
+        }
+
+        let toi = -ray.origin.y / ray.dir.y;
+
+        if toi < 0.0 {
+            return None;
+        }
+
+        let pt = ray.origin + ray.dir * toi;
+        let rho = (pt.x * pt.x + pt.z * pt.z).sqrt();
+
+        if rho > self.radius || rho < self.inner_radius {
+            return None;
+        }
+
+        let width = self.radius - self.inner_radius;
+        let radial = if rho > 0.0 {
+            Vect::new(pt.x, 0.0, pt.z) / rho
+        } else {
+            Vect::x()
+        };
+        let uvs = Point2::new(
+            0.5 + pt.z.atan2(pt.x) / TWO_PI,
+            (rho - self.inner_radius) / width,
+        );
+        let dpdu = Vect::new(-pt.z, 0.0, pt.x) * TWO_PI;
+        let dpdv = radial * width;
+
+        Some(intersection(m, &ray, toi, Vect::y(), true, uvs, (dpdu, dpdv)))
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let r = self.radius;
+        let local = AABB::new(Point::new(-r, 0.0, -r), Point::new(r, 0.0, r));
+
+        bvh::transform_aabb(&local, m)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(PLANE_OPS))
+    }
+}
+
+/// A parallelogram, e.g., a rectangle.
+///
+/// The texture coordinates of a point are its coordinates along the two edges of the
+/// parallelogram.
+pub struct Quad {
+    origin: Point,
+    edge_u: Vect,
+    edge_v: Vect,
+    normal: Vect,
+}
+
+impl Quad {
+    /// Creates the parallelogram with a corner at `origin` and the given edges leaving it.
+    pub fn new(origin: Point, edge_u: Vect, edge_v: Vect) -> Quad {
+        let normal = edge_u.cross(&edge_v);
+
+        assert!(
+            na::norm_squared(&normal) > 0.0,
+            "The edges of a quad must not be collinear."
+        );
+
+        Quad {
+            origin: origin,
+            edge_u: edge_u,
+            edge_v: edge_v,
+            normal: normal,
+        }
+    }
+
+    /// Creates a rectangle centered at the origin on the plane orthogonal to the `y` axis, with
+    /// the given half extents along the `x` and `z` axes.
+    pub fn new_rectangle(half_extents: Vector2<Scalar>) -> Quad {
+        let (hx, hz) = (half_extents.x, half_extents.y);
+
+        Quad::new(
+            Point::new(-hx, 0.0, hz),
+            Vect::new(2.0 * hx, 0.0, 0.0),
+            Vect::new(0.0, 0.0, -2.0 * hz),
+        )
+    }
+
+    /// The corner of this quad the edges leave from.
+    pub fn origin(&self) -> &Point {
+        &self.origin
+    }
+
+    /// The edge of this quad along the first texture coordinate.
+    pub fn edge_u(&self) -> &Vect {
+        &self.edge_u
+    }
+
+    /// The edge of this quad along the second texture coordinate.
+    pub fn edge_v(&self) -> &Vect {
+        &self.edge_v
+    }
+}
+
+impl Geometry for Quad {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let denom = na::dot(&ray.dir, &self.normal);
+
+        if denom == 0.0 {
+            return None;
+        }
+
+        let toi = na::dot(&(self.origin - ray.origin), &self.normal) / denom;
+
+        if toi < 0.0 {
+            return None;
+        }
+
+        // The coordinates of the hit point along the edges.
+        let q = ray.origin + ray.dir * toi - self.origin;
+        let w = self.normal / na::norm_squared(&self.normal);
+        let u = na::dot(&w, &q.cross(&self.edge_v));
+        let v = na::dot(&w, &self.edge_u.cross(&q));
+
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            return None;
+        }
+
+        Some(intersection(
+            m,
+            &ray,
+            toi,
+            self.normal,
+            true,
+            Point2::new(u, v),
+            (self.edge_u, self.edge_v),
+        ))
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let corners = [
+            self.origin + self.edge_u,
+            self.origin + self.edge_v,
+            self.origin + self.edge_u + self.edge_v,
+        ];
+        let m_origin = m * self.origin;
+        let (mins, maxs) = corners.iter().fold((m_origin, m_origin), |(mins, maxs), c| {
+            let c = m * *c;
+
+            (na::inf(&mins, &c), na::sup(&maxs, &c))
+        });
+
+        AABB::new(mins, maxs)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(PLANE_OPS))
+    }
+}
+
+/// A single triangle, with texture coordinates at its vertices.
+pub struct Triangle {
+    vertices: [Point; 3],
+    uvs: [Point2<Scalar>; 3],
+}
+
+impl Triangle {
+    /// Creates a triangle with the texture coordinates `(0, 0)`, `(1, 0)` and `(0, 1)` at its
+    /// vertices.
+    pub fn new(a: Point, b: Point, c: Point) -> Triangle {
+        Triangle::new_with_uvs(
+            a,
+            b,
+            c,
+            [
+                Point2::new(0.0, 0.0),
+                Point2::new(1.0, 0.0),
+                Point2::new(0.0, 1.0),
+            ],
+        )
+    }
+
+    /// Creates a triangle with the given texture coordinates at its vertices.
+    pub fn new_with_uvs(a: Point, b: Point, c: Point, uvs: [Point2<Scalar>; 3]) -> Triangle {
+        Triangle {
+            vertices: [a, b, c],
+            uvs: uvs,
+        }
+    }
+
+    /// The vertices of this triangle.
+    pub fn vertices(&self) -> &[Point; 3] {
+        &self.vertices
+    }
+
+    /// The texture coordinates at the vertices of this triangle.
+    pub fn uvs(&self) -> &[Point2<Scalar>; 3] {
+        &self.uvs
+    }
+}
+
+impl Geometry for Triangle {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let (a, b, c) = (&self.vertices[0], &self.vertices[1], &self.vertices[2]);
+        let (uva, uvb, uvc) = (&self.uvs[0], &self.uvs[1], &self.uvs[2]);
+
+        triangle_mesh::ray_triangle(&ray, a, b, c, Bounded::max_value()).map(|(toi, u, v)| {
+            let normal = (*b - *a).cross(&(*c - *a));
+            let w = 1.0 - u - v;
+            let uvs = Point2::from_coordinates(uva.coords * w + uvb.coords * u + uvc.coords * v);
+            // Any derivative along the second texture coordinate with the right handedness
+            // gives the same tangent frame.
+            let derivatives = match triangle_mesh::triangle_tangent(a, b, c, uva, uvb, uvc) {
+                Some((dpdu, handedness)) => (dpdu, normal.cross(&dpdu) * handedness),
+                None => (na::zero(), na::zero()),
+            };
+
+            intersection(m, &ray, toi, normal, true, uvs, derivatives)
+        })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let a = m * self.vertices[0];
+        let b = m * self.vertices[1];
+        let c = m * self.vertices[2];
+
+        AABB::new(na::inf(&na::inf(&a, &b), &c), na::sup(&na::sup(&a, &b), &c))
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(TRIANGLE_OPS))
+    }
+}
+
+/// A plane with the given normal through the origin, bounded to a rectangle.
+///
+/// The texture coordinates follow the two axes of the rectangle: the projection of the `x` axis
+/// on the plane (or of the `y` axis if the normal is close to `x`), and its cross product with
+/// the normal.
+pub struct BoundedPlane {
+    normal: Unit<Vect>,
+    half_extents: Vector2<Scalar>,
+    axes: (Vect, Vect),
+}
+
+impl BoundedPlane {
+    /// Creates a plane with the given normal and half extents along its axes.
+    pub fn new(normal: Unit<Vect>, half_extents: Vector2<Scalar>) -> BoundedPlane {
+        assert!(
+            half_extents.x > 0.0 && half_extents.y > 0.0,
+            "The half extents of a bounded plane must be positive."
+        );
+
+        let n = normal.as_ref();
+        let reference = if n.x.abs() < 0.9 { Vect::x() } else { Vect::y() };
+        let u = na::normalize(&(reference - *n * na::dot(n, &reference)));
+        let v = n.cross(&u);
+
+        BoundedPlane {
+            normal: normal,
+            half_extents: half_extents,
+            axes: (u, v),
+        }
+    }
+
+    /// The normal of this plane.
+    pub fn normal(&self) -> &Unit<Vect> {
+        &self.normal
+    }
+
+    /// The half extents of this plane along its axes.
+    pub fn half_extents(&self) -> &Vector2<Scalar> {
+        &self.half_extents
+    }
+
+    fn corners(&self) -> [Point; 4] {
+        let u = self.axes.0 * self.half_extents.x;
+        let v = self.axes.1 * self.half_extents.y;
+
+        [
+            Point::from_coordinates(-u - v),
+            Point::from_coordinates(u - v),
+            Point::from_coordinates(u + v),
+            Point::from_coordinates(-u + v),
+        ]
+    }
+}
+
+impl Geometry for BoundedPlane {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let n = self.normal.as_ref();
+        let denom = na::dot(&ray.dir, n);
+
+        if denom == 0.0 {
+            return None;
+        }
+
+        let toi = -na::dot(&ray.origin.coords, n) / denom;
+
+        if toi < 0.0 {
+            return None;
+        }
+
+        let pt = ray.origin + ray.dir * toi;
+        let x = na::dot(&pt.coords, &self.axes.0);
+        let y = na::dot(&pt.coords, &self.axes.1);
+
+        if x.abs() > self.half_extents.x || y.abs() > self.half_extents.y {
+            return None;
+        }
+
+        let uvs = Point2::new(
+            0.5 + 0.5 * x / self.half_extents.x,
+            0.5 + 0.5 * y / self.half_extents.y,
+        );
+        let dpdu = self.axes.0 * (2.0 * self.half_extents.x);
+        let dpdv = self.axes.1 * (2.0 * self.half_extents.y);
+
+        Some(intersection(m, &ray, toi, *n, true, uvs, (dpdu, dpdv)))
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let corners = self.corners();
+        let first = m * corners[0];
+        let (mins, maxs) = corners[1..].iter().fold((first, first), |(mins, maxs), c| {
+            let c = m * *c;
+
+            (na::inf(&mins, &c), na::sup(&maxs, &c))
+        });
+
+        AABB::new(mins, maxs)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(PLANE_OPS))
+    }
+}
+
+/// An open paraboloid around the `y` axis, with its apex at the origin.
+///
+/// The first texture coordinate goes around the `y` axis, and the second one from the apex to
+/// the rim.
+pub struct Paraboloid {
+    radius: Scalar,
+    height: Scalar,
+}
+
+impl Paraboloid {
+    /// Creates a paraboloid with the given radius at its rim, at the given height above its
+    /// apex.
+    pub fn new(radius: Scalar, height: Scalar) -> Paraboloid {
+        assert!(
+            radius > 0.0 && height > 0.0,
+            "The radius and height of a paraboloid must be positive."
+        );
+
+        Paraboloid {
+            radius: radius,
+            height: height,
+        }
+    }
+
+    /// The radius of the rim of this paraboloid.
+    pub fn radius(&self) -> Scalar {
+        self.radius
+    }
+
+    /// The height of the rim of this paraboloid above its apex.
+    pub fn height(&self) -> Scalar {
+        self.height
+    }
+}
+
+impl Geometry for Paraboloid {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let (o, d) = (&ray.origin, &ray.dir);
+        // x² + z² = k y
+        let k = self.radius * self.radius / self.height;
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.z * d.z) - k * d.y;
+        let c = o.x * o.x + o.z * o.z - k * o.y;
+
+        let toi = solve_quadratic(a, b, c).into_iter().find(|t| {
+            let y = o.y + d.y * *t;
+
+            *t >= 0.0 && y >= 0.0 && y <= self.height
+        });
+
+        toi.map(|toi| {
+            let pt = ray.origin + ray.dir * toi;
+            let normal = Vect::new(2.0 * pt.x, -k, 2.0 * pt.z);
+            let uvs = Point2::new(0.5 + pt.z.atan2(pt.x) / TWO_PI, pt.y / self.height);
+            let dpdu = Vect::new(-pt.z, 0.0, pt.x) * TWO_PI;
+            let dpdv = if pt.y > 0.0 {
+                let s = self.height / (2.0 * pt.y);
+
+                Vect::new(pt.x * s, self.height, pt.z * s)
+            } else {
+                na::zero()
+            };
+
+            intersection(m, &ray, toi, normal, true, uvs, (dpdu, dpdv))
+        })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let r = self.radius;
+        let local = AABB::new(Point::new(-r, 0.0, -r), Point::new(r, self.height, r));
+
+        bvh::transform_aabb(&local, m)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(QUADRIC_OPS))
+    }
+}
+
+/// An open hyperboloid of one sheet around the `y` axis, centered at the origin.
+///
+/// The first texture coordinate goes around the `y` axis, and the second one from the bottom
+/// to the top rim.
+pub struct Hyperboloid {
+    waist_radius: Scalar,
+    radius: Scalar,
+    half_height: Scalar,
+}
+
+impl Hyperboloid {
+    /// Creates a hyperboloid with the given radius at its center, and radius at its rims, at
+    /// the given distance from its center.
+    ///
+    /// A zero `waist_radius` gives a double cone.
+    pub fn new(waist_radius: Scalar, radius: Scalar, half_height: Scalar) -> Hyperboloid {
+        assert!(
+            waist_radius >= 0.0 && radius > waist_radius && half_height > 0.0,
+            "The rims of a hyperboloid must be wider than its waist."
+        );
+
+        Hyperboloid {
+            waist_radius: waist_radius,
+            radius: radius,
+            half_height: half_height,
+        }
+    }
+
+    /// The radius of this hyperboloid at its center.
+    pub fn waist_radius(&self) -> Scalar {
+        self.waist_radius
+    }
+
+    /// The radius of the rims of this hyperboloid.
+    pub fn radius(&self) -> Scalar {
+        self.radius
+    }
+
+    /// The distance from the center of this hyperboloid to its rims.
+    pub fn half_height(&self) -> Scalar {
+        self.half_height
+    }
+}
+
+impl Geometry for Hyperboloid {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let ray = local_ray(m, ray);
+        let (o, d) = (&ray.origin, &ray.dir);
+        let h = self.half_height;
+        // x² + z² - c² y² = w²
+        let w2 = self.waist_radius * self.waist_radius;
+        let c2 = (self.radius * self.radius - w2) / (h * h);
+        let a = d.x * d.x + d.z * d.z - c2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z - c2 * o.y * d.y);
+        let c = o.x * o.x + o.z * o.z - c2 * o.y * o.y - w2;
+
+        let toi = solve_quadratic(a, b, c).into_iter().find(|t| {
+            let y = o.y + d.y * *t;
+
+            *t >= 0.0 && y.abs() <= h
+        });
+
+        toi.map(|toi| {
+            let pt = ray.origin + ray.dir * toi;
+            let normal = Vect::new(pt.x, -c2 * pt.y, pt.z);
+            let uvs = Point2::new(0.5 + pt.z.atan2(pt.x) / TWO_PI, 0.5 + 0.5 * pt.y / h);
+            let dpdu = Vect::new(-pt.z, 0.0, pt.x) * TWO_PI;
+            let rho2 = pt.x * pt.x + pt.z * pt.z;
+            let dpdv = if rho2 > 0.0 {
+                let s = c2 * pt.y / rho2;
+
+                Vect::new(pt.x * s, 1.0, pt.z * s) * (2.0 * h)
+            } else {
+                na::zero()
+            };
+
+            intersection(m, &ray, toi, normal, true, uvs, (dpdu, dpdv))
+        })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        let (r, h) = (self.radius, self.half_height);
+        let local = AABB::new(Point::new(-r, -h, -r), Point::new(r, h, r));
+
+        bvh::transform_aabb(&local, m)
+    }
+
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(QUADRIC_OPS))
+    }
+}
+
+/// The real roots of `a x² + b x + c`, in increasing order.
+pub fn solve_quadratic(a: Scalar, b: Scalar, c: Scalar) -> Vec<Scalar> {
+    if is_negligible(a, b.abs() + c.abs()) {
+        return if is_negligible(b, c.abs()) {
+            Vec::new()
+        } else {
+            vec![-c / b]
+        };
+    }
+
+    let (b2, ac4) = (b * b, 4.0 * a * c);
+    let mut disc = b2 - ac4;
+
+    if disc < 0.0 {
+        // The discriminant of a double root may be rounded below zero.
+        if is_negligible(disc, b2 + ac4.abs()) {
+            disc = 0.0;
+        } else {
+            return Vec::new();
+        }
+    }
+
+    // Avoids the cancellation of `-b ± √disc`.
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+
+    if q == 0.0 {
+        return vec![0.0];
+    }
+
+    let (x1, x2) = (q / a, c / q);
+
+    if x1 < x2 {
+        vec![x1, x2]
+    } else {
+        vec![x2, x1]
+    }
+}
+
+/// The real roots of `a x³ + b x² + c x + d`, in increasing order.
+pub fn solve_cubic(a: Scalar, b: Scalar, c: Scalar, d: Scalar) -> Vec<Scalar> {
+    if is_negligible(a, b.abs() + c.abs() + d.abs()) {
+        return solve_quadratic(b, c, d);
+    }
+
+    // Substitutes x = y - A/3 to eliminate the quadratic term: y³ + 3p y + 2q.
+    let (a, b, c) = (b / a, c / a, d / a);
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q_terms = [2.0 / 27.0 * a * sq_a, -a * b / 3.0, c];
+    let q = (q_terms[0] + q_terms[1] + q_terms[2]) / 2.0;
+    let q_scale = (q_terms[0].abs() + q_terms[1].abs() + q_terms[2].abs()) / 2.0;
+    let cb_p = p * p * p;
+    let disc = q * q + cb_p;
+
+    let mut res = if is_negligible(disc, q * q + cb_p.abs()) {
+        if is_negligible(q, q_scale) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if disc < 0.0 {
+        // Three real roots.
+        let phi = (-q / (-cb_p).sqrt()).max(-1.0).min(1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        let third = PI / 3.0;
+
+        vec![t * phi.cos(), -t * (phi + third).cos(), -t * (phi - third).cos()]
+    } else {
+        let sqrt_disc = disc.sqrt();
+
+        vec![(sqrt_disc - q).cbrt() - (sqrt_disc + q).cbrt()]
+    };
+
+    for x in res.iter_mut() {
+        *x -= a / 3.0
+    }
+
+    sort(&mut res);
+    res
+}
+
+/// The real roots of `a x⁴ + b x³ + c x² + d x + e`, in increasing order.
+///
+/// The roots are computed with Ferrari's method and should be polished, e.g., with a few Newton
+/// iterations, when a good precision is needed.
+pub fn solve_quartic(a: Scalar, b: Scalar, c: Scalar, d: Scalar, e: Scalar) -> Vec<Scalar> {
+    if is_negligible(a, b.abs() + c.abs() + d.abs() + e.abs()) {
+        return solve_cubic(b, c, d, e);
+    }
+
+    // Substitutes x = y - A/4 to eliminate the cubic term: y⁴ + p y² + q y + r.
+    let (a, b, c, d) = (b / a, c / a, d / a, e / a);
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c;
+    let r_terms = [-3.0 / 256.0 * sq_a * sq_a, sq_a * b / 16.0, -a * c / 4.0, d];
+    let r = r_terms[0] + r_terms[1] + r_terms[2] + r_terms[3];
+    let r_scale = r_terms.iter().fold(0.0, |acc, t| acc + t.abs());
+
+    let mut res = if is_negligible(r, r_scale) {
+        // y (y³ + p y + q) = 0
+        let mut res = solve_cubic(1.0, 0.0, p, q);
+        res.push(0.0);
+        res
+    } else {
+        // Any root of the resolvent cubic splits the quartic into two quadratics.
+        let z = match solve_cubic(1.0, -p / 2.0, -r, r * p / 2.0 - q * q / 8.0).pop() {
+            Some(z) => z,
+            None => return Vec::new(),
+        };
+
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+
+        let u = if is_negligible(u, z * z + r.abs()) {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return Vec::new();
+        };
+
+        let v = if is_negligible(v, 2.0 * z.abs() + p.abs()) {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return Vec::new();
+        };
+
+        let v = if q < 0.0 { -v } else { v };
+        let mut res = solve_quadratic(1.0, v, z - u);
+        res.extend(solve_quadratic(1.0, -v, z + u));
+        res
+    };
+
+    for x in res.iter_mut() {
+        *x -= a / 4.0
+    }
+
+    sort(&mut res);
+    res
+}
+
+// Refines a root of the polynomial with the given coefficients, by increasing degree, with a few
+// Newton iterations.
+fn polish_root(coeffs: &[Scalar], mut x: Scalar) -> Scalar {
+    for _ in 0..2 {
+        let (f, df) = coeffs
+            .iter()
+            .rev()
+            .fold((0.0, 0.0), |(f, df), c| (f * x + *c, df * x + f));
+
+        if df == 0.0 {
+            break;
+        }
+
+        x -= f / df;
+    }
+
+    x
+}
+
+// Whether `x`, computed from terms whose magnitudes add up to `scale`, is zero up to its
+// rounding error.
+#[inline]
+fn is_negligible(x: Scalar, scale: Scalar) -> bool {
+    x.abs() <= scale * TOLERANCE
+}
+
+fn sort(roots: &mut Vec<Scalar>) {
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+fn local_ray(m: &Isometry, ray: &Ray<Scalar>) -> Ray<Scalar> {
+    let inv = m.inverse();
+
+    Ray::new(inv * ray.origin, inv * ray.dir)
+}
+
+// Builds the intersection of a local ray at `toi`, with the unnormalized local normal `normal`,
+// and the derivatives `(dpdu, dpdv)` of the local surface with respect to the texture
+// coordinates.
+//
+// The normal of open surfaces faces the ray origin.
+fn intersection(
+    m: &Isometry,
+    ray: &Ray<Scalar>,
+    toi: Scalar,
+    normal: Vect,
+    open: bool,
+    uvs: Point2<Scalar>,
+    (dpdu, dpdv): (Vect, Vect),
+) -> Intersection {
+    let outward = na::normalize(&normal);
+    let flipped = open && na::dot(&outward, &ray.dir) > 0.0;
+    let normal = if flipped { -outward } else { outward };
+    let handedness = if na::dot(&outward.cross(&dpdu), &dpdv) < 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+
+    let mut res = Intersection::new(toi, m * normal, m * normal, Some(uvs));
+
+    // Gram-Schmidt orthogonalization against the normal. The frame is built on the outward
+    // side, and flipped with the normal.
+    let tangent = dpdu - outward * na::dot(&outward, &dpdu);
+    let norm = na::norm(&tangent);
+
+    if norm > 0.0 {
+        let tangent = tangent / norm;
+        let bitangent = outward.cross(&tangent) * handedness;
+
+        res.tangent_frame = if flipped {
+            Some((m * -tangent, m * -bitangent))
+        } else {
+            Some((m * tangent, m * bitangent))
+        };
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Checks that every root is found up to `eps`, relative to the largest root, and that every
+    // solution is a root, regardless of the multiplicities.
+    fn assert_roots(solutions: Vec<Scalar>, roots: &[Scalar], eps: Scalar) {
+        let scale = roots.iter().fold(0.0, |acc: Scalar, r| acc.max(r.abs()));
+        let close = |a: Scalar, b: Scalar| (a - b).abs() <= eps * scale;
+
+        assert!(
+            roots.iter().all(|r| solutions.iter().any(|s| close(*r, *s))),
+            "{:?} misses some of the roots {:?}",
+            solutions,
+            roots
+        );
+        assert!(
+            solutions.iter().all(|s| roots.iter().any(|r| close(*r, *s))),
+            "{:?} are not all among the roots {:?}",
+            solutions,
+            roots
+        );
+    }
+
+    // The coefficients, by decreasing degree, of the monic polynomial with the given roots,
+    // scaled by `scale`.
+    fn poly(roots: &[Scalar], scale: Scalar) -> Vec<Scalar> {
+        let mut res = vec![scale];
+
+        for r in roots.iter() {
+            res.push(0.0);
+
+            for i in (1..res.len()).rev() {
+                res[i] = res[i] - *r * res[i - 1];
+            }
+        }
+
+        res
+    }
+
+    fn cast(geometry: &Geometry, origin: Point, dir: Vect) -> Option<Intersection> {
+        geometry.cast(&Isometry::identity(), &Ray::new(origin, dir), false)
+    }
+
+    fn uvs(geometry: &Geometry, origin: Point, dir: Vect) -> Point2<Scalar> {
+        cast(geometry, origin, dir).unwrap().uvs.unwrap()
+    }
+
+    #[test]
+    fn solvers_find_simple_and_multiple_roots_at_any_scale() {
+        // The coefficients of each polynomial are scaled, which must not change its roots.
+        for scale in [1.0e-10, 1.0, 1.0e10].iter() {
+            let s = *scale;
+
+            let c = poly(&[1.0, 3.0], s);
+            assert_roots(solve_quadratic(c[0], c[1], c[2]), &[1.0, 3.0], 1.0e-12);
+            let c = poly(&[2.0, 2.0], s);
+            assert_roots(solve_quadratic(c[0], c[1], c[2]), &[2.0], 1.0e-12);
+
+            let c = poly(&[1.0, 2.0, 3.0], s);
+            assert_roots(solve_cubic(c[0], c[1], c[2], c[3]), &[1.0, 2.0, 3.0], 1.0e-12);
+            let c = poly(&[1.0, 1.0, 2.0], s);
+            assert_roots(solve_cubic(c[0], c[1], c[2], c[3]), &[1.0, 2.0], 1.0e-6);
+            let c = poly(&[2.0, 2.0, 2.0], s);
+            assert_roots(solve_cubic(c[0], c[1], c[2], c[3]), &[2.0], 1.0e-6);
+
+            let c = poly(&[1.0, 2.0, 3.0, 4.0], s);
+            let roots = solve_quartic(c[0], c[1], c[2], c[3], c[4]);
+            assert_roots(roots, &[1.0, 2.0, 3.0, 4.0], 1.0e-12);
+            let c = poly(&[1.0, 1.0, 2.0, 2.0], s);
+            let roots = solve_quartic(c[0], c[1], c[2], c[3], c[4]);
+            assert_roots(roots, &[1.0, 2.0], 1.0e-6);
+            let c = poly(&[1.0, 1.0, 1.0, 1.0], s);
+            let roots = solve_quartic(c[0], c[1], c[2], c[3], c[4]);
+            assert_roots(roots, &[1.0], 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn solvers_find_roots_far_from_one() {
+        let c = poly(&[1.0e-3, 1.0e-3, 2.0e-3, 2.0e-3], 1.0);
+        assert_roots(solve_quartic(c[0], c[1], c[2], c[3], c[4]), &[1.0e-3, 2.0e-3], 1.0e-6);
+
+        let c = poly(&[100.0, 100.0, 200.0, 200.0], 1.0);
+        assert_roots(solve_quartic(c[0], c[1], c[2], c[3], c[4]), &[100.0, 200.0], 1.0e-6);
+
+        let c = poly(&[1.0e3, 1.0e3, 3.0e3], 1.0);
+        assert_roots(solve_cubic(c[0], c[1], c[2], c[3]), &[1.0e3, 3.0e3], 1.0e-6);
+    }
+
+    #[test]
+    fn solvers_report_no_complex_roots() {
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_empty());
+        assert!(solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn torus_is_hit_from_outside_and_inside_the_hole() {
+        let torus = Torus::new(2.0, 0.5);
+        let toi = |origin: Point, dir: Vect| cast(&torus, origin, dir).map(|inter| inter.toi);
+        let close = |toi: Option<Scalar>, expected: Scalar| {
+            (toi.unwrap() - expected).abs() < 1.0e-9
+        };
+
+        // From outside, through the near tube.
+        assert!(close(toi(Point::new(-5.0, 0.3, 0.0), Vect::x()), 2.6));
+        // From outside, where the torus touches its bounding box.
+        assert!(close(toi(Point::new(-5.0, 0.0, 0.0), Vect::x()), 2.5));
+        // From the hole, toward the inner side of the tube.
+        assert!(close(toi(Point::new(0.0, 0.3, 0.0), Vect::x()), 1.6));
+        // From above, with a direction that is not unit.
+        assert!(close(toi(Point::new(2.3, 5.0, 0.0), -Vect::y() * 2.0), 2.3));
+        // From inside the tube.
+        assert!(close(toi(Point::new(2.0, 0.0, 0.0), Vect::x()), 0.5));
+
+        // Through the hole, and above the torus.
+        assert!(toi(Point::new(0.0, 5.0, 0.0), -Vect::y()).is_none());
+        assert!(toi(Point::new(1.0, 5.0, 0.0), -Vect::y()).is_none());
+        assert!(toi(Point::new(-5.0, 0.6, 0.0), Vect::x()).is_none());
+        // Away from the torus, from the hole.
+        assert!(toi(Point::new(0.0, 0.0, 0.0), Vect::y()).is_none());
+    }
+
+    #[test]
+    fn primitives_map_known_points_to_their_texture_coordinates() {
+        let close = |uvs: Point2<Scalar>, u: Scalar, v: Scalar| {
+            (uvs.x - u).abs() < 1.0e-9 && (uvs.y - v).abs() < 1.0e-9
+        };
+        let down = -Vect::y();
+
+        // The top of the tube, where the angle around the `y` axis is zero.
+        let torus = Torus::new(2.0, 0.5);
+        assert!(close(uvs(&torus, Point::new(2.0, 5.0, 0.0), down), 0.5, 0.75));
+
+        let disk = Disk::new_annulus(2.0, 1.0);
+        assert!(close(uvs(&disk, Point::new(1.5, 1.0, 0.0), down), 0.5, 0.5));
+        assert!(close(uvs(&disk, Point::new(0.0, 1.0, 2.0), down), 0.75, 1.0));
+
+        let quad = Quad::new_rectangle(Vector2::new(1.0, 2.0));
+        assert!(close(uvs(&quad, Point::new(0.5, 1.0, -1.0), down), 0.75, 0.75));
+
+        let triangle = Triangle::new(
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 0.0, 1.0),
+        );
+        assert!(close(uvs(&triangle, Point::new(0.25, 1.0, 0.5), down), 0.25, 0.5));
+
+        let plane = BoundedPlane::new(Unit::new_normalize(Vect::y()), Vector2::new(1.0, 2.0));
+        assert!(close(uvs(&plane, Point::new(0.5, 1.0, -1.0), down), 0.75, 0.75));
+
+        let paraboloid = Paraboloid::new(1.0, 2.0);
+        assert!(close(uvs(&paraboloid, Point::new(0.5, 5.0, 0.0), down), 0.5, 0.25));
+
+        let hyperboloid = Hyperboloid::new(1.0, 2.0, 1.0);
+        let origin = Point::new(0.0, 0.0, -5.0);
+        assert!(close(uvs(&hyperboloid, origin, Vect::z()), 0.25, 0.5));
+    }
+}
@@ -0,0 +1,246 @@
+//! Packets of coherent rays traced together.
+//!
+//! Ray data are stored in structure-of-arrays form so that the box and triangle tests of the
+//! whole packet are a handful of 4-wide operations the compiler can vectorize.
+
+use math::{Isometry, Point, Scalar};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use num::Bounded;
+
+/// The maximum number of rays of a packet.
+pub const PACKET_SIZE: usize = 4;
+
+/// A lane mask: the i-th lane is active if the i-th element is `true`.
+pub type Mask = [bool; PACKET_SIZE];
+
+/// A packet of up to `PACKET_SIZE` rays.
+pub struct RayPacket {
+    rays: Vec<Ray<Scalar>>,
+    tmins: [Scalar; PACKET_SIZE],
+    orig: [[Scalar; PACKET_SIZE]; 3],
+    dir: [[Scalar; PACKET_SIZE]; 3],
+    inv_dir: [[Scalar; PACKET_SIZE]; 3],
+}
+
+impl RayPacket {
+    /// Creates a packet from one to `PACKET_SIZE` rays.
+    pub fn new(rays: Vec<Ray<Scalar>>) -> RayPacket {
+        RayPacket::new_with_tmins(rays, [0.0; PACKET_SIZE])
+    }
+
+    /// Creates a packet from one to `PACKET_SIZE` rays ignoring the intersections closer than
+    /// their respective entry of `tmins`.
+    pub fn new_with_tmins(rays: Vec<Ray<Scalar>>, tmins: [Scalar; PACKET_SIZE]) -> RayPacket {
+        assert!(
+            !rays.is_empty() && rays.len() <= PACKET_SIZE,
+            "A ray packet must contain between 1 and {} rays.",
+            PACKET_SIZE
+        );
+
+        let mut orig = [[0.0; PACKET_SIZE]; 3];
+        let mut dir = [[0.0; PACKET_SIZE]; 3];
+        let mut inv_dir = [[0.0; PACKET_SIZE]; 3];
+
+        for lane in 0..PACKET_SIZE {
+            // Unused lanes duplicate the first ray and are masked out.
+            let ray = if lane < rays.len() {
+                &rays[lane]
+            } else {
+                &rays[0]
+            };
+
+            for i in 0..3 {
+                orig[i][lane] = ray.origin[i];
+                dir[i][lane] = ray.dir[i];
+                inv_dir[i][lane] = 1.0 / ray.dir[i];
+            }
+        }
+
+        RayPacket {
+            rays: rays,
+            tmins: tmins,
+            orig: orig,
+            dir: dir,
+            inv_dir: inv_dir,
+        }
+    }
+
+    /// The number of rays of this packet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    /// The i-th ray of this packet.
+    #[inline]
+    pub fn ray(&self, i: usize) -> &Ray<Scalar> {
+        &self.rays[i]
+    }
+
+    /// The time of impact under which intersections with the i-th ray are ignored.
+    #[inline]
+    pub fn tmin(&self, i: usize) -> Scalar {
+        self.tmins[i]
+    }
+
+    /// The mask of the lanes occupied by a ray.
+    #[inline]
+    pub fn mask(&self) -> Mask {
+        let mut res = [false; PACKET_SIZE];
+
+        for lane in 0..self.rays.len() {
+            res[lane] = true;
+        }
+
+        res
+    }
+
+    /// This packet with every ray transformed by the inverse of `m`.
+    pub fn inverse_transformed(&self, m: &Isometry) -> RayPacket {
+        let inv = m.inverse();
+        let rays = self
+            .rays
+            .iter()
+            .map(|r| Ray::new(inv * r.origin, inv * r.dir))
+            .collect();
+
+        RayPacket::new_with_tmins(rays, self.tmins)
+    }
+
+    /// Slab test of every active ray against an AABB.
+    ///
+    /// Returns the mask of the rays entering the AABB before their maximum time of impact, and
+    /// the smallest time of entry among them.
+    #[inline]
+    pub fn intersects_aabb(
+        &self,
+        aabb: &AABB<Scalar>,
+        max_tois: &[Scalar; PACKET_SIZE],
+        mask: &Mask,
+    ) -> (Mask, Scalar) {
+        let mins = aabb.mins();
+        let maxs = aabb.maxs();
+
+        self.intersects_box(
+            &[mins.x, mins.y, mins.z],
+            &[maxs.x, maxs.y, maxs.z],
+            max_tois,
+            mask,
+        )
+    }
+
+    /// Slab test of every active ray against the box with the bounds `mins` and `maxs`.
+    ///
+    /// This is `intersects_aabb` for boxes stored by coordinates, e.g., the children of the wide
+    /// nodes of a BVH.
+    #[inline]
+    pub fn intersects_box(
+        &self,
+        mins: &[Scalar; 3],
+        maxs: &[Scalar; 3],
+        max_tois: &[Scalar; PACKET_SIZE],
+        mask: &Mask,
+    ) -> (Mask, Scalar) {
+        let mut tmin: [Scalar; PACKET_SIZE] = [0.0; PACKET_SIZE];
+        let mut tmax = *max_tois;
+
+        for i in 0..3 {
+            let min = mins[i];
+            let max = maxs[i];
+
+            for lane in 0..PACKET_SIZE {
+                let t1 = (min - self.orig[i][lane]) * self.inv_dir[i][lane];
+                let t2 = (max - self.orig[i][lane]) * self.inv_dir[i][lane];
+
+                // `max` and `min` ignore the NaNs of rays parallel to and on a slab boundary.
+                tmin[lane] = tmin[lane].max(t1.min(t2));
+                tmax[lane] = tmax[lane].min(t1.max(t2));
+            }
+        }
+
+        let mut res = [false; PACKET_SIZE];
+        let mut entry: Scalar = Bounded::max_value();
+
+        for lane in 0..PACKET_SIZE {
+            res[lane] = mask[lane] && tmin[lane] <= tmax[lane];
+
+            if res[lane] && tmin[lane] < entry {
+                entry = tmin[lane]
+            }
+        }
+
+        (res, entry)
+    }
+
+    /// Möller–Trumbore test of every active ray against a triangle.
+    ///
+    /// Returns, for each lane, the time of impact and the barycentric coordinates of the hit
+    /// relative to `b` and `c`. Hits closer than the lane `tmin` are ignored.
+    #[inline]
+    pub fn intersects_triangle(
+        &self,
+        a: &Point,
+        b: &Point,
+        c: &Point,
+        max_tois: &[Scalar; PACKET_SIZE],
+        mask: &Mask,
+    ) -> [Option<(Scalar, Scalar, Scalar)>; PACKET_SIZE] {
+        let ab = *b - *a;
+        let ac = *c - *a;
+        let mut res = [None; PACKET_SIZE];
+
+        let mut toi = [0.0; PACKET_SIZE];
+        let mut u = [0.0; PACKET_SIZE];
+        let mut v = [0.0; PACKET_SIZE];
+        let mut det = [0.0; PACKET_SIZE];
+
+        for lane in 0..PACKET_SIZE {
+            let dx = self.dir[0][lane];
+            let dy = self.dir[1][lane];
+            let dz = self.dir[2][lane];
+
+            // p = dir × ac
+            let px = dy * ac.z - dz * ac.y;
+            let py = dz * ac.x - dx * ac.z;
+            let pz = dx * ac.y - dy * ac.x;
+
+            det[lane] = ab.x * px + ab.y * py + ab.z * pz;
+            let inv_det = 1.0 / det[lane];
+
+            let sx = self.orig[0][lane] - a.x;
+            let sy = self.orig[1][lane] - a.y;
+            let sz = self.orig[2][lane] - a.z;
+
+            // q = s × ab
+            let qx = sy * ab.z - sz * ab.y;
+            let qy = sz * ab.x - sx * ab.z;
+            let qz = sx * ab.y - sy * ab.x;
+
+            u[lane] = (sx * px + sy * py + sz * pz) * inv_det;
+            v[lane] = (dx * qx + dy * qy + dz * qz) * inv_det;
+            toi[lane] = (ac.x * qx + ac.y * qy + ac.z * qz) * inv_det;
+        }
+
+        for lane in 0..PACKET_SIZE {
+            if mask[lane]
+                && det[lane] != 0.0
+                && u[lane] >= 0.0
+                && v[lane] >= 0.0
+                && u[lane] + v[lane] <= 1.0
+                && toi[lane] >= self.tmins[lane]
+                && toi[lane] <= max_tois[lane]
+            {
+                res[lane] = Some((toi[lane], u[lane], v[lane]))
+            }
+        }
+
+        res
+    }
+}
+
+/// Whether at least one lane of `mask` is active.
+#[inline]
+pub fn any(mask: &Mask) -> bool {
+    mask.iter().any(|m| *m)
+}
@@ -1,10 +1,19 @@
-use math::{Point, Scalar, Vect};
+use math::{self, Isometry, Point, Scalar, Vect};
+use medium::Medium;
 use ncollide3d::query::Ray;
+use std::sync::Arc;
 
 pub struct RayWithEnergy {
     pub ray: Ray<Scalar>,
+    /// Intersections closer than this time of impact are ignored.
+    ///
+    /// This is zero for rays spawned from a surface, whose origin is offset instead.
+    pub tmin: Scalar,
     pub refr: Scalar,
     pub energy: f32,
+    /// The medium the ray travels in, with the transform of the node it fills, or `None` for
+    /// the medium of the scene.
+    pub medium: Option<(Arc<Medium>, Isometry)>,
 }
 
 impl RayWithEnergy {
@@ -15,8 +24,33 @@ impl RayWithEnergy {
     pub fn new_with_energy(orig: Point, dir: Vect, refr: Scalar, energy: f32) -> RayWithEnergy {
         RayWithEnergy {
             ray: Ray::new(orig, dir),
+            tmin: 0.0,
             refr: refr,
             energy: energy,
+            medium: None,
+        }
+    }
+
+    /// Creates a ray leaving the point `pt` of a surface with the geometric normal `normal`.
+    ///
+    /// The ray origin is offset from `pt`, whose coordinates have errors bounded by `error`, so
+    /// that it does not intersect that surface again.
+    pub fn spawn(
+        pt: &Point,
+        error: &Vect,
+        normal: &Vect,
+        dir: Vect,
+        refr: Scalar,
+        energy: f32,
+    ) -> RayWithEnergy {
+        let orig = math::offset_ray_origin(pt, error, normal, &dir);
+
+        RayWithEnergy {
+            ray: Ray::new(orig, dir),
+            tmin: 0.0,
+            refr: refr,
+            energy: energy,
+            medium: None,
         }
     }
 }
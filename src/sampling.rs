@@ -0,0 +1,35 @@
+//! Random sampling of directions, shared by the materials and the light transport algorithms.
+
+use math::{self, Scalar, Vect};
+use na;
+use rand::random;
+
+/// Samples a unit vector uniformly.
+pub fn uniform_sphere() -> Vect {
+    let z = 1.0 - 2.0 * random::<Scalar>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * math::PI * random::<Scalar>();
+
+    Vect::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Samples a unit vector with a cosine-weighted distribution around the unit vector `normal`.
+pub fn cosine_direction(normal: &Vect) -> Vect {
+    let r = random::<Scalar>().sqrt();
+    let phi = 2.0 * math::PI * random::<Scalar>();
+    let (t1, t2) = orthonormal_basis(normal);
+
+    *normal * (1.0 - r * r).max(0.0).sqrt() + t1 * (r * phi.cos()) + t2 * (r * phi.sin())
+}
+
+/// Two unit vectors orthogonal to the unit vector `n` and to each other.
+pub fn orthonormal_basis(n: &Vect) -> (Vect, Vect) {
+    let t = if n.x.abs() > n.z.abs() {
+        Vect::new(-n.y, n.x, 0.0)
+    } else {
+        Vect::new(0.0, -n.z, n.y)
+    };
+    let t = na::normalize(&t);
+
+    (t, n.cross(&t))
+}
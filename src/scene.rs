@@ -3,6 +3,7 @@ use num::Zero;
 use num_cpus;
 use rand::random;
 use std::cmp;
+use std::f32;
 use std::iter;
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -10,18 +11,40 @@ use std::thread;
 
 use na::{self, Matrix4, Point2, Point3, Point4, Vector2, Vector3};
 use ncollide3d::bounding_volume::AABB;
-use ncollide3d::partitioning::{BVTCostFn, BVT};
-use ncollide3d::query::{Ray, RayCast, RayIntersection};
+use ncollide3d::query::Ray;
+use num::Bounded;
 
+use bvh::BVH;
+use intersection::Intersection;
 use light::Light;
-use math::{Isometry, Point, Scalar, Vect};
+use math::{self, Isometry, Point, Scalar, Vect};
+use medium::Medium;
+use ray_packet::{RayPacket, PACKET_SIZE};
 use ray_with_energy::RayWithEnergy;
 use scene_node::SceneNode;
 
+/// The default ratio between the SAH cost per leaf of the refitted top-level BVH and its cost
+/// per leaf when last built above which it is rebuilt from scratch.
+pub const DEFAULT_REBUILD_THRESHOLD: Scalar = 1.5;
+
+// The greatest probability for a path to go on after a scattering event in a medium, which
+// bounds the expected number of scattering events of a path in a medium that does not absorb.
+const MAX_SCATTERING_SURVIVAL: f32 = 0.95;
+
+// The maximum number of boundary crossings of a ray with the geometry of a medium.
+const MAX_CROSSINGS: usize = 64;
+
 pub struct Scene {
     background: Vector3<f32>,
     lights: Vec<Light>,
-    world: BVT<Arc<SceneNode>, AABB<Scalar>>,
+    nodes: Vec<Arc<SceneNode>>,
+    medium: Option<Arc<Medium>>,
+    // Whether some node has an interior medium.
+    has_media: bool,
+    world: BVH,
+    // SAH cost per leaf of `world` when last built.
+    built_cost: Scalar,
+    rebuild_threshold: Option<Scalar>,
 }
 
 pub type Vless = Vector2<Scalar>;
@@ -42,67 +65,76 @@ pub fn render(
         .collect();
     let pixels: Arc<RwLock<Vec<Vector3<f32>>>> = Arc::new(RwLock::new(pixels));
 
-    let nrays = resolution.y * resolution.x * (ray_per_pixel as f64);
+    let nrays = resolution.y * resolution.x * (ray_per_pixel as Scalar);
 
     println!("Tracing {} rays.", nrays as i32);
 
     let num_thread = num_cpus::get();
     let resx = resolution.x as usize;
     let resy = resolution.y as usize;
+    let resolution = resolution.clone();
 
     let mpixels = pixels.clone();
     let mscene = scene.clone();
 
     let mut children = Vec::new();
 
+    // Primary rays of neighboring pixels are coherent: they are traced by packets covering
+    // 2×2 tiles of pixels.
+    let tiles_x = (resx + 1) / 2;
+    let ntiles = tiles_x * ((resy + 1) / 2);
+
     for i in 0..num_thread {
         let pixels = mpixels.clone();
         let scene = mscene.clone();
-        let parts = npixels / num_thread + 1;
+        let parts = ntiles / num_thread + 1;
         let low_limit = parts * i;
-        let up_limit = cmp::min(parts * (i + 1), npixels);
+        let up_limit = cmp::min(parts * (i + 1), ntiles);
 
         children.push(thread::spawn(move || {
-            let mut pxs = Vec::with_capacity(up_limit - low_limit);
-            for ipt in low_limit..up_limit {
-                let j = ipt / resx;
-                let i = ipt - j * resx;
-
-                let mut tot_c: Vector3<f32> = na::zero();
+            let mut pxs = Vec::new();
+
+            for tile in low_limit..up_limit {
+                let ty = tile / tiles_x;
+                let tx = tile - ty * tiles_x;
+                // The pixels of the tile, which may be cut by the right and bottom borders.
+                let ipts: Vec<usize> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .map(|&(dx, dy)| (2 * tx + dx, 2 * ty + dy))
+                    .filter(|&(x, y)| x < resx && y < resy)
+                    .map(|(x, y)| x + y * resx)
+                    .collect();
+                let mut tot_c: Vec<Vector3<f32>> =
+                    iter::repeat(na::zero()).take(ipts.len()).collect();
 
                 for _ in 0usize..ray_per_pixel {
-                    let shift = Vless::from_element(0.5);
-                    let perturbation = (random::<Vless>() - shift) * window_width;
-                    let orig = Vector2::new(i as f64, j as f64) + perturbation;
-
-                    /*
-                     * unproject
-                     */
-                    let device_x = (orig.x / (resx as f64) - 0.5) * 2.0;
-                    let device_y = -(orig.y / (resy as f64) - 0.5) * 2.0;
-                    let start = Point4::new(device_x, device_y, -1.0, 1.0);
-                    let h_eye = projection * start;
-                    let eye = Point3::from_homogeneous(h_eye.coords).unwrap();
-                    let ray = Ray::new(camera_eye, na::normalize(&(eye - camera_eye)));
-
-                    let c: Vector3<f32> =
-                        scene.trace(&RayWithEnergy::new(ray.origin.clone(), ray.dir));
-
-                    tot_c = tot_c + c;
+                    let rays = ipts
+                        .iter()
+                        .map(|i| {
+                            primary_ray(&resolution, window_width, &camera_eye, &projection, *i)
+                        })
+                        .collect();
+                    let packet = RayPacket::new(rays);
+                    let casts = scene.cast_packet(&packet);
+
+                    for (lane, cast) in casts.into_iter().enumerate() {
+                        let ray = packet.ray(lane);
+                        let ray = RayWithEnergy::new(ray.origin.clone(), ray.dir);
+                        let c = scene.radiance(&ray, cast);
+
+                        tot_c[lane] = tot_c[lane] + c;
+                    }
                 }
 
-                pxs.push(tot_c / (ray_per_pixel as f32));
+                for (ipt, c) in ipts.into_iter().zip(tot_c.into_iter()) {
+                    pxs.push((ipt, c / (ray_per_pixel as f32)));
+                }
             }
 
-            {
-                let pxs = &pxs[..];
-                let mut bpixels = pixels.write().unwrap();
-                for ipt in low_limit..up_limit {
-                    let j = ipt / resx;
-                    let i = ipt - j * resx;
+            let mut bpixels = pixels.write().unwrap();
 
-                    bpixels[i + j * resx as usize] = pxs[ipt - low_limit];
-                }
+            for (ipt, c) in pxs.into_iter() {
+                bpixels[ipt] = c;
             }
         }));
     }
@@ -115,21 +147,49 @@ pub fn render(
     Image::new(resolution.clone(), out_pixels)
 }
 
+/// The ray from `camera_eye` through the pixel `ipt`, in row-major order, jittered by at most
+/// half of `window_width` pixels.
+pub fn primary_ray(
+    resolution: &Vless,
+    window_width: Scalar,
+    camera_eye: &Point,
+    projection: &Matrix4<Scalar>,
+    ipt: usize,
+) -> Ray<Scalar> {
+    let resx = resolution.x as usize;
+    let j = ipt / resx;
+    let i = ipt - j * resx;
+    let shift = Vless::from_element(0.5);
+    let perturbation = (random::<Vless>() - shift) * window_width;
+    let orig = Vector2::new(i as Scalar, j as Scalar) + perturbation;
+
+    /*
+     * unproject
+     */
+    let device_x = (orig.x / resolution.x - 0.5) * 2.0;
+    let device_y = -(orig.y / resolution.y - 0.5) * 2.0;
+    let start = Point4::new(device_x, device_y, -1.0, 1.0);
+    let h_eye = projection * start;
+    let eye = Point3::from_homogeneous(h_eye.coords).unwrap();
+
+    Ray::new(*camera_eye, na::normalize(&(eye - camera_eye)))
+}
+
 impl Scene {
     pub fn new(nodes: Vec<Arc<SceneNode>>, lights: Vec<Light>, background: Vector3<f32>) -> Scene {
-        let mut nodes_w_bvs = Vec::new();
-
-        for n in nodes.into_iter() {
-            nodes_w_bvs.push((n.clone(), n.aabb.clone()));
-        }
-
-        let bvt = BVT::new_balanced(nodes_w_bvs);
-
-        Scene {
+        let mut res = Scene {
             lights: lights,
-            world: bvt,
+            nodes: nodes,
+            medium: None,
+            has_media: false,
+            world: BVH::new(&[]),
+            built_cost: 0.0,
+            rebuild_threshold: Some(DEFAULT_REBUILD_THRESHOLD),
             background: background,
-        }
+        };
+
+        res.rebuild();
+        res
     }
 
     #[inline]
@@ -137,58 +197,445 @@ impl Scene {
         self.background = background
     }
 
+    /// The color of the rays leaving the scene.
+    #[inline]
+    pub fn background(&self) -> &Vector3<f32> {
+        &self.background
+    }
+
+    /// Sets the medium filling the space outside of the nodes with an interior medium.
+    #[inline]
+    pub fn set_medium(&mut self, medium: Option<Arc<Medium>>) {
+        self.medium = medium
+    }
+
+    #[inline]
+    pub fn medium(&self) -> Option<&Arc<Medium>> {
+        self.medium.as_ref()
+    }
+
     #[inline]
     pub fn lights(&self) -> &[Light] {
         &self.lights[..]
     }
+
+    #[inline]
+    pub fn nodes(&self) -> &[Arc<SceneNode>] {
+        &self.nodes[..]
+    }
+
+    /// Adds a node to this scene and returns its index.
+    ///
+    /// The node is inserted into the top-level BVH, which is rebuilt if its quality degraded
+    /// beyond the rebuild threshold.
+    pub fn insert_node(&mut self, node: Arc<SceneNode>) -> usize {
+        let i = self.nodes.len();
+
+        self.has_media = self.has_media || node.medium.is_some();
+        self.nodes.push(node);
+
+        let aabbs = self.node_aabbs();
+        self.world.insert(&aabbs[..], i);
+        self.rebuild_if_degraded();
+
+        i
+    }
+
+    /// Removes the i-th node of this scene.
+    ///
+    /// The index of every following node is decremented. The node is removed from the
+    /// top-level BVH, which is rebuilt if its quality degraded beyond the rebuild threshold.
+    pub fn remove_node(&mut self, i: usize) -> Arc<SceneNode> {
+        let res = self.nodes.remove(i);
+
+        let aabbs = self.node_aabbs();
+        self.world.remove(&aabbs[..], i);
+        self.has_media = self.nodes.iter().any(|n| n.medium.is_some());
+        self.rebuild_if_degraded();
+
+        res
+    }
+
+    /// Moves the i-th node of this scene to `transform`.
+    ///
+    /// The node is replaced by an instance sharing its geometry and material.
+    pub fn set_node_transform(&mut self, i: usize, transform: Isometry) {
+        self.set_node_transforms(Some((i, transform)))
+    }
+
+    /// Moves several nodes of this scene at once, each one given by its index and its new
+    /// transform.
+    ///
+    /// The top-level BVH is refitted once after every node moved, and rebuilt if its quality
+    /// degraded beyond the rebuild threshold.
+    pub fn set_node_transforms<I>(&mut self, transforms: I)
+    where
+        I: IntoIterator<Item = (usize, Isometry)>,
+    {
+        for (i, transform) in transforms {
+            let node = Arc::new(self.nodes[i].instance(transform));
+            self.nodes[i] = node;
+        }
+
+        self.refit();
+    }
+
+    /// Sets the ratio between the SAH cost per leaf of the refitted top-level BVH and its cost
+    /// per leaf when last built above which it is rebuilt from scratch.
+    ///
+    /// The cost per leaf does not grow merely because nodes are inserted, only because the tree
+    /// gets worse at culling them.
+    ///
+    /// With `None`, moving nodes only ever refits the BVH.
+    pub fn set_rebuild_threshold(&mut self, threshold: Option<Scalar>) {
+        self.rebuild_threshold = threshold;
+        self.refit();
+    }
+
+    /// Rebuilds the top-level BVH from scratch.
+    pub fn rebuild(&mut self) {
+        self.world = BVH::new(&self.node_aabbs()[..]);
+        self.built_cost = self.world.sah_cost_per_leaf();
+        self.has_media = self.nodes.iter().any(|n| n.medium.is_some());
+    }
+
+    fn refit(&mut self) {
+        let aabbs = self.node_aabbs();
+        self.world.refit(&aabbs[..]);
+        self.rebuild_if_degraded();
+    }
+
+    fn rebuild_if_degraded(&mut self) {
+        if let Some(threshold) = self.rebuild_threshold {
+            if self.world.sah_cost_per_leaf() > self.built_cost * threshold {
+                self.rebuild()
+            }
+        }
+    }
+
+    fn node_aabbs(&self) -> Vec<AABB<Scalar>> {
+        self.nodes.iter().map(|n| n.aabb.clone()).collect()
+    }
 }
 
 impl Scene {
-    pub fn intersects_ray(&self, ray: &Ray<Scalar>, maxtoi: Scalar) -> Option<Vector3<f32>> {
-        let mut filter = Vector3::new(1.0, 1.0, 1.0);
+    /// Computes the color filter applied to the light traveling along `ray` from `tmin` to
+    /// `maxtoi`.
+    ///
+    /// Returns `None` if an opaque object blocks the ray.
+    pub fn intersects_ray(
+        &self,
+        ray: &Ray<Scalar>,
+        tmin: Scalar,
+        maxtoi: Scalar,
+    ) -> Option<Vector3<f32>> {
+        let mut filter = Some(Vector3::new(1.0, 1.0, 1.0));
+
+        self.world.visit_ray(ray, maxtoi, |i| {
+            let b = &self.nodes[i];
+
+            match b.cast_with_tmin(ray, tmin) {
+                Some(t) => {
+                    if t.toi <= maxtoi {
+                        filter = filter_light(b, ray, &t, filter.take().unwrap());
+                        filter.is_some()
+                    } else {
+                        true
+                    }
+                }
+                None => true,
+            }
+        });
+
+        filter.map(|f| f.component_mul(&self.media_transmittance(ray, tmin, maxtoi)))
+    }
+
+    /// Computes the color filters applied to the light traveling along each ray of `packet`
+    /// from its `tmin` to its maximum time of impact.
+    ///
+    /// The i-th filter is `None` if an opaque object blocks the i-th ray.
+    pub fn intersects_packet(
+        &self,
+        packet: &RayPacket,
+        maxtois: &[Scalar; PACKET_SIZE],
+    ) -> Vec<Option<Vector3<f32>>> {
+        let mut filters: Vec<Option<Vector3<f32>>> = iter::repeat(Some(Vector3::new(1.0, 1.0, 1.0)))
+            .take(packet.len())
+            .collect();
+        let mut active = packet.mask();
+
+        self.world.visit_packet(packet, maxtois, &mut active, |i, lanes| {
+            let b = &self.nodes[i];
+            let inters = b.cast_packet(packet, lanes, maxtois);
+
+            for (lane, inter) in inters.into_iter().enumerate() {
+                if let Some(t) = inter {
+                    if t.toi <= maxtois[lane] {
+                        let filter = filters[lane].take().unwrap();
+                        filters[lane] = filter_light(b, packet.ray(lane), &t, filter);
+
+                        if filters[lane].is_none() {
+                            lanes[lane] = false;
+                        }
+                    }
+                }
+            }
+        });
+
+        if self.medium.is_some() || self.has_media {
+            for (lane, filter) in filters.iter_mut().enumerate() {
+                if let Some(ref mut f) = *filter {
+                    let ray = packet.ray(lane);
+                    let tmin = packet.tmin(lane);
+                    *f = f.component_mul(&self.media_transmittance(ray, tmin, maxtois[lane]));
+                }
+            }
+        }
+
+        filters
+    }
 
-        let inter;
-        {
-            let mut shadow_caster = TransparentShadowsRayTOICostFn::new(ray, maxtoi, &mut filter);
-            inter = self.world.best_first_search(&mut shadow_caster);
+    /// Calls `f` with the unit direction toward each sample of `light` reaching the point `pt`,
+    /// and the color filter applied to the light along the way.
+    ///
+    /// The shadow rays leave the surface of geometric normal `normal` at `pt`, whose coordinates
+    /// have errors bounded by `error`. Since the shadow rays toward the samples of a light are
+    /// coherent, they are traced by packets.
+    pub fn visible_light_samples<F: FnMut(&Vect, &Vector3<f32>)>(
+        &self,
+        light: &Light,
+        pt: &Point,
+        error: &Vect,
+        normal: &Vect,
+        mut f: F,
+    ) {
+        let mut samples = Vec::with_capacity(light.racsample * light.racsample);
+        light.sample(&mut |pos| samples.push(pos));
+
+        for chunk in samples.chunks(PACKET_SIZE) {
+            let mut rays = Vec::with_capacity(chunk.len());
+            let mut dists = [0.0; PACKET_SIZE];
+
+            for (lane, pos) in chunk.iter().enumerate() {
+                let orig = math::offset_ray_origin(pt, error, normal, &(*pos - *pt));
+                let mut ldir = *pos - orig;
+                dists[lane] = ldir.normalize_mut();
+                rays.push(Ray::new(orig, ldir));
+            }
+
+            let packet = RayPacket::new(rays);
+            let filters = self.intersects_packet(&packet, &dists);
+
+            for (lane, filter) in filters.into_iter().enumerate() {
+                if let Some(filter) = filter {
+                    f(&packet.ray(lane).dir, &filter)
+                }
+            }
         }
+    }
 
-        if inter.is_none() {
-            Some(filter)
-        } else {
-            None
+    /// Computes the transmittance of the media crossed by `ray` from `tmin` to `maxtoi`.
+    pub fn media_transmittance(
+        &self,
+        ray: &Ray<Scalar>,
+        tmin: Scalar,
+        maxtoi: Scalar,
+    ) -> Vector3<f32> {
+        let mut res = Vector3::new(1.0, 1.0, 1.0);
+        let mut interiors = Vec::new();
+
+        if self.has_media {
+            self.world.visit_ray(ray, maxtoi, |i| {
+                let b = &self.nodes[i];
+
+                if let Some(ref medium) = b.medium {
+                    let local = local_ray(ray, &b.transform);
+
+                    for (t0, t1) in interior_segments(b, ray, tmin, maxtoi).into_iter() {
+                        res = res.component_mul(&medium.transmittance(&local, t0, t1));
+                        interiors.push((t0, t1));
+                    }
+                }
+
+                true
+            });
         }
+
+        if let Some(ref medium) = self.medium {
+            // The scene medium fills the gaps between the interiors.
+            interiors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+            let mut t = tmin;
+
+            for (t0, t1) in interiors.into_iter() {
+                if t0 > t {
+                    res = res.component_mul(&medium.transmittance(ray, t, t0));
+                }
+
+                t = t.max(t1);
+            }
+
+            if t < maxtoi {
+                res = res.component_mul(&medium.transmittance(ray, t, maxtoi));
+            }
+        }
+
+        res
+    }
+
+    /// Finds the closest node hit by `ray` beyond `tmin`.
+    pub fn cast_ray(
+        &self,
+        ray: &Ray<Scalar>,
+        tmin: Scalar,
+    ) -> Option<(&Arc<SceneNode>, Intersection)> {
+        self.world
+            .cast_ray(ray, Bounded::max_value(), |i, maxtoi| {
+                self.nodes[i]
+                    .cast_with_tmin(ray, tmin)
+                    .and_then(|inter| if inter.toi <= maxtoi { Some((inter.toi, inter)) } else { None })
+            })
+            .map(|(i, inter)| (&self.nodes[i], inter))
+    }
+
+    /// Finds the closest node hit by each ray of `packet`.
+    pub fn cast_packet(
+        &self,
+        packet: &RayPacket,
+    ) -> Vec<Option<(&Arc<SceneNode>, Intersection)>> {
+        let mut best: Vec<Option<(usize, Intersection)>> =
+            iter::repeat(None).take(packet.len()).collect();
+        let mut maxtois: [Scalar; PACKET_SIZE] = [Bounded::max_value(); PACKET_SIZE];
+
+        self.world.cast_packet(packet, &mut maxtois, |i, lanes, maxtois| {
+            let inters = self.nodes[i].cast_packet(packet, lanes, maxtois);
+
+            for (lane, inter) in inters.into_iter().enumerate() {
+                if let Some(inter) = inter {
+                    if inter.toi <= maxtois[lane] {
+                        maxtois[lane] = inter.toi;
+                        best[lane] = Some((i, inter));
+                    }
+                }
+            }
+        });
+
+        best.into_iter()
+            .map(|hit| hit.map(|(i, inter)| (&self.nodes[i], inter)))
+            .collect()
     }
 
     pub fn trace(&self, ray: &RayWithEnergy) -> Vector3<f32> {
-        let cast = self
-            .world
-            .best_first_search(&mut ClosestRayTOICostFn::new(&ray.ray));
+        self.radiance(ray, self.cast_ray(&ray.ray, ray.tmin))
+    }
+
+    /// Computes the color carried back along `ray`, given its closest intersection `hit`, through
+    /// the medium it travels in.
+    ///
+    /// The scattering events are sampled by delta tracking.
+    pub fn radiance(
+        &self,
+        ray: &RayWithEnergy,
+        hit: Option<(&Arc<SceneNode>, Intersection)>,
+    ) -> Vector3<f32> {
+        let mut weight = Vector3::new(1.0, 1.0, 1.0);
+        let medium = match ray.medium {
+            Some((ref m, ref transform)) => Some((m, local_ray(&ray.ray, transform))),
+            None => self.medium.as_ref().map(|m| (m, ray.ray.clone())),
+        };
+
+        if let Some((medium, local)) = medium {
+            let maxtoi = hit.as_ref().map(|h| h.1.toi).unwrap_or(Bounded::max_value());
+            let (scattering, w) = medium.sample(&local, ray.tmin, maxtoi);
+
+            if let Some(t) = scattering {
+                return w.component_mul(&self.scatter(ray, medium, t));
+            }
+
+            weight = w;
+        }
 
-        match cast {
+        let color = match hit {
             None => self.background.clone(),
-            Some((sn, inter)) => {
-                let pt = ray.ray.origin + ray.ray.dir * inter.toi;
-                let obj = sn
-                    .material
-                    .compute(ray, &pt, &inter.normal, &uvs(&inter), self);
-                let refl =
-                    self.trace_reflection(sn.refl_mix, sn.refl_atenuation, ray, &pt, &inter.normal);
-
-                let alpha = obj.w * sn.alpha;
-                let obj_color =
-                    Vector3::new(obj.x, obj.y, obj.z) * (1.0 - sn.refl_mix) + refl * sn.refl_mix;
-                let refr = self.trace_refraction(alpha, sn.refr_coeff, ray, &pt, &inter.normal);
-
-                if alpha == 1.0 {
-                    Vector3::new(obj_color.x, obj_color.y, obj_color.z)
-                } else {
-                    let obj_color = Vector3::new(obj_color.x, obj_color.y, obj_color.z);
-                    let refr = Vector3::new(refr.x, refr.y, refr.z);
-
-                    obj_color * alpha + refr * (1.0 - alpha)
+            Some((sn, inter)) => self.shade(ray, sn, &inter),
+        };
+
+        weight.component_mul(&color)
+    }
+
+    // Computes the light scattered by `medium` toward the origin of `ray` at its time of impact
+    // `t`: the light coming from the light sources, plus the light traced along a direction
+    // sampled from the phase function.
+    //
+    // The scattering albedo of the medium is part of the weight of the event sampled by
+    // `Medium::sample`. Since the direction is sampled proportionally to the phase function,
+    // the ratio of the phase function to the density of the direction is one, and the traced
+    // light is only divided by the probability of the path to go on, chosen by Russian
+    // roulette.
+    fn scatter(&self, ray: &RayWithEnergy, medium: &Medium, t: Scalar) -> Vector3<f32> {
+        let pt = ray.ray.origin + ray.ray.dir * t;
+        let mut res = Vector3::new(0.0, 0.0, 0.0);
+
+        for light in self.lights.iter() {
+            let mut acc = Vector3::new(0.0, 0.0, 0.0);
+
+            light.sample(&mut |pos| {
+                let mut ldir = pos - pt;
+                let dist = ldir.normalize_mut();
+
+                if let Some(filter) = self.intersects_ray(&Ray::new(pt, ldir), 0.0, dist) {
+                    // Lights have no falloff: like a diffuse surface reflects its color times
+                    // the light color, an isotropic medium scatters a quarter of it.
+                    let phase = medium.phase(&-ldir, &-ray.ray.dir) * f32::consts::PI;
+                    acc = acc + light.color.coords.component_mul(&filter) * phase;
                 }
-            }
+            });
+
+            res = res + acc / (light.racsample * light.racsample) as f32;
+        }
+
+        let sigma_s = medium.sigma_s();
+        let sigma_t = medium.sigma_a() + sigma_s;
+        let albedo = (0..3)
+            .filter(|i| sigma_t[*i] > 0.0)
+            .fold(0.0f32, |acc, i| acc.max(sigma_s[i] / sigma_t[i]));
+        let survival = albedo.min(MAX_SCATTERING_SURVIVAL);
+
+        if ray.energy > 0.1 && random::<f32>() < survival {
+            let dir = medium.sample_phase(&ray.ray.dir);
+            let mut scattered = RayWithEnergy::new_with_energy(pt, dir, ray.refr, ray.energy);
+            scattered.medium = ray.medium.clone();
+
+            res = res + self.trace(&scattered) / survival;
+        }
+
+        res
+    }
+
+    /// Computes the color carried back along `ray` from its intersection with the node `sn`.
+    pub fn shade(
+        &self,
+        ray: &RayWithEnergy,
+        sn: &SceneNode,
+        inter: &Intersection,
+    ) -> Vector3<f32> {
+        let pt = ray.ray.origin + ray.ray.dir * inter.toi;
+        let material = sn.material_at(inter);
+        let obj = material.compute(ray, &pt, inter, self);
+        let obj_rgb = Vector3::new(obj.x, obj.y, obj.z);
+
+        let refl = self.trace_reflection(sn.refl_mix, sn.refl_atenuation, ray, &pt, inter);
+
+        let alpha = obj.w * sn.alpha;
+        let obj_color = obj_rgb * (1.0 - sn.refl_mix) + refl * sn.refl_mix;
+        let refr = self.trace_refraction(alpha, sn, ray, &pt, inter);
+
+        if alpha == 1.0 {
+            Vector3::new(obj_color.x, obj_color.y, obj_color.z)
+        } else {
+            let obj_color = Vector3::new(obj_color.x, obj_color.y, obj_color.z);
+            let refr = Vector3::new(refr.x, refr.y, refr.z);
+
+            obj_color * alpha + refr * (1.0 - alpha)
         }
     }
 
@@ -199,19 +646,23 @@ impl Scene {
         attenuation: f32,
         ray: &RayWithEnergy,
         pt: &Point,
-        normal: &Vect,
+        inter: &Intersection,
     ) -> Vector3<f32> {
         if !mix.is_zero() && ray.energy > 0.1 {
-            let nproj = *normal * na::dot(&ray.ray.dir, normal);
-            let rdir = ray.ray.dir - nproj * 2.0;
+            let rdir = math::reflect(&ray.ray.dir, &inter.shading_normal);
             let new_energy = ray.energy - attenuation;
 
-            self.trace(&RayWithEnergy::new_with_energy(
-                *pt + rdir * 0.001,
+            let mut reflected = RayWithEnergy::spawn(
+                pt,
+                &inter.error,
+                &inter.normal,
                 rdir,
                 ray.refr.clone(),
                 new_energy,
-            ))
+            );
+            reflected.medium = ray.medium.clone();
+
+            self.trace(&reflected)
         } else {
             na::zero()
         }
@@ -221,12 +672,13 @@ impl Scene {
     fn trace_refraction(
         &self,
         alpha: f32,
-        coeff: Scalar,
+        sn: &SceneNode,
         ray: &RayWithEnergy,
         pt: &Point,
-        normal: &Vect,
+        inter: &Intersection,
     ) -> Vector3<f32> {
         if alpha != 1.0 {
+            let coeff = sn.refr_coeff;
             let n1;
             let n2;
 
@@ -238,102 +690,226 @@ impl Scene {
                 n2 = 1.0;
             }
 
-            let dir_along_normal = *normal * na::dot(&ray.ray.dir, normal);
-            let tangent = ray.ray.dir - dir_along_normal;
-            let new_dir = na::normalize(&(dir_along_normal + tangent * (n2 / n1)));
-            let new_pt = *pt + new_dir * 0.001f64;
+            let new_dir = math::refract(&ray.ray.dir, &inter.shading_normal, n1, n2);
+
+            let mut refracted =
+                RayWithEnergy::spawn(pt, &inter.error, &inter.normal, new_dir, n2, ray.energy);
+            refracted.medium = crossed_medium(sn, ray);
 
-            self.trace(&RayWithEnergy::new_with_energy(
-                new_pt, new_dir, n2, ray.energy,
-            ))
+            self.trace(&refracted)
         } else {
             na::zero()
         }
     }
 }
 
-fn uvs(i: &RayIntersection<Scalar>) -> Option<Point2<Scalar>> {
-    i.uvs.clone()
+// The medium `ray` travels in after crossing the boundary of `sn`.
+fn crossed_medium(sn: &SceneNode, ray: &RayWithEnergy) -> Option<(Arc<Medium>, Isometry)> {
+    match sn.medium {
+        None => ray.medium.clone(),
+        Some(ref medium) => match ray.medium {
+            // Leaving the interior of `sn`.
+            Some((ref m, ref t)) if Arc::ptr_eq(m, medium) && *t == sn.transform => None,
+            _ => Some((medium.clone(), sn.transform)),
+        },
+    }
 }
 
-/*
- * Define our own cost functions as the Arc<SceneNode> does not implement the `RayCast` trait.
- */
-pub struct ClosestRayTOICostFn<'a> {
-    ray: &'a Ray<Scalar>,
+// Expresses `ray` in the local space of a node with the given transform.
+fn local_ray(ray: &Ray<Scalar>, transform: &Isometry) -> Ray<Scalar> {
+    let inv = transform.inverse();
+
+    Ray::new(inv * ray.origin, inv * ray.dir)
 }
 
-impl<'a> ClosestRayTOICostFn<'a> {
-    pub fn new(ray: &'a Ray<Scalar>) -> ClosestRayTOICostFn<'a> {
-        ClosestRayTOICostFn { ray: ray }
+// The parts of `ray` from `tmin` to `tmax` inside the closed geometry of `b`.
+//
+// A point of the ray outside the bounding box of `b` is outside its geometry: the boundary
+// crossings are then only searched up to `tmax`. Otherwise, every crossing along the ray is
+// found: the point is inside if they are odd in number.
+fn interior_segments(
+    b: &SceneNode,
+    ray: &Ray<Scalar>,
+    tmin: Scalar,
+    tmax: Scalar,
+) -> Vec<(Scalar, Scalar)> {
+    let start = ray.origin + ray.dir * tmin;
+    let (mins, maxs) = (b.aabb.mins(), b.aabb.maxs());
+    let outside = (0..3).any(|i| start[i] < mins[i] || start[i] > maxs[i]);
+    let mut crossings = Vec::new();
+    let mut t = tmin;
+
+    while crossings.len() < MAX_CROSSINGS {
+        let origin = ray.origin + ray.dir * t;
+        let shifted = Ray::new(origin, ray.dir);
+
+        match b.geometry.cast(&b.transform, &shifted, false) {
+            Some(ref inter) if outside && t + inter.toi > tmax => break,
+            Some(inter) => {
+                t = t + inter.toi;
+                crossings.push(t);
+                t = t + na::norm(&b.geometry.hit_error(&shifted, inter.toi));
+            }
+            None => break,
+        }
     }
-}
-impl<'a> BVTCostFn<Scalar, Arc<SceneNode>, AABB<Scalar>> for ClosestRayTOICostFn<'a> {
-    type UserData = RayIntersection<Scalar>;
 
-    #[inline]
-    fn compute_bv_cost(&mut self, bv: &AABB<Scalar>) -> Option<Scalar> {
-        bv.toi_with_ray(&Isometry::identity(), self.ray, true)
+    let mut bounds = Vec::with_capacity(crossings.len() + 2);
+
+    if !outside && crossings.len() % 2 == 1 {
+        bounds.push(tmin);
     }
 
-    #[inline]
-    fn compute_b_cost(&mut self, b: &Arc<SceneNode>) -> Option<(Scalar, RayIntersection<Scalar>)> {
-        b.cast(self.ray).map(|inter| (inter.toi, inter))
+    bounds.extend(crossings);
+
+    // The ray is still inside past the crossings searched.
+    if bounds.len() % 2 == 1 {
+        bounds.push(tmax);
     }
+
+    bounds
+        .chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| (c[0].max(tmin), c[1].min(tmax)))
+        .filter(|&(t0, t1)| t0 < t1)
+        .collect()
+}
+
+fn uvs(i: &Intersection) -> Option<Point2<Scalar>> {
+    i.uvs.clone()
 }
 
-pub struct TransparentShadowsRayTOICostFn<'a> {
-    ray: &'a Ray<Scalar>,
-    maxtoi: Scalar,
-    filter: &'a mut Vector3<f32>,
+// Attenuates the light `filter` crossing the node `b` where `ray` intersects it.
+//
+// Returns `None` if the node is opaque.
+fn filter_light(
+    b: &SceneNode,
+    ray: &Ray<Scalar>,
+    inter: &Intersection,
+    filter: Vector3<f32>,
+) -> Option<Vector3<f32>> {
+    let color = b.material_at(inter).ambiant(
+        &(ray.origin + ray.dir * inter.toi),
+        &inter.shading_normal,
+        &uvs(inter),
+    );
+    let alpha = color.w * b.alpha;
+
+    if alpha == 0.0 && b.medium.is_some() {
+        // The invisible boundary of a medium.
+        return Some(filter);
+    }
+
+    if alpha < 1.0 {
+        let rgb = Vector3::new(color.x, color.y, color.z);
+        Some(filter.component_mul(&rgb) * (1.0 - alpha))
+    } else {
+        None
+    }
 }
 
-impl<'a> TransparentShadowsRayTOICostFn<'a> {
-    pub fn new(
-        ray: &'a Ray<Scalar>,
-        maxtoi: Scalar,
-        filter: &'a mut Vector3<f32>,
-    ) -> TransparentShadowsRayTOICostFn<'a> {
-        TransparentShadowsRayTOICostFn {
-            ray: ray,
-            maxtoi: maxtoi,
-            filter: filter,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use material::Material;
+    use ncollide3d::shape::Ball;
+    use triangle_mesh::{MeshPart, TriangleMesh};
+
+    struct Flat(Point4<f32>);
+
+    impl Material for Flat {
+        fn ambiant(&self, _: &Point, _: &Vect, _: &Option<Point2<Scalar>>) -> Point4<f32> {
+            self.0
         }
     }
-}
-impl<'a> BVTCostFn<Scalar, Arc<SceneNode>, AABB<Scalar>> for TransparentShadowsRayTOICostFn<'a> {
-    type UserData = ();
 
-    #[inline]
-    fn compute_bv_cost(&mut self, bv: &AABB<Scalar>) -> Option<Scalar> {
-        bv.toi_with_ray(&Isometry::identity(), self.ray, true)
+    fn flat(r: f32, g: f32, b: f32) -> Arc<Box<Material + Sync + Send>> {
+        Arc::new(Box::new(Flat(Point4::new(r, g, b, 1.0))) as Box<Material + Sync + Send>)
     }
 
-    #[inline]
-    fn compute_b_cost(&mut self, b: &Arc<SceneNode>) -> Option<(Scalar, ())> {
-        match b.cast(self.ray) {
-            Some(t) => {
-                if t.toi <= self.maxtoi {
-                    let color = b.material.ambiant(
-                        &(self.ray.origin + self.ray.dir * t.toi),
-                        &t.normal,
-                        &uvs(&t),
-                    );
-                    let alpha = color.w * b.alpha;
-
-                    if alpha < 1.0 {
-                        let rgb = Vector3::new(color.x, color.y, color.z);
-                        *self.filter = self.filter.component_mul(&rgb) * (1.0 - alpha);
-
-                        None
-                    } else {
-                        Some((t.toi, ()))
-                    }
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        }
+    // The color of the node hit by a ray cast from the origin along `-z`.
+    fn shade_center(node: SceneNode) -> Vector3<f32> {
+        let scene = Scene::new(vec![Arc::new(node)], Vec::new(), na::zero());
+        let ray = RayWithEnergy::new(Point::origin(), Vect::new(0.0, 0.0, -1.0));
+        let (sn, inter) = scene.cast_ray(&ray.ray, 0.0).expect("The ray missed the mesh.");
+
+        scene.shade(&ray, sn, &inter)
+    }
+
+    #[test]
+    fn instance_material_overrides_the_parts_of_a_mesh() {
+        // A quad made of two triangles, like an OBJ group with its own material.
+        let vertices = vec![
+            Point::new(-1.0, -1.0, 0.0),
+            Point::new(1.0, -1.0, 0.0),
+            Point::new(1.0, 1.0, 0.0),
+            Point::new(-1.0, 1.0, 0.0),
+        ];
+        let indices = vec![Point3::new(0, 1, 2), Point3::new(0, 2, 3)];
+        let mut mesh = TriangleMesh::new(Arc::new(vertices), indices, None, None);
+        let part = MeshPart {
+            material: flat(1.0, 0.0, 0.0),
+            nmap: None,
+        };
+        mesh.set_parts(vec![part], vec![0, 0]);
+
+        let template = SceneNode::new(
+            flat(0.0, 0.0, 1.0),
+            0.0,
+            0.0,
+            1.0,
+            1.0,
+            Isometry::identity(),
+            Box::new(mesh),
+            None,
+            false,
+        );
+        let transform = Isometry::new(Vect::new(0.0, 0.0, -2.0), na::zero());
+
+        // Without override, the material of the part wins over the material of the node.
+        let mut instance = template.instance(transform);
+        assert_eq!(shade_center(instance), Vector3::new(1.0, 0.0, 0.0));
+
+        instance = template.instance(transform);
+        instance.material_override = Some(flat(0.0, 1.0, 0.0));
+        assert_eq!(shade_center(instance), Vector3::new(0.0, 1.0, 0.0));
+    }
+
+    fn assert_segments(segments: Vec<(Scalar, Scalar)>, expected: &[(Scalar, Scalar)]) {
+        let close = |a: &(Scalar, Scalar), b: &(Scalar, Scalar)| {
+            (a.0 - b.0).abs() < 1.0e-6 && (a.1 - b.1).abs() < 1.0e-6
+        };
+
+        assert!(
+            segments.len() == expected.len()
+                && segments.iter().zip(expected.iter()).all(|(a, b)| close(a, b)),
+            "{:?} differs from {:?}",
+            segments,
+            expected
+        );
+    }
+
+    #[test]
+    fn interior_segments_are_bounded_by_the_ray_segment() {
+        let ball = SceneNode::new(
+            flat(1.0, 1.0, 1.0),
+            0.0,
+            0.0,
+            1.0,
+            1.0,
+            Isometry::new(Vect::new(0.0, 0.0, -5.0), na::zero()),
+            Box::new(Ball::new(1.0)),
+            None,
+            false,
+        );
+        let outside = Ray::new(Point::origin(), Vect::new(0.0, 0.0, -1.0));
+        let inside = Ray::new(Point::new(0.0, 0.0, -5.0), Vect::new(0.0, 0.0, -1.0));
+
+        assert_segments(interior_segments(&ball, &outside, 0.0, 10.0), &[(4.0, 6.0)]);
+        assert_segments(interior_segments(&ball, &outside, 0.0, 5.0), &[(4.0, 5.0)]);
+        assert_segments(interior_segments(&ball, &outside, 5.0, 10.0), &[(5.0, 6.0)]);
+        assert_segments(interior_segments(&ball, &outside, 0.0, 3.0), &[]);
+        assert_segments(interior_segments(&ball, &inside, 0.0, 0.5), &[(0.0, 0.5)]);
+        assert_segments(interior_segments(&ball, &inside, 0.0, 3.0), &[(0.0, 1.0)]);
     }
 }
@@ -1,9 +1,73 @@
+use intersection::Intersection;
 use material::Material;
-use math::{Isometry, Scalar};
+use math::{self, Isometry, Scalar, Vect};
+use medium::Medium;
+use normal_map::NormalMap;
 use ncollide3d::bounding_volume::{HasBoundingVolume, AABB};
-use ncollide3d::query::{Ray, RayCast, RayIntersection};
+use ncollide3d::query::{Ray, RayCast};
+use ray_packet::{Mask, RayPacket, PACKET_SIZE};
 use std::sync::Arc;
-use texture2d::Texture2d;
+
+/// A shape that can be ray cast and bounded, and shared among several scene nodes.
+///
+/// This is implemented by every ncollide shape that can be ray cast and bounded.
+pub trait Geometry: Send + Sync {
+    /// Casts a ray on this geometry transformed by `m`.
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, solid: bool) -> Option<Intersection>;
+
+    /// Casts the rays of the active lanes of a packet on this geometry transformed by `m`.
+    ///
+    /// Intersections farther than `max_tois` are discarded. The default implementation casts
+    /// each ray separately.
+    fn cast_packet(
+        &self,
+        m: &Isometry,
+        packet: &RayPacket,
+        mask: &Mask,
+        max_tois: &[Scalar; PACKET_SIZE],
+        solid: bool,
+    ) -> Vec<Option<Intersection>> {
+        (0..packet.len())
+            .map(|lane| {
+                if mask[lane] {
+                    self.cast(m, packet.ray(lane), solid)
+                        .and_then(|inter| if inter.toi <= max_tois[lane] { Some(inter) } else { None })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The bounding volume of this geometry transformed by `m`.
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar>;
+
+    /// A bound of the error of each coordinate of the point where `ray` hits this geometry at
+    /// the time of impact `toi`.
+    ///
+    /// The default implementation assumes the time of impact is computed iteratively, as by
+    /// the ray casts of ncollide on support-map shapes. Geometries computing it with a bounded
+    /// number of operations should give a tighter bound.
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::iterative_cast_error())
+    }
+}
+
+impl<T> Geometry for T
+where
+    T: RayCast<Scalar> + HasBoundingVolume<Scalar, AABB<Scalar>> + Send + Sync,
+{
+    #[inline]
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, solid: bool) -> Option<Intersection> {
+        self.toi_and_normal_and_uv_with_ray(m, ray, solid)
+            .map(Intersection::from_ray_intersection)
+    }
+
+    #[inline]
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        self.bounding_volume(m)
+    }
+}
 
 pub struct SceneNode {
     pub refl_mix: f32,
@@ -13,15 +77,22 @@ pub struct SceneNode {
     pub solid: bool,
     pub material: Arc<Box<Material + Sync + Send>>,
     pub transform: Isometry,
-    pub geometry: Box<RayCast<Scalar> + Sync + Send>,
+    pub geometry: Arc<Geometry>,
     pub aabb: AABB<Scalar>,
-    pub nmap: Option<Texture2d>,
+    pub nmap: Option<NormalMap>,
+    /// The medium filling the interior of this node, whose geometry must be closed.
+    ///
+    /// Rays cross the boundary of a medium, so a node with an interior medium is never cast as
+    /// solid. With an alpha of zero, the node is an invisible boundary that does not filter
+    /// light.
+    pub medium: Option<Arc<Medium>>,
+    /// A material overriding every other material of this node, including the materials the
+    /// intersections with its geometry carry, e.g., the per-part materials of a mesh.
+    pub material_override: Option<Arc<Box<Material + Sync + Send>>>,
 }
 
 impl SceneNode {
-    pub fn new<
-        G: 'static + Send + Sync + RayCast<Scalar> + HasBoundingVolume<Scalar, AABB<Scalar>>,
-    >(
+    pub fn new<G: 'static + Geometry>(
         material: Arc<Box<Material + Sync + Send>>,
         refl_mix: f32,
         refl_atenuation: f32,
@@ -29,7 +100,32 @@ impl SceneNode {
         refr_coeff: Scalar,
         transform: Isometry,
         geometry: Box<G>,
-        nmap: Option<Texture2d>,
+        nmap: Option<NormalMap>,
+        solid: bool,
+    ) -> SceneNode {
+        SceneNode::new_shared(
+            material,
+            refl_mix,
+            refl_atenuation,
+            alpha,
+            refr_coeff,
+            transform,
+            Arc::new(*geometry),
+            nmap,
+            solid,
+        )
+    }
+
+    /// Creates a scene node from a geometry that may be shared with other nodes.
+    pub fn new_shared(
+        material: Arc<Box<Material + Sync + Send>>,
+        refl_mix: f32,
+        refl_atenuation: f32,
+        alpha: f32,
+        refr_coeff: Scalar,
+        transform: Isometry,
+        geometry: Arc<Geometry>,
+        nmap: Option<NormalMap>,
         solid: bool,
     ) -> SceneNode {
         SceneNode {
@@ -38,39 +134,118 @@ impl SceneNode {
             alpha: alpha,
             refr_coeff: refr_coeff,
             material: material,
-            aabb: geometry.bounding_volume(&transform),
-            geometry: geometry as Box<RayCast<Scalar> + Sync + Send>,
+            aabb: geometry.aabb(&transform),
+            geometry: geometry,
             transform: transform,
             nmap: nmap,
             solid: solid,
+            medium: None,
+            material_override: None,
         }
     }
+
+    /// Creates a copy of this node placed at `transform`, sharing its geometry.
+    ///
+    /// The material and the other properties of the copy can be overridden afterward.
+    pub fn instance(&self, transform: Isometry) -> SceneNode {
+        SceneNode {
+            refl_mix: self.refl_mix,
+            refl_atenuation: self.refl_atenuation,
+            alpha: self.alpha,
+            refr_coeff: self.refr_coeff,
+            material: self.material.clone(),
+            aabb: self.geometry.aabb(&transform),
+            geometry: self.geometry.clone(),
+            transform: transform,
+            nmap: self.nmap.clone(),
+            solid: self.solid,
+            medium: self.medium.clone(),
+            material_override: self.material_override.clone(),
+        }
+    }
+
+    /// The material of this node at its intersection `inter`.
+    ///
+    /// This is the material override of this node if any, otherwise the material of the
+    /// intersection if any, otherwise the material of this node.
+    pub fn material_at<'a>(
+        &'a self,
+        inter: &'a Intersection,
+    ) -> &'a Arc<Box<Material + Sync + Send>> {
+        self.material_override
+            .as_ref()
+            .or(inter.material.as_ref())
+            .unwrap_or(&self.material)
+    }
 }
 
 impl SceneNode {
-    pub fn cast(&self, r: &Ray<Scalar>) -> Option<RayIntersection<Scalar>> {
-        let res = self
-            .geometry
-            .toi_and_normal_and_uv_with_ray(&self.transform, r, self.solid);
+    pub fn cast(&self, r: &Ray<Scalar>) -> Option<Intersection> {
+        self.geometry
+            .cast(&self.transform, r, self.is_solid())
+            .map(|inter| self.finish(r, inter))
+    }
 
-        if res.is_none() {
-            return None;
-        }
+    /// Casts a ray on this node, ignoring the intersections closer than `tmin`.
+    pub fn cast_with_tmin(&self, r: &Ray<Scalar>, tmin: Scalar) -> Option<Intersection> {
+        let res = self.cast(r);
 
-        match self.nmap {
-            None => res,
-            Some(ref nmap) => {
-                let mut inter = res.unwrap();
+        match res {
+            Some(ref inter) if inter.toi < tmin => {
+                // Geometries only report their closest intersection: look past `tmin` instead.
+                let shifted = Ray::new(r.origin + r.dir * tmin, r.dir);
 
-                if let Some(ref uvs) = inter.uvs {
-                    let shift_color = nmap.sample(uvs);
-                    let shift = (shift_color.x + shift_color.y + shift_color.z) / 3.0;
+                self.cast(&shifted).map(|mut inter| {
+                    inter.toi = inter.toi + tmin;
+                    inter.error = inter.error + self.geometry.hit_error(r, inter.toi);
+                    inter
+                })
+            }
+            _ => res,
+        }
+    }
 
-                    inter.toi = inter.toi - shift as f64;
+    /// Casts the rays of the active lanes of a packet on this node.
+    ///
+    /// Intersections farther than `max_tois` or closer than the `tmin` of their ray are ignored.
+    pub fn cast_packet(
+        &self,
+        packet: &RayPacket,
+        mask: &Mask,
+        max_tois: &[Scalar; PACKET_SIZE],
+    ) -> Vec<Option<Intersection>> {
+        self.geometry
+            .cast_packet(&self.transform, packet, mask, max_tois, self.is_solid())
+            .into_iter()
+            .enumerate()
+            .map(|(lane, inter)| {
+                let tmin = packet.tmin(lane);
+
+                match inter.map(|inter| self.finish(packet.ray(lane), inter)) {
+                    Some(ref inter) if inter.toi < tmin => self
+                        .cast_with_tmin(packet.ray(lane), tmin)
+                        .and_then(|inter| if inter.toi <= max_tois[lane] { Some(inter) } else { None }),
+                    inter => inter,
                 }
+            })
+            .collect()
+    }
 
-                Some(inter)
-            }
+    #[inline]
+    fn is_solid(&self) -> bool {
+        self.solid && self.medium.is_none()
+    }
+
+    // Sets the error of the hit point of `ray` and applies the normal map of this node.
+    fn finish(&self, ray: &Ray<Scalar>, inter: Intersection) -> Intersection {
+        let mut inter = inter;
+
+        inter.error = self.geometry.hit_error(ray, inter.toi);
+
+        if let Some(ref nmap) = self.nmap {
+            nmap.apply(&mut inter);
         }
+
+        inter
     }
 }
@@ -0,0 +1,351 @@
+//! Signed distance fields, ray cast by sphere tracing.
+
+use bvh;
+use intersection::Intersection;
+use math::{self, Isometry, Point, Scalar, Vect};
+use na::{self, Vector3};
+use ncollide3d::bounding_volume::AABB;
+use ncollide3d::query::Ray;
+use scene_node::Geometry;
+
+/// The default maximum number of steps of the sphere tracing of a ray.
+pub const DEFAULT_MAX_STEPS: usize = 256;
+
+/// A tree of signed distance fields, negative inside the shape they describe.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    /// A sphere centered at the origin, with the given radius.
+    Sphere(Scalar),
+    /// A box centered at the origin, with the given half extents.
+    Cuboid(Vect),
+    /// A torus centered at the origin around the `y` axis, with the given major and minor radii.
+    Torus(Scalar, Scalar),
+    /// A box centered at the origin, with the given half extents and its edges rounded with the
+    /// given radius.
+    RoundCuboid(Vect, Scalar),
+    /// The union of two fields, blended over the given distance.
+    SmoothUnion(Box<Sdf>, Box<Sdf>, Scalar),
+    /// The first field minus the second one, blended over the given distance.
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, Scalar),
+    /// The intersection of two fields, blended over the given distance.
+    SmoothIntersection(Box<Sdf>, Box<Sdf>, Scalar),
+    /// A field twisted around the `y` axis by the given angle per unit of height, in radians.
+    Twist(Box<Sdf>, Scalar),
+    /// A field repeated with the given spacing, with the given number of copies on each side of
+    /// the original along each axis.
+    ///
+    /// The field must fit inside a cell of the repetition grid. A zero spacing disables the
+    /// repetition along its axis.
+    Repeat(Box<Sdf>, Vect, Vector3<usize>),
+    /// A field placed by the given transformation.
+    Transform(Box<Sdf>, Isometry),
+}
+
+impl Sdf {
+    /// The signed distance from `pt` to the surface of this field.
+    ///
+    /// Blends and twists make this distance an approximation, see `lipschitz`.
+    pub fn distance(&self, pt: &Point) -> Scalar {
+        match *self {
+            Sdf::Sphere(r) => na::norm(&pt.coords) - r,
+            Sdf::Cuboid(ref he) => cuboid_distance(pt, he),
+            Sdf::Torus(major, minor) => {
+                let qx = (pt.x * pt.x + pt.z * pt.z).sqrt() - major;
+
+                (qx * qx + pt.y * pt.y).sqrt() - minor
+            }
+            Sdf::RoundCuboid(ref he, r) => {
+                cuboid_distance(pt, &he.map(|e| (e - r).max(0.0))) - r
+            }
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let da = a.distance(pt);
+                let db = b.distance(pt);
+
+                if k <= 0.0 {
+                    return da.min(db);
+                }
+
+                let h = na::clamp(0.5 + 0.5 * (db - da) / k, 0.0, 1.0);
+
+                mix(db, da, h) - k * h * (1.0 - h)
+            }
+            Sdf::SmoothSubtraction(ref a, ref b, k) => {
+                let da = a.distance(pt);
+                let db = b.distance(pt);
+
+                if k <= 0.0 {
+                    return da.max(-db);
+                }
+
+                let h = na::clamp(0.5 - 0.5 * (da + db) / k, 0.0, 1.0);
+
+                mix(da, -db, h) + k * h * (1.0 - h)
+            }
+            Sdf::SmoothIntersection(ref a, ref b, k) => {
+                let da = a.distance(pt);
+                let db = b.distance(pt);
+
+                if k <= 0.0 {
+                    return da.max(db);
+                }
+
+                let h = na::clamp(0.5 - 0.5 * (db - da) / k, 0.0, 1.0);
+
+                mix(db, da, h) + k * h * (1.0 - h)
+            }
+            Sdf::Twist(ref s, k) => {
+                let (sin, cos) = (k * pt.y).sin_cos();
+                let q = Point::new(cos * pt.x - sin * pt.z, pt.y, sin * pt.x + cos * pt.z);
+
+                s.distance(&q)
+            }
+            Sdf::Repeat(ref s, ref spacing, ref counts) => {
+                let mut q = *pt;
+
+                for i in 0..3 {
+                    if spacing[i] > 0.0 {
+                        let n = counts[i] as Scalar;
+                        let cell = na::clamp((pt[i] / spacing[i]).round(), -n, n);
+
+                        q[i] = pt[i] - spacing[i] * cell;
+                    }
+                }
+
+                s.distance(&q)
+            }
+            Sdf::Transform(ref s, ref m) => s.distance(&(m.inverse() * *pt)),
+        }
+    }
+
+    /// The gradient of this field at `pt`, approximated by central differences of step `h`.
+    pub fn gradient(&self, pt: &Point, h: Scalar) -> Vect {
+        let mut res = na::zero::<Vect>();
+
+        for i in 0..3 {
+            let mut dp = na::zero::<Vect>();
+            dp[i] = h;
+
+            res[i] = self.distance(&(*pt + dp)) - self.distance(&(*pt - dp));
+        }
+
+        res / (2.0 * h)
+    }
+
+    /// A bounding box of the surface of this field.
+    pub fn aabb(&self) -> AABB<Scalar> {
+        match *self {
+            Sdf::Sphere(r) => centered_aabb(Vect::new(r, r, r)),
+            Sdf::Cuboid(ref he) | Sdf::RoundCuboid(ref he, _) => centered_aabb(*he),
+            Sdf::Torus(major, minor) => {
+                centered_aabb(Vect::new(major + minor, minor, major + minor))
+            }
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let aabb_a = a.aabb();
+                let aabb_b = b.aabb();
+                // Blending moves the surface at most a quarter of the blending distance away.
+                let margin = Vect::new(k, k, k) * 0.25;
+
+                AABB::new(
+                    na::inf(aabb_a.mins(), aabb_b.mins()) - margin,
+                    na::sup(aabb_a.maxs(), aabb_b.maxs()) + margin,
+                )
+            }
+            Sdf::SmoothSubtraction(ref a, _, _) => a.aabb(),
+            Sdf::SmoothIntersection(ref a, ref b, _) => {
+                let aabb_a = a.aabb();
+                let aabb_b = b.aabb();
+                let mins = na::sup(aabb_a.mins(), aabb_b.mins());
+                let maxs = na::inf(aabb_a.maxs(), aabb_b.maxs());
+
+                // Disjoint boxes give an empty intersection, bounded by a point.
+                AABB::new(mins, na::sup(&mins, &maxs))
+            }
+            Sdf::Twist(ref s, _) => {
+                let aabb = s.aabb();
+                let r = radial_extent(&aabb);
+
+                AABB::new(
+                    Point::new(-r, aabb.mins().y, -r),
+                    Point::new(r, aabb.maxs().y, r),
+                )
+            }
+            Sdf::Repeat(ref s, ref spacing, ref counts) => {
+                let aabb = s.aabb();
+                let shift = spacing.component_mul(&counts.map(|n| n as Scalar));
+
+                AABB::new(*aabb.mins() - shift, *aabb.maxs() + shift)
+            }
+            Sdf::Transform(ref s, ref m) => bvh::transform_aabb(&s.aabb(), m),
+        }
+    }
+
+    /// An upper bound of the rate of change of this field.
+    ///
+    /// Sphere tracing divides distances by this factor so that it does not step over the
+    /// surface of fields that overestimate the actual distance, e.g., twisted ones.
+    pub fn lipschitz(&self) -> Scalar {
+        match *self {
+            Sdf::Sphere(_) | Sdf::Cuboid(_) | Sdf::Torus(..) | Sdf::RoundCuboid(..) => 1.0,
+            Sdf::SmoothUnion(ref a, ref b, _)
+            | Sdf::SmoothSubtraction(ref a, ref b, _)
+            | Sdf::SmoothIntersection(ref a, ref b, _) => a.lipschitz().max(b.lipschitz()),
+            Sdf::Twist(ref s, k) => {
+                let kr = k * radial_extent(&s.aabb());
+
+                s.lipschitz() * (1.0 + kr * kr).sqrt()
+            }
+            Sdf::Repeat(ref s, _, _) | Sdf::Transform(ref s, _) => s.lipschitz(),
+        }
+    }
+}
+
+/// A geometry whose surface is the zero level set of a signed distance field.
+pub struct SdfGeometry {
+    sdf: Sdf,
+    aabb: AABB<Scalar>,
+    lipschitz: Scalar,
+    epsilon: Scalar,
+    max_steps: usize,
+}
+
+impl SdfGeometry {
+    /// Creates a geometry from a signed distance field, with a precision relative to its size.
+    pub fn new(sdf: Sdf) -> SdfGeometry {
+        let aabb = sdf.aabb();
+        let epsilon = na::distance(aabb.mins(), aabb.maxs()) * 1.0e-4;
+
+        SdfGeometry::new_with_precision(sdf, epsilon, DEFAULT_MAX_STEPS)
+    }
+
+    /// Creates a geometry from a signed distance field.
+    ///
+    /// Sphere tracing stops at `epsilon` from the surface, or after `max_steps` steps.
+    pub fn new_with_precision(sdf: Sdf, epsilon: Scalar, max_steps: usize) -> SdfGeometry {
+        assert!(
+            epsilon > 0.0,
+            "The precision of a signed distance field must be positive."
+        );
+
+        let aabb = sdf.aabb();
+        // The margin keeps surfaces touching the bounding box, e.g., of a cuboid, off its
+        // boundary, where the tracing starts.
+        let margin = Vect::new(epsilon, epsilon, epsilon) * 2.0;
+
+        SdfGeometry {
+            aabb: AABB::new(*aabb.mins() - margin, *aabb.maxs() + margin),
+            lipschitz: sdf.lipschitz(),
+            sdf: sdf,
+            epsilon: epsilon,
+            max_steps: max_steps,
+        }
+    }
+
+    /// The signed distance field of this geometry.
+    pub fn sdf(&self) -> &Sdf {
+        &self.sdf
+    }
+
+    // Sphere traces a ray in the local frame, returning the time of impact and the outward
+    // normal of the first hit.
+    fn trace(&self, ray: &Ray<Scalar>, solid: bool) -> Option<(Scalar, Vect)> {
+        let (entry, exit) = match bvh::ray_aabb_interval(&self.aabb, ray) {
+            Some(ts) => ts,
+            None => return None,
+        };
+        let speed = na::norm(&ray.dir) * self.lipschitz;
+        let mut t = entry.max(0.0);
+        let mut d = self.sdf.distance(&(ray.origin + ray.dir * t));
+
+        if entry <= 0.0 {
+            // Rays spawned on the surface, e.g., reflected ones, first leave it.
+            let mut i = 0;
+
+            while d.abs() < self.epsilon && i < 8 {
+                t = t + 2.0 * self.epsilon / speed;
+                d = self.sdf.distance(&(ray.origin + ray.dir * t));
+                i += 1;
+            }
+
+            if d < 0.0 && solid {
+                return Some((0.0, self.normal(&ray.origin)));
+            }
+        }
+
+        // Rays starting inside the shape trace its inverse field.
+        let sign = if d < 0.0 { -1.0 } else { 1.0 };
+
+        for _ in 0..self.max_steps {
+            let dist = d * sign;
+
+            if dist < self.epsilon {
+                return Some((t, self.normal(&(ray.origin + ray.dir * t))));
+            }
+
+            t = t + dist / speed;
+
+            if t > exit {
+                return None;
+            }
+
+            d = self.sdf.distance(&(ray.origin + ray.dir * t));
+        }
+
+        None
+    }
+
+    fn normal(&self, pt: &Point) -> Vect {
+        let g = self.sdf.gradient(pt, self.epsilon);
+
+        if na::norm(&g) > 0.0 {
+            na::normalize(&g)
+        } else {
+            g
+        }
+    }
+}
+
+impl Geometry for SdfGeometry {
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, solid: bool) -> Option<Intersection> {
+        let inv = m.inverse();
+        let local = Ray::new(inv * ray.origin, inv * ray.dir);
+
+        self.trace(&local, solid).map(|(toi, n)| {
+            let n = m * n;
+
+            Intersection::new(toi, n, n, None)
+        })
+    }
+
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        bvh::transform_aabb(&self.aabb, m)
+    }
+
+    // Sphere tracing stops up to `epsilon` from the surface, on top of the rounding of the
+    // transformation of the ray and of the steps along it.
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        Vect::from_element(self.epsilon) + math::hit_point_error(ray, toi, math::gamma(3))
+    }
+}
+
+fn mix(a: Scalar, b: Scalar, t: Scalar) -> Scalar {
+    a * (1.0 - t) + b * t
+}
+
+fn cuboid_distance(pt: &Point, he: &Vect) -> Scalar {
+    let q = pt.coords.map(|x| x.abs()) - *he;
+    let outside = q.map(|x| x.max(0.0));
+
+    na::norm(&outside) + q.x.max(q.y).max(q.z).min(0.0)
+}
+
+fn centered_aabb(he: Vect) -> AABB<Scalar> {
+    AABB::new(Point::from_coordinates(-he), Point::from_coordinates(he))
+}
+
+// The largest distance between the `y` axis and a point of an AABB.
+fn radial_extent(aabb: &AABB<Scalar>) -> Scalar {
+    let x = aabb.mins().x.abs().max(aabb.maxs().x.abs());
+    let z = aabb.mins().z.abs().max(aabb.maxs().z.abs());
+
+    (x * x + z * z).sqrt()
+}
@@ -0,0 +1,699 @@
+//! Loop and Catmull–Clark subdivision surfaces.
+//!
+//! Meshes made of triangles only are refined with Loop's scheme, other meshes with
+//! Catmull–Clark's, which turns every polygon into quads. Vertices at the same position, e.g.,
+//! on both sides of a texture seam, are moved together so that the surface stays closed, while
+//! texture coordinates are linearly interpolated on each side of the seam.
+
+use mesh::{self, Coord, Face, Mesh, Normal, NormalWeighting, Polygon, StorageLocation, Vertex, UV};
+use na::{self, Point2, Point3, Vector2, Vector3};
+use std::collections::HashMap;
+use std::f32;
+use std::fmt;
+use std::sync::Arc;
+
+/// A subdivision scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// Loop's scheme, for triangle meshes.
+    Loop,
+    /// Catmull–Clark's scheme, for meshes with arbitrary polygons.
+    CatmullClark,
+}
+
+/// The reasons why a set of meshes cannot be subdivided.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubdivisionError {
+    /// The set of meshes is empty.
+    NoMesh,
+    /// The meshes do not share the same vertex buffers.
+    UnsharedVertexBuffers,
+    /// A crease references a vertex out of the vertex buffers.
+    InvalidCrease,
+}
+
+impl fmt::Display for SubdivisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            SubdivisionError::NoMesh => "cannot subdivide an empty set of meshes",
+            SubdivisionError::UnsharedVertexBuffers => {
+                "meshes subdivided together must share the same vertex buffers"
+            }
+            SubdivisionError::InvalidCrease => "a crease references a vertex out of the mesh",
+        };
+
+        f.write_str(msg)
+    }
+}
+
+/// A sharp edge of a subdivided mesh.
+#[derive(Clone, Copy, Debug)]
+pub struct Crease {
+    /// The first vertex of the edge.
+    pub a: Vertex,
+    /// The second vertex of the edge.
+    pub b: Vertex,
+    /// The number of subdivision levels during which the edge stays sharp.
+    ///
+    /// Fractional values blend the sharp and smooth rules. An infinite sharpness keeps the edge
+    /// sharp at every level.
+    pub sharpness: f32,
+}
+
+impl Crease {
+    /// Creates a crease along the edge `(a, b)`.
+    pub fn new(a: Vertex, b: Vertex, sharpness: f32) -> Crease {
+        Crease {
+            a: a,
+            b: b,
+            sharpness: sharpness,
+        }
+    }
+}
+
+/// The polygons of a mesh: the polygons it was triangulated from, if any, its faces otherwise.
+pub fn polygons(mesh: &Mesh) -> Vec<Polygon> {
+    match mesh.polygons() {
+        Some(ps) => ps.to_vec(),
+        None => mesh.faces().iter().map(|f| vec![f.x, f.y, f.z]).collect(),
+    }
+}
+
+/// The scheme used to subdivide a set of polygons.
+pub fn scheme(polygons: &[Polygon]) -> Scheme {
+    if polygons.iter().all(|p| p.len() == 3) {
+        Scheme::Loop
+    } else {
+        Scheme::CatmullClark
+    }
+}
+
+/// Subdivides a mesh `levels` times.
+///
+/// Creases index the vertex buffers of the mesh.
+pub fn subdivide(mesh: &Mesh, levels: usize, creases: &[Crease]) -> Result<Mesh, SubdivisionError> {
+    subdivide_shared(&[mesh], levels, creases).map(|mut ms| ms.pop().unwrap())
+}
+
+/// Subdivides `levels` times meshes sharing the same vertex buffers, e.g., the groups of an obj
+/// file.
+///
+/// Edges shared by several meshes are subdivided consistently, and the resulting meshes share
+/// their vertex buffers as well. Creases index the shared vertex buffers.
+pub fn subdivide_shared(
+    meshes: &[&Mesh],
+    levels: usize,
+    creases: &[Crease],
+) -> Result<Vec<Mesh>, SubdivisionError> {
+    if meshes.is_empty() {
+        return Err(SubdivisionError::NoMesh);
+    }
+
+    let nvertices = meshes[0].coords().len();
+
+    if !meshes
+        .iter()
+        .all(|m| m.coords().len() == nvertices && m.uvs().len() == nvertices)
+    {
+        return Err(SubdivisionError::UnsharedVertexBuffers);
+    }
+
+    if creases.iter().any(|c| c.a >= nvertices || c.b >= nvertices) {
+        return Err(SubdivisionError::InvalidCrease);
+    }
+
+    let groups: Vec<Vec<Polygon>> = meshes.iter().map(|m| polygons(m)).collect();
+    let scheme = if groups.iter().all(|g| scheme(&g[..]) == Scheme::Loop) {
+        Scheme::Loop
+    } else {
+        Scheme::CatmullClark
+    };
+
+    let mut level = Level::new(meshes[0].coords(), meshes[0].uvs(), groups, creases);
+
+    for _ in 0..levels {
+        level = match scheme {
+            Scheme::Loop => level.loop_subdivide(),
+            Scheme::CatmullClark => level.catmull_clark(),
+        }
+    }
+
+    let normals: Vec<Normal> = level.normals();
+    let coords: Vec<Coord> = level.corners.iter().map(|p| level.positions[*p]).collect();
+
+    let coords = StorageLocation::SharedImmutable(Arc::new(coords));
+    let normals = StorageLocation::SharedImmutable(Arc::new(normals));
+    let uvs = StorageLocation::SharedImmutable(Arc::new(level.uvs));
+
+    let meshes = level
+        .groups
+        .into_iter()
+        .map(|ps| {
+            let faces = StorageLocation::SharedImmutable(Arc::new(triangulate(&ps[..])));
+            let mut mesh = Mesh::new(
+                coords.clone(),
+                faces,
+                Some(normals.clone()),
+                Some(uvs.clone()),
+            );
+
+            if scheme == Scheme::CatmullClark {
+                mesh.set_polygons(Some(StorageLocation::SharedImmutable(Arc::new(ps))))
+            }
+
+            mesh
+        })
+        .collect();
+
+    Ok(meshes)
+}
+
+/// Infinitely sharp creases along the edges of meshes sharing the same vertex buffers whose
+/// adjacent polygons make an angle greater than `angle` radians.
+pub fn angle_creases(meshes: &[&Mesh], angle: f32) -> Vec<Crease> {
+    if meshes.is_empty() {
+        return Vec::new();
+    }
+
+    let groups = meshes.iter().map(|m| polygons(m)).collect();
+    let level = Level::new(meshes[0].coords(), meshes[0].uvs(), groups, &[]);
+    let topo = Topology::new(&level);
+    let normals: Vec<Vector3<f32>> = topo
+        .faces
+        .iter()
+        .map(|f| polygon_normal(&level.positions[..], &f[..]))
+        .collect();
+
+    // The first vertex at each position.
+    let mut vertices = vec![0; level.positions.len()];
+
+    for (v, p) in level.corners.iter().enumerate().rev() {
+        vertices[*p] = v;
+    }
+
+    let cos = angle.cos();
+
+    topo.edges
+        .iter()
+        .zip(topo.edge_faces.iter())
+        .filter(|&(_, fs)| fs.len() == 2 && na::dot(&normals[fs[0]], &normals[fs[1]]) < cos)
+        .map(|(e, _)| Crease::new(vertices[e.0], vertices[e.1], f32::INFINITY))
+        .collect()
+}
+
+/// Splits polygons into triangle fans.
+pub fn triangulate(polygons: &[Polygon]) -> Vec<Face> {
+    let mut res = Vec::with_capacity(polygons.len() * 2);
+
+    for p in polygons.iter() {
+        for i in 2..p.len() {
+            res.push(Point3::new(p[0], p[i - 1], p[i]));
+        }
+    }
+
+    res
+}
+
+// A subdivision level.
+//
+// Vertices carry the texture coordinates and index a welded position, shared by every vertex
+// with the same coordinates.
+struct Level {
+    positions: Vec<Coord>,
+    // The position of each vertex.
+    corners: Vec<usize>,
+    uvs: Vec<UV>,
+    groups: Vec<Vec<Polygon>>,
+    // The sharpness of the creases, indexed by the sorted positions of their ends.
+    creases: HashMap<(usize, usize), f32>,
+}
+
+impl Level {
+    fn new(coords: &[Coord], uvs: &[UV], groups: Vec<Vec<Polygon>>, creases: &[Crease]) -> Level {
+        let mut ids = HashMap::new();
+        let mut positions = Vec::new();
+        let mut corners = Vec::with_capacity(coords.len());
+
+        for c in coords.iter() {
+            let id = *ids.entry(mesh::position_key(c)).or_insert_with(|| {
+                positions.push(*c);
+                positions.len() - 1
+            });
+
+            corners.push(id);
+        }
+
+        let mut sharpness = HashMap::new();
+
+        for c in creases.iter() {
+            if c.sharpness > 0.0 {
+                let s = sharpness
+                    .entry(edge_key(corners[c.a], corners[c.b]))
+                    .or_insert(0.0f32);
+                *s = s.max(c.sharpness);
+            }
+        }
+
+        Level {
+            positions: positions,
+            corners: corners,
+            uvs: uvs.to_vec(),
+            groups: groups,
+            creases: sharpness,
+        }
+    }
+
+    fn catmull_clark(&self) -> Level {
+        let topo = Topology::new(self);
+        let np = self.positions.len();
+        let ne = topo.edges.len();
+        let face_points: Vec<Vector3<f32>> = topo
+            .faces
+            .iter()
+            .map(|f| self.centroid(&f[..]))
+            .collect();
+
+        let mut positions = Vec::with_capacity(np + ne + face_points.len());
+
+        for v in 0..np {
+            let p = self.positions[v].coords;
+            let n = topo.vertex_edges[v].len();
+
+            let smooth = if n < 3 || topo.vertex_faces[v].is_empty() {
+                p
+            } else {
+                let fs = &topo.vertex_faces[v];
+                let q = fs.iter().fold(na::zero::<Vector3<f32>>(), |acc, f| {
+                    acc + face_points[*f]
+                }) / fs.len() as f32;
+                let r = topo.vertex_edges[v]
+                    .iter()
+                    .fold(na::zero::<Vector3<f32>>(), |acc, e| {
+                        acc + self.midpoint(topo.edges[*e])
+                    }) / n as f32;
+
+                (q + r * 2.0 + p * (n as f32 - 3.0)) / n as f32
+            };
+
+            positions.push(Point3::from_coordinates(topo.vertex_point(self, v, smooth)));
+        }
+
+        for e in 0..ne {
+            let (a, b) = topo.edges[e];
+            let fs = &topo.edge_faces[e];
+
+            let smooth = if fs.len() == 2 {
+                (self.positions[a].coords
+                    + self.positions[b].coords
+                    + face_points[fs[0]]
+                    + face_points[fs[1]])
+                    * 0.25
+            } else {
+                self.midpoint((a, b))
+            };
+
+            positions.push(Point3::from_coordinates(topo.edge_point(self, e, smooth)));
+        }
+
+        for fp in face_points.iter() {
+            positions.push(Point3::from_coordinates(*fp));
+        }
+
+        let mut corners = self.corners.clone();
+        let mut uvs = self.uvs.clone();
+        let mut edge_vertices = HashMap::new();
+        let mut groups = Vec::with_capacity(self.groups.len());
+        let mut f = 0;
+
+        for g in self.groups.iter() {
+            let mut ps = Vec::with_capacity(g.len() * 4);
+
+            for p in g.iter() {
+                let n = p.len();
+                let center = corners.len();
+                let uv = p
+                    .iter()
+                    .fold(na::zero::<Vector2<f32>>(), |acc, v| acc + self.uvs[*v].coords)
+                    / n as f32;
+
+                corners.push(np + ne + f);
+                uvs.push(Point2::from_coordinates(uv));
+
+                let mids: Vec<Vertex> = (0..n)
+                    .map(|i| {
+                        self.edge_vertex(
+                            p[i],
+                            p[(i + 1) % n],
+                            &topo,
+                            &mut corners,
+                            &mut uvs,
+                            &mut edge_vertices,
+                        )
+                    })
+                    .collect();
+
+                for i in 0..n {
+                    ps.push(vec![p[i], mids[i], center, mids[(i + n - 1) % n]]);
+                }
+
+                f += 1;
+            }
+
+            groups.push(ps);
+        }
+
+        Level {
+            positions: positions,
+            corners: corners,
+            uvs: uvs,
+            groups: groups,
+            creases: topo.child_creases(self),
+        }
+    }
+
+    fn loop_subdivide(&self) -> Level {
+        let topo = Topology::new(self);
+        let np = self.positions.len();
+        let ne = topo.edges.len();
+
+        let mut positions = Vec::with_capacity(np + ne);
+
+        for v in 0..np {
+            let p = self.positions[v].coords;
+            let n = topo.vertex_edges[v].len();
+
+            let smooth = if n < 3 {
+                p
+            } else {
+                let nf = n as f32;
+                let c = 0.375 + (2.0 * f32::consts::PI / nf).cos() * 0.25;
+                let beta = (0.625 - c * c) / nf;
+                let neighbors = topo.vertex_edges[v]
+                    .iter()
+                    .fold(na::zero::<Vector3<f32>>(), |acc, e| {
+                        acc + self.positions[topo.other(*e, v)].coords
+                    });
+
+                p * (1.0 - nf * beta) + neighbors * beta
+            };
+
+            positions.push(Point3::from_coordinates(topo.vertex_point(self, v, smooth)));
+        }
+
+        for e in 0..ne {
+            let (a, b) = topo.edges[e];
+            let fs = &topo.edge_faces[e];
+            let opposites = if fs.len() == 2 {
+                opposite(&topo.faces[fs[0]][..], a, b)
+                    .and_then(|c| opposite(&topo.faces[fs[1]][..], a, b).map(|d| (c, d)))
+            } else {
+                None
+            };
+
+            let smooth = match opposites {
+                Some((c, d)) => {
+                    (self.positions[a].coords + self.positions[b].coords) * 0.375
+                        + (self.positions[c].coords + self.positions[d].coords) * 0.125
+                }
+                None => self.midpoint((a, b)),
+            };
+
+            positions.push(Point3::from_coordinates(topo.edge_point(self, e, smooth)));
+        }
+
+        let mut corners = self.corners.clone();
+        let mut uvs = self.uvs.clone();
+        let mut edge_vertices = HashMap::new();
+        let mut groups = Vec::with_capacity(self.groups.len());
+
+        for g in self.groups.iter() {
+            let mut ps = Vec::with_capacity(g.len() * 4);
+
+            for p in g.iter() {
+                assert!(p.len() == 3, "Loop subdivision only applies to triangles.");
+
+                let mut mid = |a, b| {
+                    self.edge_vertex(a, b, &topo, &mut corners, &mut uvs, &mut edge_vertices)
+                };
+                let m01 = mid(p[0], p[1]);
+                let m12 = mid(p[1], p[2]);
+                let m20 = mid(p[2], p[0]);
+
+                ps.push(vec![p[0], m01, m20]);
+                ps.push(vec![p[1], m12, m01]);
+                ps.push(vec![p[2], m20, m12]);
+                ps.push(vec![m01, m12, m20]);
+            }
+
+            groups.push(ps);
+        }
+
+        Level {
+            positions: positions,
+            corners: corners,
+            uvs: uvs,
+            groups: groups,
+            creases: topo.child_creases(self),
+        }
+    }
+
+    // The vertex at the edge point of the edge `(a, b)` of this level, on the next level.
+    //
+    // Edge points are at the index of the edge, after the vertex points.
+    fn edge_vertex(
+        &self,
+        a: Vertex,
+        b: Vertex,
+        topo: &Topology,
+        corners: &mut Vec<usize>,
+        uvs: &mut Vec<UV>,
+        cache: &mut HashMap<(Vertex, Vertex), Vertex>,
+    ) -> Vertex {
+        let key = edge_key(a, b);
+
+        if let Some(v) = cache.get(&key) {
+            return *v;
+        }
+
+        let e = topo.edge_ids[&edge_key(self.corners[a], self.corners[b])];
+        let id = corners.len();
+
+        corners.push(self.positions.len() + e);
+        uvs.push(na::center(&self.uvs[a], &self.uvs[b]));
+        let _ = cache.insert(key, id);
+
+        id
+    }
+
+    fn midpoint(&self, (a, b): (usize, usize)) -> Vector3<f32> {
+        (self.positions[a].coords + self.positions[b].coords) * 0.5
+    }
+
+    fn centroid(&self, face: &[usize]) -> Vector3<f32> {
+        face.iter().fold(na::zero::<Vector3<f32>>(), |acc, p| {
+            acc + self.positions[*p].coords
+        }) / face.len() as f32
+    }
+
+    // Smooth normals of every vertex, computed from the welded positions so that they are
+    // continuous across texture seams.
+    fn normals(&self) -> Vec<Normal> {
+        let polygons: Vec<Polygon> = self
+            .groups
+            .iter()
+            .flat_map(|g| g.iter())
+            .map(|p| p.iter().map(|v| self.corners[*v]).collect())
+            .collect();
+        let faces = triangulate(&polygons[..]);
+        let res = mesh::vertex_normals(&self.positions[..], &faces[..], NormalWeighting::Area);
+
+        self.corners.iter().map(|p| res[*p]).collect()
+    }
+}
+
+// The adjacency of the welded positions of a level.
+struct Topology {
+    // The polygons of every group, as positions.
+    faces: Vec<Vec<usize>>,
+    edges: Vec<(usize, usize)>,
+    edge_ids: HashMap<(usize, usize), usize>,
+    edge_faces: Vec<Vec<usize>>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(level: &Level) -> Topology {
+        let faces: Vec<Vec<usize>> = level
+            .groups
+            .iter()
+            .flat_map(|g| g.iter())
+            .map(|p| p.iter().map(|v| level.corners[*v]).collect())
+            .collect();
+
+        let mut edges = Vec::new();
+        let mut edge_ids = HashMap::new();
+        let mut edge_faces: Vec<Vec<usize>> = Vec::new();
+        let mut vertex_edges = vec![Vec::new(); level.positions.len()];
+        let mut vertex_faces = vec![Vec::new(); level.positions.len()];
+
+        for (f, face) in faces.iter().enumerate() {
+            for (i, a) in face.iter().enumerate() {
+                let key = edge_key(*a, face[(i + 1) % face.len()]);
+
+                let e = match edge_ids.get(&key) {
+                    Some(e) => *e,
+                    None => {
+                        let e = edges.len();
+
+                        edges.push(key);
+                        edge_faces.push(Vec::new());
+                        let _ = edge_ids.insert(key, e);
+                        vertex_edges[key.0].push(e);
+
+                        if key.1 != key.0 {
+                            vertex_edges[key.1].push(e);
+                        }
+
+                        e
+                    }
+                };
+
+                edge_faces[e].push(f);
+                vertex_faces[*a].push(f);
+            }
+        }
+
+        Topology {
+            faces: faces,
+            edges: edges,
+            edge_ids: edge_ids,
+            edge_faces: edge_faces,
+            vertex_edges: vertex_edges,
+            vertex_faces: vertex_faces,
+        }
+    }
+
+    fn other(&self, e: usize, v: usize) -> usize {
+        let (a, b) = self.edges[e];
+
+        if a == v {
+            b
+        } else {
+            a
+        }
+    }
+
+    // Boundary and non-manifold edges are infinitely sharp.
+    fn sharpness(&self, level: &Level, e: usize) -> f32 {
+        if self.edge_faces[e].len() != 2 {
+            f32::INFINITY
+        } else {
+            level.creases.get(&self.edges[e]).cloned().unwrap_or(0.0)
+        }
+    }
+
+    // Applies the sharp rule to the edge point `smooth` of the edge `e`.
+    fn edge_point(&self, level: &Level, e: usize, smooth: Vector3<f32>) -> Vector3<f32> {
+        let s = self.sharpness(level, e);
+
+        if s <= 0.0 {
+            smooth
+        } else {
+            blend(smooth, level.midpoint(self.edges[e]), s)
+        }
+    }
+
+    // Applies the crease and corner rules to the vertex point `smooth` of the position `v`.
+    fn vertex_point(&self, level: &Level, v: usize, smooth: Vector3<f32>) -> Vector3<f32> {
+        let p = level.positions[v].coords;
+        let mut sharp = Vec::new();
+        let mut total = 0.0;
+
+        for e in self.vertex_edges[v].iter() {
+            let s = self.sharpness(level, *e);
+
+            if s > 0.0 {
+                sharp.push(*e);
+                total = total + s;
+            }
+        }
+
+        let rule = match sharp.len() {
+            0 | 1 => return smooth,
+            2 => {
+                let a = level.positions[self.other(sharp[0], v)].coords;
+                let b = level.positions[self.other(sharp[1], v)].coords;
+
+                (a + p * 6.0 + b) * 0.125
+            }
+            _ => p,
+        };
+
+        blend(smooth, rule, total / sharp.len() as f32)
+    }
+
+    // The creases of the next level: each half of a crease is one level less sharp.
+    fn child_creases(&self, level: &Level) -> HashMap<(usize, usize), f32> {
+        let np = level.positions.len();
+        let mut res = HashMap::new();
+
+        for (key, s) in level.creases.iter() {
+            if let Some(e) = self.edge_ids.get(key) {
+                if *s > 1.0 {
+                    let _ = res.insert(edge_key(key.0, np + *e), *s - 1.0);
+                    let _ = res.insert(edge_key(np + *e, key.1), *s - 1.0);
+                }
+            }
+        }
+
+        res
+    }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// The vertex of a triangle that is not on the edge `(a, b)`.
+fn opposite(face: &[usize], a: usize, b: usize) -> Option<usize> {
+    face.iter().find(|v| **v != a && **v != b).cloned()
+}
+
+// Blends a smooth and a sharp rule depending on the sharpness `s`.
+fn blend(smooth: Vector3<f32>, sharp: Vector3<f32>, s: f32) -> Vector3<f32> {
+    if s >= 1.0 {
+        sharp
+    } else {
+        smooth * (1.0 - s) + sharp * s
+    }
+}
+
+// The normal of a polygon given by its positions, scaled by twice its area.
+fn polygon_cross(positions: &[Coord], face: &[usize]) -> Vector3<f32> {
+    let mut res = na::zero::<Vector3<f32>>();
+
+    for i in 2..face.len() {
+        let a = positions[face[0]];
+        let b = positions[face[i - 1]];
+        let c = positions[face[i]];
+
+        res = res + (b - a).cross(&(c - a));
+    }
+
+    res
+}
+
+fn polygon_normal(positions: &[Coord], face: &[usize]) -> Vector3<f32> {
+    let n = polygon_cross(positions, face);
+
+    if na::norm(&n) > 0.0 {
+        na::normalize(&n)
+    } else {
+        n
+    }
+}
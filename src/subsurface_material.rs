@@ -0,0 +1,219 @@
+//! A material scattering light below the surface of closed geometries, e.g., skin, wax or marble.
+
+use intersection::Intersection;
+use material::Material;
+use math::{self, Point, Scalar, Vect};
+use medium;
+use na::{self, Point2, Point3, Point4, Vector3};
+use ncollide3d::query::Ray;
+use rand::random;
+use ray_with_energy::RayWithEnergy;
+use sampling;
+use scene::Scene;
+use texture2d::Texture2d;
+
+// The maximum number of scattering events of a random walk.
+const MAX_STEPS: usize = 256;
+
+/// A material whose light enters the surface and scatters inside the geometry before leaving
+/// it, traced by random walks.
+///
+/// The interior is a homogeneous medium with a mean free path per color channel: the longer the
+/// mean free path, the deeper light travels and the more translucent the material looks. The
+/// scattering albedo of the medium is chosen so that thick objects reflect the diffuse color of
+/// the material. The geometry must be closed, and its node not solid so that the walks can
+/// find the surface from the inside.
+pub struct SubsurfaceMaterial {
+    ambiant_color: Point3<f32>,
+    diffuse_color: Point3<f32>,
+    mean_free_path: Vector3<f32>,
+    texture: Option<Texture2d>,
+}
+
+impl SubsurfaceMaterial {
+    /// Creates a subsurface scattering material with the given mean free path for each color
+    /// channel, in scene units.
+    ///
+    /// The diffuse color is modulated by the texture, if any.
+    pub fn new(
+        ambiant_color: Point3<f32>,
+        diffuse_color: Point3<f32>,
+        mean_free_path: Vector3<f32>,
+        texture: Option<Texture2d>,
+    ) -> SubsurfaceMaterial {
+        assert!(
+            mean_free_path.iter().all(|d| *d > 0.0),
+            "The mean free paths must be positive."
+        );
+
+        SubsurfaceMaterial {
+            ambiant_color: ambiant_color,
+            diffuse_color: diffuse_color,
+            mean_free_path: mean_free_path,
+            texture: texture,
+        }
+    }
+
+    /// The mean free path of each color channel.
+    pub fn mean_free_path(&self) -> &Vector3<f32> {
+        &self.mean_free_path
+    }
+
+    fn texture_color(&self, uvs: &Option<Point2<Scalar>>) -> Vector3<f32> {
+        match (uvs.as_ref(), self.texture.as_ref()) {
+            (Some(uvs), Some(tex)) => {
+                let c = tex.sample(uvs);
+                Vector3::new(c.x, c.y, c.z)
+            }
+            _ => Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    // Walks a path of light inside the geometry from `pt`, on its surface with the outward
+    // geometric normal `normal` and with coordinate errors bounded by `error`, and returns the
+    // light leaving the surface there.
+    //
+    // The distances are sampled with the coefficients of a channel chosen at random, and
+    // weighted by the average of the probabilities of the three channels.
+    fn random_walk(
+        &self,
+        pt: &Point,
+        error: &Vect,
+        normal: &Vect,
+        albedo: &Vector3<f32>,
+        scene: &Scene,
+    ) -> Vector3<f32> {
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        let sigma_t = self.mean_free_path.map(|d| 1.0 / d);
+        let sigma_s = sigma_t.component_mul(&albedo.map(single_scattering_albedo));
+        let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+
+        let dir = -sampling::cosine_direction(normal);
+        let mut ray = Ray::new(math::offset_ray_origin(pt, error, normal, &dir), dir);
+
+        for _ in 0..MAX_STEPS {
+            let channel = ((random::<f32>() * 3.0) as usize).min(2);
+            let t = -(1.0 - random::<Scalar>()).ln() / sigma_t[channel] as Scalar;
+
+            let hit = match scene.cast_ray(&ray, 0.0) {
+                Some((_, inter)) => inter,
+                // The geometry is not closed.
+                None => return zero,
+            };
+
+            if t >= hit.toi {
+                let tr = sigma_t.map(|s| (-s * hit.toi as f32).exp());
+
+                if mean(&tr) == 0.0 {
+                    return zero;
+                }
+
+                throughput = throughput.component_mul(&tr) / mean(&tr);
+
+                let exit = ray.origin + ray.dir * hit.toi;
+                let outward = if na::dot(&hit.normal, &ray.dir) > 0.0 {
+                    hit.normal
+                } else {
+                    -hit.normal
+                };
+
+                return throughput.component_mul(&irradiance(&exit, &hit.error, &outward, scene));
+            }
+
+            let tr = sigma_t.map(|s| (-s * t as f32).exp());
+            let pdf = sigma_t.component_mul(&tr);
+            throughput = throughput.component_mul(&sigma_s.component_mul(&tr)) / mean(&pdf);
+
+            // Russian roulette on the dimmed paths.
+            let max = throughput.x.max(throughput.y).max(throughput.z);
+
+            if max < 0.1 {
+                if random::<f32>() >= max {
+                    return zero;
+                }
+
+                throughput = throughput / max;
+            }
+
+            let orig = ray.origin + ray.dir * t;
+            ray = Ray::new(orig, medium::sample_henyey_greenstein(&ray.dir, 0.0));
+        }
+
+        zero
+    }
+}
+
+impl Material for SubsurfaceMaterial {
+    fn ambiant(&self, _: &Point, _: &Vect, uvs: &Option<Point2<Scalar>>) -> Point4<f32> {
+        let c = self.ambiant_color.coords.component_mul(&self.texture_color(uvs));
+
+        Point4::new(c.x, c.y, c.z, 1.0)
+    }
+
+    fn diffuse(&self, _: &Point, _: &Vect, uvs: &Option<Point2<Scalar>>) -> Point3<f32> {
+        Point3::from_coordinates(self.diffuse_color.coords.component_mul(&self.texture_color(uvs)))
+    }
+
+    fn compute(
+        &self,
+        ray: &RayWithEnergy,
+        point: &Point,
+        inter: &Intersection,
+        scene: &Scene,
+    ) -> Point4<f32> {
+        let mut tex_color = self.texture_color(&inter.uvs);
+
+        if let Some(c) = inter.color {
+            tex_color = tex_color.component_mul(&c.coords);
+        }
+
+        let albedo = self
+            .diffuse_color
+            .coords
+            .component_mul(&tex_color)
+            .map(|a| a.max(0.0).min(1.0));
+        let outward = if na::dot(&inter.normal, &ray.ray.dir) < 0.0 {
+            inter.normal
+        } else {
+            -inter.normal
+        };
+
+        let res = self.ambiant_color.coords.component_mul(&tex_color)
+            + self.random_walk(point, &inter.error, &outward, &albedo, scene);
+
+        Point4::new(res.x, res.y, res.z, 1.0)
+    }
+}
+
+// The light received from the light sources by the point `pt`, with coordinate errors bounded by
+// `error`, of a surface with the outward normal `normal`, weighted by the cosine of its
+// incidence.
+fn irradiance(pt: &Point, error: &Vect, normal: &Vect, scene: &Scene) -> Vector3<f32> {
+    let mut res = Vector3::new(0.0f32, 0.0, 0.0);
+
+    for light in scene.lights().iter() {
+        let mut acc = Vector3::new(0.0f32, 0.0, 0.0);
+
+        scene.visible_light_samples(light, pt, error, normal, |ldir, filter| {
+            let cos = (na::dot(ldir, normal) as f32).max(0.0);
+            acc = acc + light.color.coords.component_mul(filter) * cos;
+        });
+
+        res.axpy(1.0 / (light.racsample * light.racsample) as f32, &acc, 1.0);
+    }
+
+    res
+}
+
+// The single-scattering albedo of a medium whose multiple scattering reflects the fraction
+// `albedo` of the light, following the inversion of van de Hulst's formula by Chiang et al.
+fn single_scattering_albedo(albedo: f32) -> f32 {
+    let a = albedo;
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+
+    1.0 - s * s
+}
+
+fn mean(v: &Vector3<f32>) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
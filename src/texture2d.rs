@@ -47,16 +47,19 @@ fn get_texture_manager<T, F: Fn(&mut TextureManager) -> T>(f: F) -> T {
 }
 
 // FIXME: move this to its own file
+#[derive(Clone, Copy)]
 pub enum Interpolation {
     Bilinear,
     Nearest
 }
 
+#[derive(Clone, Copy)]
 pub enum Overflow {
     ClampToEdges,
     Wrap
 }
 
+#[derive(Clone)]
 pub struct Texture2d {
     data:     Arc<ImageData>,
     interpol: Interpolation,
@@ -204,6 +207,18 @@ impl Texture2d {
         &self.data.pixels[y * self.data.dims.x + x]
     }
 
+    /// The number of texels along the `u` axis.
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.data.dims.x
+    }
+
+    /// The number of texels along the `v` axis.
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.data.dims.y
+    }
+
     pub fn sample(&self, coords: &Point2<Scalar>) -> Point4<f32> {
         let mut ux = coords.x as f32;
         let mut uy = coords.y as f32;
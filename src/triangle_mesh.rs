@@ -0,0 +1,522 @@
+//! Triangle mesh geometry with its own SAH bounding volume hierarchy.
+
+use bvh::{self, BVH};
+use math::{self, Isometry, Point, Scalar, Vect};
+use na::{self, Point2, Point3, Vector4};
+use ncollide3d::bounding_volume::{HasBoundingVolume, AABB};
+use intersection::Intersection;
+use material::Material;
+use ncollide3d::query::Ray;
+use ncollide3d::shape::TriMesh;
+use normal_map::NormalMap;
+use num::Bounded;
+use ray_packet::{Mask, RayPacket, PACKET_SIZE};
+use scene_node::Geometry;
+use std::sync::Arc;
+
+/// The appearance of a subset of the triangles of a mesh, e.g., of a group of an OBJ file.
+#[derive(Clone)]
+pub struct MeshPart {
+    /// The material of the triangles, overriding the material of the scene node.
+    pub material: Arc<Box<Material + Sync + Send>>,
+    /// The normal map of the triangles.
+    pub nmap: Option<NormalMap>,
+}
+
+/// A triangle mesh whose triangles are indexed by a flattened SAH BVH.
+///
+/// Vertex buffers are reference-counted so that several meshes can share them. Meshes with
+/// vertex normals are smooth-shaded. The triangles can be split into parts with their own
+/// material and normal map, so that all the groups of an OBJ file share a single BVH.
+///
+/// Unlike the ncollide `TriMesh` it replaces, this mesh implements `Geometry` but not
+/// `RayCast`, since the implementation of `Geometry` for every `RayCast` shape would not keep
+/// its smooth normals, tangents and parts. Use `to_trimesh` where an ncollide shape is needed.
+pub struct TriangleMesh {
+    vertices: Arc<Vec<Point>>,
+    indices: Vec<Point3<usize>>,
+    normals: Option<Arc<Vec<Vect>>>,
+    uvs: Option<Arc<Vec<Point2<Scalar>>>>,
+    tangents: Option<Arc<Vec<Vector4<Scalar>>>>,
+    parts: Vec<MeshPart>,
+    part_ids: Vec<usize>,
+    bvh: BVH,
+}
+
+impl TriangleMesh {
+    /// Creates a triangle mesh and builds its BVH.
+    pub fn new(
+        vertices: Arc<Vec<Point>>,
+        indices: Vec<Point3<usize>>,
+        normals: Option<Arc<Vec<Vect>>>,
+        uvs: Option<Arc<Vec<Point2<Scalar>>>>,
+    ) -> TriangleMesh {
+        if let Some(ref normals) = normals {
+            assert!(
+                normals.len() == vertices.len(),
+                "A triangle mesh must have as many normals as vertices."
+            );
+        }
+
+        if let Some(ref uvs) = uvs {
+            assert!(
+                uvs.len() == vertices.len(),
+                "A triangle mesh must have as many texture coordinates as vertices."
+            );
+        }
+
+        let aabbs: Vec<AABB<Scalar>> = indices
+            .iter()
+            .map(|t| {
+                let a = &vertices[t.x];
+                let b = &vertices[t.y];
+                let c = &vertices[t.z];
+
+                AABB::new(na::inf(&na::inf(a, b), c), na::sup(&na::sup(a, b), c))
+            })
+            .collect();
+
+        let bvh = BVH::new(&aabbs[..]);
+
+        TriangleMesh {
+            vertices: vertices,
+            indices: indices,
+            normals: normals,
+            uvs: uvs,
+            tangents: None,
+            parts: Vec::new(),
+            part_ids: Vec::new(),
+            bvh: bvh,
+        }
+    }
+
+    /// The vertices of this mesh.
+    #[inline]
+    pub fn vertices(&self) -> &Arc<Vec<Point>> {
+        &self.vertices
+    }
+
+    /// The triangles of this mesh.
+    #[inline]
+    pub fn indices(&self) -> &[Point3<usize>] {
+        &self.indices[..]
+    }
+
+    /// The vertex normals of this mesh.
+    #[inline]
+    pub fn normals(&self) -> Option<&Arc<Vec<Vect>>> {
+        self.normals.as_ref()
+    }
+
+    /// The texture coordinates of this mesh.
+    #[inline]
+    pub fn uvs(&self) -> Option<&Arc<Vec<Point2<Scalar>>>> {
+        self.uvs.as_ref()
+    }
+
+    /// The vertex tangents of this mesh.
+    ///
+    /// The last component of each tangent is the handedness of the tangent frame.
+    #[inline]
+    pub fn tangents(&self) -> Option<&Arc<Vec<Vector4<Scalar>>>> {
+        self.tangents.as_ref()
+    }
+
+    /// Sets the vertex tangents of this mesh, e.g., computed by `compute_tangents`.
+    ///
+    /// Without vertex tangents, the tangent frame of a hit is derived from the texture
+    /// coordinates of the triangle hit.
+    pub fn set_tangents(&mut self, tangents: Option<Arc<Vec<Vector4<Scalar>>>>) {
+        if let Some(ref tangents) = tangents {
+            assert!(
+                tangents.len() == self.vertices.len(),
+                "A triangle mesh must have as many tangents as vertices."
+            );
+        }
+
+        self.tangents = tangents
+    }
+
+    /// The parts of this mesh, and the index of the part of each triangle.
+    #[inline]
+    pub fn parts(&self) -> (&[MeshPart], &[usize]) {
+        (&self.parts[..], &self.part_ids[..])
+    }
+
+    /// Splits the triangles of this mesh into parts, the `i`-th triangle belonging to the part
+    /// `parts[part_ids[i]]`.
+    ///
+    /// Without parts, the triangles use the material and normal map of their scene node.
+    pub fn set_parts(&mut self, parts: Vec<MeshPart>, part_ids: Vec<usize>) {
+        assert!(
+            part_ids.len() == self.indices.len(),
+            "A triangle mesh must have as many part indices as triangles."
+        );
+        assert!(
+            part_ids.iter().all(|i| *i < parts.len()),
+            "The part indices of a triangle mesh must be valid."
+        );
+
+        self.parts = parts;
+        self.part_ids = part_ids;
+    }
+
+    /// Converts this mesh into an ncollide triangle mesh, discarding its normals, tangents and
+    /// parts.
+    pub fn to_trimesh(&self) -> TriMesh<Scalar> {
+        TriMesh::new(
+            (*self.vertices).clone(),
+            self.indices.clone(),
+            self.uvs.as_ref().map(|uvs| (**uvs).clone()),
+        )
+    }
+
+    /// The BVH of this mesh triangles.
+    #[inline]
+    pub fn bvh(&self) -> &BVH {
+        &self.bvh
+    }
+
+    /// Casts a ray expressed in the mesh local space.
+    ///
+    /// Returns the index of the closest triangle hit, the time of impact, and the barycentric
+    /// coordinates of the hit relative to the second and third vertices of the triangle.
+    pub fn cast_local_ray(
+        &self,
+        ray: &Ray<Scalar>,
+        max_toi: Scalar,
+    ) -> Option<(usize, Scalar, Scalar, Scalar)> {
+        self.bvh
+            .cast_ray(ray, max_toi, |i, max_toi| {
+                let t = &self.indices[i];
+
+                ray_triangle(
+                    ray,
+                    &self.vertices[t.x],
+                    &self.vertices[t.y],
+                    &self.vertices[t.z],
+                    max_toi,
+                )
+                .map(|(toi, u, v)| (toi, (u, v)))
+            })
+            .map(|(i, (toi, (u, v)))| (i, toi, u, v))
+    }
+
+    // Builds the intersection with the `i`-th triangle from the local-space ray hitting it.
+    fn intersection(
+        &self,
+        m: &Isometry,
+        local_ray: &Ray<Scalar>,
+        i: usize,
+        toi: Scalar,
+        u: Scalar,
+        v: Scalar,
+    ) -> Intersection {
+        let t = &self.indices[i];
+        let a = &self.vertices[t.x];
+        let b = &self.vertices[t.y];
+        let c = &self.vertices[t.z];
+        let w = 1.0 - u - v;
+
+        let face_normal = na::normalize(&(*b - *a).cross(&(*c - *a)));
+
+        let smooth_normal = match self.normals {
+            Some(ref ns) => {
+                let n = ns[t.x] * w + ns[t.y] * u + ns[t.z] * v;
+                let norm = na::norm(&n);
+
+                if norm > 0.0 {
+                    n / norm
+                } else {
+                    face_normal
+                }
+            }
+            None => face_normal,
+        };
+
+        let tangent_frame = self.tangent(i, w, u, v).and_then(|(tangent, handedness)| {
+            // Gram-Schmidt orthogonalization against the shading normal.
+            let tangent = tangent - smooth_normal * na::dot(&smooth_normal, &tangent);
+            let norm = na::norm(&tangent);
+
+            if norm > 0.0 {
+                let tangent = tangent / norm;
+                let bitangent = smooth_normal.cross(&tangent) * handedness;
+
+                Some((m * tangent, m * bitangent))
+            } else {
+                None
+            }
+        });
+
+        // Both normals face the ray origin.
+        let normal = if na::dot(&face_normal, &local_ray.dir) > 0.0 {
+            -face_normal
+        } else {
+            face_normal
+        };
+
+        let flipped = na::dot(&smooth_normal, &normal) < 0.0;
+        let shading_normal = if flipped { -smooth_normal } else { smooth_normal };
+        let tangent_frame = tangent_frame.map(|(t, b)| if flipped { (-t, -b) } else { (t, b) });
+
+        let uvs = self.uvs.as_ref().map(|uvs| {
+            let uv = uvs[t.x].coords * w + uvs[t.y].coords * u + uvs[t.z].coords * v;
+
+            Point2::from_coordinates(uv)
+        });
+
+        let mut res = Intersection::new(toi, m * normal, m * shading_normal, uvs);
+        res.tangent_frame = tangent_frame;
+
+        if let Some(part) = self.part_ids.get(i).map(|id| &self.parts[*id]) {
+            res.material = Some(part.material.clone());
+
+            if let Some(ref nmap) = part.nmap {
+                nmap.apply(&mut res);
+            }
+        }
+
+        res
+    }
+
+    // The unnormalized local-space tangent of the `i`-th triangle at the given barycentric
+    // coordinates, and the handedness of the tangent frame.
+    //
+    // Uses the vertex tangents if any, or the texture coordinates derivatives of the triangle.
+    fn tangent(&self, i: usize, w: Scalar, u: Scalar, v: Scalar) -> Option<(Vect, Scalar)> {
+        let t = &self.indices[i];
+
+        if let Some(ref tangents) = self.tangents {
+            let ta = &tangents[t.x];
+            let tb = &tangents[t.y];
+            let tc = &tangents[t.z];
+            let tangent = Vect::new(ta.x, ta.y, ta.z) * w
+                + Vect::new(tb.x, tb.y, tb.z) * u
+                + Vect::new(tc.x, tc.y, tc.z) * v;
+
+            Some((tangent, if ta.w < 0.0 { -1.0 } else { 1.0 }))
+        } else {
+            self.uvs.as_ref().and_then(|uvs| {
+                triangle_tangent(
+                    &self.vertices[t.x],
+                    &self.vertices[t.y],
+                    &self.vertices[t.z],
+                    &uvs[t.x],
+                    &uvs[t.y],
+                    &uvs[t.z],
+                )
+            })
+        }
+    }
+}
+
+impl Geometry for TriangleMesh {
+    #[inline]
+    fn cast(&self, m: &Isometry, ray: &Ray<Scalar>, _: bool) -> Option<Intersection> {
+        let local_ray = Ray::new(m.inverse() * ray.origin, m.inverse() * ray.dir);
+
+        self.cast_local_ray(&local_ray, Bounded::max_value())
+            .map(|(i, toi, u, v)| self.intersection(m, &local_ray, i, toi, u, v))
+    }
+
+    fn cast_packet(
+        &self,
+        m: &Isometry,
+        packet: &RayPacket,
+        mask: &Mask,
+        max_tois: &[Scalar; PACKET_SIZE],
+        _: bool,
+    ) -> Vec<Option<Intersection>> {
+        let local_packet = packet.inverse_transformed(m);
+        let mut max_tois = *max_tois;
+        let mut hits = [None; PACKET_SIZE];
+
+        for lane in 0..PACKET_SIZE {
+            if !mask[lane] {
+                max_tois[lane] = -1.0;
+            }
+        }
+
+        self.bvh.cast_packet(&local_packet, &mut max_tois, |i, mask, max_tois| {
+            let t = &self.indices[i];
+            let inters = local_packet.intersects_triangle(
+                &self.vertices[t.x],
+                &self.vertices[t.y],
+                &self.vertices[t.z],
+                max_tois,
+                mask,
+            );
+
+            for lane in 0..PACKET_SIZE {
+                if let Some((toi, u, v)) = inters[lane] {
+                    max_tois[lane] = toi;
+                    hits[lane] = Some((i, toi, u, v));
+                }
+            }
+        });
+
+        (0..packet.len())
+            .map(|lane| {
+                hits[lane].map(|(i, toi, u, v)| {
+                    self.intersection(m, local_packet.ray(lane), i, toi, u, v)
+                })
+            })
+            .collect()
+    }
+
+    #[inline]
+    fn aabb(&self, m: &Isometry) -> AABB<Scalar> {
+        self.bounding_volume(m)
+    }
+
+    // The transformation of the ray to the local frame rounds it by γ(3), and the Möller–Trumbore
+    // test computes the time of impact with about ten dependent operations.
+    #[inline]
+    fn hit_error(&self, ray: &Ray<Scalar>, toi: Scalar) -> Vect {
+        math::hit_point_error(ray, toi, math::gamma(13))
+    }
+}
+
+impl HasBoundingVolume<Scalar, AABB<Scalar>> for TriangleMesh {
+    #[inline]
+    fn bounding_volume(&self, m: &Isometry) -> AABB<Scalar> {
+        match self.bvh.aabb() {
+            Some(aabb) => bvh::transform_aabb(aabb, m),
+            None => AABB::new(m * Point::origin(), m * Point::origin()),
+        }
+    }
+}
+
+/// Möller–Trumbore ray-triangle intersection.
+///
+/// Returns the time of impact and the barycentric coordinates `(u, v)` of the hit relative to
+/// `b` and `c`.
+#[inline]
+pub fn ray_triangle(
+    ray: &Ray<Scalar>,
+    a: &Point,
+    b: &Point,
+    c: &Point,
+    max_toi: Scalar,
+) -> Option<(Scalar, Scalar, Scalar)> {
+    let ab: Vect = *b - *a;
+    let ac: Vect = *c - *a;
+    let p = ray.dir.cross(&ac);
+    let det = na::dot(&ab, &p);
+
+    if det == 0.0 {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - *a;
+    let u = na::dot(&s, &p) * inv_det;
+
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(&ab);
+    let v = na::dot(&ray.dir, &q) * inv_det;
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let toi = na::dot(&ac, &q) * inv_det;
+
+    if toi < 0.0 || toi > max_toi {
+        None
+    } else {
+        Some((toi, u, v))
+    }
+}
+
+/// The tangent of a triangle, i.e., the derivative of its points with respect to the first
+/// texture coordinate, and the handedness of its tangent frame.
+///
+/// Returns `None` if the texture coordinates of the triangle are degenerate.
+pub fn triangle_tangent(
+    a: &Point,
+    b: &Point,
+    c: &Point,
+    uva: &Point2<Scalar>,
+    uvb: &Point2<Scalar>,
+    uvc: &Point2<Scalar>,
+) -> Option<(Vect, Scalar)> {
+    let e1 = *b - *a;
+    let e2 = *c - *a;
+    let duv1 = *uvb - *uva;
+    let duv2 = *uvc - *uva;
+    let det = duv1.x * duv2.y - duv1.y * duv2.x;
+
+    if det == 0.0 {
+        return None;
+    }
+
+    let dpdu = (e1 * duv2.y - e2 * duv1.y) / det;
+    let dpdv = (e2 * duv1.x - e1 * duv2.x) / det;
+    let handedness = if na::dot(&e1.cross(&e2).cross(&dpdu), &dpdv) < 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+
+    Some((dpdu, handedness))
+}
+
+/// Computes per-vertex tangents by accumulating the tangents of the triangles around each
+/// vertex, orthogonalized against the vertex normals.
+///
+/// The last component of each tangent is the handedness of the tangent frame. Vertices with
+/// no valid tangent get an arbitrary one orthogonal to their normal.
+pub fn compute_tangents(
+    vertices: &[Point],
+    normals: &[Vect],
+    uvs: &[Point2<Scalar>],
+    indices: &[Point3<usize>],
+) -> Vec<Vector4<Scalar>> {
+    let mut tangents = vec![na::zero::<Vect>(); vertices.len()];
+    let mut handedness = vec![0.0 as Scalar; vertices.len()];
+
+    for t in indices.iter() {
+        let tangent = triangle_tangent(
+            &vertices[t.x],
+            &vertices[t.y],
+            &vertices[t.z],
+            &uvs[t.x],
+            &uvs[t.y],
+            &uvs[t.z],
+        );
+
+        if let Some((tangent, h)) = tangent {
+            for i in [t.x, t.y, t.z].iter() {
+                tangents[*i] = tangents[*i] + tangent;
+                handedness[*i] = handedness[*i] + h;
+            }
+        }
+    }
+
+    tangents
+        .iter()
+        .zip(normals.iter())
+        .zip(handedness.iter())
+        .map(|((t, n), h)| {
+            let mut t = *t - *n * na::dot(n, t);
+
+            if na::norm(&t) == 0.0 {
+                // Any direction orthogonal to the normal.
+                t = if n.x.abs() > n.z.abs() {
+                    Vect::new(-n.y, n.x, 0.0)
+                } else {
+                    Vect::new(0.0, -n.z, n.y)
+                };
+            }
+
+            let t = if na::norm(&t) > 0.0 { na::normalize(&t) } else { t };
+
+            Vector4::new(t.x, t.y, t.z, if *h < 0.0 { -1.0 } else { 1.0 })
+        })
+        .collect()
+}